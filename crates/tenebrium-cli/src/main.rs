@@ -1,15 +1,27 @@
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::{thread_rng, RngCore};
+use tenebrium_consensus::{header_hash, Block};
 use tenebrium_core::{
-    address_from_pubkey_hex, generate_keypair, sign_message_hex, verify_message_hex, WalletError,
-    wallet_file_from_secret, wallet_file_reencrypt, wallet_keypair_from_file, WalletFile,
-    WalletKeypair,
+    address_from_pubkey_hex, generate_keypair, mnemonic_from_entropy, script_pubkey_from_address,
+    secret_from_mnemonic, sign_message_hex, verify_message_hex, WalletError,
+    wallet_file_from_secret, wallet_file_reencrypt, wallet_file_watch_only,
+    wallet_keypair_from_file, KdfParams,
+    WalletFile, WalletKeypair,
 };
-use tenebrium_utxo::{tx_sighash_v2, OutPoint, Transaction, TxIn, TxOut, UtxoError};
+use tenebrium_utxo::{
+    sighash_for_input, tx_sighash_v2, txid_to_display_hex, InMemoryUtxoSet, OutPoint, Transaction,
+    TxIn, TxOut, UtxoError, UtxoSet,
+};
+
+/// script_sig length of a signed input (64-byte signature + 32-byte pubkey)
+const SIGNED_SCRIPT_SIG_LEN: usize = 96;
+/// Minimum value a sweep output must exceed after fees
+const SWEEP_DUST_LIMIT: u64 = 546;
 
 #[derive(Parser)]
 #[command(name = "tenebrium-cli")]
@@ -40,7 +52,19 @@ enum WalletCommand {
         #[arg(long)]
         out: Option<PathBuf>,
     },
-    /// Derive address from public or secret key
+    /// Generate a new keypair backed by a BIP39 mnemonic phrase
+    Mnemonic {
+        /// Phrase length: 12, 15, 18, 21 or 24 words
+        #[arg(long, default_value_t = 24)]
+        words: usize,
+        /// Optional BIP39 passphrase mixed into the derived key
+        #[arg(long, default_value = "")]
+        mnemonic_passphrase: String,
+        /// Write JSON output to file
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Derive address from public or secret key, or a mnemonic phrase
     Address {
         /// Public key hex (32 bytes)
         #[arg(long)]
@@ -48,6 +72,12 @@ enum WalletCommand {
         /// Secret key hex (32 bytes)
         #[arg(long)]
         secret: Option<String>,
+        /// BIP39 mnemonic phrase
+        #[arg(long)]
+        mnemonic: Option<String>,
+        /// BIP39 passphrase used with --mnemonic
+        #[arg(long, default_value = "")]
+        mnemonic_passphrase: String,
     },
     /// Sign a message
     Sign {
@@ -91,6 +121,21 @@ enum WalletCommand {
         #[arg(long)]
         passphrase: Option<String>,
     },
+    /// Show non-secret wallet file fields without decrypting
+    Inspect {
+        /// Input path (JSON)
+        #[arg(long)]
+        input: PathBuf,
+    },
+    /// Write a watch-only copy of a wallet file, without the encrypted secret
+    ExportWatch {
+        /// Input wallet path (JSON)
+        #[arg(long)]
+        input: PathBuf,
+        /// Output watch-only wallet path (JSON)
+        #[arg(long)]
+        out: PathBuf,
+    },
     /// Re-encrypt wallet file (backup with new passphrase)
     Backup {
         /// Input wallet path (JSON)
@@ -106,6 +151,48 @@ enum WalletCommand {
         #[arg(long)]
         new_passphrase: Option<String>,
     },
+    /// Sweep all funds of a key to a freshly generated key
+    Rotate {
+        /// Old secret key hex (32 bytes)
+        #[arg(long)]
+        secret: String,
+        /// UTXO JSONL input (each line is {outpoint, txout})
+        #[arg(long)]
+        utxo: PathBuf,
+        /// Fee rate (satoshis per byte)
+        #[arg(long)]
+        fee_rate: u64,
+        /// Output path for the new encrypted wallet file
+        #[arg(long, default_value = "wallet.json")]
+        new_out: PathBuf,
+        /// Passphrase for the new wallet (prompt if omitted)
+        #[arg(long)]
+        passphrase: Option<String>,
+        /// Output path for the sweep tx (JSON). If omitted, prints to stdout.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Rebuild a key's UTXO set and balance by scanning stored blocks
+    Rescan {
+        /// Node data directory (reads `chain.sled`; the node must be stopped)
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Exported block file (JSONL, one block per line in chain order)
+        #[arg(long)]
+        blocks_file: Option<PathBuf>,
+        /// Secret key hex (32 bytes)
+        #[arg(long)]
+        secret: Option<String>,
+        /// Address to scan for
+        #[arg(long)]
+        address: Option<String>,
+        /// Skip blocks below this height
+        #[arg(long, default_value_t = 0)]
+        from_height: u32,
+        /// Write the found UTXOs as JSONL (usable with `tx build --utxo`)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -133,24 +220,35 @@ enum TxCommand {
         /// Secret key hex
         #[arg(long)]
         secret: String,
+        /// UTXO JSONL with the outputs being spent. When set, each input is
+        /// signed against its own per-input sighash instead of the shared
+        /// sighash_v2.
+        #[arg(long)]
+        utxo: Option<PathBuf>,
     },
-    /// Print txid (v2) and sighash
+    /// Print txid (v2, display byte order) and sighash
     Info {
         /// Input tx JSON
         #[arg(long)]
         input: PathBuf,
     },
+    /// Check a signed transaction's signatures and values against UTXO JSONL
+    Verify {
+        /// Input tx JSON
+        #[arg(long)]
+        input: PathBuf,
+        /// UTXO JSONL holding the outputs it spends
+        #[arg(long)]
+        utxo: PathBuf,
+    },
     /// Build a transaction from UTXO JSONL
     Build {
         /// UTXO JSONL input (each line is {outpoint, txout})
         #[arg(long)]
         utxo: PathBuf,
-        /// Recipient script_pubkey hex
-        #[arg(long)]
-        to_script: String,
-        /// Amount to send
-        #[arg(long)]
-        amount: u64,
+        /// Recipient as script_pubkey_hex:amount; repeat to pay several outputs
+        #[arg(long = "to")]
+        to: Vec<String>,
         /// Change script_pubkey hex
         #[arg(long)]
         change_script: String,
@@ -169,6 +267,9 @@ enum TxCommand {
         /// Sign all inputs with this secret key hex
         #[arg(long)]
         sign_secret: Option<String>,
+        /// Sort inputs and outputs per BIP69 before signing
+        #[arg(long = "sort-bip69")]
+        sort_bip69: bool,
     },
 }
 
@@ -184,8 +285,14 @@ enum CliError {
     Utxo(#[from] UtxoError),
     #[error("hex error: {0}")]
     Hex(#[from] hex::FromHexError),
+    #[error("sled error: {0}")]
+    Sled(#[from] sled::Error),
     #[error("invalid arguments: {0}")]
     InvalidArgs(String),
+    #[error("signature for input {0} does not verify")]
+    SignatureCheck(usize),
+    #[error("transaction failed verification")]
+    VerifyFailed,
 }
 
 #[derive(clap::ValueEnum, Debug, Clone, Copy)]
@@ -203,6 +310,63 @@ struct KeygenOutput {
     address: String,
 }
 
+#[derive(Serialize)]
+struct MnemonicOutput {
+    mnemonic: String,
+    #[serde(flatten)]
+    key: KeygenOutput,
+}
+
+#[derive(Serialize)]
+struct RescanOutput {
+    script_pubkey_hex: String,
+    tip_hash: Option<String>,
+    tip_height: Option<u32>,
+    scanned_blocks: usize,
+    balance: u64,
+    utxos: Vec<RescanUtxo>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RescanUtxo {
+    outpoint: OutPoint,
+    txout: TxOut,
+    height: u32,
+}
+
+#[derive(Serialize)]
+struct TxVerifyOutput {
+    inputs: Vec<InputVerifyOutput>,
+    /// Inputs minus outputs, when the values check out
+    fee: Option<u64>,
+    value_error: Option<String>,
+    valid: bool,
+}
+
+#[derive(Serialize)]
+struct InputVerifyOutput {
+    index: usize,
+    prevout: OutPoint,
+    ok: bool,
+    /// Which sighash the signature commits to: "per_input" or "tx"
+    sighash: Option<&'static str>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct WalletInspectOutput {
+    version: u32,
+    kdf: String,
+    kdf_params: KdfParams,
+    cipher: String,
+    public_key_hex: String,
+    address: String,
+    watch_only: bool,
+    /// Address derived from `public_key_hex`, if the key parses
+    derived_address: Option<String>,
+    address_matches: bool,
+}
+
 fn main() {
     if let Err(err) = run() {
         eprintln!("Error: {err}");
@@ -234,16 +398,48 @@ fn run_wallet(command: WalletCommand) -> Result<(), CliError> {
             }
             Ok(())
         }
-        WalletCommand::Address { pubkey, secret } => {
-            let address = match (pubkey, secret) {
-                (Some(pubkey), None) => address_from_pubkey_hex(&pubkey)?,
-                (None, Some(secret)) => {
+        WalletCommand::Mnemonic {
+            words,
+            mnemonic_passphrase,
+            out,
+        } => {
+            if !matches!(words, 12 | 15 | 18 | 21 | 24) {
+                return Err(CliError::InvalidArgs(format!(
+                    "--words must be 12, 15, 18, 21 or 24 (got {words})"
+                )));
+            }
+            let mut entropy = vec![0u8; words / 3 * 4];
+            thread_rng().fill_bytes(&mut entropy);
+            let output = mnemonic_output(&entropy, &mnemonic_passphrase)?;
+            let json = serde_json::to_string_pretty(&output)?;
+            match out {
+                Some(path) => {
+                    std::fs::write(path, json)?;
+                }
+                None => {
+                    println!("{json}");
+                }
+            }
+            Ok(())
+        }
+        WalletCommand::Address {
+            pubkey,
+            secret,
+            mnemonic,
+            mnemonic_passphrase,
+        } => {
+            let address = match (pubkey, secret, mnemonic) {
+                (Some(pubkey), None, None) => address_from_pubkey_hex(&pubkey)?,
+                (None, Some(secret), None) => {
                     let kp = WalletKeypair::from_secret_hex(&secret)?;
                     kp.address()?
                 }
+                (None, None, Some(phrase)) => {
+                    keypair_from_mnemonic(&phrase, &mnemonic_passphrase)?.address()?
+                }
                 _ => {
                     return Err(CliError::InvalidArgs(
-                        "provide exactly one of --pubkey or --secret".to_string(),
+                        "provide exactly one of --pubkey, --secret or --mnemonic".to_string(),
                     ))
                 }
             };
@@ -285,6 +481,26 @@ fn run_wallet(command: WalletCommand) -> Result<(), CliError> {
             println!("{json}");
             Ok(())
         }
+        WalletCommand::Inspect { input } => {
+            let data = std::fs::read_to_string(input)?;
+            let wallet: WalletFile = serde_json::from_str(&data)?;
+            let output = inspect_wallet(&wallet);
+            let matches = output.address_matches;
+            println!("{}", serde_json::to_string_pretty(&output)?);
+            if !matches {
+                return Err(CliError::InvalidArgs(
+                    "wallet address does not match its public key".to_string(),
+                ));
+            }
+            Ok(())
+        }
+        WalletCommand::ExportWatch { input, out } => {
+            let data = std::fs::read_to_string(input)?;
+            let wallet: WalletFile = serde_json::from_str(&data)?;
+            let watch = wallet_file_watch_only(&wallet)?;
+            std::fs::write(out, serde_json::to_string_pretty(&watch)?)?;
+            Ok(())
+        }
         WalletCommand::Backup {
             input,
             out,
@@ -300,7 +516,272 @@ fn run_wallet(command: WalletCommand) -> Result<(), CliError> {
             std::fs::write(out, json)?;
             Ok(())
         }
+        WalletCommand::Rotate {
+            secret,
+            utxo,
+            fee_rate,
+            new_out,
+            passphrase,
+            out,
+        } => {
+            if new_out.exists() {
+                return Err(CliError::InvalidArgs(format!(
+                    "refusing to overwrite {}",
+                    new_out.display()
+                )));
+            }
+            let utxos = read_utxo_jsonl(&utxo)?;
+            let new_kp = generate_keypair();
+            let tx = build_sweep_tx(&utxos, &secret, &new_kp, fee_rate)?;
+            let passphrase = resolve_new_passphrase(passphrase)?;
+            let wallet = wallet_file_from_secret(&new_kp.secret_key_hex(), &passphrase)?;
+            // Persist the new key before emitting the tx that pays to it.
+            std::fs::write(&new_out, serde_json::to_string_pretty(&wallet)?)?;
+            eprintln!("new address: {}", new_kp.address()?);
+            write_json(TxFile::from_transaction(&tx), out)?;
+            Ok(())
+        }
+        WalletCommand::Rescan {
+            data_dir,
+            blocks_file,
+            secret,
+            address,
+            from_height,
+            out,
+        } => {
+            let script = match (secret, address) {
+                (Some(secret), None) => WalletKeypair::from_secret_hex(&secret)?.script_pubkey(),
+                (None, Some(address)) => script_pubkey_from_address(&address)?,
+                _ => {
+                    return Err(CliError::InvalidArgs(
+                        "provide exactly one of --secret or --address".to_string(),
+                    ))
+                }
+            };
+            let chain = match (data_dir, blocks_file) {
+                (Some(dir), None) => read_chain_db(&dir, from_height)?,
+                (None, Some(path)) => read_block_jsonl(&path)?,
+                _ => {
+                    return Err(CliError::InvalidArgs(
+                        "provide exactly one of --data-dir or --blocks-file".to_string(),
+                    ))
+                }
+            };
+            let output = rescan_chain(&chain, &script, from_height)?;
+            if let Some(path) = out {
+                let mut lines = String::new();
+                for utxo in &output.utxos {
+                    lines.push_str(&serde_json::to_string(utxo)?);
+                    lines.push('\n');
+                }
+                std::fs::write(path, lines)?;
+            }
+            println!("{}", serde_json::to_string_pretty(&output)?);
+            Ok(())
+        }
+    }
+}
+
+/// Height of the first block in a scanned chain: the genesis block if it has
+/// no parent, otherwise a child of the (unstored) genesis block.
+fn first_height(block: &Block) -> u32 {
+    if block.header.prev_block_hash == [0u8; 32] {
+        0
+    } else {
+        1
+    }
+}
+
+/// Read the node's best chain from `<data_dir>/chain.sled`, starting at
+/// `from_height`; see [`read_main_chain`]. The node must be stopped, as it
+/// holds the DB lock.
+fn read_chain_db(data_dir: &Path, from_height: u32) -> Result<Vec<(u32, Block)>, CliError> {
+    let db_path = data_dir.join("chain.sled");
+    if !db_path.is_dir() {
+        return Err(CliError::InvalidArgs(format!(
+            "no chain.sled in {}",
+            data_dir.display()
+        )));
+    }
+    read_main_chain(&sled::open(db_path)?, from_height)
+}
+
+/// Best-chain blocks from `from_height` up. The `main_index` tree maps each
+/// best-chain height (a big-endian u32) to its block hash and the `blocks`
+/// tree holds the bodies as JSON, so heights are exact even when the node
+/// has pruned old blocks.
+///
+/// The genesis block is skipped: its body is not stored and its coinbase
+/// pays the fixed genesis script. Heights below the `pruned_below_height`
+/// meta marker are an error, as the rescan would silently miss their
+/// outputs. Reading stops at the first block whose body has not been
+/// downloaded yet.
+fn read_main_chain(db: &sled::Db, from_height: u32) -> Result<Vec<(u32, Block)>, CliError> {
+    let from_height = from_height.max(1);
+    let pruned_below = match db.open_tree("meta")?.get("pruned_below_height")? {
+        Some(bytes) => bytes
+            .as_ref()
+            .try_into()
+            .map(u32::from_le_bytes)
+            .map_err(|_| CliError::InvalidArgs("invalid pruned_below_height".to_string()))?,
+        None => 0,
+    };
+    if from_height < pruned_below {
+        return Err(CliError::InvalidArgs(format!(
+            "blocks below height {pruned_below} are pruned; rescan with --from-height {pruned_below} or higher"
+        )));
+    }
+    let main_index = db.open_tree("main_index")?;
+    let blocks = db.open_tree("blocks")?;
+    let mut chain = Vec::new();
+    for item in main_index.range(from_height.to_be_bytes()..) {
+        let (key, hash) = item?;
+        let height = key
+            .as_ref()
+            .try_into()
+            .map(u32::from_be_bytes)
+            .map_err(|_| CliError::InvalidArgs("invalid main_index key".to_string()))?;
+        let Some(bytes) = blocks.get(&hash)? else {
+            break;
+        };
+        chain.push((height, serde_json::from_slice(&bytes)?));
+    }
+    Ok(chain)
+}
+
+/// Read an exported chain: one block JSON per line, each extending the last.
+fn read_block_jsonl(path: &Path) -> Result<Vec<(u32, Block)>, CliError> {
+    let reader = BufReader::new(std::fs::File::open(path)?);
+    let mut chain: Vec<(u32, Block)> = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let block: Block = serde_json::from_str(line)?;
+        let height = match chain.last() {
+            Some((height, prev)) => {
+                if block.header.prev_block_hash != header_hash(&prev.header) {
+                    return Err(CliError::InvalidArgs(format!(
+                        "block at height {} does not extend the previous line",
+                        height + 1
+                    )));
+                }
+                height + 1
+            }
+            None => first_height(&block),
+        };
+        chain.push((height, block));
+    }
+    Ok(chain)
+}
+
+/// Replay `chain` from `from_height`, tracking outputs paying `script` and
+/// dropping them when spent. Outputs created below `from_height` are not seen,
+/// so their spends are ignored.
+fn rescan_chain(
+    chain: &[(u32, Block)],
+    script: &[u8],
+    from_height: u32,
+) -> Result<RescanOutput, CliError> {
+    let mut owned: HashMap<OutPoint, RescanUtxo> = HashMap::new();
+    let mut scanned_blocks = 0;
+    for (height, block) in chain.iter().filter(|(height, _)| *height >= from_height) {
+        scanned_blocks += 1;
+        for tx in &block.txs {
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    owned.remove(&vin.prevout);
+                }
+            }
+            let outpoints = Transaction::make_outpoints(tx)?;
+            for (outpoint, txout) in outpoints.into_iter().zip(&tx.vout) {
+                if txout.script_pubkey == script {
+                    owned.insert(
+                        outpoint.clone(),
+                        RescanUtxo {
+                            outpoint,
+                            txout: txout.clone(),
+                            height: *height,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    let mut utxos: Vec<RescanUtxo> = owned.into_values().collect();
+    utxos.sort_by(|a, b| {
+        (a.height, a.outpoint.txid, a.outpoint.vout).cmp(&(b.height, b.outpoint.txid, b.outpoint.vout))
+    });
+    let mut balance = 0u64;
+    for utxo in &utxos {
+        balance = balance
+            .checked_add(utxo.txout.value)
+            .ok_or_else(|| CliError::InvalidArgs("balance overflow".to_string()))?;
     }
+    let tip = chain.last();
+    Ok(RescanOutput {
+        script_pubkey_hex: hex::encode(script),
+        tip_hash: tip.map(|(_, block)| hex::encode(header_hash(&block.header))),
+        tip_height: tip.map(|(height, _)| *height),
+        scanned_blocks,
+        balance,
+        utxos,
+    })
+}
+
+/// Build and sign a transaction spending every UTXO locked to `old_secret`
+/// into a single output paying `new_kp`.
+fn build_sweep_tx(
+    utxos: &[UtxoEntry],
+    old_secret: &str,
+    new_kp: &WalletKeypair,
+    fee_rate: u64,
+) -> Result<Transaction, CliError> {
+    let old_script = WalletKeypair::from_secret_hex(old_secret)?.script_pubkey();
+    let owned: Vec<&UtxoEntry> = utxos
+        .iter()
+        .filter(|entry| entry.txout.script_pubkey == old_script)
+        .collect();
+    if owned.is_empty() {
+        return Err(CliError::InvalidArgs("no utxos for old key".to_string()));
+    }
+    let mut total = 0u64;
+    for entry in &owned {
+        total = total
+            .checked_add(entry.txout.value)
+            .ok_or_else(|| CliError::InvalidArgs("input sum overflow".to_string()))?;
+    }
+    let new_script = new_kp.script_pubkey();
+    let size = estimate_tx_size(owned.len(), false, SIGNED_SCRIPT_SIG_LEN, &[new_script.len()], 0);
+    let fee = fee_rate.saturating_mul(size as u64);
+    let value = total.saturating_sub(fee);
+    if value <= SWEEP_DUST_LIMIT {
+        return Err(CliError::InvalidArgs(format!(
+            "sweep output would be dust after fees (inputs={total} fee={fee})"
+        )));
+    }
+
+    let mut tx = Transaction {
+        version: 1,
+        vin: owned
+            .iter()
+            .map(|entry| TxIn {
+                prevout: entry.outpoint.clone(),
+                script_sig: Vec::new(),
+                sequence: 0xffff_ffff,
+            })
+            .collect(),
+        vout: vec![TxOut {
+            value,
+            script_pubkey: new_script,
+        }],
+        lock_time: 0,
+    };
+    sign_all_inputs(&mut tx, old_secret)?;
+    Ok(tx)
 }
 
 fn run_tx(command: TxCommand) -> Result<(), CliError> {
@@ -320,11 +801,19 @@ fn run_tx(command: TxCommand) -> Result<(), CliError> {
             write_json(out_file, out)?;
             Ok(())
         }
-        TxCommand::Sign { input, out, secret } => {
+        TxCommand::Sign {
+            input,
+            out,
+            secret,
+            utxo,
+        } => {
             let data = std::fs::read_to_string(input)?;
             let tx_file: TxFile = serde_json::from_str(data.trim_start_matches('\u{feff}'))?;
             let mut tx = tx_file.to_transaction()?;
-            sign_all_inputs(&mut tx, &secret)?;
+            match utxo {
+                Some(path) => sign_inputs_with_prevouts(&mut tx, &secret, &read_utxo_jsonl(&path)?)?,
+                None => sign_all_inputs(&mut tx, &secret)?,
+            }
             let out_file = TxFile::from_transaction(&tx);
             write_json(out_file, out)?;
             Ok(())
@@ -335,66 +824,58 @@ fn run_tx(command: TxCommand) -> Result<(), CliError> {
             let tx = tx_file.to_transaction()?;
             let txid = tx.txid_v2()?;
             let sighash = tx_sighash_v2(&tx)?;
-            println!("txid_v2={}", hex::encode(txid));
+            println!("txid_v2={}", txid_to_display_hex(&txid));
             println!("sighash_v2={}", hex::encode(sighash));
             Ok(())
         }
+        TxCommand::Verify { input, utxo } => {
+            let data = std::fs::read_to_string(input)?;
+            let tx_file: TxFile = serde_json::from_str(data.trim_start_matches('\u{feff}'))?;
+            let tx = tx_file.to_transaction()?;
+            let output = verify_tx(&tx, &read_utxo_jsonl(&utxo)?)?;
+            println!("{}", serde_json::to_string_pretty(&output)?);
+            if !output.valid {
+                return Err(CliError::VerifyFailed);
+            }
+            Ok(())
+        }
         TxCommand::Build {
             utxo,
-            to_script,
-            amount,
+            to,
             change_script,
             fee,
             fee_rate,
             strategy,
             out,
             sign_secret,
+            sort_bip69,
         } => {
             let utxos = read_utxo_jsonl(&utxo)?;
-            let to_script = hex::decode(&to_script)?;
+            let recipients = to
+                .iter()
+                .map(|spec| parse_recipient(spec))
+                .collect::<Result<Vec<_>, _>>()?;
             let change_script = hex::decode(&change_script)?;
             let fee = resolve_fee(fee, fee_rate)?;
-            let script_sig_len = if sign_secret.is_some() { 96 } else { 0 };
-            let (selected, input_sum, fee) = select_utxos(
+            let script_sig_len = if sign_secret.is_some() {
+                SIGNED_SCRIPT_SIG_LEN
+            } else {
+                0
+            };
+            let mut tx = build_payment_tx(
                 &utxos,
-                amount,
+                recipients,
+                change_script,
                 fee,
                 fee_rate,
-                &to_script,
-                &change_script,
                 script_sig_len,
                 strategy,
             )?;
-
-            let mut vin = Vec::with_capacity(selected.len());
-            for entry in &selected {
-                vin.push(TxIn {
-                    prevout: entry.outpoint.clone(),
-                    script_sig: Vec::new(),
-                    sequence: 0xffff_ffff,
-                });
-            }
-
-            let mut vout = Vec::new();
-            vout.push(TxOut {
-                value: amount,
-                script_pubkey: to_script,
-            });
-            let change_value = input_sum - amount - fee;
-            if change_value > 0 {
-                vout.push(TxOut {
-                    value: change_value,
-                    script_pubkey: change_script,
-                });
+            if sort_bip69 {
+                sort_tx_bip69(&mut tx);
             }
 
-            let mut tx = Transaction {
-                version: 1,
-                vin,
-                vout,
-                lock_time: 0,
-            };
-
+            // Sign last so the signature commits to the final input order.
             if let Some(secret) = sign_secret {
                 sign_all_inputs(&mut tx, &secret)?;
             }
@@ -414,6 +895,35 @@ fn keygen_output(kp: &WalletKeypair) -> Result<KeygenOutput, WalletError> {
     })
 }
 
+fn keypair_from_mnemonic(phrase: &str, passphrase: &str) -> Result<WalletKeypair, WalletError> {
+    let secret = secret_from_mnemonic(phrase, passphrase)?;
+    WalletKeypair::from_secret_hex(&hex::encode(secret))
+}
+
+fn mnemonic_output(entropy: &[u8], passphrase: &str) -> Result<MnemonicOutput, WalletError> {
+    let mnemonic = mnemonic_from_entropy(entropy)?;
+    let kp = keypair_from_mnemonic(&mnemonic, passphrase)?;
+    Ok(MnemonicOutput {
+        mnemonic,
+        key: keygen_output(&kp)?,
+    })
+}
+
+fn inspect_wallet(wallet: &WalletFile) -> WalletInspectOutput {
+    let derived_address = address_from_pubkey_hex(&wallet.public_key_hex).ok();
+    WalletInspectOutput {
+        version: wallet.version,
+        kdf: wallet.kdf.clone(),
+        kdf_params: wallet.kdf_params.clone(),
+        cipher: wallet.cipher.clone(),
+        public_key_hex: wallet.public_key_hex.clone(),
+        address: wallet.address.clone(),
+        watch_only: wallet.watch_only,
+        address_matches: derived_address.as_deref() == Some(wallet.address.as_str()),
+        derived_address,
+    }
+}
+
 fn write_json<T: Serialize>(value: T, out: Option<PathBuf>) -> Result<(), CliError> {
     let json = serde_json::to_string_pretty(&value)?;
     match out {
@@ -427,21 +937,161 @@ fn write_json<T: Serialize>(value: T, out: Option<PathBuf>) -> Result<(), CliErr
     Ok(())
 }
 
+/// BIP69 ordering: inputs by (prevout txid, vout), outputs by
+/// (value, script_pubkey). Txids compare in display order, i.e. with their
+/// stored bytes reversed, as the BIP specifies.
+fn sort_tx_bip69(tx: &mut Transaction) {
+    tx.vin.sort_by(|a, b| {
+        a.prevout
+            .txid
+            .iter()
+            .rev()
+            .cmp(b.prevout.txid.iter().rev())
+            .then(a.prevout.vout.cmp(&b.prevout.vout))
+    });
+    tx.vout.sort_by(|a, b| {
+        a.value
+            .cmp(&b.value)
+            .then_with(|| a.script_pubkey.cmp(&b.script_pubkey))
+    });
+}
+
 fn sign_all_inputs(tx: &mut Transaction, secret_hex: &str) -> Result<(), CliError> {
     let sighash = tx_sighash_v2(tx)?;
     let sig_hex = sign_message_hex(secret_hex, &sighash)?;
     let pubkey_hex = WalletKeypair::from_secret_hex(secret_hex)?.public_key_hex();
     let sig_bytes = hex::decode(sig_hex)?;
-    let pub_bytes = hex::decode(pubkey_hex)?;
+    let pub_bytes = hex::decode(&pubkey_hex)?;
     let mut script = Vec::with_capacity(sig_bytes.len() + pub_bytes.len());
     script.extend(sig_bytes);
     script.extend(pub_bytes);
+    // Every input carries the same script, so one check covers them all.
+    check_input_signature(0, &pubkey_hex, &sighash, &script)?;
     for vin in &mut tx.vin {
         vin.script_sig = script.clone();
     }
     Ok(())
 }
 
+/// Verify a freshly built `signature || pubkey` script_sig against the key
+/// that made it, so a sighash or key-handling bug fails here instead of
+/// producing a transaction nobody can spend.
+fn check_input_signature(
+    index: usize,
+    pubkey_hex: &str,
+    sighash: &[u8],
+    script_sig: &[u8],
+) -> Result<(), CliError> {
+    let Some(signature) = script_sig.get(..64) else {
+        return Err(CliError::SignatureCheck(index));
+    };
+    if !verify_message_hex(pubkey_hex, sighash, &hex::encode(signature))? {
+        return Err(CliError::SignatureCheck(index));
+    }
+    Ok(())
+}
+
+/// Check each input's `signature || pubkey` script_sig against the output
+/// it spends, accepting a signature over either [`sighash_for_input`] or the
+/// whole-transaction [`tx_sighash_v2`], then check value conservation.
+fn verify_tx(tx: &Transaction, utxos: &[UtxoEntry]) -> Result<TxVerifyOutput, CliError> {
+    let tx_sighash = tx_sighash_v2(tx)?;
+    let mut inputs = Vec::with_capacity(tx.vin.len());
+    for (index, vin) in tx.vin.iter().enumerate() {
+        let prevout = utxos
+            .iter()
+            .find(|entry| entry.outpoint == vin.prevout)
+            .map(|entry| &entry.txout);
+        let checked = match prevout {
+            Some(prevout) => verify_input(tx, index, prevout, &tx_sighash),
+            None => Err(format!("missing utxo {:?}", vin.prevout)),
+        };
+        inputs.push(InputVerifyOutput {
+            index,
+            prevout: vin.prevout.clone(),
+            ok: checked.is_ok(),
+            sighash: checked.as_ref().ok().copied(),
+            error: checked.err(),
+        });
+    }
+
+    let mut set = InMemoryUtxoSet::new();
+    for entry in utxos {
+        set.insert(entry.outpoint.clone(), entry.txout.clone());
+    }
+    let (fee, value_error) = match Transaction::validate_value_conservation(tx, &set) {
+        Ok(fee) => (Some(fee), None),
+        Err(err) => (None, Some(err.to_string())),
+    };
+    let valid = value_error.is_none() && inputs.iter().all(|input| input.ok);
+    Ok(TxVerifyOutput {
+        inputs,
+        fee,
+        value_error,
+        valid,
+    })
+}
+
+/// Verify input `index` against `prevout`, returning the sighash kind its
+/// signature covers or why it failed.
+fn verify_input(
+    tx: &Transaction,
+    index: usize,
+    prevout: &TxOut,
+    tx_sighash: &[u8; 32],
+) -> Result<&'static str, String> {
+    let script_sig = &tx.vin[index].script_sig;
+    if script_sig.len() != SIGNED_SCRIPT_SIG_LEN {
+        return Err(format!(
+            "script_sig is {} bytes, expected {SIGNED_SCRIPT_SIG_LEN}",
+            script_sig.len()
+        ));
+    }
+    let (signature, pubkey) = script_sig.split_at(64);
+    let pubkey_hex = hex::encode(pubkey);
+    let address = address_from_pubkey_hex(&pubkey_hex).map_err(|err| err.to_string())?;
+    let script = script_pubkey_from_address(&address).map_err(|err| err.to_string())?;
+    if script != prevout.script_pubkey {
+        return Err(format!("pubkey {address} does not match the spent script_pubkey"));
+    }
+    let signature_hex = hex::encode(signature);
+    let per_input = sighash_for_input(tx, index, prevout).map_err(|err| err.to_string())?;
+    for (kind, sighash) in [("per_input", &per_input), ("tx", tx_sighash)] {
+        if verify_message_hex(&pubkey_hex, sighash, &signature_hex).map_err(|err| err.to_string())? {
+            return Ok(kind);
+        }
+    }
+    Err("signature does not verify".to_string())
+}
+
+/// Sign every input against [`sighash_for_input`] using the matching entry
+/// from `utxos`, so each script_sig commits to the value it spends.
+fn sign_inputs_with_prevouts(
+    tx: &mut Transaction,
+    secret_hex: &str,
+    utxos: &[UtxoEntry],
+) -> Result<(), CliError> {
+    let pubkey_hex = WalletKeypair::from_secret_hex(secret_hex)?.public_key_hex();
+    let pub_bytes = hex::decode(&pubkey_hex)?;
+    let mut scripts = Vec::with_capacity(tx.vin.len());
+    for (index, vin) in tx.vin.iter().enumerate() {
+        let prevout = utxos
+            .iter()
+            .find(|entry| entry.outpoint == vin.prevout)
+            .map(|entry| &entry.txout)
+            .ok_or_else(|| UtxoError::MissingUtxo(vin.prevout.clone()))?;
+        let sighash = sighash_for_input(tx, index, prevout)?;
+        let mut script = hex::decode(sign_message_hex(secret_hex, &sighash)?)?;
+        script.extend_from_slice(&pub_bytes);
+        check_input_signature(index, &pubkey_hex, &sighash, &script)?;
+        scripts.push(script);
+    }
+    for (vin, script) in tx.vin.iter_mut().zip(scripts) {
+        vin.script_sig = script;
+    }
+    Ok(())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct TxFile {
     version: i32,
@@ -573,17 +1223,92 @@ fn read_utxo_jsonl(path: &PathBuf) -> Result<Vec<UtxoEntry>, CliError> {
     Ok(out)
 }
 
+/// Parse a `--to` value of the form `script_hex:amount`.
+fn parse_recipient(spec: &str) -> Result<TxOut, CliError> {
+    let (script, value) = spec.rsplit_once(':').ok_or_else(|| {
+        CliError::InvalidArgs(format!("recipient must be script_hex:amount, got {spec}"))
+    })?;
+    let value = value
+        .parse()
+        .map_err(|e| CliError::InvalidArgs(format!("invalid amount in {spec}: {e}")))?;
+    Ok(TxOut {
+        value,
+        script_pubkey: hex::decode(script)?,
+    })
+}
+
+/// Unsigned transaction paying every recipient from coins selected out of
+/// `utxos` for their combined amount, with change to `change_script` when
+/// anything is left after the fee.
+fn build_payment_tx(
+    utxos: &[UtxoEntry],
+    recipients: Vec<TxOut>,
+    change_script: Vec<u8>,
+    fee: u64,
+    fee_rate: Option<u64>,
+    script_sig_len: usize,
+    strategy: CoinSelect,
+) -> Result<Transaction, CliError> {
+    if recipients.is_empty() {
+        return Err(CliError::InvalidArgs("provide at least one --to".to_string()));
+    }
+    let mut amount = 0u64;
+    for recipient in &recipients {
+        amount = amount
+            .checked_add(recipient.value)
+            .ok_or_else(|| CliError::InvalidArgs("amount overflow".to_string()))?;
+    }
+    let to_script_lens: Vec<usize> = recipients.iter().map(|out| out.script_pubkey.len()).collect();
+    let (selected, input_sum, fee) = select_utxos(
+        utxos,
+        amount,
+        fee,
+        fee_rate,
+        &to_script_lens,
+        &change_script,
+        script_sig_len,
+        strategy,
+    )?;
+
+    let vin = selected
+        .iter()
+        .map(|entry| TxIn {
+            prevout: entry.outpoint.clone(),
+            script_sig: Vec::new(),
+            sequence: 0xffff_ffff,
+        })
+        .collect();
+    let mut vout = recipients;
+    let change_value = input_sum - amount - fee;
+    if change_value > 0 {
+        vout.push(TxOut {
+            value: change_value,
+            script_pubkey: change_script,
+        });
+    }
+    Ok(Transaction {
+        version: 1,
+        vin,
+        vout,
+        lock_time: 0,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
 fn select_utxos(
     utxos: &[UtxoEntry],
     amount: u64,
     fee: u64,
     fee_rate: Option<u64>,
-    to_script: &[u8],
+    to_script_lens: &[usize],
     change_script: &[u8],
     script_sig_len: usize,
     strategy: CoinSelect,
 ) -> Result<(Vec<UtxoEntry>, u64, u64), CliError> {
+    // Start from outpoint order so equal values always tie-break the same way:
+    // the value sorts below are stable and the best-fit scans keep the first match.
     let mut pool: Vec<UtxoEntry> = utxos.to_vec();
+    pool.sort_by(|a, b| a.outpoint.cmp(&b.outpoint));
     if let CoinSelect::BestFit = strategy {
         if let Some(rate) = fee_rate {
             if let Some((entry, calc_fee)) = best_fit_single(
@@ -591,7 +1316,7 @@ fn select_utxos(
                 amount,
                 rate,
                 script_sig_len,
-                to_script.len(),
+                to_script_lens,
                 change_script.len(),
             ) {
                 return Ok((vec![entry.clone()], entry.txout.value, calc_fee));
@@ -608,16 +1333,16 @@ fn select_utxos(
     }
     match strategy {
         CoinSelect::LargestFirst => {
-            pool.sort_by(|a, b| b.txout.value.cmp(&a.txout.value));
+            pool.sort_by_key(|e| std::cmp::Reverse(e.txout.value));
         }
         CoinSelect::SmallestFirst => {
-            pool.sort_by(|a, b| a.txout.value.cmp(&b.txout.value));
+            pool.sort_by_key(|e| e.txout.value);
         }
         CoinSelect::Random => {
             pool.shuffle(&mut thread_rng());
         }
         CoinSelect::BestFit => {
-            pool.sort_by(|a, b| b.txout.value.cmp(&a.txout.value));
+            pool.sort_by_key(|e| std::cmp::Reverse(e.txout.value));
         }
     }
 
@@ -636,7 +1361,7 @@ fn select_utxos(
                         selected.len(),
                         true,
                         script_sig_len,
-                        to_script.len(),
+                        to_script_lens,
                         change_script.len(),
                     ) as u64,
                 );
@@ -648,16 +1373,15 @@ fn select_utxos(
                     if change_value > 0 {
                         fee_with_change
                     } else {
-                        let fee_no_change = rate.saturating_mul(
+                        rate.saturating_mul(
                             estimate_tx_size(
                                 selected.len(),
                                 false,
                                 script_sig_len,
-                                to_script.len(),
+                                to_script_lens,
                                 change_script.len(),
                             ) as u64,
-                        );
-                        fee_no_change
+                        )
                     }
                 } else {
                     fee_with_change
@@ -681,17 +1405,17 @@ fn best_fit_single(
     amount: u64,
     rate: u64,
     script_sig_len: usize,
-    to_script_len: usize,
+    to_script_lens: &[usize],
     change_script_len: usize,
 ) -> Option<(UtxoEntry, u64)> {
     let mut best: Option<(UtxoEntry, u64, u64)> = None; // (entry, fee, excess)
     for entry in pool {
         let sum = entry.txout.value;
         let fee_with_change = rate.saturating_mul(
-            estimate_tx_size(1, true, script_sig_len, to_script_len, change_script_len) as u64,
+            estimate_tx_size(1, true, script_sig_len, to_script_lens, change_script_len) as u64,
         );
         let fee_no_change = rate.saturating_mul(
-            estimate_tx_size(1, false, script_sig_len, to_script_len, change_script_len) as u64,
+            estimate_tx_size(1, false, script_sig_len, to_script_lens, change_script_len) as u64,
         );
 
         let target_with_change = amount.saturating_add(fee_with_change);
@@ -721,22 +1445,35 @@ fn best_fit_single(
     best.map(|(entry, fee, _)| (entry, fee))
 }
 
+/// Size of a transaction with `vin_count` inputs, one output per entry of
+/// `to_script_lens`, and a change output if `has_change`.
 fn estimate_tx_size(
     vin_count: usize,
     has_change: bool,
     script_sig_len: usize,
-    to_script_len: usize,
+    to_script_lens: &[usize],
     change_script_len: usize,
 ) -> usize {
-    let header = 4 + 8 + 8 + 4;
-    let vin_size = 32 + 4 + 8 + script_sig_len + 4;
-    let to_out = 8 + 8 + to_script_len;
-    let change_out = if has_change {
-        8 + 8 + change_script_len
-    } else {
-        0
+    let input = TxIn {
+        prevout: OutPoint::NULL,
+        script_sig: vec![0u8; script_sig_len],
+        sequence: 0,
     };
-    header + vin_size * vin_count + to_out + change_out
+    let output = |len: usize| TxOut {
+        value: 0,
+        script_pubkey: vec![0u8; len],
+    };
+    let mut vout: Vec<TxOut> = to_script_lens.iter().map(|&len| output(len)).collect();
+    if has_change {
+        vout.push(output(change_script_len));
+    }
+    Transaction {
+        version: 1,
+        vin: vec![input; vin_count],
+        vout,
+        lock_time: 0,
+    }
+    .vsize()
 }
 
 fn resolve_fee(fee: Option<u64>, fee_rate: Option<u64>) -> Result<u64, CliError> {
@@ -781,3 +1518,612 @@ fn resolve_new_passphrase(passphrase: Option<String>) -> Result<String, CliError
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tenebrium_consensus::params::GENESIS_TIME;
+    use tenebrium_consensus::{check_pow, genesis_hash, NetworkParams};
+    use tenebrium_core::wallet_file_from_secret_with_kdf;
+
+    /// sled releases its file lock shortly after the last handle is dropped;
+    /// wait for that before opening the same DB again in this process.
+    fn wait_for_db_unlock(data_dir: &Path) {
+        let lock = std::fs::File::open(data_dir.join("chain.sled").join("db")).unwrap();
+        for _ in 0..100 {
+            if lock.try_lock().is_ok() {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        panic!("chain.sled is still locked");
+    }
+
+    fn entry(tag: u8, value: u64, script: Vec<u8>) -> UtxoEntry {
+        UtxoEntry {
+            outpoint: OutPoint {
+                txid: [tag; 32],
+                vout: 0,
+            },
+            txout: TxOut {
+                value,
+                script_pubkey: script,
+            },
+        }
+    }
+
+    #[test]
+    fn equal_value_selection_ignores_input_order() {
+        let mut utxos: Vec<UtxoEntry> = (1..=6).map(|tag| entry(tag, 100, vec![tag])).collect();
+        utxos.push(entry(7, 40, vec![7]));
+        let select = |utxos: &[UtxoEntry], strategy| {
+            let (selected, sum, fee) =
+                select_utxos(utxos, 250, 10, None, &[1], &[2], SIGNED_SCRIPT_SIG_LEN, strategy)
+                    .unwrap();
+            let tags: Vec<u8> = selected.iter().map(|e| e.outpoint.txid[0]).collect();
+            (tags, sum, fee)
+        };
+
+        let mut reversed = utxos.clone();
+        reversed.reverse();
+        let mut rotated = utxos.clone();
+        rotated.rotate_left(3);
+        for strategy in [CoinSelect::LargestFirst, CoinSelect::SmallestFirst, CoinSelect::BestFit] {
+            let expected = select(&utxos, strategy);
+            assert_eq!(select(&reversed, strategy), expected);
+            assert_eq!(select(&rotated, strategy), expected);
+        }
+        assert_eq!(select(&reversed, CoinSelect::LargestFirst).0, vec![1, 2, 3]);
+        assert_eq!(select(&reversed, CoinSelect::SmallestFirst).0, vec![7, 1, 2, 3]);
+    }
+
+    #[test]
+    fn two_recipient_build_changes_from_combined_target() {
+        let utxos = vec![entry(1, 5_000, vec![9]), entry(2, 3_000, vec![9])];
+        let recipients = vec![
+            parse_recipient("aa:2000").unwrap(),
+            parse_recipient("bbbb:2500").unwrap(),
+        ];
+        let tx = build_payment_tx(
+            &utxos,
+            recipients.clone(),
+            vec![0xcc],
+            0,
+            Some(2),
+            SIGNED_SCRIPT_SIG_LEN,
+            CoinSelect::LargestFirst,
+        )
+        .unwrap();
+
+        // The 5000 coin covers 4500 plus the fee; change gets the rest.
+        assert_eq!(tx.vin.len(), 1);
+        assert_eq!(tx.vout.len(), 3);
+        assert_eq!(tx.vout[..2], recipients[..]);
+        assert_eq!(tx.vout[0].value, 2_000);
+        assert_eq!(tx.vout[1].value, 2_500);
+        let fee = 2 * estimate_tx_size(1, true, SIGNED_SCRIPT_SIG_LEN, &[1, 2], 1) as u64;
+        assert_eq!(tx.vout[2].script_pubkey, vec![0xcc]);
+        assert_eq!(tx.vout[2].value, 5_000 - 4_500 - fee);
+        // Every recipient output counts towards the size estimate.
+        assert!(
+            estimate_tx_size(1, true, SIGNED_SCRIPT_SIG_LEN, &[1, 2], 1)
+                > estimate_tx_size(1, true, SIGNED_SCRIPT_SIG_LEN, &[1], 1)
+        );
+
+        let build = |recipients| {
+            build_payment_tx(&utxos, recipients, vec![0xcc], 10, None, 0, CoinSelect::LargestFirst)
+        };
+        assert!(matches!(build(Vec::new()), Err(CliError::InvalidArgs(_))));
+        assert!(matches!(parse_recipient("aa2000"), Err(CliError::InvalidArgs(_))));
+        assert!(matches!(parse_recipient("aa:x"), Err(CliError::InvalidArgs(_))));
+        assert!(matches!(
+            build(vec![parse_recipient("aa:7000").unwrap(), parse_recipient("bb:1000").unwrap()]),
+            Err(CliError::InvalidArgs(msg)) if msg == "insufficient funds"
+        ));
+    }
+
+    #[test]
+    fn signing_self_check_catches_a_bad_signature() {
+        let secret = hex::encode([5u8; 32]);
+        let pubkey_hex = WalletKeypair::from_secret_hex(&secret).unwrap().public_key_hex();
+        let mut tx = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: [1u8; 32],
+                    vout: 0,
+                },
+                script_sig: vec![],
+                sequence: u32::MAX,
+            }],
+            vout: vec![TxOut {
+                value: 10,
+                script_pubkey: vec![1],
+            }],
+            lock_time: 0,
+        };
+        sign_all_inputs(&mut tx, &secret).unwrap();
+        let sighash = tx_sighash_v2(&tx).unwrap();
+        let script = tx.vin[0].script_sig.clone();
+        check_input_signature(0, &pubkey_hex, &sighash, &script).unwrap();
+
+        let mut flipped = script.clone();
+        flipped[0] ^= 1;
+        assert!(matches!(
+            check_input_signature(0, &pubkey_hex, &sighash, &flipped),
+            Err(CliError::SignatureCheck(0))
+        ));
+        let other_key = hex::decode(sign_message_hex(&hex::encode([6u8; 32]), &sighash).unwrap()).unwrap();
+        assert!(matches!(
+            check_input_signature(2, &pubkey_hex, &sighash, &other_key),
+            Err(CliError::SignatureCheck(2))
+        ));
+        assert!(matches!(
+            check_input_signature(0, &pubkey_hex, &sighash, &script[..10]),
+            Err(CliError::SignatureCheck(0))
+        ));
+    }
+
+    #[test]
+    fn mnemonic_output_restores_through_address() {
+        let output = mnemonic_output(&[0x7f; 16], "TREZOR").unwrap();
+        assert_eq!(
+            output.mnemonic,
+            "legal winner thank year wave sausage worth useful legal winner thank yellow"
+        );
+        let kp = keypair_from_mnemonic(&output.mnemonic, "TREZOR").unwrap();
+        assert_eq!(kp.secret_key_hex(), output.key.secret_key_hex);
+        assert_eq!(kp.address().unwrap(), output.key.address);
+        // A different passphrase restores a different key from the same words.
+        let other = keypair_from_mnemonic(&output.mnemonic, "").unwrap();
+        assert_ne!(other.address().unwrap(), output.key.address);
+        assert!(matches!(
+            keypair_from_mnemonic("legal winner thank", ""),
+            Err(WalletError::InvalidWalletFile(_))
+        ));
+    }
+
+    #[test]
+    fn rotate_sweeps_old_key_to_new_wallet() {
+        let old_secret = hex::encode([11u8; 32]);
+        let old_kp = WalletKeypair::from_secret_hex(&old_secret).unwrap();
+        let new_kp = generate_keypair();
+        let utxos = vec![
+            entry(1, 40_000, old_kp.script_pubkey()),
+            entry(2, 60_000, old_kp.script_pubkey()),
+            entry(3, 99_000, vec![0xde, 0xad]),
+        ];
+
+        let tx = build_sweep_tx(&utxos, &old_secret, &new_kp, 2).unwrap();
+        assert_eq!(tx.vin.len(), 2);
+        assert_eq!(tx.vout.len(), 1);
+        assert_eq!(tx.vout[0].script_pubkey, new_kp.script_pubkey());
+        let fee = 100_000 - tx.vout[0].value;
+        assert_eq!(fee, 2 * tx.canonical_bytes_v2().unwrap().len() as u64);
+
+        let sighash = tx_sighash_v2(&tx).unwrap();
+        for vin in &tx.vin {
+            let (sig, pubkey) = vin.script_sig.split_at(64);
+            assert_eq!(hex::encode(pubkey), old_kp.public_key_hex());
+            assert!(verify_message_hex(&hex::encode(pubkey), &sighash, &hex::encode(sig)).unwrap());
+        }
+
+        let wallet = wallet_file_from_secret_with_kdf(
+            &new_kp.secret_key_hex(),
+            "rotate",
+            &[1u8; 16],
+            &[2u8; 12],
+            16,
+            8,
+            1,
+        )
+        .unwrap();
+        let restored = wallet_keypair_from_file(&wallet, "rotate").unwrap();
+        assert_eq!(restored.script_pubkey(), tx.vout[0].script_pubkey);
+    }
+
+    #[test]
+    fn inspect_reports_fields_and_flags_tampered_address() {
+        let kp = generate_keypair();
+        let mut wallet = wallet_file_from_secret_with_kdf(
+            &kp.secret_key_hex(),
+            "inspect",
+            &[3u8; 16],
+            &[4u8; 12],
+            16,
+            8,
+            1,
+        )
+        .unwrap();
+
+        let report = inspect_wallet(&wallet);
+        assert_eq!(report.version, wallet.version);
+        assert_eq!(report.kdf, "scrypt");
+        assert_eq!(report.kdf_params.n, 16);
+        assert_eq!(report.cipher, wallet.cipher);
+        assert_eq!(report.public_key_hex, kp.public_key_hex());
+        assert_eq!(report.address, kp.address().unwrap());
+        assert!(report.address_matches);
+
+        wallet.address = generate_keypair().address().unwrap();
+        let report = inspect_wallet(&wallet);
+        assert!(!report.address_matches);
+        assert_eq!(report.derived_address, Some(kp.address().unwrap()));
+    }
+
+    #[test]
+    fn export_watch_strips_the_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("wallet.json");
+        let out = dir.path().join("watch.json");
+        let kp = generate_keypair();
+        let wallet = wallet_file_from_secret_with_kdf(
+            &kp.secret_key_hex(),
+            "watch",
+            &[5u8; 16],
+            &[6u8; 12],
+            16,
+            8,
+            1,
+        )
+        .unwrap();
+        std::fs::write(&input, serde_json::to_string(&wallet).unwrap()).unwrap();
+
+        run_wallet(WalletCommand::ExportWatch {
+            input,
+            out: out.clone(),
+        })
+        .unwrap();
+        let data = std::fs::read_to_string(out).unwrap();
+        assert!(!data.contains(&wallet.ciphertext_hex));
+        let watch: WalletFile = serde_json::from_str(&data).unwrap();
+        let report = inspect_wallet(&watch);
+        assert!(report.watch_only && report.address_matches);
+        assert_eq!(report.address, kp.address().unwrap());
+        assert!(matches!(
+            wallet_keypair_from_file(&watch, "watch"),
+            Err(WalletError::WatchOnly)
+        ));
+    }
+
+    #[test]
+    fn bip69_sorts_before_signing() {
+        let secret = hex::encode([13u8; 32]);
+        let kp = WalletKeypair::from_secret_hex(&secret).unwrap();
+        let input = |tag: u8, vout: u32| TxIn {
+            prevout: OutPoint {
+                txid: [tag; 32],
+                vout,
+            },
+            script_sig: Vec::new(),
+            sequence: 0xffff_ffff,
+        };
+        let output = |value: u64, script: Vec<u8>| TxOut {
+            value,
+            script_pubkey: script,
+        };
+        let mut tx = Transaction {
+            version: 1,
+            vin: vec![input(9, 0), input(2, 5), input(2, 1)],
+            vout: vec![output(500, vec![3]), output(100, vec![9]), output(100, vec![1, 2])],
+            lock_time: 0,
+        };
+
+        sort_tx_bip69(&mut tx);
+        sign_all_inputs(&mut tx, &secret).unwrap();
+
+        let prevouts: Vec<([u8; 32], u32)> =
+            tx.vin.iter().map(|v| (v.prevout.txid, v.prevout.vout)).collect();
+        assert_eq!(prevouts, vec![([2; 32], 1), ([2; 32], 5), ([9; 32], 0)]);
+        let outs: Vec<(u64, Vec<u8>)> =
+            tx.vout.iter().map(|o| (o.value, o.script_pubkey.clone())).collect();
+        assert_eq!(outs, vec![(100, vec![1, 2]), (100, vec![9]), (500, vec![3])]);
+
+        let sighash = tx_sighash_v2(&tx).unwrap();
+        for vin in &tx.vin {
+            let (sig, pubkey) = vin.script_sig.split_at(64);
+            assert_eq!(hex::encode(pubkey), kp.public_key_hex());
+            assert!(verify_message_hex(&hex::encode(pubkey), &sighash, &hex::encode(sig)).unwrap());
+        }
+    }
+
+    #[test]
+    fn bip69_matches_the_bip_test_vector() {
+        use tenebrium_utxo::txid_from_display_hex;
+
+        // BIP69 test vector 1 (tx 0a6a357e...), in its expected order.
+        let inputs = [
+            ("0e53ec5dfb2cb8a71fec32dc9a634a35b7e24799295ddd5278217822e0b31f57", 0),
+            ("26aa6e6d8b9e49bb0630aac301db6757c02e3619feb4ee0eea81eb1672947024", 1),
+            ("28e0fdd185542f2c6ea19030b0796051e7772b6026dd5ddccd7a2f93b73e6fc2", 0),
+            ("381de9b9ae1a94d9c17f6a08ef9d341a5ce29e2e60c36a52d333ff6203e58d5d", 1),
+            ("3b8b2f8efceb60ba78ca8bba206a137f14cb5ea4035e761ee204302d46b98de2", 0),
+            ("402b2c02411720bf409eff60d05adad684f135838962823f3614cc657dd7bc0a", 1),
+            ("54ffff182965ed0957dba1239c27164ace5a73c9b62a660c74b7b7f15ff61e7a", 1),
+            ("643e5f4e66373a57251fb173151e838ccd27d279aca882997e005016bb53d5aa", 0),
+            ("6c1d56f31b2de4bfc6aaea28396b333102b1f600da9c6d6149e96ca43f1102b1", 1),
+            ("7a1de137cbafb5c70405455c49c5104ca3057a1f1243e6563bb9245c9c88c191", 0),
+            ("7d037ceb2ee0dc03e82f17be7935d238b35d1deabf953a892a4507bfbeeb3ba4", 1),
+            ("a5e899dddb28776ea9ddac0a502316d53a4a3fca607c72f66c470e0412e34086", 0),
+            ("b4112b8f900a7ca0c8b0e7c4dfad35c6be5f6be46b3458974988e1cdb2fa61b8", 0),
+            ("bafd65e3c7f3f9fdfdc1ddb026131b278c3be1af90a4a6ffa78c4658f9ec0c85", 0),
+            ("de0411a1e97484a2804ff1dbde260ac19de841bebad1880c782941aca883b4e9", 1),
+            ("f0a130a84912d03c1d284974f563c5949ac13f8342b8112edff52971599e6a45", 0),
+            ("f320832a9d2e2452af63154bc687493484a0e7745ebd3aaf9ca19eb80834ad60", 0),
+        ];
+        let outputs = [
+            (400_057_456, "76a9144a5fba237213a062f6f57978f796390bdcf8d01588ac"),
+            (40_000_000_000, "76a9145be32612930b8323add2212a4ec03c1562084f8488ac"),
+        ];
+        let mut tx = Transaction {
+            version: 1,
+            vin: inputs
+                .iter()
+                .rev()
+                .map(|(txid, vout)| TxIn {
+                    prevout: OutPoint {
+                        txid: txid_from_display_hex(txid).unwrap(),
+                        vout: *vout,
+                    },
+                    script_sig: Vec::new(),
+                    sequence: 0xffff_ffff,
+                })
+                .collect(),
+            vout: outputs
+                .iter()
+                .rev()
+                .map(|(value, script)| TxOut {
+                    value: *value,
+                    script_pubkey: hex::decode(script).unwrap(),
+                })
+                .collect(),
+            lock_time: 0,
+        };
+
+        sort_tx_bip69(&mut tx);
+
+        let sorted: Vec<(String, u32)> = tx
+            .vin
+            .iter()
+            .map(|v| (txid_to_display_hex(&v.prevout.txid), v.prevout.vout))
+            .collect();
+        let expected: Vec<(String, u32)> = inputs.iter().map(|(txid, vout)| (txid.to_string(), *vout)).collect();
+        assert_eq!(sorted, expected);
+        let values: Vec<u64> = tx.vout.iter().map(|o| o.value).collect();
+        assert_eq!(values, vec![400_057_456, 40_000_000_000]);
+    }
+
+    #[test]
+    fn per_input_signing_commits_to_each_prevout() {
+        let secret = hex::encode([13u8; 32]);
+        let kp = WalletKeypair::from_secret_hex(&secret).unwrap();
+        let utxos = vec![
+            entry(1, 700, kp.script_pubkey()),
+            entry(2, 300, kp.script_pubkey()),
+        ];
+        let mut tx = Transaction {
+            version: 1,
+            vin: utxos
+                .iter()
+                .map(|e| TxIn {
+                    prevout: e.outpoint.clone(),
+                    script_sig: Vec::new(),
+                    sequence: 0xffff_ffff,
+                })
+                .collect(),
+            vout: vec![TxOut {
+                value: 900,
+                script_pubkey: vec![4],
+            }],
+            lock_time: 0,
+        };
+
+        sign_inputs_with_prevouts(&mut tx, &secret, &utxos).unwrap();
+        assert_ne!(tx.vin[0].script_sig, tx.vin[1].script_sig);
+        for (index, vin) in tx.vin.iter().enumerate() {
+            let sighash = sighash_for_input(&tx, index, &utxos[index].txout).unwrap();
+            let (sig, pubkey) = vin.script_sig.split_at(64);
+            assert_eq!(hex::encode(pubkey), kp.public_key_hex());
+            assert!(verify_message_hex(&hex::encode(pubkey), &sighash, &hex::encode(sig)).unwrap());
+        }
+
+        let err = sign_inputs_with_prevouts(&mut tx, &secret, &utxos[..1]).unwrap_err();
+        assert!(matches!(err, CliError::Utxo(UtxoError::MissingUtxo(_))));
+    }
+
+    #[test]
+    fn verify_accepts_signed_tx_and_flags_tampering() {
+        let secret = hex::encode([14u8; 32]);
+        let kp = WalletKeypair::from_secret_hex(&secret).unwrap();
+        let utxos = vec![
+            entry(1, 700, kp.script_pubkey()),
+            entry(2, 300, kp.script_pubkey()),
+        ];
+        let unsigned = Transaction {
+            version: 1,
+            vin: utxos
+                .iter()
+                .map(|e| TxIn {
+                    prevout: e.outpoint.clone(),
+                    script_sig: Vec::new(),
+                    sequence: 0xffff_ffff,
+                })
+                .collect(),
+            vout: vec![TxOut {
+                value: 900,
+                script_pubkey: vec![4],
+            }],
+            lock_time: 0,
+        };
+
+        let mut per_input = unsigned.clone();
+        sign_inputs_with_prevouts(&mut per_input, &secret, &utxos).unwrap();
+        let report = verify_tx(&per_input, &utxos).unwrap();
+        assert!(report.valid);
+        assert_eq!(report.fee, Some(100));
+        assert!(report.inputs.iter().all(|input| input.sighash == Some("per_input")));
+
+        let mut whole = unsigned.clone();
+        sign_all_inputs(&mut whole, &secret).unwrap();
+        let report = verify_tx(&whole, &utxos).unwrap();
+        assert!(report.valid);
+        assert!(report.inputs.iter().all(|input| input.sighash == Some("tx")));
+
+        // Redirecting the output breaks every signature.
+        let mut tampered = per_input.clone();
+        tampered.vout[0].script_pubkey = vec![5];
+        let report = verify_tx(&tampered, &utxos).unwrap();
+        assert!(!report.valid);
+        assert!(report.inputs.iter().all(|input| !input.ok));
+        assert_eq!(report.fee, Some(100));
+
+        // Overspending fails on value even though the prevouts are known.
+        let mut greedy = unsigned.clone();
+        greedy.vout[0].value = 1_001;
+        sign_inputs_with_prevouts(&mut greedy, &secret, &utxos).unwrap();
+        let report = verify_tx(&greedy, &utxos).unwrap();
+        assert!(report.inputs.iter().all(|input| input.ok));
+        assert!(!report.valid);
+        assert!(report.value_error.is_some());
+
+        // A key that does not own the prevout, and a missing prevout.
+        let other = vec![entry(1, 700, vec![9; 32]), utxos[1].clone()];
+        let report = verify_tx(&per_input, &other).unwrap();
+        assert!(!report.inputs[0].ok);
+        assert!(report.inputs[0].error.as_deref().unwrap().contains("does not match"));
+        let report = verify_tx(&per_input, &utxos[..1]).unwrap();
+        assert!(!report.inputs[1].ok);
+        assert!(!report.valid);
+    }
+
+    #[test]
+    fn rotate_refuses_dust_sweep() {
+        let old_secret = hex::encode([12u8; 32]);
+        let old_kp = WalletKeypair::from_secret_hex(&old_secret).unwrap();
+        let utxos = vec![entry(1, 1_000, old_kp.script_pubkey())];
+        let err = build_sweep_tx(&utxos, &old_secret, &generate_keypair(), 5).unwrap_err();
+        assert!(err.to_string().contains("dust"));
+    }
+
+    #[test]
+    fn rescan_tracks_receives_and_spends() {
+        let kp = WalletKeypair::from_secret_hex(&hex::encode([14u8; 32])).unwrap();
+        let mine = kp.script_pubkey();
+        let other = vec![0xee; 32];
+        let coinbase = |tag: u32, value: u64, script: &[u8]| Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint::NULL,
+                script_sig: vec![],
+                sequence: u32::MAX,
+            }],
+            vout: vec![TxOut {
+                value,
+                script_pubkey: script.to_vec(),
+            }],
+            lock_time: tag,
+        };
+        let params = NetworkParams::mainnet();
+        let genesis_hash = genesis_hash(&params).unwrap();
+        let block = |prev: [u8; 32], time: u32, txs: Vec<Transaction>| {
+            let mut block = Block::new(1, prev, GENESIS_TIME + time, params.genesis_bits, 0, txs).unwrap();
+            while !check_pow(&block.header).unwrap() {
+                block.header.nonce += 1;
+            }
+            block
+        };
+
+        let b1 = block(genesis_hash, 600, vec![coinbase(1, 50, &mine)]);
+        let received = OutPoint {
+            txid: b1.txs[0].txid_v2().unwrap(),
+            vout: 0,
+        };
+        let spend = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: received,
+                script_sig: Vec::new(),
+                sequence: 0xffff_ffff,
+            }],
+            vout: vec![
+                TxOut {
+                    value: 30,
+                    script_pubkey: other.clone(),
+                },
+                TxOut {
+                    value: 19,
+                    script_pubkey: mine.clone(),
+                },
+            ],
+            lock_time: 0,
+        };
+        let b2 = block(header_hash(&b1.header), 1_200, vec![coinbase(2, 50, &other), spend.clone()]);
+        let b3 = block(header_hash(&b2.header), 1_800, vec![coinbase(3, 50, &mine)]);
+        // Stale sibling of b1 paying the key: must not be counted.
+        let stale = block(genesis_hash, 900, vec![coinbase(9, 1_000, &mine)]);
+
+        // The data dir is written by the node itself, genesis included.
+        let temp = tempfile::tempdir().unwrap();
+        let tip = tenebriumd::p2p::import_blocks(temp.path(), "mainnet", &[b1.clone(), stale, b2.clone(), b3.clone()])
+            .unwrap();
+        assert_eq!(tip, 3);
+        wait_for_db_unlock(temp.path());
+        let mut exported = String::new();
+        for block in [&b1, &b2, &b3] {
+            exported.push_str(&serde_json::to_string(block).unwrap());
+            exported.push('\n');
+        }
+        let export_path = temp.path().join("blocks.jsonl");
+        std::fs::write(&export_path, exported).unwrap();
+
+        let from_dir = read_chain_db(temp.path(), 0).unwrap();
+        let from_file = read_block_jsonl(&export_path).unwrap();
+        assert_eq!(from_dir, from_file);
+        let heights: Vec<u32> = from_dir.iter().map(|(height, _)| *height).collect();
+        assert_eq!(heights, vec![1, 2, 3]);
+
+        let report = rescan_chain(&from_dir, &mine, 0).unwrap();
+        assert_eq!(report.scanned_blocks, 3);
+        assert_eq!(report.tip_height, Some(3));
+        assert_eq!(report.balance, 69);
+        let found: Vec<(OutPoint, u64, u32)> = report
+            .utxos
+            .iter()
+            .map(|utxo| (utxo.outpoint.clone(), utxo.txout.value, utxo.height))
+            .collect();
+        let change = OutPoint {
+            txid: spend.txid_v2().unwrap(),
+            vout: 1,
+        };
+        let reward = OutPoint {
+            txid: b3.txs[0].txid_v2().unwrap(),
+            vout: 0,
+        };
+        assert_eq!(found, vec![(change, 19, 2), (reward.clone(), 50, 3)]);
+
+        let late = rescan_chain(&from_dir, &mine, 3).unwrap();
+        assert_eq!(late.scanned_blocks, 1);
+        assert_eq!(late.balance, 50);
+        assert_eq!(late.utxos[0].outpoint, reward);
+
+        // Pruned below height 2: a rescan from above the pruning point
+        // still gets exact heights, one reaching into it is refused.
+        wait_for_db_unlock(temp.path());
+        let report = tenebriumd::p2p::prune_data_dir(temp.path(), "mainnet", 1).unwrap();
+        assert_eq!(report.pruned_below, 2);
+        wait_for_db_unlock(temp.path());
+        let db = sled::open(temp.path().join("chain.sled")).unwrap();
+        let pruned = read_main_chain(&db, 2).unwrap();
+        assert_eq!(pruned.iter().map(|(height, _)| *height).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(rescan_chain(&pruned, &mine, 2).unwrap().balance, 69);
+        assert!(matches!(read_main_chain(&db, 0), Err(CliError::InvalidArgs(_))));
+
+        // A block whose body has not been downloaded yet ends the chain.
+        db.open_tree("blocks").unwrap().remove(header_hash(&b3.header)).unwrap();
+        let partial = read_main_chain(&db, 2).unwrap();
+        assert_eq!(partial.iter().map(|(height, _)| *height).collect::<Vec<_>>(), vec![2]);
+
+        let by_address = script_pubkey_from_address(&kp.address().unwrap()).unwrap();
+        assert_eq!(rescan_chain(&from_file, &by_address, 0).unwrap().balance, 69);
+    }
+}
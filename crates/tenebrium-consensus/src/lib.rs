@@ -1,11 +1,25 @@
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use tenebrium_utxo::Transaction;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tenebrium_utxo::{OutPoint, Transaction, TxOut, UtxoSet};
+
+pub mod params;
+pub use params::NetworkParams;
+
+/// Root of an empty txid list. Every block has a coinbase, so a header
+/// carrying this root is never valid.
+pub const EMPTY_MERKLE_ROOT: [u8; 32] = [0u8; 32];
+
+/// Compact bits of the easiest allowed target; difficulty 1.0.
+pub const POW_LIMIT_BITS: u32 = 0x207fffff;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct BlockHeader {
 	pub version: i32,
+	#[serde(with = "tenebrium_utxo::hash_serde")]
 	pub prev_block_hash: [u8; 32],
+	#[serde(with = "tenebrium_utxo::hash_serde")]
 	pub merkle_root: [u8; 32],
 	pub time: u32,
 	pub bits: u32,
@@ -27,12 +41,7 @@ impl Block {
 		nonce: u32,
 		txs: Vec<Transaction>,
 	) -> Result<Self, ConsensusError> {
-		let txids = txs
-			.iter()
-			.map(|tx| tx.txid_v2())
-			.collect::<Result<Vec<_>, _>>()
-			.map_err(ConsensusError::Utxo)?;
-		let merkle_root = merkle_root(&txids);
+		let merkle_root = merkle_root(&txids_of(&txs)?);
 		Ok(Block {
 			header: BlockHeader {
 				version,
@@ -45,6 +54,276 @@ impl Block {
 			txs,
 		})
 	}
+
+	/// Genesis block for `params` around `coinbase`: no parent, with time,
+	/// bits and nonce taken from the params and the merkle root computed.
+	/// The genesis coinbase is never applied, and is the one coinbase
+	/// allowed to have no inputs, as the pinned merkle root commits to it.
+	pub fn genesis(params: &NetworkParams, coinbase: Transaction) -> Result<Self, ConsensusError> {
+		if !coinbase.vin.is_empty() && !coinbase.is_coinbase() {
+			return Err(ConsensusError::CoinbaseRequired);
+		}
+		Block::new(
+			1,
+			[0u8; 32],
+			params.genesis_time,
+			params.genesis_bits,
+			params.genesis_nonce,
+			vec![coinbase],
+		)
+	}
+
+	/// The first transaction, or `None` for an empty block. Whether it really
+	/// is a coinbase is checked by [`Block::validate_structure`].
+	pub fn coinbase(&self) -> Option<&Transaction> {
+		self.txs.first()
+	}
+
+	/// Total output value of the coinbase (subsidy plus claimed fees).
+	pub fn coinbase_value(&self) -> Result<u64, ConsensusError> {
+		let coinbase = self.coinbase().ok_or(ConsensusError::EmptyBlock)?;
+		Transaction::sum_outputs(coinbase).map_err(ConsensusError::Utxo)
+	}
+
+	/// Height committed at the start of the coinbase's input script, or
+	/// `None` when the coinbase has no input or the prefix is malformed.
+	pub fn coinbase_height(&self) -> Option<u32> {
+		let input = self.coinbase()?.vin.first()?;
+		decode_coinbase_height(&input.script_sig)
+	}
+
+	/// Canonical (v2) txids of the transactions, in block order. Callers that
+	/// need them more than once should hold on to the result; each call hashes
+	/// every transaction again.
+	pub fn txids(&self) -> Result<Vec<[u8; 32]>, ConsensusError> {
+		txids_of(&self.txs)
+	}
+
+	/// Reject a block listing the same transaction twice. Together with
+	/// [`merkle_root_checked`] this keeps a duplicated-tail block from being
+	/// mistaken for (and cached as an invalid version of) the original.
+	pub fn check_duplicate_txids(&self) -> Result<(), ConsensusError> {
+		check_duplicates(&self.txids()?)
+	}
+
+	/// Reject a block containing a transaction that is not yet final at
+	/// `height`, with time locks measured against `mtp`, the median-time-past
+	/// of the block's parent. See [`Transaction::check_final`].
+	pub fn check_final(&self, height: u32, mtp: u32) -> Result<(), ConsensusError> {
+		for tx in &self.txs {
+			tx.check_final(height, mtp)?;
+		}
+		Ok(())
+	}
+
+	/// Structural checks that need no UTXO context: the block must contain
+	/// transactions, its merkle root must not be the empty-tree root, and the
+	/// first transaction must be a coinbase (a single input spending the null
+	/// outpoint).
+	pub fn validate_structure(&self) -> Result<(), ConsensusError> {
+		if self.header.merkle_root == EMPTY_MERKLE_ROOT {
+			return Err(ConsensusError::EmptyBlock);
+		}
+		let coinbase = self.coinbase().ok_or(ConsensusError::EmptyBlock)?;
+		if !coinbase.is_coinbase() {
+			return Err(ConsensusError::CoinbaseRequired);
+		}
+		Ok(())
+	}
+
+	/// Rules that depend on the block's place in the chain rather than on
+	/// the UTXO set, as set up in `checks`. Every path that accepts a block
+	/// runs these alongside [`Block::validate`].
+	pub fn check_contextual(&self, mut checks: ContextualChecks) -> Result<(), ConsensusError> {
+		for tx in &self.txs {
+			checks.check_tx(tx)?;
+		}
+		Ok(())
+	}
+
+	/// Full context-dependent validation against `utxos`, which is left
+	/// untouched: proof of work (unless `no_pow_check`), structure, merkle
+	/// root, per-transaction rules and value conservation (later transactions
+	/// may spend earlier ones), and a coinbase paying at most
+	/// `subsidy` plus fees. Returns the total fees.
+	pub fn validate(&self, utxos: &dyn UtxoSet, subsidy: u64, no_pow_check: bool) -> Result<u64, ConsensusError> {
+		if !no_pow_check && !check_pow(&self.header)? {
+			return Err(ConsensusError::InvalidPow);
+		}
+		self.validate_structure()?;
+		let txids = self.txids()?;
+		if merkle_root_checked(&txids)? != self.header.merkle_root {
+			return Err(ConsensusError::MerkleRootMismatch);
+		}
+		check_duplicates(&txids)?;
+
+		let mut view = BlockView::new(utxos);
+		let mut total_fees = 0u64;
+		for (i, tx) in self.txs.iter().enumerate() {
+			tx.validate_as(i == 0)?;
+			if i == 0 {
+				for (op, txout) in Transaction::make_outpoints(tx)?.into_iter().zip(&tx.vout) {
+					if view.get(&op).is_some() {
+						return Err(tenebrium_utxo::UtxoError::DuplicateOutput(op).into());
+					}
+					view.insert(op, txout.clone());
+				}
+				continue;
+			}
+			if tx.is_coinbase() {
+				return Err(ConsensusError::UnexpectedCoinbase(i));
+			}
+			let fee = Transaction::validate_value_conservation(tx, &view)?;
+			total_fees = total_fees.saturating_add(fee);
+			view.apply_tx(tx)?;
+		}
+
+		let value = self.coinbase_value()?;
+		let limit = subsidy.saturating_add(total_fees);
+		if value > limit {
+			return Err(ConsensusError::ExcessCoinbase { value, limit });
+		}
+		Ok(total_fees)
+	}
+}
+
+/// Block rules that need the block's place in the chain: the coinbase
+/// commits to `height` (BIP34), every transaction is final at `height` with
+/// time locks measured against `mtp` (the parent's median-time-past), and
+/// the block stays within `max_sigops` and `max_outputs`. Checked one
+/// transaction at a time, so a streamed block is held to the same rules as
+/// one in memory (see [`Block::check_contextual`]).
+#[derive(Debug, Clone)]
+pub struct ContextualChecks {
+	height: u32,
+	mtp: u32,
+	max_sigops: usize,
+	max_outputs: usize,
+	sigops: usize,
+	outputs: usize,
+	seen: usize,
+}
+
+impl ContextualChecks {
+	pub fn new(height: u32, mtp: u32, max_sigops: usize, max_outputs: usize) -> Self {
+		Self {
+			height,
+			mtp,
+			max_sigops,
+			max_outputs,
+			sigops: 0,
+			outputs: 0,
+			seen: 0,
+		}
+	}
+
+	/// Check the next transaction in block order. A first transaction that
+	/// is not a coinbase is left for [`Block::validate_structure`] to report.
+	pub fn check_tx(&mut self, tx: &Transaction) -> Result<(), ConsensusError> {
+		if self.seen == 0 && tx.is_coinbase() {
+			// Committing the height keeps coinbases, and so their txids, unique.
+			let committed = tx.vin.first().and_then(|input| decode_coinbase_height(&input.script_sig));
+			if committed != Some(self.height) {
+				return Err(ConsensusError::BadCoinbaseHeight(self.height));
+			}
+		}
+		self.seen += 1;
+		tx.check_final(self.height, self.mtp)?;
+		self.sigops = self.sigops.saturating_add(tx.sigop_count());
+		if self.sigops > self.max_sigops {
+			return Err(ConsensusError::TooManySigops {
+				count: self.sigops,
+				limit: self.max_sigops,
+			});
+		}
+		self.outputs = self.outputs.saturating_add(tx.vout.len());
+		if self.outputs > self.max_outputs {
+			return Err(ConsensusError::TooManyOutputs {
+				count: self.outputs,
+				limit: self.max_outputs,
+			});
+		}
+		Ok(())
+	}
+}
+
+fn txids_of(txs: &[Transaction]) -> Result<Vec<[u8; 32]>, ConsensusError> {
+	txs.iter()
+		.map(|tx| tx.txid_v2().map_err(ConsensusError::Utxo))
+		.collect()
+}
+
+fn check_duplicates(txids: &[[u8; 32]]) -> Result<(), ConsensusError> {
+	let mut seen = HashSet::with_capacity(txids.len());
+	for txid in txids {
+		if !seen.insert(*txid) {
+			return Err(ConsensusError::DuplicateTxid(*txid));
+		}
+	}
+	Ok(())
+}
+
+/// A block's own outputs and spends layered over a read-only UTXO set, so
+/// [`Block::validate`] can follow in-block spends without mutating it.
+struct BlockView<'a> {
+	base: &'a dyn UtxoSet,
+	created: HashMap<OutPoint, TxOut>,
+	spent: HashSet<OutPoint>,
+}
+
+impl<'a> BlockView<'a> {
+	fn new(base: &'a dyn UtxoSet) -> Self {
+		Self {
+			base,
+			created: HashMap::new(),
+			spent: HashSet::new(),
+		}
+	}
+}
+
+impl UtxoSet for BlockView<'_> {
+	fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
+		if let Some(txout) = self.created.get(outpoint) {
+			return Some(txout.clone());
+		}
+		if self.spent.contains(outpoint) {
+			return None;
+		}
+		self.base.get(outpoint)
+	}
+
+	fn insert(&mut self, outpoint: OutPoint, txout: TxOut) {
+		self.spent.remove(&outpoint);
+		self.created.insert(outpoint, txout);
+	}
+
+	fn remove(&mut self, outpoint: &OutPoint) -> Option<TxOut> {
+		if let Some(txout) = self.created.remove(outpoint) {
+			return Some(txout);
+		}
+		let txout = self.base.get(outpoint)?;
+		self.spent.insert(outpoint.clone()).then_some(txout)
+	}
+
+	fn len(&self) -> usize {
+		(self.base.len() + self.created.len()).saturating_sub(self.spent.len())
+	}
+
+	fn total_value(&self) -> Result<u64, tenebrium_utxo::UtxoError> {
+		let overflow = || tenebrium_utxo::UtxoError::Overflow;
+		let created = self
+			.created
+			.values()
+			.try_fold(0u64, |total, txout| total.checked_add(txout.value).ok_or_else(overflow))?;
+		let spent: u64 = self
+			.spent
+			.iter()
+			.filter_map(|outpoint| self.base.get(outpoint))
+			.map(|txout| txout.value)
+			.sum();
+		let total = self.base.total_value()?.checked_add(created).ok_or_else(overflow)?;
+		Ok(total - spent)
+	}
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -53,6 +332,68 @@ pub enum ConsensusError {
 	Utxo(#[from] tenebrium_utxo::UtxoError),
 	#[error("invalid bits")]
 	InvalidBits,
+	#[error("invalid merkle proof: {0}")]
+	InvalidMerkleProof(String),
+	#[error("empty block")]
+	EmptyBlock,
+	#[error("first transaction must be a coinbase (one null-prevout input)")]
+	CoinbaseRequired,
+	#[error("merkle tree is mutated (duplicate hashes paired)")]
+	MutatedMerkleTree,
+	#[error("duplicate txid: {0:02x?}")]
+	DuplicateTxid([u8; 32]),
+	#[error("invalid proof of work")]
+	InvalidPow,
+	#[error("merkle root mismatch")]
+	MerkleRootMismatch,
+	#[error("coinbase-shaped transaction at index {0}")]
+	UnexpectedCoinbase(usize),
+	#[error("coinbase pays {value}, more than subsidy plus fees ({limit})")]
+	ExcessCoinbase { value: u64, limit: u64 },
+	#[error("coinbase does not commit to block height {0}")]
+	BadCoinbaseHeight(u32),
+	#[error("too many sigops: {count} (max {limit})")]
+	TooManySigops { count: usize, limit: usize },
+	#[error("too many outputs: {count} (max {limit})")]
+	TooManyOutputs { count: usize, limit: usize },
+}
+
+/// Script prefix committing a coinbase to `height` (BIP34): one push of the
+/// minimal little-endian script-number encoding. Height 0 is the empty push.
+pub fn encode_coinbase_height(height: u32) -> Vec<u8> {
+	let mut number = height.to_le_bytes().to_vec();
+	while number.last() == Some(&0) {
+		number.pop();
+	}
+	// A set top bit would read back as negative.
+	if number.last().is_some_and(|byte| byte & 0x80 != 0) {
+		number.push(0);
+	}
+	let mut script = Vec::with_capacity(number.len() + 1);
+	script.push(number.len() as u8);
+	script.extend_from_slice(&number);
+	script
+}
+
+/// Height pushed by the first bytes of `script_sig`, accepting only what
+/// [`encode_coinbase_height`] produces; anything may follow the push.
+pub fn decode_coinbase_height(script_sig: &[u8]) -> Option<u32> {
+	let len = *script_sig.first()? as usize;
+	let number = script_sig.get(1..1 + len)?;
+	if len > 5 {
+		return None;
+	}
+	let mut bytes = [0u8; 8];
+	bytes[..len].copy_from_slice(number);
+	let height = u32::try_from(u64::from_le_bytes(bytes)).ok()?;
+	(encode_coinbase_height(height) == script_sig[..1 + len]).then_some(height)
+}
+
+/// Hash of the genesis block [`Block::genesis`] builds from
+/// [`NetworkParams::genesis_coinbase`].
+pub fn genesis_hash(params: &NetworkParams) -> Result<[u8; 32], ConsensusError> {
+	let genesis = Block::genesis(params, params.genesis_coinbase())?;
+	Ok(header_hash(&genesis.header))
 }
 
 pub fn header_hash(header: &BlockHeader) -> [u8; 32] {
@@ -64,17 +405,22 @@ pub fn header_hash(header: &BlockHeader) -> [u8; 32] {
 	bytes.extend_from_slice(&header.bits.to_le_bytes());
 	bytes.extend_from_slice(&header.nonce.to_le_bytes());
 	let first = Sha256::digest(&bytes);
-	let second = Sha256::digest(&first);
+	let second = Sha256::digest(first);
 	let mut out = [0u8; 32];
 	out.copy_from_slice(&second);
 	out
 }
 
+/// Sign bit of the compact mantissa.
+pub const COMPACT_SIGN_BIT: u32 = 0x0080_0000;
+
+/// Compact bits with the sign bit (0x00800000) set encode a negative
+/// target and are rejected.
 pub fn bits_to_target(bits: u32) -> Result<[u8; 32], ConsensusError> {
-	if bits == 0 {
+	if bits == 0 || bits & COMPACT_SIGN_BIT != 0 {
 		return Err(ConsensusError::InvalidBits);
 	}
-	let exponent = (bits >> 24) as u32;
+	let exponent = bits >> 24;
 	let mantissa = bits & 0x007f_ffff;
 	if mantissa == 0 {
 		return Err(ConsensusError::InvalidBits);
@@ -105,15 +451,71 @@ pub fn bits_to_target(bits: u32) -> Result<[u8; 32], ConsensusError> {
 	Ok(target)
 }
 
+/// Big-endian 256-bit target as a float (precision loss is fine for display).
+fn target_to_f64(target: &[u8; 32]) -> f64 {
+	target.iter().fold(0.0, |acc, byte| acc * 256.0 + *byte as f64)
+}
+
+/// Human-readable difficulty: the [`POW_LIMIT_BITS`] target divided by the
+/// target encoded in `bits`, so the easiest blocks are 1.0 and halving the
+/// target doubles it. Invalid bits report 0.0 rather than an error, since
+/// this is only used for display.
+pub fn difficulty(bits: u32) -> f64 {
+	let (Ok(limit), Ok(target)) = (bits_to_target(POW_LIMIT_BITS), bits_to_target(bits)) else {
+		return 0.0;
+	};
+	let target = target_to_f64(&target);
+	if target == 0.0 {
+		return 0.0;
+	}
+	target_to_f64(&limit) / target
+}
+
+/// Estimated network hash rate (hashes per second) needed to find a block of
+/// the given `difficulty` every `block_time_secs` on average.
+pub fn network_hashrate(difficulty: f64, block_time_secs: u32) -> f64 {
+	if block_time_secs == 0 {
+		return 0.0;
+	}
+	// Expected hashes per block at difficulty 1 is 2^256 / limit_target.
+	let Ok(limit) = bits_to_target(POW_LIMIT_BITS) else {
+		return 0.0;
+	};
+	let hashes_at_one = 2f64.powi(256) / target_to_f64(&limit);
+	difficulty * hashes_at_one / block_time_secs as f64
+}
+
 pub fn check_pow(header: &BlockHeader) -> Result<bool, ConsensusError> {
-	let target = bits_to_target(header.bits)?;
-	let hash = header_hash(header);
-	Ok(hash_leq(&hash, &target))
+	hash_meets_target(&header_hash(header), header.bits)
+}
+
+/// Whether an already computed header `hash` satisfies the target encoded
+/// in `bits`, for miners checking candidates without rehashing the header.
+pub fn hash_meets_target(hash: &[u8; 32], bits: u32) -> Result<bool, ConsensusError> {
+	let target = bits_to_target(bits)?;
+	Ok(hash_leq(hash, &target))
 }
 
+/// Nonces [`mine_header_with_progress`] tries between progress callbacks.
+pub const MINE_PROGRESS_INTERVAL: u32 = 100_000;
+
 pub fn mine_header(header: &mut BlockHeader, max_nonce: u32) -> Result<Option<u32>, ConsensusError> {
-	for _ in 0..=max_nonce {
-		if check_pow(header)? {
+	mine_header_with_progress(header, max_nonce, |_| {})
+}
+
+/// [`mine_header`] that calls `progress` with the number of nonces tried so
+/// far after every [`MINE_PROGRESS_INTERVAL`] of them.
+pub fn mine_header_with_progress(
+	header: &mut BlockHeader,
+	max_nonce: u32,
+	mut progress: impl FnMut(u32),
+) -> Result<Option<u32>, ConsensusError> {
+	let target = bits_to_target(header.bits)?;
+	for tried in 0..=max_nonce {
+		if tried > 0 && tried.is_multiple_of(MINE_PROGRESS_INTERVAL) {
+			progress(tried);
+		}
+		if hash_leq(&header_hash(header), &target) {
 			return Ok(Some(header.nonce));
 		}
 		header.nonce = header.nonce.wrapping_add(1);
@@ -121,7 +523,70 @@ pub fn mine_header(header: &mut BlockHeader, max_nonce: u32) -> Result<Option<u3
 	Ok(None)
 }
 
-fn hash_leq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+/// Header version bits a miner may roll as extra nonce (BIP320's mask).
+pub const VERSION_ROLLING_MASK: i32 = 0x1fff_e000;
+/// Largest extra nonce [`mine_header_parallel`] tries: 16 rolled version bits.
+pub const MAX_EXTRA_NONCE: u32 = (VERSION_ROLLING_MASK as u32) >> 13;
+/// Hashes a mining worker tries between checks of the stop flags.
+const STOP_CHECK_INTERVAL: u32 = 4096;
+
+/// `version` with its [`VERSION_ROLLING_MASK`] bits replaced by `extra_nonce`.
+pub fn roll_version(version: i32, extra_nonce: u32) -> i32 {
+	(version & !VERSION_ROLLING_MASK) | (((extra_nonce & MAX_EXTRA_NONCE) << 13) as i32)
+}
+
+/// Search for a solving `(nonce, extra_nonce)` on `threads` workers. Each
+/// worker scans its own slice of the 32-bit nonce range, then moves on to the
+/// next extra nonce, applied to the header with [`roll_version`], so the
+/// search covers 2^48 headers. Workers poll `stop` (and each other's success)
+/// every few thousand hashes; `Ok(None)` means `stop` was raised or the whole
+/// space was exhausted. `header` is not modified.
+pub fn mine_header_parallel(
+	header: &BlockHeader,
+	threads: usize,
+	stop: &AtomicBool,
+) -> Result<Option<(u32, u32)>, ConsensusError> {
+	let target = bits_to_target(header.bits)?;
+	let threads = threads.clamp(1, u32::MAX as usize) as u32;
+	let chunk = (u32::MAX / threads).saturating_add(1);
+	let found = AtomicBool::new(false);
+	let solution = std::thread::scope(|scope| {
+		let workers: Vec<_> = (0..threads)
+			.map(|worker| {
+				let (found, target) = (&found, &target);
+				scope.spawn(move || {
+					let first = worker * chunk;
+					let last = if worker + 1 == threads { u32::MAX } else { first + chunk - 1 };
+					let mut candidate = header.clone();
+					for extra_nonce in 0..=MAX_EXTRA_NONCE {
+						candidate.version = roll_version(header.version, extra_nonce);
+						for nonce in first..=last {
+							if (nonce - first).is_multiple_of(STOP_CHECK_INTERVAL)
+								&& (stop.load(Ordering::Relaxed) || found.load(Ordering::Relaxed))
+							{
+								return None;
+							}
+							candidate.nonce = nonce;
+							if hash_leq(&header_hash(&candidate), target) {
+								found.store(true, Ordering::Relaxed);
+								return Some((nonce, extra_nonce));
+							}
+						}
+					}
+					None
+				})
+			})
+			.collect();
+		workers
+			.into_iter()
+			.filter_map(|worker| worker.join().ok().flatten())
+			.next()
+	});
+	Ok(solution)
+}
+
+/// `a <= b`, both read as big-endian 256-bit numbers.
+pub fn hash_leq(a: &[u8; 32], b: &[u8; 32]) -> bool {
 	for i in 0..32 {
 		if a[i] < b[i] {
 			return true;
@@ -134,33 +599,340 @@ fn hash_leq(a: &[u8; 32], b: &[u8; 32]) -> bool {
 }
 
 pub fn merkle_root(txids: &[[u8; 32]]) -> [u8; 32] {
+	compute_merkle_root(txids).0
+}
+
+/// [`merkle_root`] that refuses mutated trees (CVE-2012-2459). A lone node is
+/// paired with itself, so a level ending in two equal hashes hashes the same
+/// as one ending in a single copy: `[a, b, c]` and `[a, b, c, c]` share a
+/// root. Any level holding an explicit pair of equal hashes is rejected.
+pub fn merkle_root_checked(txids: &[[u8; 32]]) -> Result<[u8; 32], ConsensusError> {
+	match compute_merkle_root(txids) {
+		(_, true) => Err(ConsensusError::MutatedMerkleTree),
+		(root, false) => Ok(root),
+	}
+}
+
+/// Root plus whether any level paired two equal hashes that were both present.
+fn compute_merkle_root(txids: &[[u8; 32]]) -> ([u8; 32], bool) {
 	if txids.is_empty() {
-		return [0u8; 32];
+		return (EMPTY_MERKLE_ROOT, false);
 	}
+	let mut mutated = false;
 	let mut level = txids.to_vec();
 	while level.len() > 1 {
-		let mut next = Vec::with_capacity((level.len() + 1) / 2);
+		let mut next = Vec::with_capacity(level.len().div_ceil(2));
 		let mut i = 0;
 		while i < level.len() {
 			let left = level[i];
 			let right = if i + 1 < level.len() {
+				mutated |= level[i + 1] == left;
 				level[i + 1]
 			} else {
 				level[i]
 			};
-			let mut data = Vec::with_capacity(64);
-			data.extend_from_slice(&left);
-			data.extend_from_slice(&right);
-			let first = Sha256::digest(&data);
-			let second = Sha256::digest(&first);
-			let mut out = [0u8; 32];
-			out.copy_from_slice(&second);
-			next.push(out);
+			next.push(merkle_parent(&left, &right));
 			i += 2;
 		}
 		level = next;
 	}
-	level[0]
+	(level[0], mutated)
+}
+
+/// Incremental merkle root over a stream of txids, holding at most one
+/// pending hash per tree level. Produces the same root as [`merkle_root`].
+#[derive(Debug, Clone, Default)]
+pub struct MerkleAccumulator {
+	count: u64,
+	inner: Vec<Option<[u8; 32]>>,
+	mutated: bool,
+}
+
+impl MerkleAccumulator {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn push(&mut self, txid: [u8; 32]) {
+		let mut hash = txid;
+		let mut level = 0;
+		loop {
+			if level == self.inner.len() {
+				self.inner.push(None);
+			}
+			match self.inner[level].take() {
+				Some(left) => {
+					self.mutated |= left == hash;
+					hash = merkle_parent(&left, &hash);
+					level += 1;
+				}
+				None => {
+					self.inner[level] = Some(hash);
+					break;
+				}
+			}
+		}
+		self.count += 1;
+	}
+
+	pub fn len(&self) -> u64 {
+		self.count
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	/// Whether an explicit pair of equal hashes was merged, as rejected by
+	/// [`merkle_root_checked`].
+	pub fn is_mutated(&self) -> bool {
+		self.mutated
+	}
+
+	pub fn root(&self) -> [u8; 32] {
+		if self.count == 0 {
+			return [0u8; 32];
+		}
+		let mut count = self.count;
+		let mut level = count.trailing_zeros() as usize;
+		let mut hash = self.inner[level].expect("pending hash at lowest set level");
+		// Lone nodes are paired with themselves, as in merkle_root.
+		while count != 1u64 << level {
+			hash = merkle_parent(&hash, &hash);
+			count += 1u64 << level;
+			level += 1;
+			while count & (1u64 << level) == 0 {
+				let left = self.inner[level].expect("pending hash at carry level");
+				hash = merkle_parent(&left, &hash);
+				level += 1;
+			}
+		}
+		hash
+	}
+}
+
+/// Inclusion proof for `txids[index]`: sibling hashes from leaf to root,
+/// each flagged `true` when the sibling sits on the right. A node without a
+/// sibling is paired with itself, as in [`merkle_root`], so its own hash is
+/// the sibling. A single-transaction block yields an empty proof; `None` for
+/// an out-of-range index.
+pub fn merkle_proof(txids: &[[u8; 32]], index: usize) -> Option<Vec<([u8; 32], bool)>> {
+	if index >= txids.len() {
+		return None;
+	}
+	let mut proof = Vec::new();
+	let mut level = txids.to_vec();
+	let mut index = index;
+	while level.len() > 1 {
+		let sibling = if index.is_multiple_of(2) {
+			(*level.get(index + 1).unwrap_or(&level[index]), true)
+		} else {
+			(level[index - 1], false)
+		};
+		proof.push(sibling);
+		level = level
+			.chunks(2)
+			.map(|pair| merkle_parent(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+			.collect();
+		index /= 2;
+	}
+	Some(proof)
+}
+
+/// Fold `proof` from `txid` upwards and compare with `root`.
+pub fn verify_merkle_proof(txid: &[u8; 32], proof: &[([u8; 32], bool)], root: &[u8; 32]) -> bool {
+	let computed = proof.iter().fold(*txid, |hash, (sibling, sibling_right)| {
+		if *sibling_right {
+			merkle_parent(&hash, sibling)
+		} else {
+			merkle_parent(sibling, &hash)
+		}
+	});
+	computed == *root
+}
+
+fn merkle_parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+	let mut data = Vec::with_capacity(64);
+	data.extend_from_slice(left);
+	data.extend_from_slice(right);
+	let first = Sha256::digest(&data);
+	let second = Sha256::digest(first);
+	let mut out = [0u8; 32];
+	out.copy_from_slice(&second);
+	out
+}
+
+/// Header plus a BIP37-style partial merkle tree proving which transactions
+/// of the block were matched, without shipping the full block.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MerkleBlock {
+	pub header: BlockHeader,
+	pub total_txs: u32,
+	/// Hashes of the pruned subtrees, in depth-first order
+	pub matched_hashes: Vec<[u8; 32]>,
+	/// Depth-first traversal bits, packed LSB-first
+	pub flags: Vec<u8>,
+}
+
+impl MerkleBlock {
+	/// Build a merkleblock for `block`, where `matches[i]` marks transaction `i`
+	/// as one the receiver wants proven.
+	pub fn from_block(block: &Block, matches: &[bool]) -> Result<Self, ConsensusError> {
+		if matches.len() != block.txs.len() {
+			return Err(ConsensusError::InvalidMerkleProof(
+				"match list length mismatch".to_string(),
+			));
+		}
+		let txids = block.txids()?;
+		let mut builder = PartialTreeBuilder {
+			txids: &txids,
+			matches,
+			bits: Vec::new(),
+			hashes: Vec::new(),
+		};
+		if !txids.is_empty() {
+			builder.traverse(tree_height(txids.len()), 0);
+		}
+		let mut flags = vec![0u8; builder.bits.len().div_ceil(8)];
+		for (i, bit) in builder.bits.iter().enumerate() {
+			if *bit {
+				flags[i / 8] |= 1 << (i % 8);
+			}
+		}
+		Ok(Self {
+			header: block.header.clone(),
+			total_txs: txids.len() as u32,
+			matched_hashes: builder.hashes,
+			flags,
+		})
+	}
+
+	/// Rebuild the merkle root from the partial tree and check it against the
+	/// header. Returns the matched txids in block order.
+	pub fn verify(&self) -> Result<Vec<[u8; 32]>, ConsensusError> {
+		let invalid = |msg: &str| ConsensusError::InvalidMerkleProof(msg.to_string());
+		if self.total_txs == 0 {
+			return Err(invalid("no transactions"));
+		}
+		if self.matched_hashes.len() > self.total_txs as usize {
+			return Err(invalid("more hashes than transactions"));
+		}
+		if self.flags.len() * 8 < self.matched_hashes.len() {
+			return Err(invalid("not enough flag bits"));
+		}
+		let mut extractor = PartialTreeExtractor {
+			total: self.total_txs as usize,
+			hashes: &self.matched_hashes,
+			flags: &self.flags,
+			bits_used: 0,
+			hashes_used: 0,
+			matched: Vec::new(),
+		};
+		let root = extractor.traverse(tree_height(self.total_txs as usize), 0)?;
+		if extractor.hashes_used != self.matched_hashes.len() {
+			return Err(invalid("unused hashes"));
+		}
+		if extractor.bits_used.div_ceil(8) != self.flags.len() {
+			return Err(invalid("unused flag bits"));
+		}
+		if root != self.header.merkle_root {
+			return Err(invalid("merkle root mismatch"));
+		}
+		Ok(extractor.matched)
+	}
+}
+
+fn tree_width(total: usize, height: u32) -> usize {
+	(total + (1 << height) - 1) >> height
+}
+
+fn tree_height(total: usize) -> u32 {
+	let mut height = 0;
+	while tree_width(total, height) > 1 {
+		height += 1;
+	}
+	height
+}
+
+struct PartialTreeBuilder<'a> {
+	txids: &'a [[u8; 32]],
+	matches: &'a [bool],
+	bits: Vec<bool>,
+	hashes: Vec<[u8; 32]>,
+}
+
+impl PartialTreeBuilder<'_> {
+	fn hash_at(&self, height: u32, pos: usize) -> [u8; 32] {
+		if height == 0 {
+			return self.txids[pos];
+		}
+		let left = self.hash_at(height - 1, pos * 2);
+		let right = if pos * 2 + 1 < tree_width(self.txids.len(), height - 1) {
+			self.hash_at(height - 1, pos * 2 + 1)
+		} else {
+			left
+		};
+		merkle_parent(&left, &right)
+	}
+
+	fn traverse(&mut self, height: u32, pos: usize) {
+		let start = pos << height;
+		let end = ((pos + 1) << height).min(self.txids.len());
+		let parent_of_match = self.matches[start..end].iter().any(|m| *m);
+		self.bits.push(parent_of_match);
+		if height == 0 || !parent_of_match {
+			let hash = self.hash_at(height, pos);
+			self.hashes.push(hash);
+			return;
+		}
+		self.traverse(height - 1, pos * 2);
+		if pos * 2 + 1 < tree_width(self.txids.len(), height - 1) {
+			self.traverse(height - 1, pos * 2 + 1);
+		}
+	}
+}
+
+struct PartialTreeExtractor<'a> {
+	total: usize,
+	hashes: &'a [[u8; 32]],
+	flags: &'a [u8],
+	bits_used: usize,
+	hashes_used: usize,
+	matched: Vec<[u8; 32]>,
+}
+
+impl PartialTreeExtractor<'_> {
+	fn traverse(&mut self, height: u32, pos: usize) -> Result<[u8; 32], ConsensusError> {
+		let invalid = |msg: &str| ConsensusError::InvalidMerkleProof(msg.to_string());
+		if self.bits_used >= self.flags.len() * 8 {
+			return Err(invalid("ran out of flag bits"));
+		}
+		let parent_of_match = self.flags[self.bits_used / 8] & (1 << (self.bits_used % 8)) != 0;
+		self.bits_used += 1;
+		if height == 0 || !parent_of_match {
+			let hash = *self
+				.hashes
+				.get(self.hashes_used)
+				.ok_or_else(|| invalid("ran out of hashes"))?;
+			self.hashes_used += 1;
+			if height == 0 && parent_of_match {
+				self.matched.push(hash);
+			}
+			return Ok(hash);
+		}
+		let left = self.traverse(height - 1, pos * 2)?;
+		let right = if pos * 2 + 1 < tree_width(self.total, height - 1) {
+			let right = self.traverse(height - 1, pos * 2 + 1)?;
+			if right == left {
+				// Identical siblings would let a proof hide a duplicated tx.
+				return Err(invalid("duplicate sibling hashes"));
+			}
+			right
+		} else {
+			left
+		};
+		Ok(merkle_parent(&left, &right))
+	}
 }
 
 #[cfg(test)]
@@ -187,6 +959,356 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn validate_structure_rejects_empty_and_missing_coinbase() {
+		let empty = Block::new(1, [0u8; 32], 0, 0x207fffff, 0, vec![]).unwrap();
+		assert!(matches!(empty.validate_structure(), Err(ConsensusError::EmptyBlock)));
+
+		let no_coinbase = Block::new(1, [0u8; 32], 0, 0x207fffff, 0, vec![tx_with_id(1)]).unwrap();
+		assert!(matches!(
+			no_coinbase.validate_structure(),
+			Err(ConsensusError::CoinbaseRequired)
+		));
+	}
+
+	fn coinbase_input() -> TxIn {
+		TxIn {
+			prevout: OutPoint::NULL,
+			script_sig: vec![],
+			sequence: u32::MAX,
+		}
+	}
+
+	fn coinbase_paying(value: u64) -> Transaction {
+		Transaction {
+			version: 1,
+			vin: vec![coinbase_input()],
+			vout: vec![TxOut {
+				value,
+				script_pubkey: vec![1],
+			}],
+			lock_time: 0,
+		}
+	}
+
+	#[test]
+	fn validate_spends_in_block_and_returns_fees() {
+		use tenebrium_utxo::InMemoryUtxoSet;
+
+		let funding = OutPoint {
+			txid: [9u8; 32],
+			vout: 0,
+		};
+		let mut utxos = InMemoryUtxoSet::new();
+		utxos.insert(
+			funding.clone(),
+			TxOut {
+				value: 100,
+				script_pubkey: vec![1],
+			},
+		);
+		let spend = |prevout: OutPoint, value: u64| Transaction {
+			version: 1,
+			vin: vec![TxIn {
+				prevout,
+				script_sig: vec![],
+				sequence: 0,
+			}],
+			vout: vec![TxOut {
+				value,
+				script_pubkey: vec![2],
+			}],
+			lock_time: 0,
+		};
+		let first = spend(funding.clone(), 90);
+		let second = spend(
+			OutPoint {
+				txid: first.txid_v2().unwrap(),
+				vout: 0,
+			},
+			85,
+		);
+		let block = |coinbase: u64, txs: Vec<Transaction>| {
+			let mut all = vec![coinbase_paying(coinbase)];
+			all.extend(txs);
+			Block::new(1, [0u8; 32], 0, POW_LIMIT_BITS, 0, all).unwrap()
+		};
+
+		let ok = block(65, vec![first.clone(), second.clone()]);
+		assert_eq!(ok.validate(&utxos, 50, true).unwrap(), 15);
+		// The set is only read.
+		assert!(utxos.get(&funding).is_some());
+
+		let greedy = block(66, vec![first.clone(), second.clone()]);
+		assert!(matches!(
+			greedy.validate(&utxos, 50, true),
+			Err(ConsensusError::ExcessCoinbase { value: 66, limit: 65 })
+		));
+
+		// Spending the same output twice within a block.
+		let twice = block(50, vec![first.clone(), spend(funding, 80)]);
+		assert!(matches!(
+			twice.validate(&utxos, 50, true),
+			Err(ConsensusError::Utxo(tenebrium_utxo::UtxoError::MissingUtxo(_)))
+		));
+
+		// Out of order: the child comes before its parent.
+		let reordered = block(50, vec![second, first]);
+		assert!(matches!(
+			reordered.validate(&utxos, 50, true),
+			Err(ConsensusError::Utxo(tenebrium_utxo::UtxoError::MissingUtxo(_)))
+		));
+	}
+
+	#[test]
+	fn validate_rejects_malformed_blocks() {
+		use tenebrium_utxo::{InMemoryUtxoSet, UtxoError};
+
+		let utxos = InMemoryUtxoSet::new();
+
+		let empty = Block::new(1, [0u8; 32], 0, POW_LIMIT_BITS, 0, vec![]).unwrap();
+		assert!(matches!(empty.validate(&utxos, 50, true), Err(ConsensusError::EmptyBlock)));
+
+		let mut bad_root = Block::new(1, [0u8; 32], 0, POW_LIMIT_BITS, 0, vec![coinbase_paying(50)]).unwrap();
+		bad_root.header.merkle_root = [7u8; 32];
+		assert!(matches!(
+			bad_root.validate(&utxos, 50, true),
+			Err(ConsensusError::MerkleRootMismatch)
+		));
+
+		let mut with_inputs = coinbase_paying(50);
+		with_inputs.vin = tx_with_id(1).vin;
+		let block = Block::new(1, [0u8; 32], 0, POW_LIMIT_BITS, 0, vec![with_inputs]).unwrap();
+		assert!(matches!(
+			block.validate(&utxos, 50, true),
+			Err(ConsensusError::CoinbaseRequired)
+		));
+
+		let two_coinbases = Block::new(
+			1,
+			[0u8; 32],
+			0,
+			POW_LIMIT_BITS,
+			0,
+			vec![coinbase_paying(50), coinbase_paying(51)],
+		)
+		.unwrap();
+		assert!(matches!(
+			two_coinbases.validate(&utxos, 50, true),
+			Err(ConsensusError::UnexpectedCoinbase(1))
+		));
+		let no_inputs = Transaction {
+			vin: vec![],
+			..coinbase_paying(1)
+		};
+		let block = Block::new(1, [0u8; 32], 0, POW_LIMIT_BITS, 0, vec![coinbase_paying(50), no_inputs]).unwrap();
+		assert!(matches!(
+			block.validate(&utxos, 50, true),
+			Err(ConsensusError::Utxo(UtxoError::EmptyInputs))
+		));
+
+		let greedy = Block::new(1, [0u8; 32], 0, POW_LIMIT_BITS, 0, vec![coinbase_paying(100)]).unwrap();
+		assert!(matches!(
+			greedy.validate(&utxos, 50, true),
+			Err(ConsensusError::ExcessCoinbase { value: 100, limit: 50 })
+		));
+
+		// Find a nonce that misses the easiest target; PoW is only skipped on request.
+		let mut unmined = Block::new(1, [0u8; 32], 0, POW_LIMIT_BITS, 0, vec![coinbase_paying(50)]).unwrap();
+		while check_pow(&unmined.header).unwrap() {
+			unmined.header.nonce += 1;
+		}
+		assert!(matches!(unmined.validate(&utxos, 50, false), Err(ConsensusError::InvalidPow)));
+		assert_eq!(unmined.validate(&utxos, 50, true).unwrap(), 0);
+	}
+
+	#[test]
+	fn genesis_builder_matches_pinned_merkle_root() {
+		let params = NetworkParams::mainnet();
+		let genesis = Block::genesis(&params, params.genesis_coinbase()).unwrap();
+		assert_eq!(genesis.header.merkle_root, params::GENESIS_MERKLE_ROOT);
+		assert_eq!(genesis.header.prev_block_hash, [0u8; 32]);
+		assert_eq!(genesis.header.time, params.genesis_time);
+		assert_eq!(genesis.header.nonce, params.genesis_nonce);
+		assert!(check_pow(&genesis.header).unwrap());
+		assert_eq!(genesis_hash(&params).unwrap(), header_hash(&genesis.header));
+
+		let spend = Transaction {
+			vin: vec![TxIn {
+				prevout: OutPoint { txid: [1; 32], vout: 0 },
+				script_sig: vec![],
+				sequence: 0,
+			}],
+			..params.genesis_coinbase()
+		};
+		assert!(matches!(
+			Block::genesis(&params, spend),
+			Err(ConsensusError::CoinbaseRequired)
+		));
+	}
+
+	#[test]
+	fn mining_progress_fires_every_interval() {
+		// The hardest target: no nonce will solve it.
+		let mut header = BlockHeader {
+			version: 1,
+			prev_block_hash: [0u8; 32],
+			merkle_root: [1u8; 32],
+			time: 1,
+			bits: 0x0300_0001,
+			nonce: 0,
+		};
+		let mut calls = Vec::new();
+		let found = mine_header_with_progress(&mut header, 2 * MINE_PROGRESS_INTERVAL + 5, |tried| calls.push(tried))
+			.unwrap();
+		assert_eq!(found, None);
+		assert_eq!(calls, vec![MINE_PROGRESS_INTERVAL, 2 * MINE_PROGRESS_INTERVAL]);
+
+		let mut easy = BlockHeader { bits: POW_LIMIT_BITS, nonce: 0, ..header };
+		let mut fired = false;
+		assert!(mine_header_with_progress(&mut easy, 10, |_| fired = true).unwrap().is_some());
+		assert!(!fired);
+	}
+
+	#[test]
+	fn parallel_mining_solves_easy_target_and_honours_stop() {
+		let header = BlockHeader {
+			version: 1,
+			prev_block_hash: [3u8; 32],
+			merkle_root: [4u8; 32],
+			time: 1,
+			bits: POW_LIMIT_BITS,
+			nonce: 0,
+		};
+		let stop = AtomicBool::new(false);
+		let (nonce, extra_nonce) = mine_header_parallel(&header, 4, &stop).unwrap().unwrap();
+		let solved = BlockHeader {
+			version: roll_version(header.version, extra_nonce),
+			nonce,
+			..header.clone()
+		};
+		assert!(check_pow(&solved).unwrap());
+		assert_eq!(roll_version(-1, MAX_EXTRA_NONCE), -1);
+		assert_eq!(roll_version(1, 1), 1 | 0x2000);
+
+		stop.store(true, Ordering::Relaxed);
+		assert_eq!(mine_header_parallel(&header, 4, &stop).unwrap(), None);
+		assert!(matches!(
+			mine_header_parallel(&BlockHeader { bits: 0x0480_0000, ..header }, 1, &AtomicBool::new(false)),
+			Err(ConsensusError::InvalidBits)
+		));
+	}
+
+	#[test]
+	fn coinbase_height_round_trips_minimally() {
+		assert_eq!(encode_coinbase_height(0), vec![0]);
+		assert_eq!(encode_coinbase_height(1), vec![1, 1]);
+		assert_eq!(encode_coinbase_height(128), vec![2, 0x80, 0]);
+		assert_eq!(encode_coinbase_height(u32::MAX), vec![5, 0xff, 0xff, 0xff, 0xff, 0]);
+		for height in [0, 1, 127, 128, 255, 256, 0x7fff_ffff, u32::MAX] {
+			let mut script = encode_coinbase_height(height);
+			script.extend_from_slice(b"extra nonce");
+			assert_eq!(decode_coinbase_height(&script), Some(height));
+		}
+		// Padded, negative, truncated and oversized pushes are refused.
+		assert_eq!(decode_coinbase_height(&[2, 1, 0]), None);
+		assert_eq!(decode_coinbase_height(&[1, 0x80]), None);
+		assert_eq!(decode_coinbase_height(&[3, 1, 2]), None);
+		assert_eq!(decode_coinbase_height(&[6, 1, 0, 0, 0, 0, 0]), None);
+		assert_eq!(decode_coinbase_height(&[]), None);
+	}
+
+	#[test]
+	fn coinbase_value_sums_first_tx_and_rejects_empty_block() {
+		let coinbase = Transaction {
+			version: 1,
+			vin: vec![],
+			vout: vec![
+				TxOut {
+					value: 30,
+					script_pubkey: vec![1],
+				},
+				TxOut {
+					value: 25,
+					script_pubkey: vec![2],
+				},
+			],
+			lock_time: 0,
+		};
+		let block = Block::new(1, [0u8; 32], 0, 0x207fffff, 0, vec![coinbase]).unwrap();
+		assert_eq!(block.coinbase(), Some(&block.txs[0]));
+		assert_eq!(
+			block.coinbase_value().unwrap(),
+			Transaction::sum_outputs(&block.txs[0]).unwrap()
+		);
+		assert_eq!(block.coinbase_value().unwrap(), 55);
+
+		let mut empty = block;
+		empty.txs.clear();
+		assert!(empty.coinbase().is_none());
+		assert!(matches!(empty.coinbase_value(), Err(ConsensusError::EmptyBlock)));
+	}
+
+	#[test]
+	fn validate_structure_rejects_empty_merkle_root() {
+		let coinbase = Transaction {
+			version: 1,
+			vin: vec![coinbase_input()],
+			vout: vec![TxOut {
+				value: 50,
+				script_pubkey: vec![1],
+			}],
+			lock_time: 0,
+		};
+		let block = Block::new(1, [0u8; 32], 0, 0x207fffff, 0, vec![coinbase]).unwrap();
+		assert_ne!(block.header.merkle_root, EMPTY_MERKLE_ROOT);
+		block.validate_structure().unwrap();
+
+		let mut zeroed = block;
+		zeroed.header.merkle_root = EMPTY_MERKLE_ROOT;
+		assert!(matches!(zeroed.validate_structure(), Err(ConsensusError::EmptyBlock)));
+	}
+
+	#[test]
+	fn validate_structure_coinbase_input_convention() {
+		let null_input = TxIn {
+			prevout: OutPoint::NULL,
+			script_sig: vec![0x03, 0x01, 0x00, 0x00],
+			sequence: u32::MAX,
+		};
+		let coinbase = |vin: Vec<TxIn>| Transaction {
+			version: 1,
+			vin,
+			vout: vec![TxOut {
+				value: 50,
+				script_pubkey: vec![1],
+			}],
+			lock_time: 0,
+		};
+		let block = |cb: Transaction| Block::new(1, [0u8; 32], 0, 0x207fffff, 0, vec![cb]).unwrap();
+
+		block(coinbase(vec![null_input.clone()])).validate_structure().unwrap();
+		// Only the genesis coinbase goes without an input.
+		assert!(matches!(
+			block(coinbase(vec![])).validate_structure(),
+			Err(ConsensusError::CoinbaseRequired)
+		));
+
+		let mut second = null_input.clone();
+		second.prevout.vout = 0;
+		let two_inputs = block(coinbase(vec![null_input.clone(), second]));
+		assert!(matches!(
+			two_inputs.validate_structure(),
+			Err(ConsensusError::CoinbaseRequired)
+		));
+		let mut non_null = null_input;
+		non_null.prevout.txid = [1u8; 32];
+		assert!(matches!(
+			block(coinbase(vec![non_null])).validate_structure(),
+			Err(ConsensusError::CoinbaseRequired)
+		));
+	}
+
 	#[test]
 	fn merkle_root_single() {
 		let tx = tx_with_id(1);
@@ -204,6 +1326,127 @@ mod tests {
 		assert_ne!(root, [0u8; 32]);
 	}
 
+	#[test]
+	fn merkle_accumulator_matches_merkle_root() {
+		assert_eq!(MerkleAccumulator::new().root(), merkle_root(&[]));
+		for n in 1..=17u8 {
+			let txids: Vec<[u8; 32]> = (1..=n).map(|i| tx_with_id(i).txid_v2().unwrap()).collect();
+			let mut acc = MerkleAccumulator::new();
+			for txid in &txids {
+				acc.push(*txid);
+			}
+			assert_eq!(acc.root(), merkle_root(&txids), "n={n}");
+			assert!(!acc.is_mutated(), "n={n}");
+		}
+	}
+
+	#[test]
+	fn block_txids_match_per_tx_hashes_and_header_root() {
+		let txs: Vec<Transaction> = (1..=5).map(tx_with_id).collect();
+		let manual: Vec<[u8; 32]> = txs.iter().map(|tx| tx.txid_v2().unwrap()).collect();
+		let block = Block::new(1, [0u8; 32], 0, POW_LIMIT_BITS, 0, txs).unwrap();
+		assert_eq!(block.txids().unwrap(), manual);
+		// The root Block::new stores in the header is the one a fresh computation gives.
+		assert_eq!(block.header.merkle_root, merkle_root(&block.txids().unwrap()));
+		assert_eq!(block.header.merkle_root, merkle_root(&manual));
+
+		let mut duplicated = block.clone();
+		duplicated.txs.push(duplicated.txs[1].clone());
+		assert!(matches!(
+			duplicated.check_duplicate_txids(),
+			Err(ConsensusError::DuplicateTxid(txid)) if txid == manual[1]
+		));
+	}
+
+	#[test]
+	fn duplicated_tail_collides_but_is_rejected() {
+		let txs: Vec<Transaction> = (1..=3).map(tx_with_id).collect();
+		let mut padded = txs.clone();
+		padded.push(txs[2].clone());
+		let txids: Vec<[u8; 32]> = txs.iter().map(|tx| tx.txid_v2().unwrap()).collect();
+		let padded_ids: Vec<[u8; 32]> = padded.iter().map(|tx| tx.txid_v2().unwrap()).collect();
+
+		// The classic collision: both lists share a root.
+		assert_eq!(merkle_root(&txids), merkle_root(&padded_ids));
+		assert_eq!(merkle_root_checked(&txids).unwrap(), merkle_root(&txids));
+		assert!(matches!(
+			merkle_root_checked(&padded_ids),
+			Err(ConsensusError::MutatedMerkleTree)
+		));
+
+		let mut acc = MerkleAccumulator::new();
+		padded_ids.iter().for_each(|txid| acc.push(*txid));
+		assert_eq!(acc.root(), merkle_root(&txids));
+		assert!(acc.is_mutated());
+
+		let block = Block::new(1, [0u8; 32], 0, POW_LIMIT_BITS, 0, txs).unwrap();
+		block.check_duplicate_txids().unwrap();
+		let mutated = Block {
+			header: block.header.clone(),
+			txs: padded,
+		};
+		assert!(matches!(
+			mutated.check_duplicate_txids(),
+			Err(ConsensusError::DuplicateTxid(txid)) if txid == txids[2]
+		));
+	}
+
+	#[test]
+	fn merkle_proofs_verify_for_every_index() {
+		let txids: Vec<[u8; 32]> = (1..=5).map(|i| tx_with_id(i).txid_v2().unwrap()).collect();
+		let root = merkle_root(&txids);
+		for (index, txid) in txids.iter().enumerate() {
+			let proof = merkle_proof(&txids, index).unwrap();
+			assert_eq!(proof.len(), 3, "index {index}");
+			assert!(verify_merkle_proof(txid, &proof, &root), "index {index}");
+			let other = txids[(index + 1) % txids.len()];
+			assert!(!verify_merkle_proof(&other, &proof, &root), "index {index}");
+		}
+		// The lone fifth leaf is paired with itself.
+		assert_eq!(merkle_proof(&txids, 4).unwrap()[0], (txids[4], true));
+		assert!(merkle_proof(&txids, 5).is_none());
+
+		let single = [txids[0]];
+		let proof = merkle_proof(&single, 0).unwrap();
+		assert!(proof.is_empty());
+		assert!(verify_merkle_proof(&txids[0], &proof, &merkle_root(&single)));
+		assert!(merkle_proof(&[], 0).is_none());
+	}
+
+	#[test]
+	fn merkle_block_proves_matched_txs() {
+		let txs: Vec<Transaction> = (1..=7).map(tx_with_id).collect();
+		let block = Block::new(1, [0u8; 32], 0, 0x207fffff, 0, txs.clone()).unwrap();
+		let matches: Vec<bool> = (0..txs.len()).map(|i| i == 2 || i == 6).collect();
+
+		let mb = MerkleBlock::from_block(&block, &matches).unwrap();
+		assert!(mb.matched_hashes.len() < txs.len());
+		let matched = mb.verify().unwrap();
+		assert_eq!(
+			matched,
+			vec![txs[2].txid_v2().unwrap(), txs[6].txid_v2().unwrap()]
+		);
+	}
+
+	#[test]
+	fn merkle_block_rejects_tampering() {
+		let txs: Vec<Transaction> = (1..=4).map(tx_with_id).collect();
+		let block = Block::new(1, [0u8; 32], 0, 0x207fffff, 0, txs).unwrap();
+		let mb = MerkleBlock::from_block(&block, &[false, true, false, false]).unwrap();
+
+		let mut bad_hash = mb.clone();
+		bad_hash.matched_hashes[0][0] ^= 1;
+		assert!(bad_hash.verify().is_err());
+
+		let mut bad_root = mb.clone();
+		bad_root.header.merkle_root = [9u8; 32];
+		assert!(bad_root.verify().is_err());
+
+		let mut extra_hash = mb;
+		extra_hash.matched_hashes.push([0u8; 32]);
+		assert!(extra_hash.verify().is_err());
+	}
+
 	#[test]
 	fn bits_to_target_bitcoin_style() {
 		let target = bits_to_target(0x1d00ffff).unwrap();
@@ -213,6 +1456,61 @@ mod tests {
 			0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 		];
 		assert_eq!(target, expected);
+		assert!(matches!(bits_to_target(0x1d80ffff), Err(ConsensusError::InvalidBits)));
+		assert!(matches!(bits_to_target(0x04923456), Err(ConsensusError::InvalidBits)));
+	}
+
+	#[test]
+	fn hash_meets_target_at_the_boundary() {
+		// Exponent 3: the target is exactly 0x000101.
+		let bits = 0x0300_0101;
+		let mut at = [0u8; 32];
+		at[30] = 0x01;
+		at[31] = 0x01;
+		let mut below = at;
+		below[31] = 0x00;
+		let mut above = at;
+		above[31] = 0x02;
+		assert!(hash_meets_target(&below, bits).unwrap());
+		assert!(hash_meets_target(&at, bits).unwrap());
+		assert!(!hash_meets_target(&above, bits).unwrap());
+		assert!(hash_leq(&below, &at) && !hash_leq(&above, &at));
+		assert!(matches!(hash_meets_target(&below, 0x1d80ffff), Err(ConsensusError::InvalidBits)));
+
+		let mut header = BlockHeader {
+			version: 1,
+			prev_block_hash: [3u8; 32],
+			merkle_root: [4u8; 32],
+			time: 1,
+			bits: 0x2000ffff,
+			nonce: 0,
+		};
+		let mut met = 0;
+		for nonce in 0..512 {
+			header.nonce = nonce;
+			let meets = hash_meets_target(&header_hash(&header), header.bits).unwrap();
+			assert_eq!(meets, check_pow(&header).unwrap());
+			met += meets as u32;
+		}
+		assert!(met > 0 && met < 512, "{met}");
+	}
+
+	#[test]
+	fn difficulty_is_relative_to_pow_limit() {
+		assert_eq!(difficulty(POW_LIMIT_BITS), 1.0);
+		// Same mantissa, one byte smaller exponent: target / 256.
+		assert_eq!(difficulty(0x1f7fffff), 256.0);
+		// Half the mantissa: half the target, twice the difficulty.
+		let halved = difficulty(0x203fffff);
+		assert!((halved - 2.0).abs() < 1e-3, "{halved}");
+
+		assert_eq!(difficulty(0), 0.0);
+		assert_eq!(difficulty(0x20000000), 0.0);
+
+		let one = network_hashrate(1.0, 600);
+		assert!(one > 0.0);
+		assert!((network_hashrate(2.0, 600) / one - 2.0).abs() < 1e-9);
+		assert_eq!(network_hashrate(1.0, 0), 0.0);
 	}
 
 	#[test]
@@ -0,0 +1,161 @@
+//! Per-network consensus parameters.
+
+use tenebrium_utxo::{Transaction, TxOut};
+
+/// Mainnet initial block subsidy (50 coins)
+pub const INITIAL_SUBSIDY: u64 = 50_0000_0000;
+/// Mainnet halving interval in blocks
+pub const HALVING_INTERVAL: u32 = 210_000;
+/// Devnet halving interval, short enough to reach in tests
+pub const DEVNET_HALVING_INTERVAL: u32 = 150;
+/// Default limit on how far a header's time may be ahead of local time
+pub const MAX_FUTURE_DRIFT_SECS: u32 = 2 * 60 * 60;
+/// Intended average time between blocks
+pub const TARGET_BLOCK_TIME_SECS: u32 = 600;
+/// Blocks between difficulty retargets
+pub const DIFFICULTY_WINDOW: u32 = 10;
+/// Default limit on signature operations per block
+pub const MAX_BLOCK_SIGOPS: usize = 20_000;
+/// Default limit on outputs created per block, bounding UTXO set growth
+pub const MAX_BLOCK_OUTPUTS: usize = 50_000;
+/// Confirmations a coinbase output needs before it can be spent
+pub const COINBASE_MATURITY: u32 = 100;
+/// P2P message magic prefixes, one per network
+pub const MAINNET_MAGIC: [u8; 4] = *b"TNBm";
+pub const TESTNET_MAGIC: [u8; 4] = *b"TNBt";
+pub const DEVNET_MAGIC: [u8; 4] = *b"TNBd";
+/// Genesis header fields shared by all networks
+pub const GENESIS_TIME: u32 = 1_769_936_400;
+pub const GENESIS_BITS: u32 = 0x207fffff;
+pub const GENESIS_NONCE: u32 = 2;
+/// Output script of the genesis coinbase
+pub const GENESIS_COINBASE_SCRIPT: &[u8] = b"Tenebrium";
+/// Merkle root [`crate::Block::genesis`] produces for the mainnet coinbase;
+/// changing it would fork every existing chain.
+pub const GENESIS_MERKLE_ROOT: [u8; 32] = [
+	169, 121, 2, 123, 39, 241, 216, 194, 36, 201, 186, 237, 157, 93, 25, 228,
+	155, 68, 174, 228, 3, 8, 168, 36, 245, 208, 58, 173, 18, 205, 179, 58,
+];
+
+/// Per-network consensus parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkParams {
+	pub genesis_time: u32,
+	pub genesis_bits: u32,
+	pub genesis_nonce: u32,
+	pub initial_subsidy: u64,
+	pub halving_interval: u32,
+	/// Intended average time between blocks; retargeting steers towards it
+	pub target_block_time_secs: u32,
+	/// Blocks per retarget window
+	pub difficulty_window: u32,
+	/// Prefix of every P2P frame; peers on other networks are dropped on read
+	pub magic: [u8; 4],
+	/// Headers timed more than this many seconds ahead of local time are rejected
+	pub max_future_drift_secs: u32,
+	/// Blocks whose transactions need more signature checks are invalid
+	pub max_block_sigops: usize,
+	/// Blocks creating more outputs, summed over all transactions, are invalid
+	pub max_block_outputs: usize,
+	/// Coinbase outputs are unspendable until this many blocks have been built on them
+	pub coinbase_maturity: u32,
+}
+
+impl Default for NetworkParams {
+	fn default() -> Self {
+		Self::mainnet()
+	}
+}
+
+impl NetworkParams {
+	pub fn mainnet() -> Self {
+		Self {
+			genesis_time: GENESIS_TIME,
+			genesis_bits: GENESIS_BITS,
+			genesis_nonce: GENESIS_NONCE,
+			initial_subsidy: INITIAL_SUBSIDY,
+			halving_interval: HALVING_INTERVAL,
+			target_block_time_secs: TARGET_BLOCK_TIME_SECS,
+			difficulty_window: DIFFICULTY_WINDOW,
+			magic: MAINNET_MAGIC,
+			max_future_drift_secs: MAX_FUTURE_DRIFT_SECS,
+			max_block_sigops: MAX_BLOCK_SIGOPS,
+			max_block_outputs: MAX_BLOCK_OUTPUTS,
+			coinbase_maturity: COINBASE_MATURITY,
+		}
+	}
+
+	/// Mainnet schedule with its own message magic.
+	pub fn testnet() -> Self {
+		Self {
+			magic: TESTNET_MAGIC,
+			..Self::mainnet()
+		}
+	}
+
+	/// Mainnet rules with a short halving interval and its own magic.
+	pub fn devnet() -> Self {
+		Self {
+			halving_interval: DEVNET_HALVING_INTERVAL,
+			magic: DEVNET_MAGIC,
+			..Self::mainnet()
+		}
+	}
+
+	/// Params for a network id; unknown ids use mainnet rules.
+	pub fn for_network(network_id: &str) -> Self {
+		match network_id {
+			"devnet" => Self::devnet(),
+			"testnet" => Self::testnet(),
+			_ => Self::mainnet(),
+		}
+	}
+
+	/// The genesis coinbase: the initial subsidy paid to
+	/// [`GENESIS_COINBASE_SCRIPT`], with no inputs.
+	pub fn genesis_coinbase(&self) -> Transaction {
+		Transaction {
+			version: 1,
+			vin: Vec::new(),
+			vout: vec![TxOut {
+				value: self.initial_subsidy,
+				script_pubkey: GENESIS_COINBASE_SCRIPT.to_vec(),
+			}],
+			lock_time: 0,
+		}
+	}
+
+	/// Coinbase subsidy at `height`, halving every `halving_interval` blocks.
+	pub fn block_subsidy(&self, height: u32) -> u64 {
+		let halvings = height / self.halving_interval.max(1);
+		if halvings >= 64 {
+			return 0;
+		}
+		self.initial_subsidy >> halvings
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn mainnet_subsidy_schedule() {
+		let params = NetworkParams::mainnet();
+		assert_eq!(params.block_subsidy(0), INITIAL_SUBSIDY);
+		assert_eq!(params.block_subsidy(HALVING_INTERVAL - 1), INITIAL_SUBSIDY);
+		assert_eq!(params.block_subsidy(HALVING_INTERVAL), INITIAL_SUBSIDY / 2);
+		assert_eq!(params.block_subsidy(HALVING_INTERVAL * 64), 0);
+	}
+	#[test]
+	fn network_magics_are_distinct() {
+		let magics = [
+			NetworkParams::for_network("mainnet").magic,
+			NetworkParams::for_network("testnet").magic,
+			NetworkParams::for_network("devnet").magic,
+		];
+		assert_ne!(magics[0], magics[1]);
+		assert_ne!(magics[0], magics[2]);
+		assert_ne!(magics[1], magics[2]);
+	}
+}
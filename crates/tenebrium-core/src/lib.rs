@@ -1,7 +1,12 @@
+pub mod mnemonic;
 pub mod wallet;
 
+pub use mnemonic::{entropy_from_mnemonic, mnemonic_from_entropy, secret_from_mnemonic};
+
 pub use wallet::{
-	address_from_pubkey_hex, generate_keypair, sign_message_hex, verify_message_hex,
-	wallet_file_from_secret, wallet_file_from_secret_with_kdf, wallet_file_reencrypt,
-	wallet_keypair_from_file, KdfParams, WalletError, WalletFile, WalletKeypair, ADDRESS_HRP,
+	address_from_pubkey_hex, generate_keypair, script_pubkey_from_address, sign_message_hex,
+	verify_batch, verify_message_hex, wallet_file_from_secret, wallet_file_from_secret_with_kdf,
+	wallet_file_reencrypt, wallet_file_watch_only, wallet_keypair_from_file,
+	wallet_pubkey_from_file, KdfParams, WalletError, WalletFile,
+	WalletKeypair, ADDRESS_HRP, WALLET_FORMAT_VERSION,
 };
@@ -0,0 +1,173 @@
+//! BIP39 mnemonic phrases for backing up a wallet secret.
+//!
+//! A phrase encodes 128 to 256 bits of entropy plus a SHA-256 checksum as
+//! 11-bit indices into the English wordlist. The ed25519 secret is the first
+//! 32 bytes of the BIP39 seed, PBKDF2-HMAC-SHA512 over the phrase with salt
+//! `"mnemonic" + passphrase`. Phrases and passphrases are used as given, without
+//! NFKD normalization, which matches BIP39 for ASCII input.
+
+use crate::wallet::WalletError;
+use sha2::{Digest, Sha256, Sha512};
+use std::sync::OnceLock;
+
+const WORDLIST: &str = include_str!("bip39_english.txt");
+const SEED_ROUNDS: u32 = 2048;
+
+fn wordlist() -> &'static [&'static str] {
+    static WORDS: OnceLock<Vec<&'static str>> = OnceLock::new();
+    WORDS.get_or_init(|| WORDLIST.lines().collect())
+}
+
+/// Encode `entropy` (16, 20, 24, 28 or 32 bytes) as a 12- to 24-word phrase.
+pub fn mnemonic_from_entropy(entropy: &[u8]) -> Result<String, WalletError> {
+    if !(16..=32).contains(&entropy.len()) || !entropy.len().is_multiple_of(4) {
+        return Err(WalletError::InvalidSecretLength(entropy.len()));
+    }
+    // One checksum bit per 32 bits of entropy, so it always fits in the first hash byte.
+    let mut bits = entropy.to_vec();
+    bits.push(Sha256::digest(entropy)[0]);
+    let word_count = entropy.len() * 8 * 33 / 32 / 11;
+    let words = wordlist();
+    let phrase: Vec<&str> = (0..word_count)
+        .map(|word| {
+            let index = (0..11).fold(0usize, |acc, bit| {
+                let pos = word * 11 + bit;
+                acc << 1 | (bits[pos / 8] >> (7 - pos % 8) & 1) as usize
+            });
+            words[index]
+        })
+        .collect();
+    Ok(phrase.join(" "))
+}
+
+/// Check `phrase`'s words and checksum and return the entropy it encodes.
+pub fn entropy_from_mnemonic(phrase: &str) -> Result<Vec<u8>, WalletError> {
+    let words = wordlist();
+    let indices = phrase
+        .split_whitespace()
+        .map(|word| {
+            words
+                .binary_search(&word)
+                .map_err(|_| WalletError::InvalidWalletFile(format!("unknown mnemonic word '{word}'")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if !matches!(indices.len(), 12 | 15 | 18 | 21 | 24) {
+        return Err(WalletError::InvalidWalletFile(format!(
+            "mnemonic has {} words (expected 12, 15, 18, 21 or 24)",
+            indices.len()
+        )));
+    }
+
+    let checksum_bits = indices.len() / 3;
+    let mut bytes = vec![0u8; (indices.len() * 11).div_ceil(8)];
+    for (word, index) in indices.iter().enumerate() {
+        for bit in 0..11 {
+            if index >> (10 - bit) & 1 == 1 {
+                let pos = word * 11 + bit;
+                bytes[pos / 8] |= 0x80 >> (pos % 8);
+            }
+        }
+    }
+    let entropy_len = checksum_bits * 32 / 8;
+    let checksum = bytes[entropy_len] >> (8 - checksum_bits);
+    let entropy = bytes[..entropy_len].to_vec();
+    if Sha256::digest(&entropy)[0] >> (8 - checksum_bits) != checksum {
+        return Err(WalletError::InvalidWalletFile(
+            "mnemonic checksum mismatch".to_string(),
+        ));
+    }
+    Ok(entropy)
+}
+
+/// Derive the 32-byte ed25519 secret from a checksummed phrase and optional passphrase.
+pub fn secret_from_mnemonic(phrase: &str, passphrase: &str) -> Result<[u8; 32], WalletError> {
+    entropy_from_mnemonic(phrase)?;
+    let phrase = phrase.split_whitespace().collect::<Vec<_>>().join(" ");
+    let salt = format!("mnemonic{passphrase}");
+    let mut seed = [0u8; 64];
+    pbkdf2::pbkdf2_hmac::<Sha512>(phrase.as_bytes(), salt.as_bytes(), SEED_ROUNDS, &mut seed);
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(&seed[..32]);
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (entropy, phrase, BIP39 seed with passphrase "TREZOR") from the reference vectors.
+    const VECTORS: &[(&str, &str, &str)] = &[
+        (
+            "00000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+        ),
+        (
+            "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+        ),
+        (
+            "80808080808080808080808080808080",
+            "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+            "d71de856f81a8acc65e6fc851a38d4d7ec216fd0796d0a6827a3ad6ed5511a30fa280f12eb2e47ed2ac03b5c462a0358d18d69fe4f985ec81778c1b370b652a8",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+            "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069",
+        ),
+        (
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon art",
+            "bda85446c68413707090a52022edd26a1c9462295029f2e60cd7c4f2bbd3097170af7a4d73245cafa9c3cca8d561a7c3de6f5d4a10be8ed2a5e608d68f92fcc8",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote",
+            "dd48c104698c30cfe2b6142103248622fb7bb0ff692eebb00089b32d22484e1613912f0a5b694407be899ffd31ed3992c456cdf60f5d4564b8ba3f05a69890ad",
+        ),
+    ];
+
+    #[test]
+    fn reference_vectors_round_trip() {
+        assert_eq!(wordlist().len(), 2048);
+        for (entropy_hex, phrase, seed_hex) in VECTORS {
+            let entropy = hex::decode(entropy_hex).unwrap();
+            assert_eq!(mnemonic_from_entropy(&entropy).unwrap(), *phrase);
+            assert_eq!(entropy_from_mnemonic(phrase).unwrap(), entropy);
+            let secret = secret_from_mnemonic(phrase, "TREZOR").unwrap();
+            assert_eq!(hex::encode(secret), seed_hex[..64], "{phrase}");
+        }
+    }
+
+    #[test]
+    fn bad_phrases_are_rejected() {
+        let phrase = VECTORS[0].1;
+        // Swapping the last word keeps the length but breaks the checksum.
+        let bad_checksum = phrase.replace("about", "abandon");
+        let unknown_word = phrase.replace("about", "aboutt");
+        let short = phrase.replacen("abandon ", "", 1);
+        for bad in [bad_checksum.as_str(), unknown_word.as_str(), short.as_str(), ""] {
+            assert!(
+                matches!(secret_from_mnemonic(bad, ""), Err(WalletError::InvalidWalletFile(_))),
+                "{bad}"
+            );
+        }
+        assert!(matches!(
+            mnemonic_from_entropy(&[0u8; 15]),
+            Err(WalletError::InvalidSecretLength(15))
+        ));
+
+        // Extra whitespace does not change the derived secret; the passphrase does.
+        let spaced = format!("  {}\n", phrase.replace(' ', "   "));
+        assert_eq!(
+            secret_from_mnemonic(&spaced, "").unwrap(),
+            secret_from_mnemonic(phrase, "").unwrap()
+        );
+        assert_ne!(
+            secret_from_mnemonic(phrase, "").unwrap(),
+            secret_from_mnemonic(phrase, "TREZOR").unwrap()
+        );
+    }
+}
@@ -1,13 +1,15 @@
 use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
-use bech32::{ToBase32, Variant};
+use bech32::{FromBase32, ToBase32, Variant};
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use rand::rngs::OsRng;
-use rand::RngCore;
+use rand::{CryptoRng, RngCore};
 use scrypt::{scrypt, Params as ScryptParams};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 pub const ADDRESS_HRP: &str = "tn";
+/// Newest wallet file format this build writes; all earlier versions are readable
+pub const WALLET_FORMAT_VERSION: u32 = 1;
 
 #[derive(Debug, thiserror::Error)]
 pub enum WalletError {
@@ -31,6 +33,15 @@ pub enum WalletError {
     AesGcm,
     #[error("invalid wallet file: {0}")]
     InvalidWalletFile(String),
+    #[error("invalid address: {0}")]
+    InvalidAddress(String),
+    #[error(
+        "unsupported wallet file version {found} (this build reads versions 1 to {max_supported}; \
+         upgrade to open newer wallet files)"
+    )]
+    UnsupportedVersion { found: u32, max_supported: u32 },
+    #[error("watch-only wallet file has no secret key")]
+    WatchOnly,
 }
 
 pub struct WalletKeypair {
@@ -39,7 +50,12 @@ pub struct WalletKeypair {
 
 impl WalletKeypair {
     pub fn generate() -> Self {
-        let signing_key = SigningKey::generate(&mut OsRng);
+        Self::generate_with_rng(&mut OsRng)
+    }
+
+    /// Generate from a caller-supplied RNG, e.g. a seeded one for reproducible tests.
+    pub fn generate_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        let signing_key = SigningKey::generate(rng);
         Self { signing_key }
     }
 
@@ -67,6 +83,12 @@ impl WalletKeypair {
         address_from_pubkey(&self.signing_key.verifying_key())
     }
 
+    /// Locking script paying to this key: the 32-byte address program
+    /// (SHA-256 of the public key).
+    pub fn script_pubkey(&self) -> Vec<u8> {
+        Sha256::digest(self.signing_key.verifying_key().as_bytes()).to_vec()
+    }
+
     pub fn sign_message(&self, message: &[u8]) -> String {
         let sig = self.signing_key.sign(message);
         hex::encode(sig.to_bytes())
@@ -92,6 +114,22 @@ pub fn address_from_pubkey_hex(pubkey_hex: &str) -> Result<String, WalletError>
     address_from_pubkey(&verifying_key)
 }
 
+/// Decode an address back to the locking script it pays (the 32-byte program).
+pub fn script_pubkey_from_address(address: &str) -> Result<Vec<u8>, WalletError> {
+    let (hrp, data, variant) = bech32::decode(address)?;
+    if hrp != ADDRESS_HRP || variant != Variant::Bech32 {
+        return Err(WalletError::InvalidAddress(format!("unexpected prefix {hrp}")));
+    }
+    let program = Vec::<u8>::from_base32(&data)?;
+    if program.len() != 32 {
+        return Err(WalletError::InvalidAddress(format!(
+            "program length {}",
+            program.len()
+        )));
+    }
+    Ok(program)
+}
+
 pub fn sign_message_hex(secret_hex: &str, message: &[u8]) -> Result<String, WalletError> {
     let kp = WalletKeypair::from_secret_hex(secret_hex)?;
     Ok(kp.sign_message(message))
@@ -121,6 +159,33 @@ pub fn verify_message_hex(
     Ok(verifying_key.verify(message, &signature).is_ok())
 }
 
+/// Verify `(pubkey, message, signature)` triples together, returning `false`
+/// if any signature fails. Every key and signature is length-checked and
+/// parsed before any verification, so malformed input is an error rather than
+/// a `false`. Items are still checked one by one: ed25519-dalek's `batch`
+/// feature needs `merlin`, which this workspace does not depend on yet.
+pub fn verify_batch(items: &[(Vec<u8>, Vec<u8>, Vec<u8>)]) -> Result<bool, WalletError> {
+    let mut parsed = Vec::with_capacity(items.len());
+    for (pubkey, message, signature) in items {
+        let pub_array: [u8; 32] = pubkey
+            .as_slice()
+            .try_into()
+            .map_err(|_| WalletError::InvalidPublicKeyLength(pubkey.len()))?;
+        let sig_array: [u8; 64] = signature
+            .as_slice()
+            .try_into()
+            .map_err(|_| WalletError::InvalidSignatureLength(signature.len()))?;
+        parsed.push((
+            VerifyingKey::from_bytes(&pub_array)?,
+            message.as_slice(),
+            Signature::from_bytes(&sig_array),
+        ));
+    }
+    Ok(parsed
+        .iter()
+        .all(|(key, message, signature)| key.verify(message, signature).is_ok()))
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WalletFile {
     pub version: u32,
@@ -131,6 +196,10 @@ pub struct WalletFile {
     pub ciphertext_hex: String,
     pub public_key_hex: String,
     pub address: String,
+    /// Set on files made by [`wallet_file_watch_only`]: the kdf, nonce and
+    /// ciphertext fields are empty and only the public key and address remain
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub watch_only: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -187,7 +256,7 @@ pub fn wallet_file_from_secret_with_kdf(
         .map_err(|_| WalletError::AesGcm)?;
 
     Ok(WalletFile {
-        version: 1,
+        version: WALLET_FORMAT_VERSION,
         kdf: "scrypt".to_string(),
         kdf_params: KdfParams {
             salt_hex: hex::encode(salt),
@@ -200,15 +269,59 @@ pub fn wallet_file_from_secret_with_kdf(
         ciphertext_hex: hex::encode(ciphertext),
         public_key_hex,
         address,
+        watch_only: false,
     })
 }
 
+/// Copy of `wallet` without the encrypted secret, for monitoring an address
+/// on a machine that must never hold its key. The public key and address are
+/// checked against each other first.
+pub fn wallet_file_watch_only(wallet: &WalletFile) -> Result<WalletFile, WalletError> {
+    let pubkey = wallet_pubkey_from_file(wallet)?;
+    if address_from_pubkey_hex(&hex::encode(pubkey))? != wallet.address {
+        return Err(WalletError::InvalidWalletFile("address mismatch".to_string()));
+    }
+    Ok(WalletFile {
+        version: wallet.version,
+        kdf: String::new(),
+        kdf_params: KdfParams {
+            salt_hex: String::new(),
+            n: 0,
+            r: 0,
+            p: 0,
+        },
+        cipher: String::new(),
+        nonce_hex: String::new(),
+        ciphertext_hex: String::new(),
+        public_key_hex: wallet.public_key_hex.clone(),
+        address: wallet.address.clone(),
+        watch_only: true,
+    })
+}
+
+/// Public key of a full or watch-only wallet file, without decrypting.
+pub fn wallet_pubkey_from_file(wallet: &WalletFile) -> Result<[u8; 32], WalletError> {
+    let bytes = hex::decode(&wallet.public_key_hex)?;
+    let pubkey: [u8; 32] = bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| WalletError::InvalidPublicKeyLength(bytes.len()))?;
+    VerifyingKey::from_bytes(&pubkey)?;
+    Ok(pubkey)
+}
+
 pub fn wallet_keypair_from_file(
     wallet: &WalletFile,
     passphrase: &str,
 ) -> Result<WalletKeypair, WalletError> {
-    if wallet.version != 1 {
-        return Err(WalletError::InvalidWalletFile("unsupported version".to_string()));
+    if wallet.version == 0 || wallet.version > WALLET_FORMAT_VERSION {
+        return Err(WalletError::UnsupportedVersion {
+            found: wallet.version,
+            max_supported: WALLET_FORMAT_VERSION,
+        });
+    }
+    if wallet.watch_only {
+        return Err(WalletError::WatchOnly);
     }
     if wallet.kdf != "scrypt" || wallet.cipher != "aes-256-gcm" {
         return Err(WalletError::InvalidWalletFile(
@@ -294,6 +407,79 @@ mod tests {
         assert!(ok);
     }
 
+    #[test]
+    fn batch_verify_fails_on_one_bad_signature() {
+        let items: Vec<(Vec<u8>, Vec<u8>, Vec<u8>)> = (1..=4u8)
+            .map(|i| {
+                let kp = WalletKeypair::from_secret_hex(&hex::encode([i; 32])).unwrap();
+                let message = vec![i; 10];
+                let signature = hex::decode(kp.sign_message(&message)).unwrap();
+                (hex::decode(kp.public_key_hex()).unwrap(), message, signature)
+            })
+            .collect();
+        assert!(verify_batch(&items).unwrap());
+        assert!(verify_batch(&[]).unwrap());
+
+        let mut corrupted = items.clone();
+        corrupted[2].2[5] ^= 1;
+        assert!(!verify_batch(&corrupted).unwrap());
+        let mut wrong_message = items.clone();
+        wrong_message[0].1.push(0);
+        assert!(!verify_batch(&wrong_message).unwrap());
+
+        let mut short_sig = items.clone();
+        short_sig[3].2.pop();
+        assert!(matches!(
+            verify_batch(&short_sig),
+            Err(WalletError::InvalidSignatureLength(63))
+        ));
+        let mut long_key = items;
+        long_key[1].0.push(0);
+        assert!(matches!(
+            verify_batch(&long_key),
+            Err(WalletError::InvalidPublicKeyLength(33))
+        ));
+    }
+
+    #[test]
+    fn seeded_rng_generates_reproducible_keys() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let a = WalletKeypair::generate_with_rng(&mut StdRng::seed_from_u64(42));
+        let b = WalletKeypair::generate_with_rng(&mut StdRng::seed_from_u64(42));
+        let c = WalletKeypair::generate_with_rng(&mut StdRng::seed_from_u64(43));
+        assert_eq!(a.secret_key_hex(), b.secret_key_hex());
+        assert_ne!(a.secret_key_hex(), c.secret_key_hex());
+
+        // The default path draws from the OS RNG.
+        assert_ne!(
+            WalletKeypair::generate().secret_key_hex(),
+            WalletKeypair::generate().secret_key_hex()
+        );
+    }
+
+    #[test]
+    fn script_pubkey_is_address_program() {
+        let kp = WalletKeypair::from_secret_hex(&hex::encode([5u8; 32])).unwrap();
+        let (hrp, data, _) = bech32::decode(&kp.address().unwrap()).unwrap();
+        assert_eq!(hrp, ADDRESS_HRP);
+        let program: Vec<u8> = bech32::FromBase32::from_base32(&data).unwrap();
+        assert_eq!(kp.script_pubkey(), program);
+    }
+
+    #[test]
+    fn address_decodes_to_script_pubkey() {
+        let kp = WalletKeypair::from_secret_hex(&hex::encode([6u8; 32])).unwrap();
+        let script = script_pubkey_from_address(&kp.address().unwrap()).unwrap();
+        assert_eq!(script, kp.script_pubkey());
+
+        let foreign = bech32::encode("bc", [0u8; 32].to_base32(), Variant::Bech32).unwrap();
+        assert!(matches!(
+            script_pubkey_from_address(&foreign),
+            Err(WalletError::InvalidAddress(_))
+        ));
+    }
+
     #[test]
     fn wallet_file_encrypt_decrypt() {
         let secret = [3u8; 32];
@@ -303,6 +489,28 @@ mod tests {
         assert_eq!(kp.public_key_hex(), kp2.public_key_hex());
     }
 
+    #[test]
+    fn wallet_file_version_gate() {
+        let secret = hex::encode([8u8; 32]);
+        let mut wallet =
+            wallet_file_from_secret_with_kdf(&secret, "pass", &[1u8; 16], &[2u8; 12], 16, 8, 1)
+                .unwrap();
+        assert_eq!(wallet.version, WALLET_FORMAT_VERSION);
+        wallet.version = 1;
+        wallet_keypair_from_file(&wallet, "pass").unwrap();
+
+        wallet.version = WALLET_FORMAT_VERSION + 1;
+        let Err(err) = wallet_keypair_from_file(&wallet, "pass") else {
+            panic!("future wallet version must be rejected");
+        };
+        assert!(matches!(
+            err,
+            WalletError::UnsupportedVersion { found, max_supported }
+                if found == WALLET_FORMAT_VERSION + 1 && max_supported == WALLET_FORMAT_VERSION
+        ));
+        assert!(err.to_string().contains(&format!("version {}", WALLET_FORMAT_VERSION + 1)));
+    }
+
     #[test]
     fn wallet_file_reencrypt_test() {
         let secret = [4u8; 32];
@@ -313,6 +521,44 @@ mod tests {
         assert_eq!(kp.public_key_hex(), kp2.public_key_hex());
     }
 
+    #[test]
+    fn watch_only_file_keeps_pubkey_but_cannot_sign() {
+        let secret = hex::encode([9u8; 32]);
+        let wallet =
+            wallet_file_from_secret_with_kdf(&secret, "pass", &[1u8; 16], &[2u8; 12], 16, 8, 1)
+                .unwrap();
+        let watch = wallet_file_watch_only(&wallet).unwrap();
+        assert!(watch.watch_only && !wallet.watch_only);
+        assert!(watch.ciphertext_hex.is_empty() && watch.nonce_hex.is_empty());
+        assert_eq!(watch.address, wallet.address);
+        assert_eq!(
+            wallet_pubkey_from_file(&watch).unwrap(),
+            wallet_pubkey_from_file(&wallet).unwrap()
+        );
+
+        // The flag survives a JSON round trip; full files omit it entirely.
+        let json = serde_json::to_string(&watch).unwrap();
+        let watch: WalletFile = serde_json::from_str(&json).unwrap();
+        assert!(watch.watch_only);
+        assert!(!serde_json::to_string(&wallet).unwrap().contains("watch_only"));
+
+        assert!(matches!(
+            wallet_keypair_from_file(&watch, "pass"),
+            Err(WalletError::WatchOnly)
+        ));
+        assert!(matches!(
+            wallet_file_reencrypt(&watch, "pass", "new"),
+            Err(WalletError::WatchOnly)
+        ));
+
+        let mut tampered = wallet.clone();
+        tampered.address = WalletKeypair::generate().address().unwrap();
+        assert!(matches!(
+            wallet_file_watch_only(&tampered),
+            Err(WalletError::InvalidWalletFile(_))
+        ));
+    }
+
     #[test]
     fn wallet_vector_matches() {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -31,9 +31,9 @@ fn main() {
         "name": "simple",
         "tx": tx1,
         "canonical_v2": encode(&c2),
-        "txid_v2": encode(&t2),
+        "txid_v2": encode(t2),
         "canonical_v1": encode(&c1),
-        "txid_v1": encode(&t1),
+        "txid_v1": encode(t1),
     }));
 
     // Multiple inputs
@@ -78,9 +78,9 @@ fn main() {
         "name": "multiple_inputs",
         "tx": tx2,
         "canonical_v2": encode(&c2),
-        "txid_v2": encode(&t2),
+        "txid_v2": encode(t2),
         "canonical_v1": encode(&c1),
-        "txid_v1": encode(&t1),
+        "txid_v1": encode(t1),
     }));
 
     // Script boundary (1000 bytes)
@@ -110,9 +110,9 @@ fn main() {
         "name": "script_boundary",
         "tx": tx3,
         "canonical_v2": encode(&c2),
-        "txid_v2": encode(&t2),
+        "txid_v2": encode(t2),
         "canonical_v1": encode(&c1),
-        "txid_v1": encode(&t1),
+        "txid_v1": encode(t1),
     }));
 
     // Edge values
@@ -141,9 +141,9 @@ fn main() {
         "name": "edge_values",
         "tx": tx4,
         "canonical_v2": encode(&c2),
-        "txid_v2": encode(&t2),
+        "txid_v2": encode(t2),
         "canonical_v1": encode(&c1),
-        "txid_v1": encode(&t1),
+        "txid_v1": encode(t1),
     }));
 
     println!("{}", serde_json::to_string_pretty(&vectors).unwrap());
@@ -0,0 +1,79 @@
+//! Serde adapter for 32-byte hashes, for use with `#[serde(with = "...")]`.
+//!
+//! Hashes serialize as 64-digit lowercase hex strings. Deserialization also
+//! accepts the older form, a JSON array of 32 byte values, so files and
+//! messages written before the switch keep loading until they are migrated.
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserializer, Serializer};
+use std::fmt;
+
+pub fn serialize<S: Serializer>(hash: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(hash))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+    deserializer.deserialize_any(HashVisitor)
+}
+
+struct HashVisitor;
+
+impl<'de> Visitor<'de> for HashVisitor {
+    type Value = [u8; 32];
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a 64-digit hex string or an array of 32 bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<[u8; 32], E> {
+        let mut hash = [0u8; 32];
+        hex::decode_to_slice(value, &mut hash).map_err(|err| E::custom(format!("hash hex: {err}")))?;
+        Ok(hash)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<[u8; 32], A::Error> {
+        let mut hash = [0u8; 32];
+        for (index, byte) in hash.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(index, &self))?;
+        }
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(33, &self));
+        }
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::OutPoint;
+
+    #[test]
+    fn array_and_hex_forms_deserialize_alike() {
+        let mut txid = [0u8; 32];
+        txid[0] = 0xab;
+        txid[31] = 7;
+        let outpoint = OutPoint { txid, vout: 3 };
+
+        let hex_form = serde_json::to_string(&outpoint).unwrap();
+        assert_eq!(
+            hex_form,
+            format!(r#"{{"txid":"{}","vout":3}}"#, hex::encode(txid))
+        );
+        let array_form = format!(r#"{{"txid":{:?},"vout":3}}"#, txid);
+        let from_hex: OutPoint = serde_json::from_str(&hex_form).unwrap();
+        let from_array: OutPoint = serde_json::from_str(&array_form).unwrap();
+        assert_eq!(from_hex, outpoint);
+        assert_eq!(from_array, outpoint);
+
+        for bad in [
+            r#"{"txid":"abcd","vout":3}"#.to_string(),
+            format!(r#"{{"txid":{:?},"vout":3}}"#, [1u8; 31]),
+            format!(r#"{{"txid":{:?},"vout":3}}"#, [1u8; 33]),
+            r#"{"txid":7,"vout":3}"#.to_string(),
+        ] {
+            assert!(serde_json::from_str::<OutPoint>(&bad).is_err(), "{bad}");
+        }
+    }
+}
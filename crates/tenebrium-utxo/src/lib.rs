@@ -5,25 +5,82 @@
 
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+pub mod hash_serde;
 mod reindex;
 pub use reindex::{
-    map_outpoints_v1_to_v2, ReindexErrorEntry, ReindexErrorKind, ReindexReport,
+    check_vout_coverage, map_outpoints_v1_to_v2, ReindexErrorEntry, ReindexErrorKind, ReindexReport,
 };
+mod varint;
+pub use varint::{compact_size_len, read_compact_size, write_compact_size};
 
+/// Sighash type committed by [`sighash_single_for_input`]
+pub const SIGHASH_SINGLE: u32 = 3;
 /// Maximum allowed script size in bytes (DoS mitigation)
 pub const MAX_SCRIPT_SIZE: usize = 10_000;
 /// Maximum allowed number of inputs or outputs in a transaction (temporary cap)
 pub const MAX_TX_INOUTS: usize = 10_000;
+/// Inputs above this count are checked for duplicates with a HashSet
+const DUPLICATE_SCAN_HASHSET_MIN: usize = 32;
+/// First byte of a provably unspendable data-carrier script (OP_RETURN);
+/// such outputs may carry zero value
+pub const DATA_CARRIER_MARKER: u8 = 0x6a;
+/// `lock_time` values below this are block heights, the rest unix times
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// Transaction versions accepted by [`Transaction::validate`]. The version is
+/// still encoded as a plain little-endian i32, so rejecting the others does
+/// not change any existing txid.
+pub const SUPPORTED_TX_VERSIONS: [i32; 2] = [1, 2];
 
-/// Basic OutPoint identifying an output in a transaction
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// Basic OutPoint identifying an output in a transaction. Ordered by
+/// `(txid, vout)`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct OutPoint {
+    #[serde(with = "hash_serde")]
     pub txid: [u8; 32],
     pub vout: u32,
 }
 
+impl OutPoint {
+    /// Prevout of a coinbase input: all-zero txid and `u32::MAX` vout.
+    pub const NULL: OutPoint = OutPoint {
+        txid: [0u8; 32],
+        vout: u32::MAX,
+    };
+
+    pub fn is_null(&self) -> bool {
+        *self == OutPoint::NULL
+    }
+}
+
+/// `fee / size` as a fee rate; zero for a zero size rather than a division
+/// by zero.
+pub fn fee_per_byte(fee: u64, size: usize) -> f64 {
+    if size == 0 {
+        return 0.0;
+    }
+    fee as f64 / size as f64
+}
+
+/// Hex of `txid` in display order, i.e. byte-reversed as block explorers
+/// show hashes. Storage, serialization and hashing keep internal order.
+pub fn txid_to_display_hex(txid: &[u8; 32]) -> String {
+    let mut reversed = *txid;
+    reversed.reverse();
+    hex::encode(reversed)
+}
+
+/// Parse a display-order txid as printed by [`txid_to_display_hex`].
+pub fn txid_from_display_hex(s: &str) -> Result<[u8; 32], UtxoError> {
+    let mut txid = [0u8; 32];
+    hex::decode_to_slice(s.trim(), &mut txid)
+        .map_err(|err| UtxoError::InvalidHex(format!("txid {s:?}: {err}")))?;
+    txid.reverse();
+    Ok(txid)
+}
+
 /// Transaction input
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TxIn {
@@ -48,6 +105,50 @@ pub struct Transaction {
     pub lock_time: u32,
 }
 
+/// JSON shape hashed by txid v1; field order and names must not change.
+#[derive(Serialize)]
+struct LegacyTxJson<'a> {
+    version: i32,
+    vin: Vec<LegacyTxInJson<'a>>,
+    vout: &'a [TxOut],
+    lock_time: u32,
+}
+
+#[derive(Serialize)]
+struct LegacyTxInJson<'a> {
+    prevout: LegacyOutPointJson,
+    script_sig: &'a [u8],
+    sequence: u32,
+}
+
+#[derive(Serialize)]
+struct LegacyOutPointJson {
+    txid: [u8; 32],
+    vout: u32,
+}
+
+impl<'a> From<&'a Transaction> for LegacyTxJson<'a> {
+    fn from(tx: &'a Transaction) -> Self {
+        LegacyTxJson {
+            version: tx.version,
+            vin: tx
+                .vin
+                .iter()
+                .map(|input| LegacyTxInJson {
+                    prevout: LegacyOutPointJson {
+                        txid: input.prevout.txid,
+                        vout: input.prevout.vout,
+                    },
+                    script_sig: &input.script_sig,
+                    sequence: input.sequence,
+                })
+                .collect(),
+            vout: &tx.vout,
+            lock_time: tx.lock_time,
+        }
+    }
+}
+
 /// Errors for UTXO crate
 #[derive(thiserror::Error, Debug)]
 pub enum UtxoError {
@@ -67,11 +168,95 @@ pub enum UtxoError {
     DuplicateOutput(OutPoint),
     #[error("serialization error: {0}")]
     SerdeError(#[from] serde_json::Error),
+    #[error("non-canonical encoding: {0}")]
+    NonCanonicalEncoding(String),
+    #[error("truncated input: needed {needed} bytes at offset {offset}")]
+    Truncated { offset: usize, needed: usize },
+    #[error("transaction not final: lock_time={lock_time}")]
+    NotFinal { lock_time: u32 },
+    #[error("transaction has no inputs")]
+    EmptyInputs,
+    #[error("transaction has no outputs")]
+    EmptyOutputs,
+    #[error("output {0} has zero value")]
+    ZeroValueOutput(usize),
+    #[error("dust output {index}: value {value} below {min}")]
+    DustOutput { index: usize, value: u64, min: u64 },
+    #[error("input index {index} out of range ({inputs} inputs)")]
+    InputIndexOutOfRange { index: usize, inputs: usize },
+    #[error("output index {index} out of range ({outputs} outputs)")]
+    OutputIndexOutOfRange { index: usize, outputs: usize },
+    #[error("unsupported transaction version: {0}")]
+    UnsupportedVersion(i32),
+    #[error("invalid hex: {0}")]
+    InvalidHex(String),
+    #[error("immature coinbase spend: {outpoint:?} created at height {created}, spent at {height} (maturity {maturity})")]
+    ImmatureCoinbase {
+        outpoint: OutPoint,
+        created: u32,
+        height: u32,
+        maturity: u32,
+    },
+    #[error("block must start with a coinbase")]
+    MissingCoinbase,
+    #[error("coinbase pays {value}, more than subsidy plus fees ({limit})")]
+    ExcessCoinbase { value: u64, limit: u64 },
 }
 
 impl Transaction {
+    /// A coinbase has exactly one input, spending [`OutPoint::NULL`]; its
+    /// script_sig carries the block height and any extra data. The null
+    /// input is never looked up in the UTXO set.
+    pub fn is_coinbase(&self) -> bool {
+        matches!(self.vin.as_slice(), [only] if only.prevout.is_null())
+    }
+
+    /// Output `vout`, or `None` when the transaction has fewer outputs.
+    pub fn output(&self, vout: u32) -> Option<&TxOut> {
+        self.vout.get(usize::try_from(vout).ok()?)
+    }
+
+    /// Value of output `vout`, or `None` when it does not exist.
+    pub fn output_value(&self, vout: u32) -> Option<u64> {
+        self.output(vout).map(|out| out.value)
+    }
+
+    /// Signature checks this transaction costs: one per input, except for a
+    /// coinbase, whose inputs are never verified.
+    pub fn sigop_count(&self) -> usize {
+        if self.is_coinbase() {
+            0
+        } else {
+            self.vin.len()
+        }
+    }
+
+    /// Whether the transaction may be included in a block at `height` whose
+    /// reference time is `time`. A zero `lock_time` is always final, otherwise
+    /// it must not exceed the height (below [`LOCKTIME_THRESHOLD`]) or the
+    /// time; inputs that all use `sequence == u32::MAX` opt out of the lock.
+    pub fn check_final(&self, height: u32, time: u32) -> Result<(), UtxoError> {
+        if self.lock_time == 0 {
+            return Ok(());
+        }
+        let cutoff = if self.lock_time < LOCKTIME_THRESHOLD {
+            height
+        } else {
+            time
+        };
+        if self.lock_time <= cutoff || self.vin.iter().all(|vin| vin.sequence == u32::MAX) {
+            return Ok(());
+        }
+        Err(UtxoError::NotFinal {
+            lock_time: self.lock_time,
+        })
+    }
+
     /// Validate transaction fields for v0.1 policy
     pub fn validate(&self) -> Result<(), UtxoError> {
+        if !SUPPORTED_TX_VERSIONS.contains(&self.version) {
+            return Err(UtxoError::UnsupportedVersion(self.version));
+        }
         if self.vin.len() > MAX_TX_INOUTS {
             return Err(UtxoError::TooManyInOut(self.vin.len(), MAX_TX_INOUTS));
         }
@@ -94,6 +279,73 @@ impl Transaction {
                 ));
             }
         }
+        self.check_no_duplicate_inputs()
+    }
+
+    /// [`Transaction::validate`] plus the rules that depend on where the
+    /// transaction appears: every transaction needs at least one output and
+    /// no zero-value outputs (except [`DATA_CARRIER_MARKER`] scripts), and
+    /// only a coinbase (`coinbase == true`, the first transaction of a
+    /// block) may have no inputs.
+    pub fn validate_as(&self, coinbase: bool) -> Result<(), UtxoError> {
+        self.validate()?;
+        if !coinbase && self.vin.is_empty() {
+            return Err(UtxoError::EmptyInputs);
+        }
+        if self.vout.is_empty() {
+            return Err(UtxoError::EmptyOutputs);
+        }
+        if let Some(index) = self
+            .vout
+            .iter()
+            .position(|out| out.value == 0 && !is_data_carrier(out, Some(DATA_CARRIER_MARKER)))
+        {
+            return Err(UtxoError::ZeroValueOutput(index));
+        }
+        Ok(())
+    }
+
+    /// Reject outputs worth less than `min_value`, except data carriers
+    /// whose script starts with [`DATA_CARRIER_MARKER`].
+    pub fn check_dust(&self, min_value: u64) -> Result<(), UtxoError> {
+        self.check_dust_with_marker(min_value, Some(DATA_CARRIER_MARKER))
+    }
+
+    /// [`Transaction::check_dust`] with a custom data-carrier marker byte;
+    /// `None` exempts nothing.
+    pub fn check_dust_with_marker(&self, min_value: u64, marker: Option<u8>) -> Result<(), UtxoError> {
+        for (index, out) in self.vout.iter().enumerate() {
+            if out.value < min_value && !is_data_carrier(out, marker) {
+                return Err(UtxoError::DustOutput {
+                    index,
+                    value: out.value,
+                    min: min_value,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject a transaction spending the same outpoint twice. Small input
+    /// lists are sorted and scanned, larger ones go through a HashSet.
+    pub fn check_no_duplicate_inputs(&self) -> Result<(), UtxoError> {
+        if self.vin.len() < 2 {
+            return Ok(());
+        }
+        if self.vin.len() <= DUPLICATE_SCAN_HASHSET_MIN {
+            let mut prevouts: Vec<&OutPoint> = self.vin.iter().map(|vin| &vin.prevout).collect();
+            prevouts.sort_unstable_by_key(|op| (op.txid, op.vout));
+            if let Some(pair) = prevouts.windows(2).find(|pair| pair[0] == pair[1]) {
+                return Err(UtxoError::DuplicateInput(pair[0].clone()));
+            }
+            return Ok(());
+        }
+        let mut seen: HashSet<&OutPoint> = HashSet::with_capacity(self.vin.len());
+        for vin in &self.vin {
+            if !seen.insert(&vin.prevout) {
+                return Err(UtxoError::DuplicateInput(vin.prevout.clone()));
+            }
+        }
         Ok(())
     }
 
@@ -135,14 +387,17 @@ impl Transaction {
         tx: &Transaction,
         utxos: &dyn UtxoSet,
     ) -> Result<u64, UtxoError> {
-        let mut seen: HashSet<OutPoint> = HashSet::new();
-        for vin in &tx.vin {
-            if !seen.insert(vin.prevout.clone()) {
-                return Err(UtxoError::DuplicateInput(vin.prevout.clone()));
-            }
-        }
-        let input_sum = Transaction::sum_inputs(tx, utxos)?;
-        let output_sum = Transaction::sum_outputs(tx)?;
+        tx.check_no_duplicate_inputs()?;
+        tx.fee(utxos)
+    }
+
+    /// Inputs minus outputs, looking inputs up in `utxos`; zero when they are
+    /// equal, [`UtxoError::ValueNotConserved`] when outputs are larger. Unlike
+    /// [`Transaction::validate_value_conservation`], a duplicated input is
+    /// not rejected here and counts twice.
+    pub fn fee(&self, utxos: &dyn UtxoSet) -> Result<u64, UtxoError> {
+        let input_sum = Transaction::sum_inputs(self, utxos)?;
+        let output_sum = Transaction::sum_outputs(self)?;
         if input_sum < output_sum {
             return Err(UtxoError::ValueNotConserved {
                 input: input_sum,
@@ -152,9 +407,16 @@ impl Transaction {
         Ok(input_sum - output_sum)
     }
 
-    /// Canonical bytes v1 (JSON-based) - kept for backward compatibility
+    /// [`Transaction::fee`] per byte of [`Transaction::vsize`].
+    pub fn fee_rate(&self, utxos: &dyn UtxoSet) -> Result<f64, UtxoError> {
+        Ok(fee_per_byte(self.fee(utxos)?, self.vsize()))
+    }
+
+    /// Canonical bytes v1 (JSON-based) - kept for backward compatibility.
+    /// Always the original JSON shape, with txids as byte arrays, whatever
+    /// form [`OutPoint`] serializes to.
     pub fn canonical_bytes_v1(&self) -> Result<Vec<u8>, UtxoError> {
-        serde_json::to_vec(self).map_err(UtxoError::from)
+        serde_json::to_vec(&LegacyTxJson::from(self)).map_err(UtxoError::from)
     }
 
     /// Canonical bytes v2 (binary deterministic encoding)
@@ -198,7 +460,116 @@ impl Transaction {
     pub fn txid_v2(&self) -> Result<[u8; 32], UtxoError> {
         let bytes = self.canonical_bytes_v2()?;
         let first = Sha256::digest(&bytes);
-        let second = Sha256::digest(&first);
+        let second = Sha256::digest(first);
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&second);
+        Ok(out)
+    }
+
+    /// Length of [`Transaction::canonical_bytes_v2`], computed from the field
+    /// lengths without serializing. Does not validate.
+    pub fn size(&self) -> usize {
+        let vin: usize = self.vin.iter().map(|vin| 32 + 4 + 8 + vin.script_sig.len() + 4).sum();
+        let vout: usize = self.vout.iter().map(|vout| 8 + 8 + vout.script_pubkey.len()).sum();
+        4 + 8 + vin + 8 + vout + 4
+    }
+
+    /// Size used for fee rates and block limits. Equal to [`Transaction::size`]
+    /// until witness data is split out of `script_sig`; it will then discount
+    /// witness bytes.
+    pub fn vsize(&self) -> usize {
+        self.size()
+    }
+
+    /// Canonical bytes v3: same field order as v2, but every count and script
+    /// length is a CompactSize varint instead of a fixed u64:
+    /// - version i32
+    /// - vin_count varint
+    /// - for each vin: prevout.txid [32], prevout.vout u32,
+    ///   script_sig_len varint, script_sig bytes, sequence u32
+    /// - vout_count varint
+    /// - for each vout: value u64, script_pubkey_len varint, script_pubkey bytes
+    /// - lock_time u32
+    pub fn canonical_bytes_v3(&self) -> Result<Vec<u8>, UtxoError> {
+        self.validate()?;
+        let mut out: Vec<u8> = Vec::new();
+        out.extend(&self.version.to_le_bytes());
+        write_compact_size(&mut out, self.vin.len() as u64);
+        for vin in &self.vin {
+            out.extend(&vin.prevout.txid);
+            out.extend(&vin.prevout.vout.to_le_bytes());
+            write_compact_size(&mut out, vin.script_sig.len() as u64);
+            out.extend(&vin.script_sig);
+            out.extend(&vin.sequence.to_le_bytes());
+        }
+        write_compact_size(&mut out, self.vout.len() as u64);
+        for vout in &self.vout {
+            out.extend(&vout.value.to_le_bytes());
+            write_compact_size(&mut out, vout.script_pubkey.len() as u64);
+            out.extend(&vout.script_pubkey);
+        }
+        out.extend(&self.lock_time.to_le_bytes());
+        Ok(out)
+    }
+
+    /// Decode [`Transaction::canonical_bytes_v3`]. Counts and script lengths
+    /// are checked against the policy limits before anything is allocated,
+    /// and truncated input or trailing bytes are errors.
+    pub fn from_canonical_v3(bytes: &[u8]) -> Result<Transaction, UtxoError> {
+        let mut pos = 0usize;
+        let version = i32::from_le_bytes(read_array(bytes, &mut pos)?);
+
+        let vin_count = read_bounded_len(bytes, &mut pos, MAX_TX_INOUTS, UtxoError::TooManyInOut)?;
+        let mut vin = Vec::with_capacity(vin_count);
+        for _ in 0..vin_count {
+            let txid = read_array(bytes, &mut pos)?;
+            let vout = u32::from_le_bytes(read_array(bytes, &mut pos)?);
+            let script_len =
+                read_bounded_len(bytes, &mut pos, MAX_SCRIPT_SIZE, UtxoError::TooLargeScript)?;
+            let script_sig = read_slice(bytes, &mut pos, script_len)?.to_vec();
+            let sequence = u32::from_le_bytes(read_array(bytes, &mut pos)?);
+            vin.push(TxIn {
+                prevout: OutPoint { txid, vout },
+                script_sig,
+                sequence,
+            });
+        }
+
+        let vout_count = read_bounded_len(bytes, &mut pos, MAX_TX_INOUTS, UtxoError::TooManyInOut)?;
+        let mut vout = Vec::with_capacity(vout_count);
+        for _ in 0..vout_count {
+            let value = u64::from_le_bytes(read_array(bytes, &mut pos)?);
+            let script_len =
+                read_bounded_len(bytes, &mut pos, MAX_SCRIPT_SIZE, UtxoError::TooLargeScript)?;
+            let script_pubkey = read_slice(bytes, &mut pos, script_len)?.to_vec();
+            vout.push(TxOut {
+                value,
+                script_pubkey,
+            });
+        }
+
+        let lock_time = u32::from_le_bytes(read_array(bytes, &mut pos)?);
+        if pos != bytes.len() {
+            return Err(UtxoError::NonCanonicalEncoding(format!(
+                "{} trailing bytes after transaction",
+                bytes.len() - pos
+            )));
+        }
+        let tx = Transaction {
+            version,
+            vin,
+            vout,
+            lock_time,
+        };
+        tx.validate()?;
+        Ok(tx)
+    }
+
+    /// Compute txid v3 as double-SHA256 of canonical bytes v3
+    pub fn txid_v3(&self) -> Result<[u8; 32], UtxoError> {
+        let bytes = self.canonical_bytes_v3()?;
+        let first = Sha256::digest(&bytes);
+        let second = Sha256::digest(first);
         let mut out = [0u8; 32];
         out.copy_from_slice(&second);
         Ok(out)
@@ -208,7 +579,7 @@ impl Transaction {
     pub fn txid_v1(&self) -> Result<[u8; 32], UtxoError> {
         let bytes = self.canonical_bytes_v1()?;
         let first = Sha256::digest(&bytes);
-        let second = Sha256::digest(&first);
+        let second = Sha256::digest(first);
         let mut out = [0u8; 32];
         out.copy_from_slice(&second);
         Ok(out)
@@ -237,130 +608,804 @@ impl Transaction {
     pub fn make_outpoints(tx: &Transaction) -> Result<Vec<OutPoint>, UtxoError> {
         Transaction::make_outpoints_v2(tx)
     }
+
+    /// Copy of the transaction with every `script_sig` empty. Script bytes
+    /// are never copied, which matters for sighashing txs with large scripts.
+    pub fn clone_without_scripts(&self) -> Transaction {
+        Transaction {
+            version: self.version,
+            vin: self
+                .vin
+                .iter()
+                .map(|vin| TxIn {
+                    prevout: vin.prevout.clone(),
+                    script_sig: Vec::new(),
+                    sequence: vin.sequence,
+                })
+                .collect(),
+            vout: self.vout.clone(),
+            lock_time: self.lock_time,
+        }
+    }
 }
 
 /// Compute a signing hash (sighash) over canonical bytes v2 with all script_sig cleared.
 /// This is a simple baseline scheme for v0.1 tooling.
 pub fn tx_sighash_v2(tx: &Transaction) -> Result<[u8; 32], UtxoError> {
-    let mut tmp = tx.clone();
-    for vin in &mut tmp.vin {
-        vin.script_sig.clear();
-    }
-    let bytes = tmp.canonical_bytes_v2()?;
+    let bytes = tx.clone_without_scripts().canonical_bytes_v2()?;
     let first = Sha256::digest(&bytes);
-    let second = Sha256::digest(&first);
+    let second = Sha256::digest(first);
     let mut out = [0u8; 32];
     out.copy_from_slice(&second);
     Ok(out)
 }
 
+fn is_data_carrier(out: &TxOut, marker: Option<u8>) -> bool {
+    marker.is_some() && out.script_pubkey.first().copied() == marker
+}
+
+fn read_slice<'a>(bytes: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], UtxoError> {
+    let slice = pos
+        .checked_add(len)
+        .and_then(|end| bytes.get(*pos..end))
+        .ok_or(UtxoError::Truncated {
+            offset: *pos,
+            needed: len,
+        })?;
+    *pos += len;
+    Ok(slice)
+}
+
+fn read_array<const N: usize>(bytes: &[u8], pos: &mut usize) -> Result<[u8; N], UtxoError> {
+    let mut out = [0u8; N];
+    out.copy_from_slice(read_slice(bytes, pos, N)?);
+    Ok(out)
+}
+
+/// Read a CompactSize count or length, rejecting values above `max` with
+/// `too_large(value, max)`.
+fn read_bounded_len(
+    bytes: &[u8],
+    pos: &mut usize,
+    max: usize,
+    too_large: fn(usize, usize) -> UtxoError,
+) -> Result<usize, UtxoError> {
+    let n = read_compact_size(bytes, pos)?;
+    if n > max as u64 {
+        return Err(too_large(usize::try_from(n).unwrap_or(usize::MAX), max));
+    }
+    Ok(n as usize)
+}
+
+fn sha256d(bytes: &[u8]) -> [u8; 32] {
+    let second = Sha256::digest(Sha256::digest(bytes));
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&second);
+    out
+}
+
+/// Per-input signing hash committing to the output being spent (BIP143-style).
+///
+/// Preimage (little-endian, double-SHA256):
+/// - version i32
+/// - hash_prevouts: sha256d of every input's txid || vout
+/// - hash_sequence: sha256d of every input's sequence
+/// - this input's txid || vout
+/// - prevout script_pubkey_len u64, script_pubkey bytes
+/// - prevout value u64
+/// - this input's sequence u32
+/// - hash_outputs: sha256d of every output's value || script_pubkey_len || script_pubkey
+/// - lock_time u32
+///
+/// Unlike [`tx_sighash_v2`], each input gets its own digest, so inputs owned by
+/// different keys can be signed independently, and a signer learns the exact
+/// value it is spending.
+pub fn sighash_for_input(
+    tx: &Transaction,
+    index: usize,
+    prevout: &TxOut,
+) -> Result<[u8; 32], UtxoError> {
+    input_sighash(tx, index, prevout, &tx.vout, None)
+}
+
+/// [`sighash_for_input`] committing only to the output at the input's own
+/// index (SIGHASH_SINGLE), so other outputs can still be added or changed.
+/// The preimage ends with [`SIGHASH_SINGLE`] as a u32, so it never equals an
+/// all-outputs digest. An input without a matching output is an error, not
+/// a digest of nothing.
+pub fn sighash_single_for_input(
+    tx: &Transaction,
+    index: usize,
+    prevout: &TxOut,
+) -> Result<[u8; 32], UtxoError> {
+    let output = u32::try_from(index)
+        .ok()
+        .and_then(|vout| tx.output(vout))
+        .ok_or(UtxoError::OutputIndexOutOfRange {
+            index,
+            outputs: tx.vout.len(),
+        })?;
+    input_sighash(tx, index, prevout, std::slice::from_ref(output), Some(SIGHASH_SINGLE))
+}
+
+/// Shared preimage of the per-input sighashes; `outputs` is the committed
+/// subset of `tx.vout`, and a `sighash_type` is appended when given.
+fn input_sighash(
+    tx: &Transaction,
+    index: usize,
+    prevout: &TxOut,
+    outputs: &[TxOut],
+    sighash_type: Option<u32>,
+) -> Result<[u8; 32], UtxoError> {
+    let vin = tx.vin.get(index).ok_or(UtxoError::InputIndexOutOfRange {
+        index,
+        inputs: tx.vin.len(),
+    })?;
+    tx.validate()?;
+    if prevout.script_pubkey.len() > MAX_SCRIPT_SIZE {
+        return Err(UtxoError::TooLargeScript(
+            prevout.script_pubkey.len(),
+            MAX_SCRIPT_SIZE,
+        ));
+    }
+
+    let mut prevouts = Vec::with_capacity(tx.vin.len() * 36);
+    let mut sequences = Vec::with_capacity(tx.vin.len() * 4);
+    for input in &tx.vin {
+        prevouts.extend(&input.prevout.txid);
+        prevouts.extend(&input.prevout.vout.to_le_bytes());
+        sequences.extend(&input.sequence.to_le_bytes());
+    }
+    let mut committed = Vec::new();
+    for vout in outputs {
+        committed.extend(&vout.value.to_le_bytes());
+        committed.extend(&(vout.script_pubkey.len() as u64).to_le_bytes());
+        committed.extend(&vout.script_pubkey);
+    }
+
+    let mut preimage = Vec::new();
+    preimage.extend(&tx.version.to_le_bytes());
+    preimage.extend(&sha256d(&prevouts));
+    preimage.extend(&sha256d(&sequences));
+    preimage.extend(&vin.prevout.txid);
+    preimage.extend(&vin.prevout.vout.to_le_bytes());
+    preimage.extend(&(prevout.script_pubkey.len() as u64).to_le_bytes());
+    preimage.extend(&prevout.script_pubkey);
+    preimage.extend(&prevout.value.to_le_bytes());
+    preimage.extend(&vin.sequence.to_le_bytes());
+    preimage.extend(&sha256d(&committed));
+    preimage.extend(&tx.lock_time.to_le_bytes());
+    if let Some(sighash_type) = sighash_type {
+        preimage.extend(&sighash_type.to_le_bytes());
+    }
+    Ok(sha256d(&preimage))
+}
+
 /// Receipt describing changes from an apply_tx (for rollback)
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ApplyReceipt {
     pub removed: Vec<(OutPoint, TxOut)>,
     pub inserted: Vec<OutPoint>,
+    /// Metadata of each `removed` entry, in the same order. Only filled by
+    /// [`InMemoryUtxoSet::apply_tx_at_height`]; empty for sets without it.
+    pub spent_meta: Vec<CoinMeta>,
+}
+
+/// Undo data for a whole block, from [`UtxoSet::apply_block`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockReceipt {
+    pub coinbase: ApplyReceipt,
+    /// One receipt per non-coinbase transaction, in block order
+    pub txs: Vec<ApplyReceipt>,
+    /// Fees paid by `txs`
+    pub fees: u64,
+}
+
+impl BlockReceipt {
+    /// All receipts in block order, coinbase first.
+    pub fn iter(&self) -> impl Iterator<Item = &ApplyReceipt> {
+        std::iter::once(&self.coinbase).chain(&self.txs)
+    }
 }
 
 /// UTXO set trait
+///
+/// Mutating methods take `&mut self`, so a set shared between threads needs an
+/// outer lock held for the whole mutation. [`InMemoryUtxoSet::reader`] gives
+/// other threads a read path that does not wait for that lock.
 pub trait UtxoSet {
     fn get(&self, outpoint: &OutPoint) -> Option<TxOut>;
     fn insert(&mut self, outpoint: OutPoint, txout: TxOut);
     fn remove(&mut self, outpoint: &OutPoint) -> Option<TxOut>;
 
-    /// Apply a transaction atomically, returning an ApplyReceipt for possible rollback
-    fn apply_tx(&mut self, tx: &Transaction) -> Result<ApplyReceipt, UtxoError>;
+    /// Number of unspent outputs.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sum of all unspent output values, or [`UtxoError::Overflow`] if it
+    /// does not fit in a u64.
+    fn total_value(&self) -> Result<u64, UtxoError>;
+
+    /// Creation height and coinbase flag of an unspent output, for sets that
+    /// track them. The default tracks nothing and returns `None`.
+    fn meta(&self, _outpoint: &OutPoint) -> Option<CoinMeta> {
+        None
+    }
+
+    /// Remove several outpoints, returning each with the entry it held (if
+    /// any) in input order. Stores that can group writes override this; the
+    /// default calls `remove` per outpoint.
+    fn remove_batch(&mut self, outpoints: &[OutPoint]) -> Vec<(OutPoint, Option<TxOut>)> {
+        outpoints
+            .iter()
+            .map(|op| (op.clone(), self.remove(op)))
+            .collect()
+    }
+
+    /// Insert several entries. The default calls `insert` per entry.
+    fn insert_batch(&mut self, entries: Vec<(OutPoint, TxOut)>) {
+        for (op, txout) in entries {
+            self.insert(op, txout);
+        }
+    }
+
+    /// Apply a transaction atomically, returning an ApplyReceipt for possible rollback.
+    /// The default spends all inputs with one `remove_batch` and creates all
+    /// outputs with one `insert_batch`.
+    fn apply_tx(&mut self, tx: &Transaction) -> Result<ApplyReceipt, UtxoError>
+    where
+        Self: Sized,
+    {
+        // Validate basic properties + value conservation
+        tx.validate()?;
+        let _fee = Transaction::validate_value_conservation(tx, &*self)?;
+
+        let prevouts: Vec<OutPoint> = tx.vin.iter().map(|vin| vin.prevout.clone()).collect();
+        let mut removed: Vec<(OutPoint, TxOut)> = Vec::with_capacity(prevouts.len());
+        let mut missing = None;
+        for (op, txout) in self.remove_batch(&prevouts) {
+            match txout {
+                Some(txout) => removed.push((op, txout)),
+                None => {
+                    missing.get_or_insert(op);
+                }
+            }
+        }
+        if let Some(op) = missing {
+            // put back whatever was spent before failing
+            self.insert_batch(removed);
+            return Err(UtxoError::MissingUtxo(op));
+        }
+
+        // Outputs of one tx never collide with each other, so check them all
+        // before writing any.
+        let outpoints = Transaction::make_outpoints(tx)?;
+        if let Some(op) = outpoints.iter().find(|op| self.get(op).is_some()) {
+            let op = op.clone();
+            self.insert_batch(removed);
+            return Err(UtxoError::DuplicateOutput(op));
+        }
+        self.insert_batch(outpoints.iter().cloned().zip(tx.vout.iter().cloned()).collect());
+
+        Ok(ApplyReceipt {
+            removed,
+            inserted: outpoints,
+            spent_meta: Vec::new(),
+        })
+    }
 
     /// Rollback a previous ApplyReceipt
-    fn rollback(&mut self, receipt: ApplyReceipt) -> Result<(), UtxoError>;
+    fn rollback(&mut self, receipt: ApplyReceipt) -> Result<(), UtxoError> {
+        self.remove_batch(&receipt.inserted);
+        self.insert_batch(receipt.removed);
+        Ok(())
+    }
+
+    /// Apply a block's transactions in order. `txs[0]` must be the coinbase,
+    /// which may pay out at most `subsidy` plus the other transactions' fees.
+    /// Atomic: if any step fails, everything the block already applied is
+    /// rolled back before the error is returned.
+    fn apply_block(&mut self, txs: &[Transaction], subsidy: u64) -> Result<BlockReceipt, UtxoError>
+    where
+        Self: Sized,
+    {
+        apply_block_with(
+            self,
+            txs,
+            subsidy,
+            |set, op, txout| set.insert(op, txout),
+            |set, tx| set.apply_tx(tx),
+        )
+    }
+
+    /// Undo [`UtxoSet::apply_block`], last transaction first.
+    fn rollback_block(&mut self, receipt: BlockReceipt) -> Result<(), UtxoError> {
+        for tx_receipt in receipt.txs.into_iter().rev() {
+            self.rollback(tx_receipt)?;
+        }
+        self.rollback(receipt.coinbase)
+    }
 }
 
-/// In-memory HashMap-backed UTXO set
+/// Body of [`UtxoSet::apply_block`], with the coinbase output insert and the
+/// per-transaction apply supplied by the caller so
+/// [`InMemoryUtxoSet::apply_block_at_height`] can tag heights and enforce
+/// maturity.
+fn apply_block_with<U: UtxoSet>(
+    set: &mut U,
+    txs: &[Transaction],
+    subsidy: u64,
+    mut add_coinbase_output: impl FnMut(&mut U, OutPoint, TxOut),
+    mut apply: impl FnMut(&mut U, &Transaction) -> Result<ApplyReceipt, UtxoError>,
+) -> Result<BlockReceipt, UtxoError> {
+    let (coinbase, rest) = txs
+        .split_first()
+        .filter(|(coinbase, _)| coinbase.is_coinbase())
+        .ok_or(UtxoError::MissingCoinbase)?;
+    coinbase.validate_as(true)?;
+    let value = Transaction::sum_outputs(coinbase)?;
+    let outpoints = Transaction::make_outpoints(coinbase)?;
+    if let Some(op) = outpoints.iter().find(|op| set.get(op).is_some()) {
+        return Err(UtxoError::DuplicateOutput(op.clone()));
+    }
+    for (op, txout) in outpoints.iter().zip(&coinbase.vout) {
+        add_coinbase_output(set, op.clone(), txout.clone());
+    }
+    let mut receipt = BlockReceipt {
+        coinbase: ApplyReceipt {
+            removed: Vec::new(),
+            inserted: outpoints,
+            spent_meta: Vec::new(),
+        },
+        txs: Vec::with_capacity(rest.len()),
+        fees: 0,
+    };
+
+    let mut apply_rest = || {
+        for tx in rest {
+            let fee = tx.fee(&*set)?;
+            receipt.txs.push(apply(set, tx)?);
+            receipt.fees = receipt.fees.checked_add(fee).ok_or(UtxoError::Overflow)?;
+        }
+        let limit = subsidy.saturating_add(receipt.fees);
+        if value > limit {
+            return Err(UtxoError::ExcessCoinbase { value, limit });
+        }
+        Ok(())
+    };
+    match apply_rest() {
+        Ok(()) => Ok(receipt),
+        Err(err) => {
+            set.rollback_block(receipt)?;
+            Err(err)
+        }
+    }
+}
+
+/// Approximate stored size of one UTXO entry: a 36-byte outpoint key plus
+/// value, script length and script bytes (the on-disk encoding).
+fn utxo_entry_bytes(txout: &TxOut) -> usize {
+    36 + 16 + txout.script_pubkey.len()
+}
+
+/// Where a UTXO entry came from, as tracked by [`InMemoryUtxoSet`] to
+/// enforce coinbase maturity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CoinMeta {
+    /// Height of the block that created the output
+    pub height: u32,
+    pub is_coinbase: bool,
+}
+
+impl CoinMeta {
+    /// Whether the output may be spent in a block at `height`: coinbase
+    /// outputs need `maturity` blocks on top of the one that created them.
+    pub fn is_mature(&self, height: u32, maturity: u32) -> bool {
+        !self.is_coinbase || height.saturating_sub(self.height) >= maturity
+    }
+}
+
+/// Number of lock shards used by [`ConcurrentUtxoSet`]
+pub const UTXO_SHARDS: usize = 16;
+
 #[derive(Debug, Default)]
-pub struct InMemoryUtxoSet {
-    map: HashMap<OutPoint, TxOut>,
+struct UtxoShard {
+    map: HashMap<OutPoint, (TxOut, CoinMeta)>,
+    approx_bytes: usize,
+    /// Running sum of entry values; wider than u64 so it cannot wrap
+    total_value: u128,
 }
 
-impl InMemoryUtxoSet {
+impl UtxoShard {
+    fn insert(&mut self, outpoint: OutPoint, txout: TxOut, meta: CoinMeta) -> Option<(TxOut, CoinMeta)> {
+        self.approx_bytes += utxo_entry_bytes(&txout);
+        self.total_value += u128::from(txout.value);
+        let old = self.map.insert(outpoint, (txout, meta));
+        if let Some((old, _)) = &old {
+            self.forget(old);
+        }
+        old
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Option<(TxOut, CoinMeta)> {
+        let removed = self.map.remove(outpoint)?;
+        self.forget(&removed.0);
+        Some(removed)
+    }
+
+    /// Take a removed entry out of the running totals.
+    fn forget(&mut self, txout: &TxOut) {
+        self.approx_bytes -= utxo_entry_bytes(txout);
+        self.total_value -= u128::from(txout.value);
+    }
+}
+
+/// Sharded, `Sync` storage behind [`InMemoryUtxoSet`].
+///
+/// Every read takes a read lock on one shard and every mutation a short
+/// write lock on the shard it touches, so readers holding the handle from
+/// [`InMemoryUtxoSet::reader`] proceed between the individual steps of a
+/// block apply instead of waiting for the whole block. They may observe a
+/// block (or transaction) that is partially applied; callers that need a
+/// consistent view must go through the lock guarding the writer.
+#[derive(Debug)]
+pub struct ConcurrentUtxoSet {
+    shards: Vec<RwLock<UtxoShard>>,
+}
+
+impl Default for ConcurrentUtxoSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConcurrentUtxoSet {
     pub fn new() -> Self {
         Self {
-            map: HashMap::new(),
+            shards: (0..UTXO_SHARDS).map(|_| RwLock::new(UtxoShard::default())).collect(),
         }
     }
 
+    fn shard_index(outpoint: &OutPoint) -> usize {
+        (outpoint.txid[0] as usize ^ outpoint.vout as usize) % UTXO_SHARDS
+    }
+
+    fn read_shard(&self, outpoint: &OutPoint) -> RwLockReadGuard<'_, UtxoShard> {
+        self.shards[Self::shard_index(outpoint)]
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write_shard(&self, outpoint: &OutPoint) -> RwLockWriteGuard<'_, UtxoShard> {
+        self.shards[Self::shard_index(outpoint)]
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Sum `f` over all shards, read-locking one at a time.
+    fn fold_shards<T>(&self, init: T, mut f: impl FnMut(T, &UtxoShard) -> T) -> T {
+        self.shards.iter().fold(init, |acc, shard| {
+            f(acc, &shard.read().unwrap_or_else(|poisoned| poisoned.into_inner()))
+        })
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.read_shard(outpoint).map.get(outpoint).map(|(txout, _)| txout.clone())
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.read_shard(outpoint).map.contains_key(outpoint)
+    }
+
+    pub fn meta(&self, outpoint: &OutPoint) -> Option<CoinMeta> {
+        self.read_shard(outpoint).map.get(outpoint).map(|(_, meta)| *meta)
+    }
+
+    pub fn len(&self) -> usize {
+        self.fold_shards(0, |total, shard| total + shard.map.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate encoded size of all entries, maintained on insert/remove.
+    pub fn approx_bytes(&self) -> usize {
+        self.fold_shards(0, |total, shard| total + shard.approx_bytes)
+    }
+
+    /// Sum of all entry values, or [`UtxoError::Overflow`] past u64.
+    pub fn total_value(&self) -> Result<u64, UtxoError> {
+        let total = self.fold_shards(0u128, |total, shard| total + shard.total_value);
+        u64::try_from(total).map_err(|_| UtxoError::Overflow)
+    }
+
     pub fn entries(&self) -> Vec<(OutPoint, TxOut)> {
-        self.map
-            .iter()
-            .map(|(op, txout)| (op.clone(), txout.clone()))
-            .collect()
+        self.fold_shards(Vec::new(), |mut out, shard| {
+            out.extend(shard.map.iter().map(|(op, (txout, _))| (op.clone(), txout.clone())));
+            out
+        })
+    }
+
+    pub fn insert_entry(&self, outpoint: OutPoint, txout: TxOut, meta: CoinMeta) -> Option<(TxOut, CoinMeta)> {
+        self.write_shard(&outpoint).insert(outpoint, txout, meta)
+    }
+
+    pub fn remove_entry(&self, outpoint: &OutPoint) -> Option<(TxOut, CoinMeta)> {
+        self.write_shard(outpoint).remove(outpoint)
+    }
+
+    fn set_meta(&self, outpoint: &OutPoint, meta: CoinMeta) -> bool {
+        match self.write_shard(outpoint).map.get_mut(outpoint) {
+            Some(entry) => {
+                entry.1 = meta;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn clear(&self) {
+        for shard in &self.shards {
+            *shard.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = UtxoShard::default();
+        }
     }
 }
 
-impl UtxoSet for InMemoryUtxoSet {
+impl UtxoSet for ConcurrentUtxoSet {
     fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
-        self.map.get(outpoint).cloned()
+        ConcurrentUtxoSet::get(self, outpoint)
     }
 
     fn insert(&mut self, outpoint: OutPoint, txout: TxOut) {
-        self.map.insert(outpoint, txout);
+        self.insert_entry(outpoint, txout, CoinMeta::default());
     }
 
     fn remove(&mut self, outpoint: &OutPoint) -> Option<TxOut> {
-        self.map.remove(outpoint)
+        self.remove_entry(outpoint).map(|(txout, _)| txout)
     }
 
-    fn apply_tx(&mut self, tx: &Transaction) -> Result<ApplyReceipt, UtxoError> {
-        // Validate basic properties + value conservation
+    fn len(&self) -> usize {
+        ConcurrentUtxoSet::len(self)
+    }
+
+    fn total_value(&self) -> Result<u64, UtxoError> {
+        ConcurrentUtxoSet::total_value(self)
+    }
+
+    fn meta(&self, outpoint: &OutPoint) -> Option<CoinMeta> {
+        ConcurrentUtxoSet::meta(self, outpoint)
+    }
+}
+
+/// In-memory HashMap-backed UTXO set
+///
+/// Each entry also records its [`CoinMeta`]. Entries added through the plain
+/// [`UtxoSet::insert`] get the default (height 0, not a coinbase), so only
+/// [`InMemoryUtxoSet::insert_at_height`] and
+/// [`InMemoryUtxoSet::apply_tx_at_height`] produce entries subject to maturity.
+///
+/// The entries live in a [`ConcurrentUtxoSet`] shared with
+/// [`InMemoryUtxoSet::reader`] handles, so they can be read while the owner
+/// applies a block under its own lock.
+#[derive(Debug, Default)]
+pub struct InMemoryUtxoSet {
+    coins: Arc<ConcurrentUtxoSet>,
+}
+
+impl InMemoryUtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle that reads the live set from other threads without the
+    /// lock guarding `self`; see [`ConcurrentUtxoSet`] for what it may see.
+    pub fn reader(&self) -> Arc<ConcurrentUtxoSet> {
+        Arc::clone(&self.coins)
+    }
+
+    /// Approximate encoded size of all entries, maintained on insert/remove.
+    pub fn approx_bytes(&self) -> usize {
+        self.coins.approx_bytes()
+    }
+
+    pub fn clear(&mut self) {
+        self.coins.clear();
+    }
+
+    pub fn entries(&self) -> Vec<(OutPoint, TxOut)> {
+        self.coins.entries()
+    }
+
+    /// Insert an output created at `height`, by a coinbase if `is_coinbase`.
+    pub fn insert_at_height(&mut self, outpoint: OutPoint, txout: TxOut, height: u32, is_coinbase: bool) {
+        self.insert_with_meta(outpoint, txout, CoinMeta { height, is_coinbase });
+    }
+
+    /// Re-tag an existing entry, e.g. after loading a snapshot that did not
+    /// store metadata. Returns false if `outpoint` is not in the set.
+    pub fn set_meta(&mut self, outpoint: &OutPoint, meta: CoinMeta) -> bool {
+        self.coins.set_meta(outpoint, meta)
+    }
+
+    fn insert_with_meta(&mut self, outpoint: OutPoint, txout: TxOut, meta: CoinMeta) {
+        self.coins.insert_entry(outpoint, txout, meta);
+    }
+
+    /// [`UtxoSet::apply_block`] for a block at `height`, applying each
+    /// transaction with [`InMemoryUtxoSet::apply_tx_at_height`] and tagging
+    /// the coinbase outputs as such.
+    pub fn apply_block_at_height(
+        &mut self,
+        txs: &[Transaction],
+        subsidy: u64,
+        height: u32,
+        maturity: u32,
+    ) -> Result<BlockReceipt, UtxoError> {
+        apply_block_with(
+            self,
+            txs,
+            subsidy,
+            |set, op, txout| set.insert_at_height(op, txout, height, true),
+            |set, tx| set.apply_tx_at_height(tx, height, maturity),
+        )
+    }
+
+    /// [`UtxoSet::apply_tx`] for a transaction in a block at `height`: spends
+    /// of coinbase outputs younger than `maturity` blocks fail with
+    /// [`UtxoError::ImmatureCoinbase`], new outputs are tagged with `height`,
+    /// and the receipt keeps the spent entries' metadata so
+    /// [`UtxoSet::rollback`] restores them exactly.
+    pub fn apply_tx_at_height(
+        &mut self,
+        tx: &Transaction,
+        height: u32,
+        maturity: u32,
+    ) -> Result<ApplyReceipt, UtxoError> {
         tx.validate()?;
         let _fee = Transaction::validate_value_conservation(tx, &*self)?;
-
-        // Collect removed UTXOs by attempting to remove them one-by-one (so we can simulate mid-failure)
-        let mut removed: Vec<(OutPoint, TxOut)> = Vec::new();
         for vin in &tx.vin {
-            match self.remove(&vin.prevout) {
-                Some(txout) => removed.push((vin.prevout.clone(), txout)),
-                None => {
-                    // rollback any prior removals
-                    for (op, to) in removed.iter().rev() {
-                        self.insert(op.clone(), to.clone());
-                    }
-                    return Err(UtxoError::MissingUtxo(vin.prevout.clone()));
-                }
+            let meta = self
+                .meta(&vin.prevout)
+                .ok_or_else(|| UtxoError::MissingUtxo(vin.prevout.clone()))?;
+            if !meta.is_mature(height, maturity) {
+                return Err(UtxoError::ImmatureCoinbase {
+                    outpoint: vin.prevout.clone(),
+                    created: meta.height,
+                    height,
+                    maturity,
+                });
             }
         }
-
-        // Insert new outputs; track inserted outpoints
         let outpoints = Transaction::make_outpoints(tx)?;
-        let mut inserted: Vec<OutPoint> = Vec::new();
-        for (op, txout) in outpoints.into_iter().zip(tx.vout.iter()) {
-            if self.map.contains_key(&op) {
-                // collision -> rollback
-                let receipt = ApplyReceipt {
-                    removed: removed.clone(),
-                    inserted: inserted.clone(),
-                };
-                let _ = self.rollback(receipt.clone());
-                return Err(UtxoError::DuplicateOutput(op));
-            }
-            self.insert(op.clone(), txout.clone());
-            inserted.push(op);
+        if let Some(op) = outpoints.iter().find(|op| self.coins.contains(op)) {
+            return Err(UtxoError::DuplicateOutput(op.clone()));
+        }
+
+        let mut removed = Vec::with_capacity(tx.vin.len());
+        let mut spent_meta = Vec::with_capacity(tx.vin.len());
+        for vin in &tx.vin {
+            let (txout, meta) = self
+                .coins
+                .remove_entry(&vin.prevout)
+                .expect("inputs were checked above");
+            removed.push((vin.prevout.clone(), txout));
+            spent_meta.push(meta);
+        }
+        let meta = CoinMeta {
+            height,
+            is_coinbase: tx.is_coinbase(),
+        };
+        for (op, txout) in outpoints.iter().zip(&tx.vout) {
+            self.insert_with_meta(op.clone(), txout.clone(), meta);
         }
+        Ok(ApplyReceipt {
+            removed,
+            inserted: outpoints,
+            spent_meta,
+        })
+    }
+}
+
+impl UtxoSet for InMemoryUtxoSet {
+    fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.coins.get(outpoint)
+    }
+
+    fn insert(&mut self, outpoint: OutPoint, txout: TxOut) {
+        self.insert_with_meta(outpoint, txout, CoinMeta::default());
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.coins.remove_entry(outpoint).map(|(txout, _)| txout)
+    }
+
+    fn len(&self) -> usize {
+        self.coins.len()
+    }
+
+    fn total_value(&self) -> Result<u64, UtxoError> {
+        self.coins.total_value()
+    }
 
-        Ok(ApplyReceipt { removed, inserted })
+    fn meta(&self, outpoint: &OutPoint) -> Option<CoinMeta> {
+        self.coins.meta(outpoint)
     }
 
+    /// Restores the spent entries' [`CoinMeta`] when the receipt carries it.
     fn rollback(&mut self, receipt: ApplyReceipt) -> Result<(), UtxoError> {
-        // remove inserted
-        for op in &receipt.inserted {
-            self.remove(op);
-        }
-        // re-insert removed
-        for (op, txout) in receipt.removed {
-            self.insert(op, txout);
+        self.remove_batch(&receipt.inserted);
+        let metas = receipt
+            .spent_meta
+            .into_iter()
+            .chain(std::iter::repeat(CoinMeta::default()));
+        for ((op, txout), meta) in receipt.removed.into_iter().zip(metas) {
+            self.insert_with_meta(op, txout, meta);
         }
         Ok(())
     }
 }
 
+/// In-memory UTXO set ordered by outpoint, so [`BTreeMapUtxoSet::entries`]
+/// (and anything written from it) is identical across runs.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BTreeMapUtxoSet {
+    map: BTreeMap<OutPoint, TxOut>,
+}
+
+impl BTreeMapUtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Entries in `(txid, vout)` order.
+    pub fn entries(&self) -> Vec<(OutPoint, TxOut)> {
+        self.map
+            .iter()
+            .map(|(op, txout)| (op.clone(), txout.clone()))
+            .collect()
+    }
+}
+
+impl UtxoSet for BTreeMapUtxoSet {
+    fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.map.get(outpoint).cloned()
+    }
+
+    fn insert(&mut self, outpoint: OutPoint, txout: TxOut) {
+        self.map.insert(outpoint, txout);
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.map.remove(outpoint)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMapUtxoSet::len(self)
+    }
+
+    fn total_value(&self) -> Result<u64, UtxoError> {
+        checked_value_sum(self.map.values())
+    }
+}
+
+fn checked_value_sum<'a>(txouts: impl IntoIterator<Item = &'a TxOut>) -> Result<u64, UtxoError> {
+    txouts
+        .into_iter()
+        .try_fold(0u64, |total, txout| total.checked_add(txout.value).ok_or(UtxoError::Overflow))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -376,21 +1421,406 @@ mod tests {
             script_sig: b"hello".to_vec(),
             sequence: 0xffffffff,
         };
-        let txout = TxOut {
-            value: 50_000,
-            script_pubkey: b"world".to_vec(),
+        let txout = TxOut {
+            value: 50_000,
+            script_pubkey: b"world".to_vec(),
+        };
+        let tx = Transaction {
+            version: 1,
+            vin: vec![txin],
+            vout: vec![txout],
+            lock_time: 0,
+        };
+
+        let bytes = tx.to_json_bytes()?;
+        let tx2 = Transaction::from_json_bytes(&bytes)?;
+        assert_eq!(tx, tx2);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_rejects_duplicate_inputs() {
+        let input = |tag: u8, vout: u32| TxIn {
+            prevout: OutPoint {
+                txid: [tag; 32],
+                vout,
+            },
+            script_sig: vec![],
+            sequence: 0,
+        };
+        let mut tx = Transaction {
+            version: 1,
+            vin: vec![input(1, 0), input(2, 0), input(1, 1)],
+            vout: vec![],
+            lock_time: 0,
+        };
+        tx.validate().unwrap();
+
+        tx.vin.push(input(2, 0));
+        assert!(matches!(tx.validate(), Err(UtxoError::DuplicateInput(op)) if op == input(2, 0).prevout));
+        let bytes = tx.to_json_bytes().unwrap();
+        assert!(matches!(
+            Transaction::from_json_bytes(&bytes),
+            Err(UtxoError::DuplicateInput(_))
+        ));
+
+        // Past the sorted-scan size the HashSet path must agree.
+        tx.vin = (0..DUPLICATE_SCAN_HASHSET_MIN as u32 + 5).map(|i| input(3, i)).collect();
+        tx.validate().unwrap();
+        tx.vin.push(input(3, 7));
+        assert!(matches!(tx.validate(), Err(UtxoError::DuplicateInput(op)) if op == input(3, 7).prevout));
+    }
+
+    #[test]
+    fn coinbase_input_forms() {
+        let input = |prevout: OutPoint| TxIn {
+            prevout,
+            script_sig: vec![0x01, 0x02],
+            sequence: u32::MAX,
+        };
+        let mut tx = Transaction {
+            version: 1,
+            vin: vec![],
+            vout: vec![TxOut {
+                value: 50,
+                script_pubkey: vec![1],
+            }],
+            lock_time: 0,
+        };
+        // No inputs is not a coinbase; only the genesis coinbase looks like this.
+        assert!(!tx.is_coinbase());
+
+        tx.vin = vec![input(OutPoint::NULL)];
+        assert!(tx.is_coinbase());
+
+        tx.vin = vec![input(OutPoint::NULL), input(OutPoint::NULL)];
+        assert!(!tx.is_coinbase());
+
+        tx.vin = vec![input(OutPoint {
+            txid: [0u8; 32],
+            vout: 0,
+        })];
+        assert!(!tx.is_coinbase());
+    }
+
+    #[test]
+    fn validate_as_enforces_input_and_output_rules() {
+        let mut tx = Transaction {
+            version: 1,
+            vin: vec![],
+            vout: vec![TxOut {
+                value: 50,
+                script_pubkey: vec![1],
+            }],
+            lock_time: 0,
+        };
+        tx.validate_as(true).unwrap();
+        assert!(matches!(tx.validate_as(false), Err(UtxoError::EmptyInputs)));
+        // Structural validation alone still accepts it (txids need this).
+        tx.validate().unwrap();
+
+        tx.vin.push(TxIn {
+            prevout: OutPoint {
+                txid: [1u8; 32],
+                vout: 0,
+            },
+            script_sig: vec![],
+            sequence: 0,
+        });
+        tx.validate_as(false).unwrap();
+
+        tx.vout.push(TxOut {
+            value: 0,
+            script_pubkey: vec![2],
+        });
+        assert!(matches!(tx.validate_as(false), Err(UtxoError::ZeroValueOutput(1))));
+        assert!(matches!(tx.validate_as(true), Err(UtxoError::ZeroValueOutput(1))));
+
+        tx.vout.clear();
+        assert!(matches!(tx.validate_as(false), Err(UtxoError::EmptyOutputs)));
+    }
+
+    #[test]
+    fn validate_rejects_unsupported_versions() {
+        let mut tx = Transaction {
+            version: 1,
+            vin: vec![],
+            vout: vec![TxOut {
+                value: 50,
+                script_pubkey: vec![1],
+            }],
+            lock_time: 0,
+        };
+        for version in SUPPORTED_TX_VERSIONS {
+            tx.version = version;
+            tx.validate().unwrap();
+        }
+        for version in [0, -1, i32::MIN, 3] {
+            tx.version = version;
+            assert!(matches!(tx.validate(), Err(UtxoError::UnsupportedVersion(v)) if v == version));
+            assert!(tx.txid_v2().is_err());
+        }
+    }
+
+    #[test]
+    fn dust_check_exempts_data_carriers_by_marker() {
+        let tx = Transaction {
+            version: 1,
+            vin: vec![],
+            vout: vec![
+                TxOut {
+                    value: 1_000,
+                    script_pubkey: vec![1],
+                },
+                TxOut {
+                    value: 0,
+                    script_pubkey: vec![DATA_CARRIER_MARKER, 0xaa],
+                },
+            ],
+            lock_time: 0,
+        };
+        tx.check_dust(546).unwrap();
+        tx.validate_as(true).unwrap();
+        assert!(matches!(
+            tx.check_dust_with_marker(546, None),
+            Err(UtxoError::DustOutput {
+                index: 1,
+                value: 0,
+                min: 546
+            })
+        ));
+        assert!(matches!(
+            tx.check_dust_with_marker(546, Some(0x6b)),
+            Err(UtxoError::DustOutput { index: 1, .. })
+        ));
+        assert!(matches!(
+            tx.check_dust(1_001),
+            Err(UtxoError::DustOutput { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn sighash_ignores_scripts_without_copying_them() {
+        let input = |tag: u8, script_len: usize| TxIn {
+            prevout: OutPoint {
+                txid: [tag; 32],
+                vout: tag as u32,
+            },
+            script_sig: vec![tag; script_len],
+            sequence: 7,
+        };
+        let tx = Transaction {
+            version: 2,
+            vin: vec![input(1, MAX_SCRIPT_SIZE), input(2, 96)],
+            vout: vec![TxOut {
+                value: 5,
+                script_pubkey: vec![0xab; 25],
+            }],
+            lock_time: 11,
+        };
+
+        let stripped = tx.clone_without_scripts();
+        assert!(stripped.vin.iter().all(|vin| vin.script_sig.is_empty()));
+        assert_eq!(stripped.vin[1].prevout, tx.vin[1].prevout);
+        assert_eq!(stripped.vin[1].sequence, 7);
+        assert_eq!(stripped.vout, tx.vout);
+
+        // Reference: the previous clone-then-clear construction.
+        let mut cleared = tx.clone();
+        for vin in &mut cleared.vin {
+            vin.script_sig.clear();
+        }
+        assert_eq!(stripped, cleared);
+        let digest = Sha256::digest(Sha256::digest(cleared.canonical_bytes_v2().unwrap()));
+        assert_eq!(tx_sighash_v2(&tx).unwrap().as_slice(), digest.as_slice());
+    }
+
+    #[test]
+    fn sighash_for_input_commits_to_its_own_prevout() {
+        let input = |tag: u8| TxIn {
+            prevout: OutPoint {
+                txid: [tag; 32],
+                vout: tag as u32,
+            },
+            script_sig: vec![tag; 4],
+            sequence: 0xffff_fffe,
+        };
+        let prev = |value: u64, tag: u8| TxOut {
+            value,
+            script_pubkey: vec![tag; 25],
+        };
+        let tx = Transaction {
+            version: 1,
+            vin: vec![input(1), input(2)],
+            vout: vec![TxOut {
+                value: 90,
+                script_pubkey: vec![0xcd; 25],
+            }],
+            lock_time: 0,
+        };
+
+        let a = sighash_for_input(&tx, 0, &prev(50, 0xa1)).unwrap();
+        let b = sighash_for_input(&tx, 1, &prev(50, 0xa1)).unwrap();
+        assert_ne!(a, b, "same prevout, different input position");
+
+        // Changing input 1's prevout value only changes input 1's digest.
+        let b_more = sighash_for_input(&tx, 1, &prev(51, 0xa1)).unwrap();
+        assert_ne!(b, b_more);
+        assert_eq!(a, sighash_for_input(&tx, 0, &prev(50, 0xa1)).unwrap());
+        assert_ne!(b, sighash_for_input(&tx, 1, &prev(50, 0xa2)).unwrap());
+
+        // Scripts are not committed to, so signing one input can't invalidate another.
+        let mut signed = tx.clone();
+        signed.vin[1].script_sig = vec![0xee; 100];
+        assert_eq!(a, sighash_for_input(&signed, 0, &prev(50, 0xa1)).unwrap());
+
+        let mut other_outputs = tx.clone();
+        other_outputs.vout[0].value = 89;
+        assert_ne!(a, sighash_for_input(&other_outputs, 0, &prev(50, 0xa1)).unwrap());
+    }
+
+    #[test]
+    fn output_access_is_bounds_checked() {
+        let tx = Transaction {
+            version: 1,
+            vin: vec![],
+            vout: vec![
+                TxOut {
+                    value: 7,
+                    script_pubkey: vec![1],
+                },
+                TxOut {
+                    value: 9,
+                    script_pubkey: vec![2],
+                },
+            ],
+            lock_time: 0,
+        };
+        assert_eq!(tx.output(1), Some(&tx.vout[1]));
+        assert_eq!(tx.output_value(0), Some(7));
+        assert_eq!(tx.output(2), None);
+        assert_eq!(tx.output_value(u32::MAX), None);
+    }
+
+    #[test]
+    fn sighash_single_commits_to_matching_output_only() {
+        let input = |tag: u8| TxIn {
+            prevout: OutPoint {
+                txid: [tag; 32],
+                vout: 0,
+            },
+            script_sig: vec![],
+            sequence: 0,
+        };
+        let out = |value: u64| TxOut {
+            value,
+            script_pubkey: vec![0xcd; 25],
+        };
+        let prev = out(100);
+        let mut tx = Transaction {
+            version: 1,
+            vin: vec![input(1), input(2)],
+            vout: vec![out(40)],
+            lock_time: 0,
+        };
+        let single = sighash_single_for_input(&tx, 0, &prev).unwrap();
+        assert_ne!(single, sighash_for_input(&tx, 0, &prev).unwrap());
+        // Input 1 has no output 1: a clean error instead of a panic.
+        assert!(matches!(
+            sighash_single_for_input(&tx, 1, &prev),
+            Err(UtxoError::OutputIndexOutOfRange { index: 1, outputs: 1 })
+        ));
+
+        // Adding an output leaves input 0's digest alone and gives input 1 one.
+        tx.vout.push(out(50));
+        assert_eq!(sighash_single_for_input(&tx, 0, &prev).unwrap(), single);
+        assert!(sighash_single_for_input(&tx, 1, &prev).is_ok());
+        tx.vout[0].value = 41;
+        assert_ne!(sighash_single_for_input(&tx, 0, &prev).unwrap(), single);
+    }
+
+    #[test]
+    fn sighash_for_input_rejects_bad_index() {
+        let mut tx = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: [1u8; 32],
+                    vout: 0,
+                },
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![],
+            lock_time: 0,
+        };
+        let prevout = TxOut {
+            value: 1,
+            script_pubkey: vec![],
+        };
+        assert!(matches!(
+            sighash_for_input(&tx, 1, &prevout),
+            Err(UtxoError::InputIndexOutOfRange { index: 1, inputs: 1 })
+        ));
+
+        tx.vin.clear();
+        assert!(matches!(
+            sighash_for_input(&tx, 0, &prevout),
+            Err(UtxoError::InputIndexOutOfRange { index: 0, inputs: 0 })
+        ));
+    }
+
+    #[test]
+    fn btreemap_set_serializes_identically_regardless_of_insert_order() {
+        let entries: Vec<(OutPoint, TxOut)> = (0..50u8)
+            .map(|i| {
+                (
+                    OutPoint {
+                        txid: [i.wrapping_mul(37); 32],
+                        vout: (i % 3) as u32,
+                    },
+                    TxOut {
+                        value: i as u64,
+                        script_pubkey: vec![i],
+                    },
+                )
+            })
+            .collect();
+        let jsonl = |set: &BTreeMapUtxoSet| {
+            set.entries()
+                .iter()
+                .map(|entry| serde_json::to_string(entry).unwrap() + "\n")
+                .collect::<String>()
         };
+
+        let mut forward = BTreeMapUtxoSet::new();
+        for (op, txout) in entries.iter().cloned() {
+            forward.insert(op, txout);
+        }
+        let mut reverse = BTreeMapUtxoSet::new();
+        for (op, txout) in entries.iter().rev().cloned() {
+            reverse.insert(op, txout);
+        }
+        assert_eq!(jsonl(&forward), jsonl(&reverse));
+        let keys: Vec<OutPoint> = forward.entries().into_iter().map(|(op, _)| op).collect();
+        assert!(keys.windows(2).all(|w| (w[0].txid, w[0].vout) < (w[1].txid, w[1].vout)));
+
+        // apply/rollback come from the trait defaults.
+        let spent = keys[0].clone();
         let tx = Transaction {
             version: 1,
-            vin: vec![txin],
-            vout: vec![txout],
+            vin: vec![TxIn {
+                prevout: spent.clone(),
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![],
             lock_time: 0,
         };
-
-        let bytes = tx.to_json_bytes()?;
-        let tx2 = Transaction::from_json_bytes(&bytes)?;
-        assert_eq!(tx, tx2);
-        Ok(())
+        let receipt = forward.apply_tx(&tx).unwrap();
+        assert!(forward.get(&spent).is_none());
+        forward.rollback(receipt).unwrap();
+        assert_eq!(forward, reverse);
     }
 
     #[test]
@@ -443,6 +1873,81 @@ mod tests {
         assert!(set.get(&outpoint).is_none());
     }
 
+    #[test]
+    fn in_memory_utxo_set_tracks_approx_bytes() {
+        let mut set = InMemoryUtxoSet::new();
+        assert_eq!(set.approx_bytes(), 0);
+        let op = |tag: u8| OutPoint {
+            txid: [tag; 32],
+            vout: 0,
+        };
+        let out = |len: usize| TxOut {
+            value: 1,
+            script_pubkey: vec![0u8; len],
+        };
+
+        set.insert(op(1), out(10));
+        set.insert(op(2), out(20));
+        assert_eq!(set.approx_bytes(), (52 + 10) + (52 + 20));
+
+        // replacing an entry swaps its size rather than double counting
+        set.insert(op(1), out(4));
+        assert_eq!(set.approx_bytes(), (52 + 4) + (52 + 20));
+
+        set.remove(&op(2));
+        assert_eq!(set.approx_bytes(), 52 + 4);
+        assert!(set.remove(&op(9)).is_none());
+        assert_eq!(set.approx_bytes(), 52 + 4);
+
+        set.insert(op(3), out(0));
+        set.clear();
+        assert_eq!(set.approx_bytes(), 0);
+        assert!(set.entries().is_empty());
+    }
+
+    #[test]
+    fn utxo_sets_track_len_and_total_value() {
+        let op = |tag: u8| OutPoint {
+            txid: [tag; 32],
+            vout: 0,
+        };
+        let out = |value: u64| TxOut {
+            value,
+            script_pubkey: vec![1],
+        };
+
+        let mut set = InMemoryUtxoSet::new();
+        assert!(set.is_empty());
+        assert_eq!(set.total_value().unwrap(), 0);
+        set.insert(op(1), out(100));
+        set.insert(op(2), out(250));
+        assert_eq!((set.len(), set.total_value().unwrap()), (2, 350));
+        // Replacing an entry swaps its value rather than adding to it.
+        set.insert(op(1), out(40));
+        assert_eq!((set.len(), set.total_value().unwrap()), (2, 290));
+        set.remove(&op(2));
+        assert!(set.remove(&op(9)).is_none());
+        assert_eq!((set.len(), set.total_value().unwrap()), (1, 40));
+
+        // The running total outgrows u64 without wrapping and recovers.
+        set.insert(op(3), out(u64::MAX));
+        assert!(matches!(set.total_value(), Err(UtxoError::Overflow)));
+        assert_eq!(set.len(), 2);
+        set.remove(&op(3));
+        assert_eq!(set.total_value().unwrap(), 40);
+        set.clear();
+        assert_eq!((set.len(), set.total_value().unwrap()), (0, 0));
+
+        let mut btree = BTreeMapUtxoSet::new();
+        for (tag, value) in [(1, 7), (2, u64::MAX - 7)] {
+            UtxoSet::insert(&mut btree, op(tag), out(value));
+        }
+        assert_eq!(UtxoSet::total_value(&btree).unwrap(), u64::MAX);
+        UtxoSet::insert(&mut btree, op(3), out(1));
+        assert!(matches!(UtxoSet::total_value(&btree), Err(UtxoError::Overflow)));
+        assert_eq!(UtxoSet::len(&btree), 3);
+    }
+
     #[test]
     fn overflow_on_outputs() {
         let tx = Transaction {
@@ -710,6 +2215,524 @@ mod tests {
         assert_eq!(set.get(&collision).unwrap().value, 999);
     }
 
+    #[test]
+    fn batch_apply_matches_per_item_apply() {
+        let funding = |i: u8| OutPoint {
+            txid: [i; 32],
+            vout: 0,
+        };
+        let spend = |prevouts: Vec<OutPoint>, values: &[u64]| Transaction {
+            version: 1,
+            vin: prevouts
+                .into_iter()
+                .map(|prevout| TxIn {
+                    prevout,
+                    script_sig: vec![],
+                    sequence: 0,
+                })
+                .collect(),
+            vout: values
+                .iter()
+                .map(|&value| TxOut {
+                    value,
+                    script_pubkey: vec![value as u8],
+                })
+                .collect(),
+            lock_time: 0,
+        };
+        let first = spend(vec![funding(1), funding(2)], &[70, 20, 5]);
+        let first_outs = Transaction::make_outpoints(&first).unwrap();
+        let second = spend(vec![first_outs[0].clone(), first_outs[2].clone()], &[60, 10]);
+
+        let mut batched = InMemoryUtxoSet::new();
+        let mut per_item = InMemoryUtxoSet::new();
+        for i in 1..=2 {
+            let txout = TxOut {
+                value: 50,
+                script_pubkey: vec![i],
+            };
+            batched.insert(funding(i), txout.clone());
+            per_item.insert(funding(i), txout);
+        }
+
+        let mut receipts = Vec::new();
+        for tx in [&first, &second] {
+            let receipt = batched.apply_tx(tx).unwrap();
+            let removed = tx
+                .vin
+                .iter()
+                .map(|vin| (vin.prevout.clone(), per_item.remove(&vin.prevout).unwrap()))
+                .collect();
+            let inserted = Transaction::make_outpoints(tx).unwrap();
+            for (op, txout) in inserted.iter().zip(&tx.vout) {
+                per_item.insert(op.clone(), txout.clone());
+            }
+            assert_eq!(
+                receipt,
+                ApplyReceipt {
+                    removed,
+                    inserted,
+                    spent_meta: Vec::new(),
+                }
+            );
+            receipts.push(receipt);
+        }
+        let sorted = |set: &InMemoryUtxoSet| {
+            let mut entries = set.entries();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries
+        };
+        assert_eq!(sorted(&batched), sorted(&per_item));
+        assert_eq!(batched.approx_bytes(), per_item.approx_bytes());
+
+        // Rolling back through the batch methods restores the funding outputs.
+        for receipt in receipts.into_iter().rev() {
+            batched.rollback(receipt).unwrap();
+        }
+        assert_eq!(
+            sorted(&batched).into_iter().map(|(op, _)| op).collect::<Vec<_>>(),
+            vec![funding(1), funding(2)]
+        );
+    }
+
+    #[test]
+    fn fee_and_fee_rate_of_known_transaction() {
+        let funding = OutPoint {
+            txid: [4u8; 32],
+            vout: 1,
+        };
+        let mut utxos = InMemoryUtxoSet::new();
+        utxos.insert(
+            funding.clone(),
+            TxOut {
+                value: 10_000,
+                script_pubkey: vec![1],
+            },
+        );
+        let spend = |value: u64| Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: funding.clone(),
+                script_sig: vec![0u8; 96],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value,
+                script_pubkey: vec![2; 32],
+            }],
+            lock_time: 0,
+        };
+
+        // 24 bytes of framing + 144 per input + 48 per output.
+        let tx = spend(7_840);
+        assert_eq!(tx.size(), 216);
+        assert_eq!(tx.fee(&utxos).unwrap(), 2_160);
+        assert_eq!(tx.fee_rate(&utxos).unwrap(), 10.0);
+        assert_eq!(Transaction::validate_value_conservation(&tx, &utxos).unwrap(), 2_160);
+
+        // Spending everything is a zero fee; overspending is an error.
+        assert_eq!(spend(10_000).fee(&utxos).unwrap(), 0);
+        assert_eq!(spend(10_000).fee_rate(&utxos).unwrap(), 0.0);
+        assert!(matches!(
+            spend(10_001).fee(&utxos),
+            Err(UtxoError::ValueNotConserved {
+                input: 10_000,
+                output: 10_001
+            })
+        ));
+        assert!(matches!(
+            spend(1).fee(&InMemoryUtxoSet::new()),
+            Err(UtxoError::MissingUtxo(_))
+        ));
+
+        assert_eq!(fee_per_byte(100, 0), 0.0);
+        assert_eq!(fee_per_byte(100, 8), 12.5);
+    }
+
+    #[test]
+    fn display_hex_reverses_txid_bytes() {
+        let mut txid = [0u8; 32];
+        txid[0] = 0x01;
+        txid[31] = 0xfe;
+        let display = txid_to_display_hex(&txid);
+        assert_eq!(display, format!("fe{}01", "00".repeat(30)));
+        assert_eq!(txid_from_display_hex(&display).unwrap(), txid);
+        assert_ne!(hex::decode(&display).unwrap(), txid.to_vec());
+
+        let tx = Transaction {
+            version: 1,
+            vin: vec![],
+            vout: vec![TxOut {
+                value: 1,
+                script_pubkey: vec![1],
+            }],
+            lock_time: 0,
+        };
+        let txid = tx.txid_v2().unwrap();
+        assert_eq!(txid_from_display_hex(&txid_to_display_hex(&txid)).unwrap(), txid);
+
+        for bad in ["", "abcd", &"zz".repeat(32), &"00".repeat(33)] {
+            assert!(matches!(txid_from_display_hex(bad), Err(UtxoError::InvalidHex(_))), "{bad}");
+        }
+    }
+
+    #[test]
+    fn check_final_compares_height_and_time_locks() {
+        let tx = |lock_time: u32, sequence: u32| Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: [3u8; 32],
+                    vout: 0,
+                },
+                script_sig: vec![],
+                sequence,
+            }],
+            vout: vec![TxOut {
+                value: 10,
+                script_pubkey: vec![1],
+            }],
+            lock_time,
+        };
+        let not_final = |result: Result<(), UtxoError>, expected: u32| {
+            matches!(result, Err(UtxoError::NotFinal { lock_time }) if lock_time == expected)
+        };
+
+        assert!(tx(0, 0).check_final(0, 0).is_ok());
+
+        // Height locks: final from the locked height on, whatever the time.
+        assert!(not_final(tx(500, 0).check_final(499, u32::MAX), 500));
+        assert!(tx(500, 0).check_final(500, 0).is_ok());
+        let last_height = LOCKTIME_THRESHOLD - 1;
+        assert!(not_final(tx(last_height, 0).check_final(last_height - 1, u32::MAX), last_height));
+
+        // Time locks: compared against the median-time-past, not the height.
+        let time = LOCKTIME_THRESHOLD + 1_000;
+        assert!(not_final(tx(time, 0).check_final(u32::MAX, time - 1), time));
+        assert!(tx(time, 0).check_final(0, time).is_ok());
+        assert!(tx(LOCKTIME_THRESHOLD, 0).check_final(0, LOCKTIME_THRESHOLD).is_ok());
+        assert!(not_final(
+            tx(LOCKTIME_THRESHOLD, 0).check_final(u32::MAX, LOCKTIME_THRESHOLD - 1),
+            LOCKTIME_THRESHOLD
+        ));
+
+        // Every input at the final sequence disables the lock.
+        assert!(tx(time, u32::MAX).check_final(0, 0).is_ok());
+    }
+
+    #[test]
+    fn coinbase_outputs_mature_after_maturity_blocks() {
+        let coinbase_out = OutPoint {
+            txid: [9u8; 32],
+            vout: 0,
+        };
+        let mut set = InMemoryUtxoSet::new();
+        set.insert_at_height(
+            coinbase_out.clone(),
+            TxOut {
+                value: 50,
+                script_pubkey: vec![1],
+            },
+            10,
+            true,
+        );
+        let spend = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: coinbase_out.clone(),
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 40,
+                script_pubkey: vec![2],
+            }],
+            lock_time: 0,
+        };
+
+        match set.apply_tx_at_height(&spend, 109, 100) {
+            Err(UtxoError::ImmatureCoinbase {
+                outpoint,
+                created: 10,
+                height: 109,
+                maturity: 100,
+            }) => assert_eq!(outpoint, coinbase_out),
+            other => panic!("expected ImmatureCoinbase, got {other:?}"),
+        }
+        assert!(set.get(&coinbase_out).is_some());
+
+        let receipt = set.apply_tx_at_height(&spend, 110, 100).unwrap();
+        let new_out = Transaction::make_outpoints(&spend).unwrap().remove(0);
+        assert_eq!(
+            set.meta(&new_out),
+            Some(CoinMeta {
+                height: 110,
+                is_coinbase: false
+            })
+        );
+
+        // Rolling back puts the coinbase back with its age, so it stays immature.
+        set.rollback(receipt).unwrap();
+        assert_eq!(
+            set.meta(&coinbase_out),
+            Some(CoinMeta {
+                height: 10,
+                is_coinbase: true
+            })
+        );
+        assert!(matches!(
+            set.apply_tx_at_height(&spend, 50, 100),
+            Err(UtxoError::ImmatureCoinbase { .. })
+        ));
+    }
+
+    /// Transaction spending `inputs` into one output per value; spending
+    /// [`OutPoint::NULL`] alone makes a coinbase.
+    fn block_tx(inputs: &[&OutPoint], values: &[u64], tag: u8) -> Transaction {
+        Transaction {
+            version: 1,
+            vin: inputs
+                .iter()
+                .map(|op| TxIn {
+                    prevout: (*op).clone(),
+                    script_sig: vec![],
+                    sequence: 0,
+                })
+                .collect(),
+            vout: values
+                .iter()
+                .map(|value| TxOut {
+                    value: *value,
+                    script_pubkey: vec![tag],
+                })
+                .collect(),
+            lock_time: 0,
+        }
+    }
+
+    /// A set holding a coinbase output of 1000 from height 1 and a plain
+    /// output of 500.
+    fn funded_set() -> (InMemoryUtxoSet, OutPoint, OutPoint) {
+        let coinbase_out = OutPoint {
+            txid: [21u8; 32],
+            vout: 0,
+        };
+        let plain_out = OutPoint {
+            txid: [22u8; 32],
+            vout: 1,
+        };
+        let mut set = InMemoryUtxoSet::new();
+        let txout = |value| TxOut {
+            value,
+            script_pubkey: vec![1],
+        };
+        set.insert_at_height(coinbase_out.clone(), txout(1_000), 1, true);
+        set.insert(plain_out.clone(), txout(500));
+        (set, coinbase_out, plain_out)
+    }
+
+    fn snapshot(set: &InMemoryUtxoSet) -> Vec<(OutPoint, TxOut, Option<CoinMeta>)> {
+        let mut entries: Vec<_> = set
+            .entries()
+            .into_iter()
+            .map(|(op, txout)| {
+                let meta = set.meta(&op);
+                (op, txout, meta)
+            })
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    #[test]
+    fn apply_block_and_rollback_block_round_trip() {
+        let (mut set, coinbase_out, plain_out) = funded_set();
+        let before = snapshot(&set);
+        let tx1 = block_tx(&[&coinbase_out], &[900], 3);
+        let tx1_out = Transaction::make_outpoints(&tx1).unwrap().remove(0);
+        // tx2 spends tx1's output within the same block.
+        let tx2 = block_tx(&[&tx1_out], &[850], 4);
+        let tx3 = block_tx(&[&plain_out], &[500], 5);
+        let txs = vec![block_tx(&[&OutPoint::NULL], &[200], 9), tx1, tx2, tx3];
+
+        let receipt = set.apply_block_at_height(&txs, 50, 10, 5).unwrap();
+        assert_eq!(receipt.fees, 150);
+        assert_eq!(receipt.txs.len(), 3);
+        assert_eq!(receipt.iter().count(), 4);
+        assert_eq!((set.len(), set.total_value().unwrap()), (3, 200 + 850 + 500));
+        assert!(set.get(&tx1_out).is_none());
+        let coinbase_op = receipt.coinbase.inserted[0].clone();
+        assert_eq!(
+            set.meta(&coinbase_op),
+            Some(CoinMeta {
+                height: 10,
+                is_coinbase: true
+            })
+        );
+
+        set.rollback_block(receipt).unwrap();
+        assert_eq!(snapshot(&set), before);
+
+        // The generic trait method agrees on a set without metadata.
+        let mut btree = BTreeMapUtxoSet::new();
+        for (op, txout, _) in &before {
+            UtxoSet::insert(&mut btree, op.clone(), txout.clone());
+        }
+        let receipt = btree.apply_block(&txs, 50).unwrap();
+        assert_eq!(UtxoSet::total_value(&btree).unwrap(), 1_550);
+        btree.rollback_block(receipt).unwrap();
+        assert_eq!(
+            btree.entries(),
+            before.into_iter().map(|(op, txout, _)| (op, txout)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn failed_block_apply_leaves_set_untouched() {
+        let (mut set, coinbase_out, plain_out) = funded_set();
+        let before = snapshot(&set);
+        let missing = OutPoint {
+            txid: [23u8; 32],
+            vout: 0,
+        };
+        let coinbase = block_tx(&[&OutPoint::NULL], &[50], 9);
+
+        // The second spend fails after the coinbase and the first are applied.
+        let txs = vec![
+            coinbase.clone(),
+            block_tx(&[&plain_out], &[400], 3),
+            block_tx(&[&missing], &[10], 4),
+        ];
+        assert!(matches!(set.apply_block(&txs, 50), Err(UtxoError::MissingUtxo(op)) if op == missing));
+        assert_eq!(snapshot(&set), before);
+
+        // An immature coinbase spend restores the earlier spend's metadata.
+        let txs = vec![
+            coinbase.clone(),
+            block_tx(&[&plain_out], &[400], 3),
+            block_tx(&[&coinbase_out], &[1_000], 4),
+        ];
+        assert!(matches!(
+            set.apply_block_at_height(&txs, 50, 5, 100),
+            Err(UtxoError::ImmatureCoinbase { created: 1, height: 5, .. })
+        ));
+        assert_eq!(snapshot(&set), before);
+
+        // Only checked once every fee is known.
+        let txs = vec![block_tx(&[&OutPoint::NULL], &[151], 9), block_tx(&[&plain_out], &[400], 3)];
+        assert!(matches!(
+            set.apply_block(&txs, 50),
+            Err(UtxoError::ExcessCoinbase { value: 151, limit: 150 })
+        ));
+        assert_eq!(snapshot(&set), before);
+
+        let no_coinbase = vec![block_tx(&[&plain_out], &[400], 3)];
+        assert!(matches!(set.apply_block(&no_coinbase, 50), Err(UtxoError::MissingCoinbase)));
+        assert!(matches!(set.apply_block(&[], 50), Err(UtxoError::MissingCoinbase)));
+        assert_eq!(snapshot(&set), before);
+    }
+
+    #[test]
+    fn size_matches_v2_encoding_of_vector_txs() -> Result<(), UtxoError> {
+        let s = include_str!("../test_vectors/vectors.json");
+        let vecs: serde_json::Value = serde_json::from_str(s.trim_start_matches('\u{FEFF}'))?;
+        let expected = [
+            ("simple", 88),
+            ("multiple_inputs", 166),
+            ("script_boundary", 2088),
+            ("edge_values", 88),
+        ];
+        let vecs = vecs.as_array().unwrap();
+        assert_eq!(vecs.len(), expected.len());
+        for (v, (name, size)) in vecs.iter().zip(expected) {
+            assert_eq!(v["name"].as_str().unwrap(), name);
+            let tx: Transaction = serde_json::from_value(v["tx"].clone())?;
+            assert_eq!(tx.size(), size, "size of {}", name);
+            assert_eq!(tx.size(), tx.canonical_bytes_v2()?.len());
+            assert_eq!(tx.vsize(), tx.size());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_v3_vectors_roundtrip() -> Result<(), UtxoError> {
+        let s = include_str!("../test_vectors/vectors_v3.json");
+        let vecs: serde_json::Value = serde_json::from_str(s)?;
+        for v in vecs.as_array().unwrap() {
+            let name = v["name"].as_str().unwrap();
+            let tx: Transaction = serde_json::from_value(v["tx"].clone())?;
+            let c3 = tx.canonical_bytes_v3()?;
+            assert_eq!(
+                hex::encode(&c3),
+                v["canonical_v3"].as_str().unwrap(),
+                "canonical_v3 mismatch for {}",
+                name
+            );
+            assert_eq!(
+                hex::encode(tx.txid_v3()?),
+                v["txid_v3"].as_str().unwrap(),
+                "txid_v3 mismatch for {}",
+                name
+            );
+            assert!(c3.len() < tx.canonical_bytes_v2()?.len());
+            assert_eq!(Transaction::from_canonical_v3(&c3)?, tx, "roundtrip for {}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_v3_decoder_rejects_malformed_input() {
+        let tx = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: [9u8; 32],
+                    vout: 1,
+                },
+                script_sig: vec![1, 2, 3],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 7,
+                script_pubkey: vec![4; 300],
+            }],
+            lock_time: 0,
+        };
+        let bytes = tx.canonical_bytes_v3().unwrap();
+        for len in 0..bytes.len() {
+            assert!(
+                matches!(
+                    Transaction::from_canonical_v3(&bytes[..len]),
+                    Err(UtxoError::Truncated { .. })
+                ),
+                "prefix of {len} bytes"
+            );
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(matches!(
+            Transaction::from_canonical_v3(&trailing),
+            Err(UtxoError::NonCanonicalEncoding(_))
+        ));
+
+        // vin count of u64::MAX must fail on the bound, not try to allocate.
+        let mut huge_count = 1i32.to_le_bytes().to_vec();
+        huge_count.extend([0xff; 9]);
+        assert!(matches!(
+            Transaction::from_canonical_v3(&huge_count),
+            Err(UtxoError::TooManyInOut(_, MAX_TX_INOUTS))
+        ));
+
+        // script_sig length just over the limit (offset: version + count + outpoint).
+        let mut huge_script = bytes[..41].to_vec();
+        write_compact_size(&mut huge_script, MAX_SCRIPT_SIZE as u64 + 1);
+        assert!(matches!(
+            Transaction::from_canonical_v3(&huge_script),
+            Err(UtxoError::TooLargeScript(_, MAX_SCRIPT_SIZE))
+        ));
+    }
+
     #[test]
     fn cross_language_vectors_match() -> Result<(), UtxoError> {
         // load generated vectors and verify canonical bytes + txid for v1 and v2
@@ -717,12 +2740,12 @@ mod tests {
         // Some Windows tools may write a BOM; tolerate it by trimming.
         let s = s.trim_start_matches('\u{FEFF}');
         let vecs: serde_json::Value =
-            serde_json::from_str(s).map_err(|e| UtxoError::SerdeError(e))?;
+            serde_json::from_str(s).map_err(UtxoError::SerdeError)?;
         for v in vecs.as_array().unwrap() {
             let name = v["name"].as_str().unwrap();
             let tx_value = &v["tx"];
             let tx: Transaction =
-                serde_json::from_value(tx_value.clone()).map_err(|e| UtxoError::SerdeError(e))?;
+                serde_json::from_value(tx_value.clone()).map_err(UtxoError::SerdeError)?;
 
             // v2 checks
             let c2 = tx.canonical_bytes_v2()?;
@@ -734,7 +2757,7 @@ mod tests {
                 name
             );
             assert_eq!(
-                hex::encode(&txid2),
+                hex::encode(txid2),
                 v["txid_v2"].as_str().unwrap(),
                 "txid_v2 mismatch for {}",
                 name
@@ -750,7 +2773,7 @@ mod tests {
                 name
             );
             assert_eq!(
-                hex::encode(&txid1),
+                hex::encode(txid1),
                 v["txid_v1"].as_str().unwrap(),
                 "txid_v1 mismatch for {}",
                 name
@@ -758,4 +2781,94 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn reader_proceeds_while_apply_holds_the_lock() {
+        use std::sync::{mpsc, Mutex};
+        use std::thread;
+
+        let mut set = InMemoryUtxoSet::new();
+        let funding = OutPoint {
+            txid: [7u8; 32],
+            vout: 0,
+        };
+        let unrelated = OutPoint {
+            txid: [8u8; 32],
+            vout: 3,
+        };
+        set.insert(
+            funding.clone(),
+            TxOut {
+                value: 100,
+                script_pubkey: vec![],
+            },
+        );
+        set.insert(
+            unrelated.clone(),
+            TxOut {
+                value: 5,
+                script_pubkey: vec![],
+            },
+        );
+        let reader = set.reader();
+
+        // A chain of spends applied one at a time under the writer's lock,
+        // pausing between steps so the "block" stays in progress.
+        let mut txs = Vec::new();
+        let mut prev = funding.clone();
+        for i in 0..3u64 {
+            let tx = Transaction {
+                version: 1,
+                vin: vec![TxIn {
+                    prevout: prev.clone(),
+                    script_sig: vec![],
+                    sequence: 0,
+                }],
+                vout: vec![TxOut {
+                    value: 90 - i,
+                    script_pubkey: vec![i as u8],
+                }],
+                lock_time: 0,
+            };
+            prev = OutPoint {
+                txid: tx.txid().unwrap(),
+                vout: 0,
+            };
+            txs.push(tx);
+        }
+        let first_out = Transaction::make_outpoints(&txs[0]).unwrap()[0].clone();
+
+        let set = Arc::new(Mutex::new(set));
+        let (step_tx, step_rx) = mpsc::channel();
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+        let writer = {
+            let set = Arc::clone(&set);
+            thread::spawn(move || {
+                let mut guard = set.lock().unwrap();
+                for tx in &txs {
+                    guard.apply_tx(tx).unwrap();
+                    step_tx.send(()).unwrap();
+                    resume_rx.recv().unwrap();
+                }
+            })
+        };
+
+        step_rx.recv().unwrap();
+        // The writer still holds the mutex; the reader sees the partial state.
+        assert!(set.try_lock().is_err());
+        assert_eq!(reader.get(&unrelated).unwrap().value, 5);
+        assert!(!reader.contains(&funding));
+        assert_eq!(reader.get(&first_out).unwrap().value, 90);
+        for _ in 0..2 {
+            resume_tx.send(()).unwrap();
+            step_rx.recv().unwrap();
+        }
+        resume_tx.send(()).unwrap();
+        writer.join().unwrap();
+
+        assert!(!reader.contains(&first_out));
+        assert_eq!(reader.get(&prev).unwrap().value, 88);
+        assert_eq!(reader.len(), 2);
+        assert_eq!(reader.total_value().unwrap(), 93);
+    }
 }
@@ -73,5 +73,94 @@ pub fn map_outpoints_v1_to_v2(
             OutPoint { txid: txid_v2, vout },
         ));
     }
+    debug_assert!(check_vout_coverage(tx, &out).is_ok());
     Ok(out)
 }
+
+/// Check that `mappings` for `tx` cover every vout index `0..vout.len()`
+/// exactly once and that each pair keeps the same index on both sides.
+/// Returns a description of the first problem found.
+pub fn check_vout_coverage(
+    tx: &Transaction,
+    mappings: &[(OutPoint, OutPoint)],
+) -> Result<(), String> {
+    if mappings.len() != tx.vout.len() {
+        return Err(format!(
+            "mapping count {} does not match output count {}",
+            mappings.len(),
+            tx.vout.len()
+        ));
+    }
+    let mut seen = vec![false; tx.vout.len()];
+    for (v1, v2) in mappings {
+        if v1.vout != v2.vout {
+            return Err(format!("vout reordered: v1 {} mapped to v2 {}", v1.vout, v2.vout));
+        }
+        match seen.get_mut(v1.vout as usize) {
+            Some(slot) if *slot => return Err(format!("duplicate vout {}", v1.vout)),
+            Some(slot) => *slot = true,
+            None => return Err(format!("vout {} out of range", v1.vout)),
+        }
+    }
+    // Count matches and there are no dupes or out-of-range indices, so every
+    // slot is filled; no separate gap check is needed.
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TxIn, TxOut};
+
+    fn tx_with_outputs(n: usize) -> Transaction {
+        Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: [4u8; 32],
+                    vout: 0,
+                },
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: (0..n)
+                .map(|i| TxOut {
+                    value: i as u64 + 1,
+                    script_pubkey: vec![i as u8],
+                })
+                .collect(),
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn mapping_covers_every_vout_once() {
+        let tx = tx_with_outputs(5);
+        let pairs = map_outpoints_v1_to_v2(&tx).unwrap();
+        let vouts: Vec<u32> = pairs.iter().map(|(v1, _)| v1.vout).collect();
+        assert_eq!(vouts, vec![0, 1, 2, 3, 4]);
+        check_vout_coverage(&tx, &pairs).unwrap();
+        assert!(check_vout_coverage(&tx_with_outputs(0), &[]).is_ok());
+    }
+
+    #[test]
+    fn coverage_check_catches_gaps_dupes_and_reorders() {
+        let tx = tx_with_outputs(3);
+        let pairs = map_outpoints_v1_to_v2(&tx).unwrap();
+
+        assert!(check_vout_coverage(&tx, &pairs[..2]).is_err());
+
+        let mut dupe = pairs.clone();
+        dupe[2] = dupe[1].clone();
+        assert!(check_vout_coverage(&tx, &dupe).unwrap_err().contains("duplicate"));
+
+        let mut reordered = pairs.clone();
+        reordered[0].1.vout = 2;
+        assert!(check_vout_coverage(&tx, &reordered).unwrap_err().contains("reordered"));
+
+        let mut gap = pairs;
+        gap[2].0.vout = 3;
+        gap[2].1.vout = 3;
+        assert!(check_vout_coverage(&tx, &gap).unwrap_err().contains("out of range"));
+    }
+}
@@ -0,0 +1,132 @@
+//! Bitcoin-style CompactSize varint used by the compact binary encoding.
+//!
+//! Values below 0xfd are a single byte; larger values use a 0xfd/0xfe/0xff
+//! marker followed by a 2/4/8-byte little-endian integer. Decoding is strict:
+//! a value must use the shortest form, otherwise the same transaction would
+//! have several byte encodings (and therefore several txids).
+
+use crate::UtxoError;
+
+/// Encoded length of `n` in bytes (1, 3, 5 or 9)
+pub fn compact_size_len(n: u64) -> usize {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+/// Append the minimal CompactSize encoding of `n` to `out`.
+pub fn write_compact_size(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => out.push(n as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend(&(n as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend(&n.to_le_bytes());
+        }
+    }
+}
+
+/// Decode a CompactSize at `*pos`, advancing `pos` past it.
+///
+/// Rejects truncated input and non-minimal encodings.
+pub fn read_compact_size(bytes: &[u8], pos: &mut usize) -> Result<u64, UtxoError> {
+    let start = *pos;
+    let marker = *bytes.get(start).ok_or(UtxoError::Truncated {
+        offset: start,
+        needed: 1,
+    })?;
+    let (width, min) = match marker {
+        0xfd => (2, 0xfd),
+        0xfe => (4, 0x1_0000),
+        0xff => (8, 0x1_0000_0000),
+        _ => {
+            *pos = start + 1;
+            return Ok(marker as u64);
+        }
+    };
+    let body = bytes
+        .get(start + 1..start + 1 + width)
+        .ok_or(UtxoError::Truncated {
+            offset: start + 1,
+            needed: width,
+        })?;
+    let mut buf = [0u8; 8];
+    buf[..width].copy_from_slice(body);
+    let n = u64::from_le_bytes(buf);
+    if n < min {
+        return Err(UtxoError::NonCanonicalEncoding(format!(
+            "varint {n} at offset {start} encoded in {} bytes",
+            width + 1
+        )));
+    }
+    *pos = start + 1 + width;
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Result<u64, UtxoError> {
+        let mut pos = 0;
+        let n = read_compact_size(bytes, &mut pos)?;
+        assert_eq!(pos, bytes.len());
+        Ok(n)
+    }
+
+    #[test]
+    fn canonical_forms_roundtrip() {
+        for n in [
+            0u64,
+            1,
+            0xfc,
+            0xfd,
+            0xffff,
+            0x1_0000,
+            0xffff_ffff,
+            0x1_0000_0000,
+            u64::MAX,
+        ] {
+            let mut out = Vec::new();
+            write_compact_size(&mut out, n);
+            assert_eq!(out.len(), compact_size_len(n));
+            assert_eq!(decode(&out).unwrap(), n);
+        }
+    }
+
+    #[test]
+    fn non_minimal_encodings_rejected() {
+        let over_long: [&[u8]; 7] = [
+            &[0xfd, 0x01, 0x00],
+            &[0xfd, 0xfc, 0x00],
+            &[0xfe, 0x01, 0x00, 0x00, 0x00],
+            &[0xfe, 0xff, 0xff, 0x00, 0x00],
+            &[0xff, 0x01, 0, 0, 0, 0, 0, 0, 0],
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0],
+            &[0xff, 0, 0, 0, 0, 0, 0, 0, 0],
+        ];
+        for bytes in over_long {
+            assert!(matches!(
+                decode(bytes),
+                Err(UtxoError::NonCanonicalEncoding(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn truncated_varint_rejected() {
+        for bytes in [&[][..], &[0xfd, 0x00], &[0xfe, 0, 0, 1], &[0xff, 0, 0, 0]] {
+            assert!(matches!(decode(bytes), Err(UtxoError::Truncated { .. })));
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Synthetic transaction-apply benchmark behind `tenebriumd bench`.
+//!
+//! Builds a UTXO set and a batch of transactions spending it from a seeded
+//! RNG, then times the per-transaction hot path of block application:
+//! `validate_value_conservation` followed by `apply_tx`.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+use tenebrium_utxo::{InMemoryUtxoSet, OutPoint, Transaction, TxIn, TxOut, UtxoError, UtxoSet};
+
+/// Fee each synthetic transaction leaves for the miner.
+const BENCH_FEE: u64 = 1_000;
+
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub txs: usize,
+    pub inputs: usize,
+    pub elapsed: Duration,
+}
+
+impl BenchReport {
+    pub fn txs_per_sec(&self) -> f64 {
+        self.txs as f64 / self.secs()
+    }
+
+    pub fn lookups_per_sec(&self) -> f64 {
+        self.inputs as f64 / self.secs()
+    }
+
+    fn secs(&self) -> f64 {
+        self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// `txs` transactions of `inputs_per_tx` inputs each, all spending outputs
+/// of the returned set. The same `seed` always yields the same workload.
+pub fn synthetic_workload(
+    txs: usize,
+    inputs_per_tx: usize,
+    seed: u64,
+) -> (InMemoryUtxoSet, Vec<Transaction>) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut utxos = InMemoryUtxoSet::new();
+    let mut batch = Vec::with_capacity(txs);
+    for _ in 0..txs {
+        let mut vin = Vec::with_capacity(inputs_per_tx);
+        let mut total = 0u64;
+        for _ in 0..inputs_per_tx {
+            let outpoint = OutPoint {
+                txid: rng.gen(),
+                vout: rng.gen_range(0..4),
+            };
+            let value = rng.gen_range(10_000..1_000_000);
+            total += value;
+            let script_pubkey: [u8; 32] = rng.gen();
+            utxos.insert(
+                outpoint.clone(),
+                TxOut {
+                    value,
+                    script_pubkey: script_pubkey.to_vec(),
+                },
+            );
+            vin.push(TxIn {
+                prevout: outpoint,
+                script_sig: vec![0u8; 96],
+                sequence: u32::MAX,
+            });
+        }
+        let script_pubkey: [u8; 32] = rng.gen();
+        batch.push(Transaction {
+            version: 1,
+            vin,
+            vout: vec![TxOut {
+                value: total - BENCH_FEE,
+                script_pubkey: script_pubkey.to_vec(),
+            }],
+            lock_time: 0,
+        });
+    }
+    (utxos, batch)
+}
+
+/// Time conservation checks and applies over a fresh synthetic workload.
+/// Generation is not part of the measurement.
+pub fn run_bench(txs: usize, inputs_per_tx: usize, seed: u64) -> Result<BenchReport, UtxoError> {
+    let (mut utxos, batch) = synthetic_workload(txs, inputs_per_tx, seed);
+    let start = Instant::now();
+    for tx in &batch {
+        Transaction::validate_value_conservation(tx, &utxos)?;
+        utxos.apply_tx(tx)?;
+    }
+    Ok(BenchReport {
+        txs,
+        inputs: txs * inputs_per_tx,
+        elapsed: start.elapsed(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_bench_completes_with_throughput() {
+        let report = run_bench(50, 3, 7).unwrap();
+        assert_eq!(report.txs, 50);
+        assert_eq!(report.inputs, 150);
+        assert!(report.txs_per_sec() > 0.0);
+        assert!(report.lookups_per_sec() > report.txs_per_sec());
+
+        // Same seed, same workload.
+        let (a_utxos, a_txs) = synthetic_workload(5, 2, 9);
+        let (b_utxos, b_txs) = synthetic_workload(5, 2, 9);
+        assert_eq!(a_txs, b_txs);
+        assert_eq!(a_utxos.approx_bytes(), b_utxos.approx_bytes());
+        assert_ne!(synthetic_workload(5, 2, 10).1, a_txs);
+    }
+}
@@ -0,0 +1,221 @@
+//! Streaming block validation.
+//!
+//! Transactions are deserialized, checked and applied to the UTXO set one at a
+//! time while the merkle root is accumulated incrementally, so the block itself
+//! is never held in memory (apart from the 32 byte txids kept for the
+//! duplicate check). The UTXO set is the caller's: `submit-block` still loads
+//! its whole UTXO file into memory, so only the block side is bounded. The
+//! rules are those of [`tenebrium_consensus::Block::validate`] plus the shared
+//! [`ContextualChecks`], run per transaction. The merkle root is only known
+//! after the last transaction, so a mismatching block may already have been
+//! partially applied: callers must discard the UTXO set on error.
+
+use serde::de::{DeserializeSeed, Deserializer as _, Error as _, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Read;
+use tenebrium_consensus::{
+    check_pow, BlockHeader, ConsensusError, ContextualChecks, MerkleAccumulator, EMPTY_MERKLE_ROOT,
+};
+use tenebrium_utxo::{Transaction, UtxoError, UtxoSet};
+
+/// Block files larger than this are validated with [`validate_block_stream`]
+pub const STREAM_BLOCK_THRESHOLD_BYTES: u64 = 1_000_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlockStreamError {
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("consensus error: {0}")]
+    Consensus(#[from] ConsensusError),
+}
+
+struct StreamState<'a, U> {
+    utxos: &'a mut U,
+    reward: Option<u64>,
+    no_pow_check: bool,
+    contextual: ContextualChecks,
+    merkle: MerkleAccumulator,
+    seen: HashSet<[u8; 32]>,
+    coinbase_value: u64,
+    total_fees: u64,
+    error: Option<BlockStreamError>,
+}
+
+impl<U: UtxoSet> StreamState<'_, U> {
+    fn accept_header(&mut self, header: &BlockHeader) -> Result<(), ConsensusError> {
+        if header.merkle_root == EMPTY_MERKLE_ROOT {
+            return Err(ConsensusError::EmptyBlock);
+        }
+        if !self.no_pow_check && !check_pow(header)? {
+            return Err(ConsensusError::InvalidPow);
+        }
+        Ok(())
+    }
+
+    fn accept_tx(&mut self, tx: Transaction) -> Result<(), ConsensusError> {
+        let txid = tx.txid_v2()?;
+        if !self.seen.insert(txid) {
+            return Err(ConsensusError::DuplicateTxid(txid));
+        }
+        self.merkle.push(txid);
+        self.contextual.check_tx(&tx)?;
+        if self.merkle.len() == 1 {
+            if !tx.is_coinbase() {
+                return Err(ConsensusError::CoinbaseRequired);
+            }
+            tx.validate_as(true)?;
+            if self.reward.is_some() {
+                self.coinbase_value = Transaction::sum_outputs(&tx)?;
+            }
+            for (op, txout) in Transaction::make_outpoints(&tx)?.into_iter().zip(tx.vout) {
+                if self.utxos.get(&op).is_some() {
+                    return Err(UtxoError::DuplicateOutput(op).into());
+                }
+                self.utxos.insert(op, txout);
+            }
+        } else {
+            tx.validate_as(false)?;
+            if tx.is_coinbase() {
+                return Err(ConsensusError::UnexpectedCoinbase(self.seen.len() - 1));
+            }
+            let fee = Transaction::validate_value_conservation(&tx, &*self.utxos)?;
+            self.total_fees = self.total_fees.saturating_add(fee);
+            self.utxos.apply_tx(&tx)?;
+        }
+        Ok(())
+    }
+
+    /// Stash a validation error so it survives the trip through serde.
+    fn fail<E: serde::de::Error>(&mut self, err: BlockStreamError) -> E {
+        let msg = err.to_string();
+        self.error = Some(err);
+        E::custom(msg)
+    }
+}
+
+struct BlockVisitor<'s, 'a, U> {
+    state: &'s mut StreamState<'a, U>,
+}
+
+impl<'de, U: UtxoSet> Visitor<'de> for BlockVisitor<'_, '_, U> {
+    type Value = BlockHeader;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a block object")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<BlockHeader, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut header = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "header" => {
+                    let value: BlockHeader = map.next_value()?;
+                    if let Err(err) = self.state.accept_header(&value) {
+                        return Err(self.state.fail(err.into()));
+                    }
+                    header = Some(value);
+                }
+                "txs" => map.next_value_seed(TxsSeed {
+                    state: &mut *self.state,
+                })?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        header.ok_or_else(|| A::Error::missing_field("header"))
+    }
+}
+
+struct TxsSeed<'s, 'a, U> {
+    state: &'s mut StreamState<'a, U>,
+}
+
+impl<'de, U: UtxoSet> DeserializeSeed<'de> for TxsSeed<'_, '_, U> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, U: UtxoSet> Visitor<'de> for TxsSeed<'_, '_, U> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a JSON array of transactions")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        while let Some(tx) = seq.next_element::<Transaction>()? {
+            if let Err(err) = self.state.accept_tx(tx) {
+                return Err(self.state.fail(err.into()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Validate a JSON-encoded block read from `reader`, applying its
+/// transactions to `utxos` as they are parsed. Enforces the same rules as
+/// [`tenebrium_consensus::Block::validate`] plus `contextual`, and reports
+/// violations with the same [`ConsensusError`] variants, though not
+/// necessarily the same one when a block breaks several rules: the merkle
+/// root can only be checked at the end. Returns the block header on success.
+pub fn validate_block_stream<R: Read, U: UtxoSet>(
+    reader: R,
+    utxos: &mut U,
+    reward: Option<u64>,
+    no_pow_check: bool,
+    contextual: ContextualChecks,
+) -> Result<BlockHeader, BlockStreamError> {
+    let mut state = StreamState {
+        utxos,
+        reward,
+        no_pow_check,
+        contextual,
+        merkle: MerkleAccumulator::new(),
+        seen: HashSet::new(),
+        coinbase_value: 0,
+        total_fees: 0,
+        error: None,
+    };
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let parsed = de.deserialize_map(BlockVisitor { state: &mut state });
+    if let Some(err) = state.error.take() {
+        return Err(err);
+    }
+    let header = parsed?;
+    de.end()?;
+
+    if state.merkle.is_empty() {
+        return Err(ConsensusError::EmptyBlock.into());
+    }
+    if state.merkle.is_mutated() {
+        return Err(ConsensusError::MutatedMerkleTree.into());
+    }
+    if state.merkle.root() != header.merkle_root {
+        return Err(ConsensusError::MerkleRootMismatch.into());
+    }
+    if let Some(reward) = reward {
+        let limit = reward.saturating_add(state.total_fees);
+        if state.coinbase_value > limit {
+            return Err(ConsensusError::ExcessCoinbase {
+                value: state.coinbase_value,
+                limit,
+            }
+            .into());
+        }
+    }
+    Ok(header)
+}
@@ -1,4 +1,5 @@
-use crate::mempool::{Mempool, MempoolEntry};
+use crate::mempool::Mempool;
+use std::collections::HashSet;
 use tenebrium_consensus::{Block, ConsensusError};
 use tenebrium_utxo::Transaction;
 
@@ -8,6 +9,8 @@ pub enum TemplateError {
     Consensus(#[from] ConsensusError),
     #[error("block size limit exceeded")]
     SizeLimit,
+    #[error("block output limit exceeded")]
+    OutputLimit,
 }
 
 #[derive(Debug, Clone)]
@@ -18,6 +21,11 @@ pub struct BlockTemplate {
     pub total_size: usize,
 }
 
+/// Fill a block on `prev_block_hash` from the mempool. Transactions that
+/// would take the block past `max_block_bytes`, `max_sigops` or
+/// `max_outputs` are left out, so the template passes the node's own
+/// block limits.
+#[allow(clippy::too_many_arguments)]
 pub fn build_block_template(
     mempool: &Mempool,
     coinbase: Transaction,
@@ -26,32 +34,49 @@ pub fn build_block_template(
     bits: u32,
     version: i32,
     max_block_bytes: usize,
+    max_sigops: usize,
+    max_outputs: usize,
 ) -> Result<BlockTemplate, TemplateError> {
     let mut txs = Vec::new();
     let mut total_fees = 0u64;
     let mut total_size = 0usize;
+    let mut total_sigops = 0usize;
+    let mut total_outputs = 0usize;
 
-    let mut entries = mempool.entries();
-    entries.sort_by(|a, b| {
-        let rate_cmp = fee_rate(b)
-            .partial_cmp(&fee_rate(a))
-            .unwrap_or(std::cmp::Ordering::Equal);
-        if rate_cmp == std::cmp::Ordering::Equal {
-            a.txid_v2.cmp(&b.txid_v2)
-        } else {
-            rate_cmp
-        }
-    });
+    // Packages ranked by ancestor fee rate, parents first, so a high-fee
+    // child pulls its low-fee parent in with it.
+    let entries = mempool.entries_in_topological_fee_order();
 
     txs.push(coinbase.clone());
-    total_size += coinbase.canonical_bytes_v2().map_err(ConsensusError::Utxo)?.len();
+    coinbase.validate().map_err(ConsensusError::Utxo)?;
+    total_size += coinbase.vsize();
+    total_outputs += coinbase.vout.len();
+    if total_outputs > max_outputs {
+        return Err(TemplateError::OutputLimit);
+    }
 
+    let mut included = HashSet::new();
     for entry in entries {
-        if total_size + entry.size_bytes > max_block_bytes {
+        let sigops = entry.tx.sigop_count();
+        let outputs = entry.tx.vout.len();
+        if total_size + entry.size_bytes > max_block_bytes
+            || total_sigops + sigops > max_sigops
+            || total_outputs + outputs > max_outputs
+        {
             continue;
         }
+        // A parent left out for a limit takes its in-mempool children with it.
+        let missing_parent = entry.tx.vin.iter().any(|vin| {
+            mempool.contains(&vin.prevout.txid) && !included.contains(&vin.prevout.txid)
+        });
+        if missing_parent {
+            continue;
+        }
+        included.insert(entry.txid_v2);
         total_fees = total_fees.saturating_add(entry.fee);
         total_size += entry.size_bytes;
+        total_sigops += sigops;
+        total_outputs += outputs;
         txs.push(entry.tx.clone());
     }
 
@@ -67,19 +92,31 @@ pub fn build_block_template(
     })
 }
 
-fn fee_rate(entry: &MempoolEntry) -> f64 {
-    if entry.size_bytes == 0 {
-        return 0.0;
-    }
-    entry.fee as f64 / entry.size_bytes as f64
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::mempool::MempoolConfig;
+    use tenebrium_consensus::encode_coinbase_height;
+    use tenebrium_consensus::params::{MAX_BLOCK_OUTPUTS, MAX_BLOCK_SIGOPS};
     use tenebrium_utxo::{InMemoryUtxoSet, OutPoint, TxIn, TxOut, UtxoSet};
 
+    /// Coinbase for a block at height 0, matching the zero prev hash used below.
+    fn make_coinbase(value: u64) -> Transaction {
+        Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint::NULL,
+                script_sig: encode_coinbase_height(0),
+                sequence: u32::MAX,
+            }],
+            vout: vec![TxOut {
+                value,
+                script_pubkey: vec![0],
+            }],
+            lock_time: 0,
+        }
+    }
+
     fn make_tx(prev: OutPoint, value: u64) -> Transaction {
         Transaction {
             version: 1,
@@ -115,15 +152,7 @@ mod tests {
         let mut mempool = Mempool::new(MempoolConfig::default());
         mempool.add_tx(tx.clone(), &utxos).unwrap();
 
-        let coinbase = Transaction {
-            version: 1,
-            vin: vec![],
-            vout: vec![TxOut {
-                value: 500,
-                script_pubkey: vec![0],
-            }],
-            lock_time: 0,
-        };
+        let coinbase = make_coinbase(500);
 
         let template = build_block_template(
             &mempool,
@@ -133,9 +162,184 @@ mod tests {
             0,
             1,
             1_000_000,
+            MAX_BLOCK_SIGOPS,
+            MAX_BLOCK_OUTPUTS,
         )
         .unwrap();
 
         assert_eq!(template.block.txs.len(), 2);
     }
+
+    #[test]
+    fn build_template_includes_spilled_mempool_txs() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = MempoolConfig {
+            max_memory_txs: Some(1),
+            ..MempoolConfig::default()
+        };
+        let mut mempool = Mempool::with_spill(cfg, db.open_tree("mempool_spill").unwrap()).unwrap();
+        let mut utxos = InMemoryUtxoSet::new();
+        for (tag, value) in [(1u8, 1_900), (2, 1_500)] {
+            let outpoint = OutPoint {
+                txid: [tag; 32],
+                vout: 0,
+            };
+            utxos.insert(
+                outpoint.clone(),
+                TxOut {
+                    value: 2_000,
+                    script_pubkey: vec![tag],
+                },
+            );
+            mempool.add_tx(make_tx(outpoint, value), &utxos).unwrap();
+        }
+        assert_eq!(mempool.spilled_len(), 1);
+
+        let coinbase = make_coinbase(500);
+        let template = build_block_template(
+            &mempool,
+            coinbase,
+            [0u8; 32],
+            0,
+            0,
+            1,
+            1_000_000,
+            MAX_BLOCK_SIGOPS,
+            MAX_BLOCK_OUTPUTS,
+        )
+        .unwrap();
+
+        assert_eq!(template.block.txs.len(), 3);
+        assert_eq!(template.total_fees, 600);
+    }
+
+    #[test]
+    fn child_fee_pulls_low_fee_parent_into_block() {
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        let fund = |tag: u8| OutPoint {
+            txid: [tag; 32],
+            vout: 0,
+        };
+        for tag in [1u8, 2] {
+            utxos.insert(
+                fund(tag),
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: vec![tag],
+                },
+            );
+        }
+        // Parent pays 100, child 5_000; an unrelated tx pays 1_000. The
+        // parent's extra output makes it the largest of the three.
+        let mut parent = make_tx(fund(1), 9_300);
+        parent.vout.push(TxOut {
+            value: 600,
+            script_pubkey: vec![7; 64],
+        });
+        let parent_id = parent.txid_v2().unwrap();
+        let child = make_tx(OutPoint { txid: parent_id, vout: 0 }, 4_300);
+        let other = make_tx(fund(2), 9_000);
+        mempool.add_tx(parent.clone(), &utxos).unwrap();
+        mempool.add_tx(child.clone(), &utxos).unwrap();
+        mempool.add_tx(other.clone(), &utxos).unwrap();
+
+        let order: Vec<[u8; 32]> = mempool
+            .entries_in_topological_fee_order()
+            .iter()
+            .map(|entry| entry.txid_v2)
+            .collect();
+        assert_eq!(order, vec![parent_id, child.txid_v2().unwrap(), other.txid_v2().unwrap()]);
+
+        let coinbase = make_coinbase(500);
+        // Room for the package only: it beats the single tx.
+        let limit = coinbase.vsize() + parent.vsize() + child.vsize();
+        let build = |limit: usize| {
+            build_block_template(
+                &mempool,
+                coinbase.clone(),
+                [0u8; 32],
+                0,
+                0,
+                1,
+                limit,
+                MAX_BLOCK_SIGOPS,
+                MAX_BLOCK_OUTPUTS,
+            )
+            .unwrap()
+        };
+        let template = build(limit);
+        assert_eq!(template.block.txs[1..], [parent.clone(), child.clone()]);
+        assert_eq!(template.total_fees, 5_100);
+
+        // Without room for the parent, the child is never included alone.
+        let limit = coinbase.vsize() + child.vsize();
+        let template = build(limit);
+        assert_eq!(template.block.txs[1..], [other]);
+    }
+
+    #[test]
+    fn template_stays_within_sigop_and_output_limits() {
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        let fund = |tag: u8, vout: u32| OutPoint {
+            txid: [tag; 32],
+            vout,
+        };
+        for (tag, vout) in [(1u8, 0u32), (1, 1), (2, 0), (3, 0)] {
+            utxos.insert(
+                fund(tag, vout),
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: vec![tag],
+                },
+            );
+        }
+        // Highest fee first: a two-input tx (2 sigops), a three-output fan
+        // out, then a plain one-in one-out spend.
+        let mut two_inputs = make_tx(fund(1, 0), 15_000);
+        two_inputs.vin.push(TxIn {
+            prevout: fund(1, 1),
+            script_sig: vec![],
+            sequence: 0xffff_ffff,
+        });
+        let mut fan_out = make_tx(fund(2, 0), 3_000);
+        for tag in [7u8, 8] {
+            fan_out.vout.push(TxOut {
+                value: 3_000,
+                script_pubkey: vec![tag],
+            });
+        }
+        let plain = make_tx(fund(3, 0), 9_500);
+        for tx in [&two_inputs, &fan_out, &plain] {
+            mempool.add_tx(tx.clone(), &utxos).unwrap();
+        }
+        let coinbase = make_coinbase(500);
+        let build = |max_sigops: usize, max_outputs: usize| {
+            build_block_template(
+                &mempool,
+                coinbase.clone(),
+                [0u8; 32],
+                0,
+                0,
+                1,
+                1_000_000,
+                max_sigops,
+                max_outputs,
+            )
+        };
+
+        let all = build(MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS).unwrap();
+        assert_eq!(all.block.txs[1..], [two_inputs.clone(), fan_out.clone(), plain.clone()]);
+
+        // Two sigops left after the first tx: only one more single-input tx fits.
+        let template = build(3, MAX_BLOCK_OUTPUTS).unwrap();
+        assert_eq!(template.block.txs[1..], [two_inputs.clone(), fan_out.clone()]);
+
+        // Coinbase and first tx use 2 of 4 outputs: the fan out is skipped.
+        let template = build(MAX_BLOCK_SIGOPS, 4).unwrap();
+        assert_eq!(template.block.txs[1..], [two_inputs, plain]);
+
+        assert!(matches!(build(MAX_BLOCK_SIGOPS, 0), Err(TemplateError::OutputLimit)));
+    }
 }
@@ -0,0 +1,213 @@
+//! BIP37-style Bloom filter used by SPV peers to limit transaction relay.
+//!
+//! Privacy caveat: as with BIP37, a loaded filter tells the serving node which
+//! scripts and outpoints a light client is interested in. False positives only
+//! add weak deniability; a node that sees several filters from the same client
+//! (or one tuned to a low false-positive rate) can link them to its wallet.
+//! Filters are also not updated automatically when a match is found, so clients
+//! must `FilterAdd` new outpoints they want to follow.
+
+use serde::{Deserialize, Serialize};
+use tenebrium_utxo::{OutPoint, Transaction};
+
+/// Maximum filter size in bytes (same bound as BIP37)
+pub const MAX_BLOOM_FILTER_BYTES: usize = 36_000;
+/// Maximum number of hash functions (same bound as BIP37)
+pub const MAX_BLOOM_HASH_FUNCS: u32 = 50;
+/// Maximum size of a single `FilterAdd` element
+pub const MAX_FILTER_ADD_BYTES: usize = 520;
+
+const SEED_MULTIPLIER: u32 = 0xFBA4_C795;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BloomError {
+    #[error("bloom filter empty")]
+    Empty,
+    #[error("bloom filter too large: {0} bytes (max {1})")]
+    TooLarge(usize, usize),
+    #[error("bloom hash function count invalid: {0} (max {1})")]
+    InvalidHashFuncs(u32, u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    pub data: Vec<u8>,
+    pub hash_funcs: u32,
+    pub tweak: u32,
+}
+
+impl BloomFilter {
+    /// Size a filter for `elements` items at the given false-positive rate,
+    /// clamped to the protocol limits.
+    #[allow(dead_code)]
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let elements = elements.max(1) as f64;
+        let bits = -1.0 / (ln2 * ln2) * elements * fp_rate.ln();
+        let bytes = ((bits / 8.0) as usize).clamp(1, MAX_BLOOM_FILTER_BYTES);
+        let hash_funcs = ((bytes * 8) as f64 / elements * ln2) as u32;
+        Self {
+            data: vec![0u8; bytes],
+            hash_funcs: hash_funcs.clamp(1, MAX_BLOOM_HASH_FUNCS),
+            tweak,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), BloomError> {
+        if self.data.is_empty() {
+            return Err(BloomError::Empty);
+        }
+        if self.data.len() > MAX_BLOOM_FILTER_BYTES {
+            return Err(BloomError::TooLarge(self.data.len(), MAX_BLOOM_FILTER_BYTES));
+        }
+        if self.hash_funcs == 0 || self.hash_funcs > MAX_BLOOM_HASH_FUNCS {
+            return Err(BloomError::InvalidHashFuncs(
+                self.hash_funcs,
+                MAX_BLOOM_HASH_FUNCS,
+            ));
+        }
+        Ok(())
+    }
+
+    fn bit_index(&self, n: u32, item: &[u8]) -> usize {
+        let seed = n.wrapping_mul(SEED_MULTIPLIER).wrapping_add(self.tweak);
+        murmur3_32(seed, item) as usize % (self.data.len() * 8)
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        for n in 0..self.hash_funcs {
+            let idx = self.bit_index(n, item);
+            self.data[idx >> 3] |= 1 << (idx & 7);
+        }
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        if self.data.is_empty() {
+            return false;
+        }
+        (0..self.hash_funcs).all(|n| {
+            let idx = self.bit_index(n, item);
+            self.data[idx >> 3] & (1 << (idx & 7)) != 0
+        })
+    }
+
+    /// A transaction matches when its txid, any output script, or any spent
+    /// outpoint is in the filter.
+    pub fn matches_tx(&self, tx: &Transaction, txid: &[u8; 32]) -> bool {
+        if self.contains(txid) {
+            return true;
+        }
+        if tx
+            .vout
+            .iter()
+            .any(|out| !out.script_pubkey.is_empty() && self.contains(&out.script_pubkey))
+        {
+            return true;
+        }
+        tx.vin
+            .iter()
+            .any(|vin| self.contains(&outpoint_key(&vin.prevout)))
+    }
+}
+
+/// Filter element for an outpoint: txid followed by little-endian vout.
+pub fn outpoint_key(outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = Vec::with_capacity(36);
+    key.extend_from_slice(&outpoint.txid);
+    key.extend_from_slice(&outpoint.vout.to_le_bytes());
+    key
+}
+
+fn murmur3_32(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, byte) in tail.iter().enumerate() {
+            k ^= (*byte as u32) << (8 * i);
+        }
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+    }
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tenebrium_utxo::{TxIn, TxOut};
+
+    #[test]
+    fn murmur3_bip37_vectors() {
+        assert_eq!(murmur3_32(0x0000_0000, b""), 0x0000_0000);
+        assert_eq!(murmur3_32(0xFBA4_C795, b""), 0x6a39_6f08);
+        assert_eq!(murmur3_32(0x0000_0000, &[0x00]), 0x514e_28b7);
+        assert_eq!(murmur3_32(0xFBA4_C795, &[0x00]), 0xea3f_0b17);
+        assert_eq!(murmur3_32(0x0000_0000, &[0x00, 0x11]), 0x16c6_b7ab);
+        assert_eq!(murmur3_32(0x0000_0000, &[0x00, 0x11, 0x22, 0x33]), 0xb447_1bf8);
+    }
+
+    #[test]
+    fn filter_matches_script_and_outpoint() {
+        let spent = OutPoint {
+            txid: [3u8; 32],
+            vout: 1,
+        };
+        let tx = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: spent.clone(),
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 1,
+                script_pubkey: vec![0xaa; 20],
+            }],
+            lock_time: 0,
+        };
+        let txid = tx.txid_v2().unwrap();
+
+        let mut by_script = BloomFilter::new(10, 0.0001, 7);
+        assert!(!by_script.matches_tx(&tx, &txid));
+        by_script.insert(&[0xaa; 20]);
+        assert!(by_script.matches_tx(&tx, &txid));
+
+        let mut by_outpoint = BloomFilter::new(10, 0.0001, 7);
+        by_outpoint.insert(&outpoint_key(&spent));
+        assert!(by_outpoint.matches_tx(&tx, &txid));
+    }
+
+    #[test]
+    fn filter_size_is_bounded() {
+        let filter = BloomFilter::new(10_000_000, 0.000_001, 0);
+        assert!(filter.data.len() <= MAX_BLOOM_FILTER_BYTES);
+        assert!(filter.hash_funcs <= MAX_BLOOM_HASH_FUNCS);
+        filter.validate().unwrap();
+
+        let oversized = BloomFilter {
+            data: vec![0u8; MAX_BLOOM_FILTER_BYTES + 1],
+            hash_funcs: 1,
+            tweak: 0,
+        };
+        assert!(oversized.validate().is_err());
+    }
+}
@@ -1,6 +1,10 @@
+pub mod block_stream;
+pub mod bloom;
 pub mod mempool;
 pub mod p2p;
+pub mod rpc;
 pub mod utxo_db;
+pub mod work;
 
 use clap::ValueEnum;
 
@@ -1,7 +1,12 @@
+mod bench;
+mod block_stream;
 mod block_template;
+mod bloom;
 mod mempool;
 mod p2p;
+mod rpc;
 mod utxo_db;
+mod work;
 
 use clap::{Parser, Subcommand, ValueEnum};
 use serde::{de::SeqAccess, de::Visitor, Deserialize, Serialize};
@@ -11,16 +16,29 @@ use std::fmt;
 use std::fs;
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tenebriumd::LogLevel;
+use block_stream::{validate_block_stream, BlockStreamError, STREAM_BLOCK_THRESHOLD_BYTES};
 use block_template::build_block_template;
 use mempool::{Mempool, MempoolConfig};
-use tenebrium_consensus::{check_pow, merkle_root, mine_header};
+use tenebrium_consensus::params::{MAX_BLOCK_OUTPUTS, MAX_BLOCK_SIGOPS, TARGET_BLOCK_TIME_SECS};
+use tenebrium_consensus::{
+    difficulty, encode_coinbase_height, header_hash, mine_header_parallel, mine_header_with_progress, network_hashrate,
+    roll_version,
+    ConsensusError, ContextualChecks,
+};
 use tenebrium_utxo::{
-    map_outpoints_v1_to_v2, OutPoint, ReindexErrorEntry, ReindexErrorKind, ReindexReport,
-    Transaction, UtxoError, InMemoryUtxoSet, UtxoSet,
+    check_vout_coverage, map_outpoints_v1_to_v2, OutPoint, ReindexErrorEntry, ReindexErrorKind, ReindexReport,
+    BTreeMapUtxoSet, Transaction, TxIn, UtxoError, InMemoryUtxoSet, UtxoSet, txid_from_display_hex, txid_to_display_hex,
 };
 use utxo_db::{jsonl_reader, KvUtxoStore, UtxoDbError, UtxoEntry, UtxoReader, UtxoStore};
+use work::Work;
+
+/// Default `--dbcache` budget in MiB
+const DEFAULT_DBCACHE_MB: usize = 64;
+/// Fewest blocks `prune` keeps below the tip, as reorgs need their bodies
+const MIN_PRUNE_KEEP: u32 = 288;
 
 #[derive(Debug, Parser)]
 #[command(name = "tenebriumd", version, about = "Tenebrium node daemon")]
@@ -69,12 +87,22 @@ enum Command {
         /// Dry run (no writes)
         #[arg(long = "dry-run")]
         dry_run: bool,
+        /// Suppress the settings summary and progress output
+        #[arg(long)]
+        quiet: bool,
     },
     /// Mine a block template locally (PoW)
     Mine {
-        /// Previous block hash (hex, 32 bytes)
+        /// Previous block hash (hex, 32 bytes, display byte order)
         #[arg(long)]
         prev_hash: String,
+        /// Height of the mined block, committed in its coinbase. Derived from
+        /// --data-dir when omitted (0 for an all-zero --prev-hash).
+        #[arg(long, conflicts_with = "data_dir")]
+        height: Option<u32>,
+        /// Data directory whose chain.sled knows --prev-hash's height
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
         /// Compact bits
         #[arg(long)]
         bits: u32,
@@ -84,9 +112,12 @@ enum Command {
         /// Coinbase reward value
         #[arg(long)]
         reward: u64,
-        /// Coinbase script_pubkey hex
+        /// Coinbase script_pubkey hex (single output paying the reward)
+        #[arg(long, conflicts_with = "coinbase_out", required_unless_present = "coinbase_out")]
+        coinbase_script: Option<String>,
+        /// Coinbase output as `script_hex:value` (repeatable, splits the payout)
         #[arg(long)]
-        coinbase_script: String,
+        coinbase_out: Vec<String>,
         /// Max block size in bytes
         #[arg(long, default_value_t = 1_000_000)]
         max_block_bytes: usize,
@@ -99,6 +130,10 @@ enum Command {
         /// Max nonce iterations
         #[arg(long, default_value_t = 5_000_000)]
         max_nonce: u32,
+        /// Mining threads; above 1 the search also rolls header version bits
+        /// and runs until solved, ignoring --max-nonce
+        #[arg(long, default_value_t = 1)]
+        threads: usize,
         /// Output block JSON path
         #[arg(long)]
         out: Option<PathBuf>,
@@ -120,6 +155,16 @@ enum Command {
         /// Skip PoW check
         #[arg(long)]
         no_pow_check: bool,
+        /// Height of the block, which its coinbase must commit to
+        #[arg(long)]
+        height: u32,
+        /// Median-time-past of the parent block, which time-locked
+        /// transactions are checked against
+        #[arg(long, default_value_t = 0)]
+        mtp: u32,
+        /// Network id (mainnet/testnet/devnet), for the block limits
+        #[arg(long, default_value = "mainnet")]
+        network: String,
     },
     /// Run a basic P2P node
     P2p {
@@ -147,6 +192,55 @@ enum Command {
         /// Emit periodic node stats (seconds, 0=disabled)
         #[arg(long, default_value_t = 0)]
         stats_interval: u64,
+        /// Write a JSON metrics snapshot to this path every stats interval
+        #[arg(long)]
+        export_metrics_file: Option<PathBuf>,
+        /// Warn when the in-memory UTXO set exceeds this many MiB (0=disabled)
+        #[arg(long, default_value_t = 1024)]
+        utxo_warn_mb: usize,
+        /// Relay blocks only: ignore and never request loose transactions
+        #[arg(long)]
+        blocks_only: bool,
+        /// Keep at most this many mempool txs in memory, spilling the
+        /// lowest fee-rate overflow to the data dir (requires --data-dir)
+        #[arg(long)]
+        mempool_memory_txs: Option<usize>,
+        /// Quarantine unreadable block files at startup and re-download them
+        #[arg(long)]
+        quarantine_corrupt_blocks: bool,
+        /// Cache budget in MiB for in-memory blocks and batched disk writes.
+        /// Larger values flush less often during sync but leave more blocks
+        /// to replay at startup after a crash (0 = flush every block)
+        #[arg(long, default_value_t = DEFAULT_DBCACHE_MB)]
+        dbcache: usize,
+        /// Node id announced to peers. Defaults to a random id persisted in
+        /// --data-dir (or a fresh one per run without a data dir)
+        #[arg(long)]
+        node_id: Option<String>,
+        /// Rebuild the UTXO set by replaying stored blocks, keeping the
+        /// header index (requires --data-dir)
+        #[arg(long)]
+        reindex_chainstate: bool,
+        /// After a reorg, let re-added transactions replace conflicting
+        /// mempool entries that pay a lower fee
+        #[arg(long)]
+        prune_mempool_on_reorg: bool,
+        /// Seconds to wait for a peer message before dropping the peer
+        #[arg(long, default_value_t = 30)]
+        read_timeout: u64,
+        /// Seconds a write to a peer may block
+        #[arg(long, default_value_t = 30)]
+        write_timeout: u64,
+        /// Seconds to wait for an outbound connection to be established
+        #[arg(long, default_value_t = 10)]
+        connect_timeout: u64,
+        /// Report the node as stale in stats and metrics while its tip block
+        /// is older than this many seconds (it keeps syncing)
+        #[arg(long)]
+        max_tip_age: Option<u64>,
+        /// Also serve JSON-RPC on this address (host:port)
+        #[arg(long)]
+        rpc_listen: Option<String>,
         /// Log level
         #[arg(long, value_enum, default_value_t = LogLevel::Info)]
         log_level: LogLevel,
@@ -157,6 +251,36 @@ enum Command {
         #[arg(long, value_enum, default_value_t = TxidVersion::V2)]
         txid_version: TxidVersion,
     },
+    /// Run a P2P node with a JSON-RPC 2.0 server over HTTP
+    Rpc {
+        /// JSON-RPC listen address (host:port)
+        #[arg(long, default_value = "127.0.0.1:8332")]
+        rpc_listen: String,
+        /// P2P listen address (host:port)
+        #[arg(long, default_value = "0.0.0.0:8333")]
+        listen: String,
+        /// Peer addresses to connect (repeatable)
+        #[arg(long)]
+        peer: Vec<String>,
+        /// Optional UTXO JSONL for tx/block validation
+        #[arg(long)]
+        utxo: Option<PathBuf>,
+        /// Skip PoW check for incoming blocks
+        #[arg(long)]
+        no_pow_check: bool,
+        /// Network id (mainnet/testnet/devnet)
+        #[arg(long, default_value = "mainnet")]
+        network: String,
+        /// Optional data directory for on-disk persistence
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Log level
+        #[arg(long, value_enum, default_value_t = LogLevel::Info)]
+        log_level: LogLevel,
+        /// Optional log file path
+        #[arg(long)]
+        log_file: Option<PathBuf>,
+    },
     /// Migrate on-disk sled schema
     DbMigrate {
         /// Data directory containing chain.sled
@@ -190,6 +314,78 @@ enum Command {
         #[arg(long)]
         force: bool,
     },
+    /// Rewrite the sled DB into a fresh one to reclaim space from deletions
+    DbCompact {
+        /// Data directory containing chain.sled
+        #[arg(long)]
+        data_dir: PathBuf,
+    },
+    /// Rewrite stored JSON from array-encoded hashes to hex strings
+    MigrateJson {
+        /// Data directory to migrate in place
+        #[arg(long)]
+        data_dir: PathBuf,
+    },
+    /// Print a block file's header fields, difficulty and implied hash rate
+    BlockInfo {
+        /// Block JSON path
+        #[arg(long)]
+        block: PathBuf,
+    },
+    /// Print a one-shot summary of a data directory without starting the node
+    Status {
+        /// Data directory containing chain.sled
+        #[arg(long)]
+        data_dir: PathBuf,
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the stored best-chain block at a height as JSON
+    GetBlock {
+        /// Data directory containing chain.sled
+        #[arg(long)]
+        data_dir: PathBuf,
+        /// Block height on the best chain
+        #[arg(long)]
+        height: u32,
+    },
+    /// Delete stored block bodies far below the tip, keeping headers and UTXOs
+    Prune {
+        /// Data directory containing chain.sled (the node must be stopped)
+        #[arg(long)]
+        data_dir: PathBuf,
+        /// Blocks below the tip whose bodies are kept
+        #[arg(long, default_value_t = MIN_PRUNE_KEEP)]
+        keep: u32,
+        /// Network id (mainnet/testnet/devnet)
+        #[arg(long, default_value = "mainnet")]
+        network: String,
+    },
+    /// Store blocks from a JSONL export; they are connected at the next start
+    ImportBlocks {
+        /// Data directory containing chain.sled (the node must be stopped)
+        #[arg(long)]
+        data_dir: PathBuf,
+        /// Block JSONL (one block per line, each extending a known header)
+        #[arg(long)]
+        blocks: PathBuf,
+        /// Network id (mainnet/testnet/devnet)
+        #[arg(long, default_value = "mainnet")]
+        network: String,
+    },
+    /// Time transaction validation and apply on a synthetic UTXO set
+    Bench {
+        /// Number of transactions to apply
+        #[arg(long, default_value_t = 10_000)]
+        txs: usize,
+        /// Inputs per transaction
+        #[arg(long, default_value_t = 2)]
+        inputs_per_tx: usize,
+        /// RNG seed for the generated workload
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
     /// Restore on-disk sled DB from backup
     DbRestore {
         /// Backup directory containing chain.sled
@@ -233,10 +429,20 @@ enum ReindexError {
     Sled(#[from] sled::Error),
     #[error("invalid arguments: {0}")]
     InvalidArgs(String),
+    #[error("consensus error: {0}")]
+    Consensus(#[from] ConsensusError),
     #[error("mining error: {0}")]
     Mining(String),
 }
 
+impl From<BlockStreamError> for ReindexError {
+    fn from(err: BlockStreamError) -> Self {
+        match err {
+            BlockStreamError::Json(e) => ReindexError::Json(e),
+            BlockStreamError::Consensus(e) => ReindexError::Consensus(e),
+        }
+    }
+}
 
 fn main() {
     if let Err(err) = run() {
@@ -262,6 +468,7 @@ fn run() -> Result<(), ReindexError> {
             verify_read,
             resume,
             dry_run,
+            quiet,
         }) => utxo_reindex(
             db,
             db_format,
@@ -275,28 +482,37 @@ fn run() -> Result<(), ReindexError> {
             verify_read,
             resume,
             dry_run,
+            quiet,
         ),
         Some(Command::Mine {
             prev_hash,
+            height,
+            data_dir,
             bits,
             time,
             reward,
             coinbase_script,
+            coinbase_out,
             max_block_bytes,
             utxo,
             txs,
             max_nonce,
+            threads,
             out,
         }) => mine_block(
             prev_hash,
+            height,
+            data_dir,
             bits,
             time,
             reward,
             coinbase_script,
+            coinbase_out,
             max_block_bytes,
             utxo,
             txs,
             max_nonce,
+            threads,
             out,
         ),
         Some(Command::SubmitBlock {
@@ -305,7 +521,15 @@ fn run() -> Result<(), ReindexError> {
             out,
             reward,
             no_pow_check,
-        }) => submit_block(block, utxo, out, reward, no_pow_check),
+            height,
+            mtp,
+            network,
+        }) => {
+            let params = tenebrium_consensus::NetworkParams::for_network(&network);
+            let contextual =
+                ContextualChecks::new(height, mtp, params.max_block_sigops, params.max_block_outputs);
+            submit_block(block, utxo, out, reward, no_pow_check, contextual)
+        }
         Some(Command::P2p {
             listen,
             peer,
@@ -315,10 +539,51 @@ fn run() -> Result<(), ReindexError> {
             network,
             data_dir,
             stats_interval,
+            export_metrics_file,
+            utxo_warn_mb,
+            blocks_only,
+            mempool_memory_txs,
+            quarantine_corrupt_blocks,
+            dbcache,
+            node_id,
+            reindex_chainstate,
+            prune_mempool_on_reorg,
+            read_timeout,
+            write_timeout,
+            connect_timeout,
+            max_tip_age,
+            rpc_listen,
             log_level,
             log_file,
             txid_version,
         }) => {
+            if export_metrics_file.is_some() && stats_interval == 0 {
+                return Err(ReindexError::InvalidArgs(
+                    "--export-metrics-file requires --stats-interval > 0".to_string(),
+                ));
+            }
+            if mempool_memory_txs.is_some() && data_dir.is_none() {
+                return Err(ReindexError::InvalidArgs(
+                    "--mempool-memory-txs requires --data-dir".to_string(),
+                ));
+            }
+            for (flag, secs) in [
+                ("--read-timeout", read_timeout),
+                ("--write-timeout", write_timeout),
+                ("--connect-timeout", connect_timeout),
+            ] {
+                if secs == 0 {
+                    return Err(ReindexError::InvalidArgs(format!("{flag} must be positive")));
+                }
+            }
+            if max_tip_age == Some(0) {
+                return Err(ReindexError::InvalidArgs("--max-tip-age must be positive".to_string()));
+            }
+            if reindex_chainstate && data_dir.is_none() {
+                return Err(ReindexError::InvalidArgs(
+                    "--reindex-chainstate requires --data-dir".to_string(),
+                ));
+            }
             let mut peers = peer;
             if let Some(path) = seed_file {
                 peers.extend(load_seed_file(&path)?);
@@ -334,9 +599,57 @@ fn run() -> Result<(), ReindexError> {
             log_level,
             log_file,
             txid_version.as_u8(),
+            export_metrics_file,
+            utxo_warn_mb.saturating_mul(1024 * 1024),
+            p2p::P2pOptions {
+                blocks_only,
+                mempool_memory_txs,
+                quarantine_corrupt_blocks,
+                dbcache_mb: dbcache,
+                node_id,
+                reindex_chainstate,
+                prune_mempool_on_reorg,
+                timeouts: p2p::PeerTimeouts {
+                    read: Duration::from_secs(read_timeout),
+                    write: Duration::from_secs(write_timeout),
+                    connect: Duration::from_secs(connect_timeout),
+                },
+                max_tip_age: max_tip_age.map(Duration::from_secs),
+                rpc_listen,
+            },
         )
             .map_err(|e| ReindexError::Mining(e.to_string()))
         }
+        Some(Command::Rpc {
+            rpc_listen,
+            listen,
+            peer,
+            utxo,
+            no_pow_check,
+            network,
+            data_dir,
+            log_level,
+            log_file,
+        }) => p2p::run_p2p(
+            listen,
+            peer,
+            utxo,
+            no_pow_check,
+            network,
+            data_dir,
+            0,
+            log_level,
+            log_file,
+            TxidVersion::V2.as_u8(),
+            None,
+            0,
+            p2p::P2pOptions {
+                dbcache_mb: DEFAULT_DBCACHE_MB,
+                rpc_listen: Some(rpc_listen),
+                ..p2p::P2pOptions::default()
+            },
+        )
+        .map_err(|e| ReindexError::Mining(e.to_string())),
         Some(Command::DbMigrate {
             data_dir,
             target,
@@ -350,6 +663,82 @@ fn run() -> Result<(), ReindexError> {
             out_dir,
             force,
         }) => db_backup(data_dir, out_dir, force),
+        Some(Command::DbCompact { data_dir }) => {
+            let (before, after) = db_compact(&data_dir)?;
+            println!("compacted {}: {before} -> {after} bytes", data_dir.join("chain.sled").display());
+            Ok(())
+        }
+        Some(Command::MigrateJson { data_dir }) => {
+            let report = migrate_json(&data_dir)?;
+            println!(
+                "migrated {} files and {} sled values to hex hashes ({} already hex)",
+                report.files, report.values, report.already_hex
+            );
+            Ok(())
+        }
+        Some(Command::BlockInfo { block }) => block_info(&block),
+        Some(Command::Status { data_dir, json }) => print_status(&data_dir, json),
+        Some(Command::GetBlock { data_dir, height }) => {
+            let block = get_block(&data_dir, height)?;
+            println!("{}", serde_json::to_string_pretty(&block)?);
+            Ok(())
+        }
+        Some(Command::Prune {
+            data_dir,
+            keep,
+            network,
+        }) => {
+            if keep < MIN_PRUNE_KEEP {
+                return Err(ReindexError::InvalidArgs(format!(
+                    "--keep must be at least {MIN_PRUNE_KEEP} so reorgs can still be followed"
+                )));
+            }
+            let report = p2p::prune_data_dir(&data_dir, &network, keep)
+                .map_err(|e| ReindexError::Mining(e.to_string()))?;
+            println!(
+                "pruned {} block bodies; blocks below height {} are no longer stored",
+                report.removed, report.pruned_below
+            );
+            Ok(())
+        }
+        Some(Command::ImportBlocks {
+            data_dir,
+            blocks,
+            network,
+        }) => {
+            let mut parsed = Vec::new();
+            for line in BufReader::new(fs::File::open(&blocks)?).lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    parsed.push(serde_json::from_str::<tenebrium_consensus::Block>(&line)?);
+                }
+            }
+            let height = p2p::import_blocks(&data_dir, &network, &parsed)
+                .map_err(|e| ReindexError::Mining(e.to_string()))?;
+            println!("imported {} blocks; header tip at height {height}", parsed.len());
+            Ok(())
+        }
+        Some(Command::Bench {
+            txs,
+            inputs_per_tx,
+            seed,
+        }) => {
+            if txs == 0 || inputs_per_tx == 0 {
+                return Err(ReindexError::InvalidArgs(
+                    "--txs and --inputs-per-tx must be positive".to_string(),
+                ));
+            }
+            let report = bench::run_bench(txs, inputs_per_tx, seed)?;
+            println!(
+                "bench: {} txs, {} inputs in {:.3}s: {:.0} tx/s, {:.0} input lookups/s",
+                report.txs,
+                report.inputs,
+                report.elapsed.as_secs_f64(),
+                report.txs_per_sec(),
+                report.lookups_per_sec()
+            );
+            Ok(())
+        }
         Some(Command::DbRestore {
             backup_dir,
             data_dir,
@@ -362,6 +751,29 @@ fn run() -> Result<(), ReindexError> {
     }
 }
 
+fn block_info(block_path: &Path) -> Result<(), ReindexError> {
+    let block_json = fs::read_to_string(block_path)?;
+    let block: tenebrium_consensus::Block = serde_json::from_str(&block_json)?;
+    let header = &block.header;
+    let difficulty = difficulty(header.bits);
+    println!("hash: {}", hex::encode(header_hash(header)));
+    println!("prev: {}", hex::encode(header.prev_block_hash));
+    println!("time: {}", header.time);
+    println!("bits: {:#010x}", header.bits);
+    println!("difficulty: {difficulty:.4}");
+    println!(
+        "hashrate: {:.2} H/s (at {}s blocks)",
+        network_hashrate(difficulty, TARGET_BLOCK_TIME_SECS),
+        TARGET_BLOCK_TIME_SECS
+    );
+    println!("txs: {}", block.txs.len());
+    match block.coinbase_value() {
+        Ok(value) => println!("coinbase_value: {value}"),
+        Err(err) => println!("coinbase_value: n/a ({err})"),
+    }
+    Ok(())
+}
+
 fn db_migrate(
     data_dir: PathBuf,
     target: u32,
@@ -380,6 +792,7 @@ fn db_migrate(
             "--dry-run cannot be combined with --backup".to_string(),
         ));
     }
+    p2p::recover_interrupted_compact(&data_dir)?;
     let db_path = data_dir.join("chain.sled");
     if backup {
         let backup_path = data_dir.join("chain.sled.bak");
@@ -448,17 +861,34 @@ fn db_migrate(
                 .map_err(ReindexError::from)?;
         }
         meta.flush().map_err(ReindexError::from)?;
-        validate_schema(&db)?;
+        validate_schema_at(&db, 2)?;
         println!("migrated schema 1 -> 2");
         return Ok(());
     }
 
+    if current == 2 && target == 3 {
+        validate_schema_at(&db, 2)?;
+        if dry_run {
+            print_dry_run_summary(&db, json)?;
+            println!("dry-run: can migrate schema 2 -> 3");
+            return Ok(());
+        }
+        let rewritten = migrate_work_to_u256(&db)?;
+        meta.insert("schema_version", target.to_le_bytes().to_vec())
+            .map_err(ReindexError::from)?;
+        meta.flush().map_err(ReindexError::from)?;
+        validate_schema(&db)?;
+        println!("migrated schema 2 -> 3 ({rewritten} work entries)");
+        return Ok(());
+    }
+
     Err(ReindexError::InvalidArgs(
         "unsupported migration path".to_string(),
     ))
 }
 
 fn db_backup(data_dir: PathBuf, out_dir: PathBuf, force: bool) -> Result<(), ReindexError> {
+    p2p::recover_interrupted_compact(&data_dir)?;
     let src = data_dir.join("chain.sled");
     let dst = out_dir.join("chain.sled");
     if dst.exists() {
@@ -492,7 +922,59 @@ fn db_restore(backup_dir: PathBuf, data_dir: PathBuf, force: bool) -> Result<(),
     Ok(())
 }
 
+/// Schema 2 stored 16-byte work values from a formula that saturated at
+/// ordinary difficulties, so they are recomputed from the headers rather
+/// than widened. Headers whose parent is unknown (genesis) start at zero.
+fn migrate_work_to_u256(db: &sled::Db) -> Result<usize, ReindexError> {
+    let headers = db.open_tree("headers").map_err(ReindexError::from)?;
+    let heights = db.open_tree("heights").map_err(ReindexError::from)?;
+    let mut by_height = Vec::new();
+    for entry in headers.iter() {
+        let (key, value) = entry.map_err(ReindexError::from)?;
+        let hash = <[u8; 32]>::try_from(key.as_ref())
+            .map_err(|_| ReindexError::InvalidArgs("malformed header key".to_string()))?;
+        let header: tenebrium_consensus::BlockHeader = serde_json::from_slice(&value)?;
+        let height = heights
+            .get(hash)
+            .map_err(ReindexError::from)?
+            .and_then(|v| <[u8; 4]>::try_from(v.as_ref()).ok())
+            .map(u32::from_le_bytes)
+            .ok_or_else(|| {
+                ReindexError::InvalidArgs(format!("header {} has no height", hex::encode(hash)))
+            })?;
+        by_height.push((height, hash, header));
+    }
+    by_height.sort_by_key(|(height, _, _)| *height);
+
+    let mut work: HashMap<[u8; 32], Work> = HashMap::new();
+    for (_, hash, header) in &by_height {
+        let total = match work.get(&header.prev_block_hash) {
+            Some(prev) => {
+                let own = Work::from_bits(header.bits).ok_or_else(|| {
+                    ReindexError::InvalidArgs(format!("header {} has invalid bits", hex::encode(hash)))
+                })?;
+                prev.saturating_add(own)
+            }
+            None => Work::ZERO,
+        };
+        work.insert(*hash, total);
+    }
+
+    let work_tree = db.open_tree("work").map_err(ReindexError::from)?;
+    for (hash, total) in &work {
+        work_tree
+            .insert(hash, total.to_le_bytes().to_vec())
+            .map_err(ReindexError::from)?;
+    }
+    work_tree.flush().map_err(ReindexError::from)?;
+    Ok(work.len())
+}
+
 fn validate_schema(db: &sled::Db) -> Result<(), ReindexError> {
+    validate_schema_at(db, p2p::DB_SCHEMA_VERSION)
+}
+
+fn validate_schema_at(db: &sled::Db, expected: u32) -> Result<(), ReindexError> {
     let meta = db.open_tree("meta").map_err(ReindexError::from)?;
     let schema = meta
         .get("schema_version")
@@ -504,9 +986,9 @@ fn validate_schema(db: &sled::Db) -> Result<(), ReindexError> {
         ));
     }
     let ver = u32::from_le_bytes([schema[0], schema[1], schema[2], schema[3]]);
-    if ver != 2 {
+    if ver != expected {
         return Err(ReindexError::InvalidArgs(
-            format!("schema_version mismatch: expected 2, got {ver}"),
+            format!("schema_version mismatch: expected {expected}, got {ver}"),
         ));
     }
     let network_id = meta.get("network_id").map_err(ReindexError::from)?;
@@ -555,8 +1037,7 @@ fn check_utxo_block_sample(db: &sled::Db) -> Result<(), ReindexError> {
     for entry in blocks.iter() {
         let (_, value) = entry.map_err(ReindexError::from)?;
         let block: tenebrium_consensus::Block = serde_json::from_slice(&value)?;
-        for tx in block.txs.iter() {
-            let txid = tx.txid_v2()?;
+        for txid in block.txids()? {
             remaining.remove(&txid);
             if remaining.is_empty() {
                 break;
@@ -571,7 +1052,7 @@ fn check_utxo_block_sample(db: &sled::Db) -> Result<(), ReindexError> {
         let missing = remaining
             .iter()
             .next()
-            .map(|txid| hex::encode(txid))
+            .map(hex::encode)
             .unwrap_or_else(|| "<unknown>".to_string());
         return Err(ReindexError::InvalidArgs(format!(
             "sample utxo txid not found in blocks: {missing}"
@@ -593,7 +1074,7 @@ struct DryRunSummary {
     utxo_count: Option<u64>,
 }
 
-fn print_dry_run_summary(db: &sled::Db, json: bool) -> Result<(), ReindexError> {
+fn dry_run_summary(db: &sled::Db) -> Result<DryRunSummary, ReindexError> {
     let meta = db.open_tree("meta").map_err(ReindexError::from)?;
     let schema = meta
         .get("schema_version")
@@ -630,7 +1111,7 @@ fn print_dry_run_summary(db: &sled::Db, json: bool) -> Result<(), ReindexError>
     let utxo = db.open_tree("utxo").map_err(ReindexError::from)?;
     let blocks = db.open_tree("blocks").map_err(ReindexError::from)?;
 
-    let summary = DryRunSummary {
+    Ok(DryRunSummary {
         schema_version: schema,
         network_id,
         meta_keys: meta.len() as u64,
@@ -640,15 +1121,10 @@ fn print_dry_run_summary(db: &sled::Db, json: bool) -> Result<(), ReindexError>
         utxo: utxo.len() as u64,
         blocks: blocks.len() as u64,
         utxo_count,
-    };
-
-    if json {
-        let payload = serde_json::to_string(&summary)?;
-        println!("{payload}");
-        return Ok(());
-    }
+    })
+}
 
-    println!("dry-run summary:");
+fn print_summary_fields(summary: &DryRunSummary) {
     println!("  schema_version: {}", summary.schema_version);
     println!("  network_id: {}", summary.network_id);
     println!("  meta_keys: {}", summary.meta_keys);
@@ -660,84 +1136,453 @@ fn print_dry_run_summary(db: &sled::Db, json: bool) -> Result<(), ReindexError>
     if let Some(count) = summary.utxo_count {
         println!("  utxo_count: {count}");
     }
-    Ok(())
 }
 
-fn copy_dir_recursive(src: &PathBuf, dst: &PathBuf) -> Result<(), ReindexError> {
-    if !src.exists() {
-        return Err(ReindexError::InvalidArgs("source does not exist".to_string()));
-    }
-    for entry in std::fs::read_dir(src).map_err(ReindexError::from)? {
-        let entry = entry.map_err(ReindexError::from)?;
-        let file_type = entry.file_type().map_err(ReindexError::from)?;
-        let src_path = entry.path();
-        let dst_path = dst.join(entry.file_name());
-        if file_type.is_dir() {
-            std::fs::create_dir_all(&dst_path).map_err(ReindexError::from)?;
-            copy_dir_recursive(&src_path, &dst_path)?;
-        } else if file_type.is_file() {
-            std::fs::copy(&src_path, &dst_path).map_err(ReindexError::from)?;
-        }
+fn print_dry_run_summary(db: &sled::Db, json: bool) -> Result<(), ReindexError> {
+    let summary = dry_run_summary(db)?;
+    if json {
+        let payload = serde_json::to_string(&summary)?;
+        println!("{payload}");
+        return Ok(());
     }
+
+    println!("dry-run summary:");
+    print_summary_fields(&summary);
     Ok(())
 }
 
-fn mine_block(
-    prev_hash: String,
-    bits: u32,
-    time: Option<u32>,
-    reward: u64,
-    coinbase_script: String,
-    max_block_bytes: usize,
-    utxo: Option<PathBuf>,
-    txs: Option<PathBuf>,
-    max_nonce: u32,
-    out: Option<PathBuf>,
-) -> Result<(), ReindexError> {
-    if txs.is_some() && utxo.is_none() {
-        return Err(ReindexError::InvalidArgs(
-            "--txs requires --utxo for validation".to_string(),
-        ));
-    }
+#[derive(Debug, Serialize)]
+struct NodeStatus {
+    #[serde(flatten)]
+    summary: DryRunSummary,
+    tip_hash: Option<String>,
+    tip_height: Option<u32>,
+    total_work: Option<Work>,
+    /// Whether the `utxo_count` meta entry agrees with the utxo tree size
+    utxo_count_matches: Option<bool>,
+}
 
-    let prev_hash = decode_hex_32(&prev_hash)?;
-    let coinbase_script = hex::decode(coinbase_script)
-        .map_err(|e| ReindexError::InvalidArgs(format!("invalid coinbase script: {e}")))?;
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map_err(|e| ReindexError::Mining(e.to_string()))?
-        .as_secs() as u32;
-    let time = time.unwrap_or(now);
+/// Summarize a data directory's sled DB without writing to it. sled has no
+/// read-only mode, so this refuses to open a missing DB or one lacking any
+/// of the expected trees, since opening either would create it.
+fn node_status(data_dir: &Path) -> Result<NodeStatus, ReindexError> {
+    p2p::recover_interrupted_compact(data_dir)?;
+    let db_path = data_dir.join("chain.sled");
+    if !db_path.is_dir() {
+        return Err(ReindexError::InvalidArgs(format!(
+            "no chain.sled in {}",
+            data_dir.display()
+        )));
+    }
+    let db = sled::open(db_path).map_err(ReindexError::from)?;
+    db_status(&db)
+}
 
-    let mut mempool = Mempool::new(MempoolConfig::default());
-    if let (Some(utxo_path), Some(tx_path)) = (utxo, txs) {
-        let mut set = InMemoryUtxoSet::new();
-        let reader = jsonl_reader(&utxo_path);
-        reader.for_each(|entry| {
-            set.insert(entry.outpoint, entry.txout);
-            Ok(())
-        })?;
-        let file = fs::File::open(&tx_path)?;
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            let line = line?;
-            if line.trim().is_empty() {
-                continue;
-            }
-            let tx: Transaction = serde_json::from_str(line.trim())?;
-            mempool.add_tx(tx, &set).map_err(|e| ReindexError::Mining(e.to_string()))?;
+fn db_status(db: &sled::Db) -> Result<NodeStatus, ReindexError> {
+    let names = db.tree_names();
+    for tree in ["meta", "headers", "heights", "work", "utxo", "blocks"] {
+        if !names.iter().any(|name| name.as_ref() == tree.as_bytes()) {
+            return Err(ReindexError::InvalidArgs(format!("missing tree {tree}")));
         }
     }
+    validate_schema(db)?;
+    let summary = dry_run_summary(db)?;
 
-    let coinbase = Transaction {
-        version: 1,
-        vin: vec![],
-        vout: vec![tenebrium_utxo::TxOut {
+    let heights = db.open_tree("heights").map_err(ReindexError::from)?;
+    let work = db.open_tree("work").map_err(ReindexError::from)?;
+    let mut best: Option<([u8; 32], Work, u32)> = None;
+    for entry in work.iter() {
+        let (key, value) = entry.map_err(ReindexError::from)?;
+        let (Ok(hash), Ok(w)) = (
+            <[u8; 32]>::try_from(key.as_ref()),
+            <[u8; 32]>::try_from(value.as_ref()),
+        ) else {
+            return Err(ReindexError::InvalidArgs("malformed work entry".to_string()));
+        };
+        let w = Work::from_le_bytes(w);
+        let height = heights
+            .get(hash)
+            .map_err(ReindexError::from)?
+            .and_then(|v| <[u8; 4]>::try_from(v.as_ref()).ok())
+            .map(u32::from_le_bytes)
+            .unwrap_or(0);
+        let better = match &best {
+            Some((b_hash, b_work, b_height)) => {
+                p2p::prefer_tip((w, height, &hash), (*b_work, *b_height, b_hash))
+            }
+            None => true,
+        };
+        if better {
+            best = Some((hash, w, height));
+        }
+    }
+
+    Ok(NodeStatus {
+        tip_hash: best.map(|(hash, _, _)| hex::encode(hash)),
+        tip_height: best.map(|(_, _, height)| height),
+        total_work: best.map(|(_, w, _)| w),
+        utxo_count_matches: summary.utxo_count.map(|count| count == summary.utxo),
+        summary,
+    })
+}
+
+fn print_status(data_dir: &Path, json: bool) -> Result<(), ReindexError> {
+    let status = node_status(data_dir)?;
+    if json {
+        println!("{}", serde_json::to_string(&status)?);
+        return Ok(());
+    }
+    println!("status:");
+    println!(
+        "  tip_hash: {}",
+        status.tip_hash.as_deref().unwrap_or("<none>")
+    );
+    if let Some(height) = status.tip_height {
+        println!("  tip_height: {height}");
+    }
+    if let Some(work) = status.total_work {
+        println!("  total_work: {work}");
+    }
+    print_summary_fields(&status.summary);
+    if let Some(matches) = status.utxo_count_matches {
+        println!("  utxo_count_matches: {matches}");
+    }
+    Ok(())
+}
+
+/// Look up the best-chain block at `height` in a data directory's sled DB.
+/// Like `status`, this refuses to create a DB that is not there.
+fn get_block(data_dir: &Path, height: u32) -> Result<tenebrium_consensus::Block, ReindexError> {
+    p2p::recover_interrupted_compact(data_dir)?;
+    let db_path = data_dir.join("chain.sled");
+    if !db_path.is_dir() {
+        return Err(ReindexError::InvalidArgs(format!(
+            "no chain.sled in {}",
+            data_dir.display()
+        )));
+    }
+    let db = sled::open(db_path)?;
+    p2p::block_at_height(&db, height)
+        .map_err(|e| match e {
+            p2p::P2pError::BlockPruned(_) => ReindexError::InvalidArgs(e.to_string()),
+            e => ReindexError::Mining(e.to_string()),
+        })?
+        .ok_or_else(|| ReindexError::InvalidArgs(format!("no stored block at height {height}")))
+}
+
+/// sled releases its file lock shortly after the last handle is dropped, so
+/// retry briefly before concluding that another process holds the DB. The
+/// lock is probed directly on sled's `db` file, which sled locks with the
+/// same `flock`, because sled reports a held lock only as a formatted
+/// `ErrorKind::Other`.
+fn open_exclusive(path: &Path) -> Result<sled::Db, ReindexError> {
+    let lock_path = path.join("db");
+    let mut attempts = 0;
+    loop {
+        let probe = match fs::File::open(&lock_path) {
+            Ok(file) => file.try_lock(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => break,
+            Err(err) => return Err(err.into()),
+        };
+        match probe {
+            Ok(()) => break,
+            Err(std::fs::TryLockError::WouldBlock) if attempts < 20 => {
+                attempts += 1;
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            Err(std::fs::TryLockError::WouldBlock) => {
+                return Err(ReindexError::InvalidArgs(format!(
+                    "cannot open {}: locked by another process (is the node running?)",
+                    path.display()
+                )))
+            }
+            Err(std::fs::TryLockError::Error(err)) => return Err(err.into()),
+        }
+    }
+    Ok(sled::open(path)?)
+}
+
+fn dir_size(path: &Path) -> Result<u64, ReindexError> {
+    let mut total = 0;
+    for entry in fs::read_dir(path).map_err(ReindexError::from)? {
+        let entry = entry.map_err(ReindexError::from)?;
+        let meta = entry.metadata().map_err(ReindexError::from)?;
+        total += if meta.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            meta.len()
+        };
+    }
+    Ok(total)
+}
+
+/// Copy every tree of `chain.sled` into a fresh `chain.sled.compact` and swap
+/// it in, returning the on-disk size before and after. The original is only
+/// replaced after the copy is flushed; an interrupted swap is finished or
+/// rolled back on the next run.
+fn db_compact(data_dir: &Path) -> Result<(u64, u64), ReindexError> {
+    let db_path = data_dir.join("chain.sled");
+    let tmp_path = data_dir.join("chain.sled.compact");
+    let old_path = data_dir.join("chain.sled.old");
+    p2p::recover_interrupted_compact(data_dir)?;
+    if !db_path.is_dir() {
+        return Err(ReindexError::InvalidArgs(format!(
+            "no chain.sled in {}",
+            data_dir.display()
+        )));
+    }
+    if tmp_path.exists() {
+        fs::remove_dir_all(&tmp_path).map_err(ReindexError::from)?;
+    }
+
+    let before = dir_size(&db_path)?;
+    {
+        let src = open_exclusive(&db_path)?;
+        let dst = sled::open(&tmp_path).map_err(ReindexError::from)?;
+        dst.import(src.export());
+        dst.flush().map_err(ReindexError::from)?;
+    }
+    fs::rename(&db_path, &old_path).map_err(ReindexError::from)?;
+    fs::rename(&tmp_path, &db_path).map_err(ReindexError::from)?;
+    fs::remove_dir_all(&old_path).map_err(ReindexError::from)?;
+    let after = dir_size(&db_path)?;
+    Ok((before, after))
+}
+
+/// JSON keys that hold a 32-byte hash in stored blocks, headers,
+/// transactions and UTXO entries.
+const JSON_HASH_FIELDS: [&str; 3] = ["txid", "prev_block_hash", "merkle_root"];
+
+/// What [`migrate_json`] rewrote: whole files, sled values, and entries
+/// (files or values) that were already in hex form.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct JsonMigration {
+    files: usize,
+    values: usize,
+    already_hex: usize,
+}
+
+/// Whether `value` still carries a hash in the old array-of-bytes form.
+fn has_array_hashes(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Object(map) => map.iter().any(|(key, field)| {
+            (JSON_HASH_FIELDS.contains(&key.as_str()) && field.is_array()) || has_array_hashes(field)
+        }),
+        serde_json::Value::Array(items) => items.iter().any(has_array_hashes),
+        _ => false,
+    }
+}
+
+/// Re-encode one JSON document as `T` if it uses array hashes; `None` when it
+/// is already in hex form and can stay as it is.
+fn rewrite_hash_json<T>(bytes: &[u8], pretty: bool) -> Result<Option<Vec<u8>>, ReindexError>
+where
+    T: serde::de::DeserializeOwned + Serialize,
+{
+    let value: serde_json::Value = serde_json::from_slice(bytes)?;
+    if !has_array_hashes(&value) {
+        return Ok(None);
+    }
+    let typed: T = serde_json::from_value(value)?;
+    let out = if pretty {
+        serde_json::to_vec_pretty(&typed)?
+    } else {
+        serde_json::to_vec(&typed)?
+    };
+    Ok(Some(out))
+}
+
+/// Replace `path` with `bytes` through a temporary file, like the node's own
+/// snapshot writers.
+fn replace_file(path: &Path, bytes: &[u8]) -> Result<(), ReindexError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Rewrite the JSON a data directory stored before hashes were serialized as
+/// hex: `utxo.jsonl`, `blocks/*.json`, and the `headers`, `blocks` and
+/// `mempool_spill` trees of `chain.sled`. Each file and value is checked on
+/// its own, so a partly migrated directory can be migrated again.
+fn migrate_json(data_dir: &Path) -> Result<JsonMigration, ReindexError> {
+    let mut report = JsonMigration::default();
+
+    let utxo_path = data_dir.join("utxo.jsonl");
+    if utxo_path.is_file() {
+        let mut changed = false;
+        let mut out = Vec::new();
+        for line in fs::read_to_string(&utxo_path)?.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match rewrite_hash_json::<UtxoEntry>(line.as_bytes(), false)? {
+                Some(bytes) => {
+                    changed = true;
+                    out.extend_from_slice(&bytes);
+                }
+                None => out.extend_from_slice(line.as_bytes()),
+            }
+            out.push(b'\n');
+        }
+        if changed {
+            replace_file(&utxo_path, &out)?;
+            report.files += 1;
+        } else {
+            report.already_hex += 1;
+        }
+    }
+
+    let blocks_dir = data_dir.join("blocks");
+    if blocks_dir.is_dir() {
+        for entry in fs::read_dir(&blocks_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            match rewrite_hash_json::<tenebrium_consensus::Block>(&fs::read(&path)?, true)? {
+                Some(bytes) => {
+                    replace_file(&path, &bytes)?;
+                    report.files += 1;
+                }
+                None => report.already_hex += 1,
+            }
+        }
+    }
+
+    p2p::recover_interrupted_compact(data_dir)?;
+    let db_path = data_dir.join("chain.sled");
+    if db_path.is_dir() {
+        let db = open_exclusive(&db_path)?;
+        type Rewrite = fn(&[u8], bool) -> Result<Option<Vec<u8>>, ReindexError>;
+        let trees: [(&str, Rewrite); 3] = [
+            ("headers", rewrite_hash_json::<tenebrium_consensus::BlockHeader>),
+            ("blocks", rewrite_hash_json::<tenebrium_consensus::Block>),
+            ("mempool_spill", rewrite_hash_json::<Transaction>),
+        ];
+        for (name, rewrite) in trees {
+            let tree = db.open_tree(name)?;
+            for item in tree.iter() {
+                let (key, value) = item?;
+                match rewrite(&value, false)? {
+                    Some(bytes) => {
+                        tree.insert(key, bytes)?;
+                        report.values += 1;
+                    }
+                    None => report.already_hex += 1,
+                }
+            }
+        }
+        db.flush()?;
+    }
+    Ok(report)
+}
+
+fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<(), ReindexError> {
+    if !src.exists() {
+        return Err(ReindexError::InvalidArgs("source does not exist".to_string()));
+    }
+    for entry in std::fs::read_dir(src).map_err(ReindexError::from)? {
+        let entry = entry.map_err(ReindexError::from)?;
+        let file_type = entry.file_type().map_err(ReindexError::from)?;
+        let src_path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&dst_path).map_err(ReindexError::from)?;
+            copy_dir_recursive(&src_path, &dst_path)?;
+        } else if file_type.is_file() {
+            std::fs::copy(&src_path, &dst_path).map_err(ReindexError::from)?;
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn mine_block(
+    prev_hash: String,
+    height: Option<u32>,
+    data_dir: Option<PathBuf>,
+    bits: u32,
+    time: Option<u32>,
+    reward: u64,
+    coinbase_script: Option<String>,
+    coinbase_out: Vec<String>,
+    max_block_bytes: usize,
+    utxo: Option<PathBuf>,
+    txs: Option<PathBuf>,
+    max_nonce: u32,
+    threads: usize,
+    out: Option<PathBuf>,
+) -> Result<(), ReindexError> {
+    if threads == 0 {
+        return Err(ReindexError::InvalidArgs("--threads must be positive".to_string()));
+    }
+    if txs.is_some() && utxo.is_none() {
+        return Err(ReindexError::InvalidArgs(
+            "--txs requires --utxo for validation".to_string(),
+        ));
+    }
+
+    let prev_hash = txid_from_display_hex(&prev_hash)
+        .map_err(|e| ReindexError::InvalidArgs(format!("--prev-hash: {e}")))?;
+    let height = mined_height(&prev_hash, height, data_dir.as_deref())?;
+    let coinbase_vout = match (coinbase_script, coinbase_out.is_empty()) {
+        (Some(script), true) => vec![tenebrium_utxo::TxOut {
             value: reward,
-            script_pubkey: coinbase_script,
+            script_pubkey: hex::decode(script).map_err(|e| {
+                ReindexError::InvalidArgs(format!("invalid coinbase script: {e}"))
+            })?,
         }],
+        (None, false) => coinbase_out
+            .iter()
+            .map(|spec| parse_coinbase_out(spec))
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => {
+            return Err(ReindexError::InvalidArgs(
+                "provide either --coinbase-script or --coinbase-out".to_string(),
+            ))
+        }
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| ReindexError::Mining(e.to_string()))?
+        .as_secs() as u32;
+    let time = time.unwrap_or(now);
+
+    let mut mempool = Mempool::new(MempoolConfig::default());
+    if let (Some(utxo_path), Some(tx_path)) = (utxo, txs) {
+        let mut set = InMemoryUtxoSet::new();
+        let reader = jsonl_reader(&utxo_path);
+        reader.for_each(|entry| {
+            set.insert(entry.outpoint, entry.txout);
+            Ok(())
+        })?;
+        let file = fs::File::open(&tx_path)?;
+        let reader = BufReader::new(file);
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let tx: Transaction = serde_json::from_str(line.trim())?;
+            mempool.add_tx(tx, &set).map_err(|e| ReindexError::Mining(e.to_string()))?;
+        }
+    }
+
+    let coinbase = Transaction {
+        version: 1,
+        vin: vec![TxIn {
+            prevout: OutPoint::NULL,
+            script_sig: encode_coinbase_height(height),
+            sequence: u32::MAX,
+        }],
+        vout: coinbase_vout,
         lock_time: 0,
     };
+    coinbase.validate()?;
+    let coinbase_value = Transaction::sum_outputs(&coinbase)?;
 
     let mut template = build_block_template(
         &mempool,
@@ -747,11 +1592,39 @@ fn mine_block(
         bits,
         1,
         max_block_bytes,
+        MAX_BLOCK_SIGOPS,
+        MAX_BLOCK_OUTPUTS,
     )
     .map_err(|e| ReindexError::Mining(e.to_string()))?;
+    // Fees are only known once the template is filled.
+    if coinbase_value > reward.saturating_add(template.total_fees) {
+        return Err(ReindexError::InvalidArgs(format!(
+            "coinbase outputs total {coinbase_value} exceeds reward+fees {}",
+            reward.saturating_add(template.total_fees)
+        )));
+    }
 
     let header = &mut template.block.header;
-    let found = mine_header(header, max_nonce).map_err(|e| ReindexError::Mining(e.to_string()))?;
+    let found = if threads == 1 {
+        let started = std::time::Instant::now();
+        mine_header_with_progress(header, max_nonce, |tried| {
+            let secs = started.elapsed().as_secs_f64();
+            eprintln!(
+                "tried {tried} nonces, {:.0} H/s",
+                tried as f64 / secs.max(f64::EPSILON)
+            );
+        })
+        .map_err(|e| ReindexError::Mining(e.to_string()))?
+    } else {
+        let stop = AtomicBool::new(false);
+        let solution = mine_header_parallel(header, threads, &stop)
+            .map_err(|e| ReindexError::Mining(e.to_string()))?;
+        solution.map(|(nonce, extra_nonce)| {
+            header.version = roll_version(header.version, extra_nonce);
+            header.nonce = nonce;
+            nonce
+        })
+    };
     if found.is_none() {
         return Err(ReindexError::Mining("nonce not found".to_string()));
     }
@@ -764,16 +1637,80 @@ fn mine_block(
     Ok(())
 }
 
+/// Height of a block mined on `prev_hash`: `height` if given, 0 on an
+/// all-zero `prev_hash`, otherwise one above `prev_hash` in `data_dir`.
+fn mined_height(
+    prev_hash: &[u8; 32],
+    height: Option<u32>,
+    data_dir: Option<&Path>,
+) -> Result<u32, ReindexError> {
+    if let Some(height) = height {
+        return Ok(height);
+    }
+    if *prev_hash == [0u8; 32] {
+        return Ok(0);
+    }
+    let data_dir = data_dir.ok_or_else(|| {
+        ReindexError::InvalidArgs("--height or --data-dir is required to mine on --prev-hash".to_string())
+    })?;
+    p2p::recover_interrupted_compact(data_dir)?;
+    let db_path = data_dir.join("chain.sled");
+    if !db_path.is_dir() {
+        return Err(ReindexError::InvalidArgs(format!(
+            "no chain.sled in {}",
+            data_dir.display()
+        )));
+    }
+    let db = open_exclusive(&db_path)?;
+    let prev_height = db
+        .open_tree("heights")?
+        .get(prev_hash)?
+        .and_then(|v| <[u8; 4]>::try_from(v.as_ref()).ok())
+        .map(u32::from_le_bytes)
+        .ok_or_else(|| {
+            ReindexError::InvalidArgs(format!(
+                "--prev-hash {} is not in {}",
+                txid_to_display_hex(prev_hash),
+                db_path.display()
+            ))
+        })?;
+    prev_height
+        .checked_add(1)
+        .ok_or_else(|| ReindexError::InvalidArgs("height overflow".to_string()))
+}
+
+/// Parse a `--coinbase-out` value of the form `script_hex:value`.
+fn parse_coinbase_out(spec: &str) -> Result<tenebrium_utxo::TxOut, ReindexError> {
+    let (script, value) = spec.rsplit_once(':').ok_or_else(|| {
+        ReindexError::InvalidArgs(format!("coinbase output must be script_hex:value, got {spec}"))
+    })?;
+    let script_pubkey = hex::decode(script)
+        .map_err(|e| ReindexError::InvalidArgs(format!("invalid coinbase script: {e}")))?;
+    let value = value
+        .parse::<u64>()
+        .map_err(|e| ReindexError::InvalidArgs(format!("invalid coinbase value: {e}")))?;
+    Ok(tenebrium_utxo::TxOut {
+        value,
+        script_pubkey,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::db_migrate;
-    use super::{db_backup, db_restore};
+    use super::mine_block;
+    use super::{db_status, node_status};
+    use super::{copy_dir_recursive, db_backup, db_compact, db_restore, open_exclusive};
+    use super::{migrate_json, JsonMigration};
     use super::submit_block;
-    use super::ReindexError;
+    use super::{submit_block_in_memory, submit_block_streaming};
+    use super::{ReindexError, MAX_BLOCK_OUTPUTS, MAX_BLOCK_SIGOPS};
+    use super::{jsonl_prepass_total, stream_transactions, DbFormat};
     use crate::p2p;
+    use crate::work::Work;
     use tempfile::tempdir;
-    use tenebrium_consensus::Block;
-    use tenebrium_utxo::{OutPoint, Transaction, TxIn, TxOut};
+    use tenebrium_consensus::{encode_coinbase_height, Block, ConsensusError, ContextualChecks};
+    use tenebrium_utxo::{OutPoint, Transaction, TxIn, TxOut, UtxoError};
     use std::fs;
 
     #[test]
@@ -797,7 +1734,7 @@ mod tests {
             let db_path = temp.path().join("chain.sled");
             let db = sled::open(db_path).unwrap();
             let meta = db.open_tree("meta").unwrap();
-            meta.insert("schema_version", 2u32.to_le_bytes().to_vec())
+            meta.insert("schema_version", 3u32.to_le_bytes().to_vec())
                 .unwrap();
             meta.insert("network_id", b"mainnet".to_vec()).unwrap();
             db.open_tree("headers").unwrap();
@@ -810,7 +1747,7 @@ mod tests {
 
         let result = db_migrate(
             temp.path().to_path_buf(),
-            2,
+            3,
             true,
             false,
             true,
@@ -824,12 +1761,12 @@ mod tests {
     #[test]
     fn p2p_schema_allows_db_migrate_dry_run() {
         let temp = tempdir().unwrap();
-        let db = p2p::open_sled(&temp.path().to_path_buf()).unwrap();
+        let db = p2p::open_sled(temp.path()).unwrap();
         drop(db);
 
         let result = db_migrate(
             temp.path().to_path_buf(),
-            2,
+            3,
             true,
             false,
             true,
@@ -840,10 +1777,274 @@ mod tests {
         }
     }
 
+    #[test]
+    fn db_migrate_recomputes_work_as_256_bit() {
+        use tenebrium_consensus::header_hash;
+
+        let temp = tempdir().unwrap();
+        let coinbase = Transaction {
+            version: 1,
+            vin: coinbase_input(),
+            vout: vec![TxOut {
+                value: 50,
+                script_pubkey: vec![1],
+            }],
+            lock_time: 0,
+        };
+        let genesis = Block::new(1, [0u8; 32], 1, 0x207fffff, 0, vec![coinbase.clone()]).unwrap();
+        let hash0 = header_hash(&genesis.header);
+        let block1 = Block::new(1, hash0, 2, 0x1d00ffff, 0, vec![coinbase]).unwrap();
+        let hash1 = header_hash(&block1.header);
+        {
+            let db = sled::open(temp.path().join("chain.sled")).unwrap();
+            let meta = db.open_tree("meta").unwrap();
+            meta.insert("schema_version", 2u32.to_le_bytes().to_vec()).unwrap();
+            meta.insert("network_id", b"mainnet".to_vec()).unwrap();
+            let headers = db.open_tree("headers").unwrap();
+            let heights = db.open_tree("heights").unwrap();
+            let work = db.open_tree("work").unwrap();
+            for (header, hash, height) in [(&genesis.header, hash0, 0u32), (&block1.header, hash1, 1)] {
+                headers.insert(hash, serde_json::to_vec(header).unwrap()).unwrap();
+                heights.insert(hash, height.to_le_bytes().to_vec()).unwrap();
+                // Schema 2 work saturated to zero at both of these targets.
+                work.insert(hash, 0u128.to_le_bytes().to_vec()).unwrap();
+            }
+            db.open_tree("utxo").unwrap();
+            db.open_tree("blocks").unwrap();
+            db.flush().unwrap();
+        }
+
+        db_migrate(temp.path().to_path_buf(), 3, false, false, true, false).unwrap();
+        db_migrate(temp.path().to_path_buf(), 3, false, false, false, false).unwrap();
+
+        let db = sled::open(temp.path().join("chain.sled")).unwrap();
+        let status = db_status(&db).unwrap();
+        assert_eq!(status.summary.schema_version, 3);
+        assert_eq!(status.tip_hash, Some(hex::encode(hash1)));
+        assert_eq!(status.total_work, Work::from_bits(0x1d00ffff));
+        let stored = db.open_tree("work").unwrap().get(hash0).unwrap().unwrap();
+        assert_eq!(stored.as_ref(), &Work::ZERO.to_le_bytes()[..]);
+    }
+
+    #[test]
+    fn status_reports_tip_and_counts_without_writing() {
+        use crate::utxo_db::{encode_outpoint, encode_txout};
+        use tenebrium_consensus::header_hash;
+
+        let temp = tempdir().unwrap();
+        let coinbase = |tag: u8| Transaction {
+            version: 1,
+            vin: coinbase_input(),
+            vout: vec![TxOut {
+                value: 50,
+                script_pubkey: vec![tag],
+            }],
+            lock_time: 0,
+        };
+        let block1 = Block::new(1, [0u8; 32], 1, 0x207fffff, 0, vec![coinbase(1)]).unwrap();
+        let hash1 = header_hash(&block1.header);
+        let block2 = Block::new(1, hash1, 2, 0x207fffff, 0, vec![coinbase(2)]).unwrap();
+        let hash2 = header_hash(&block2.header);
+        let stale = Block::new(1, hash1, 3, 0x207fffff, 0, vec![coinbase(3)]).unwrap();
+        let hash_stale = header_hash(&stale.header);
+        let db = sled::open(temp.path().join("chain.sled")).unwrap();
+        let meta = db.open_tree("meta").unwrap();
+        meta.insert("schema_version", 3u32.to_le_bytes().to_vec()).unwrap();
+        meta.insert("network_id", b"devnet".to_vec()).unwrap();
+        meta.insert("utxo_count", 2u64.to_le_bytes().to_vec()).unwrap();
+        let headers = db.open_tree("headers").unwrap();
+        let heights = db.open_tree("heights").unwrap();
+        let work = db.open_tree("work").unwrap();
+        let blocks = db.open_tree("blocks").unwrap();
+        let utxo = db.open_tree("utxo").unwrap();
+        for (block, hash, height, w) in [
+            (&block1, hash1, 1u32, 10u128),
+            (&block2, hash2, 2, 20),
+            (&stale, hash_stale, 2, 15),
+        ] {
+            headers.insert(hash, serde_json::to_vec(&block.header).unwrap()).unwrap();
+            heights.insert(hash, height.to_le_bytes().to_vec()).unwrap();
+            work.insert(hash, Work::from(w).to_le_bytes().to_vec()).unwrap();
+        }
+        for block in [&block1, &block2] {
+            let hash = header_hash(&block.header);
+            blocks.insert(hash, serde_json::to_vec(block).unwrap()).unwrap();
+            let outpoint = OutPoint {
+                txid: block.txs[0].txid_v2().unwrap(),
+                vout: 0,
+            };
+            utxo.insert(encode_outpoint(&outpoint), encode_txout(&block.txs[0].vout[0]))
+                .unwrap();
+        }
+        db.flush().unwrap();
+
+        // Reuse the handle: reopening races sled's lock release.
+        let status = db_status(&db).unwrap();
+        assert_eq!(status.tip_hash, Some(hex::encode(hash2)));
+        assert_eq!(status.tip_height, Some(2));
+        assert_eq!(status.total_work, Some(Work::from(20)));
+        assert_eq!(status.summary.headers, 3);
+        assert_eq!(status.summary.blocks, 2);
+        assert_eq!(status.summary.utxo, 2);
+        assert_eq!(status.summary.network_id, "devnet");
+        assert_eq!(status.summary.schema_version, 3);
+        assert_eq!(status.utxo_count_matches, Some(true));
+        // Unlike loading the chain, status must not persist a tip.
+        assert_eq!(status.summary.meta_keys, 3);
+        assert_eq!(db_status(&db).unwrap().summary.meta_keys, 3);
+
+        let missing = tempdir().unwrap();
+        assert!(node_status(missing.path()).is_err());
+        assert!(!missing.path().join("chain.sled").exists());
+    }
+
+    #[test]
+    fn mined_block_commits_its_height_and_applies_through_p2p() {
+        use tenebrium_consensus::header_hash;
+        use tenebrium_consensus::params::{
+            NetworkParams, COINBASE_MATURITY, GENESIS_TIME, MAX_BLOCK_OUTPUTS, MAX_BLOCK_SIGOPS,
+        };
+        use tenebrium_utxo::{txid_to_display_hex, InMemoryUtxoSet, UtxoSet};
+
+        let temp = tempdir().unwrap();
+        let params = NetworkParams::mainnet();
+        let genesis = header_hash(&Block::genesis(&params, params.genesis_coinbase()).unwrap().header);
+        let db = p2p::open_sled(temp.path()).unwrap();
+        db.open_tree("heights")
+            .unwrap()
+            .insert(genesis, 0u32.to_le_bytes().to_vec())
+            .unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        let block_path = temp.path().join("block.json");
+        let subsidy = params.block_subsidy(1);
+        mine_block(
+            txid_to_display_hex(&genesis),
+            None,
+            Some(temp.path().to_path_buf()),
+            0x207fffff,
+            Some(GENESIS_TIME + 1),
+            subsidy,
+            Some("51".to_string()),
+            vec![],
+            1_000_000,
+            None,
+            None,
+            1_000_000,
+            1,
+            Some(block_path.clone()),
+        )
+        .unwrap();
+        let block: Block = serde_json::from_str(&fs::read_to_string(&block_path).unwrap()).unwrap();
+        assert_eq!(block.header.prev_block_hash, genesis);
+        assert_eq!(block.coinbase_height(), Some(1));
+
+        let mut utxos = InMemoryUtxoSet::new();
+        let apply = |height: u32, utxos: &mut InMemoryUtxoSet| {
+            p2p::apply_block_with_undo(
+                &block,
+                height,
+                0,
+                utxos,
+                true,
+                subsidy,
+                MAX_BLOCK_SIGOPS,
+                MAX_BLOCK_OUTPUTS,
+                COINBASE_MATURITY,
+            )
+        };
+        assert!(apply(2, &mut utxos).is_err());
+        apply(1, &mut utxos).unwrap();
+        assert_eq!(utxos.total_value().unwrap(), subsidy);
+
+        // Without a height source a non-genesis parent is refused.
+        let err = mine_block(
+            txid_to_display_hex(&genesis),
+            None,
+            None,
+            0x207fffff,
+            None,
+            subsidy,
+            Some("51".to_string()),
+            vec![],
+            1_000_000,
+            None,
+            None,
+            1_000_000,
+            1,
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(err, ReindexError::InvalidArgs(_)));
+    }
+
+    #[test]
+    fn mine_block_splits_coinbase_three_ways() {
+        let temp = tempdir().unwrap();
+        let block_path = temp.path().join("block.json");
+        let utxo_path = temp.path().join("utxo.jsonl");
+        let out_path = temp.path().join("utxo.out.jsonl");
+        write_empty_utxo(&utxo_path);
+        let split = vec!["aa:20".to_string(), "bbbb:20".to_string(), "cc:10".to_string()];
+        let mine = |outs: Vec<String>| {
+            mine_block(
+                hex::encode([0u8; 32]),
+                None,
+                None,
+                0x207fffff,
+                Some(1_700_000_000),
+                50,
+                None,
+                outs,
+                1_000_000,
+                None,
+                None,
+                1_000_000,
+                1,
+                Some(block_path.clone()),
+            )
+        };
+
+        mine(split).unwrap();
+        let block: Block = serde_json::from_str(&fs::read_to_string(&block_path).unwrap()).unwrap();
+        let outs: Vec<(Vec<u8>, u64)> = block.txs[0]
+            .vout
+            .iter()
+            .map(|out| (out.script_pubkey.clone(), out.value))
+            .collect();
+        assert_eq!(
+            outs,
+            vec![(vec![0xaa], 20), (vec![0xbb, 0xbb], 20), (vec![0xcc], 10)]
+        );
+        assert_eq!(Transaction::sum_outputs(&block.txs[0]).unwrap(), 50);
+        submit_block_in_memory(block_path.clone(), utxo_path, out_path.clone(), Some(50), false, at_genesis())
+            .unwrap();
+        assert_eq!(fs::read_to_string(&out_path).unwrap().lines().count(), 3);
+
+        let greedy = mine(vec!["aa:30".to_string(), "bb:30".to_string()]);
+        assert!(matches!(greedy, Err(ReindexError::InvalidArgs(msg)) if msg.contains("exceeds")));
+        assert!(matches!(mine(vec!["aa30".to_string()]), Err(ReindexError::InvalidArgs(_))));
+    }
+
     fn write_empty_utxo(path: &std::path::Path) {
         fs::write(path, "").unwrap();
     }
 
+    /// Context of a block on an all-zero parent: height 0, no time locks met.
+    fn at_genesis() -> ContextualChecks {
+        ContextualChecks::new(0, 0, MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS)
+    }
+
+    /// Null-prevout coinbase input committing to height 0.
+    fn coinbase_input() -> Vec<TxIn> {
+        vec![TxIn {
+            prevout: OutPoint::NULL,
+            script_sig: encode_coinbase_height(0),
+            sequence: u32::MAX,
+        }]
+    }
+
     fn write_block(path: &std::path::Path, block: &Block) {
         let json = serde_json::to_string_pretty(block).unwrap();
         fs::write(path, json).unwrap();
@@ -865,6 +2066,180 @@ mod tests {
         assert!(restore_dir.join("chain.sled").join("dummy").exists());
     }
 
+    #[test]
+    fn db_compact_shrinks_after_deletions_and_keeps_live_entries() {
+        let temp = tempdir().unwrap();
+        let db_path = temp.path().join("chain.sled");
+        {
+            let db = sled::open(&db_path).unwrap();
+            let utxo = db.open_tree("utxo").unwrap();
+            for i in 0u32..20_000 {
+                utxo.insert(i.to_be_bytes(), vec![7u8; 256]).unwrap();
+            }
+            db.flush().unwrap();
+            for i in 0u32..20_000 {
+                if i % 100 != 0 {
+                    utxo.remove(i.to_be_bytes()).unwrap();
+                }
+            }
+            db.open_tree("meta").unwrap().insert("schema_version", 3u32.to_le_bytes().to_vec()).unwrap();
+            db.flush().unwrap();
+        }
+
+        let (before, after) = db_compact(temp.path()).unwrap();
+        assert!(after < before, "{after} >= {before}");
+        assert!(!temp.path().join("chain.sled.compact").exists());
+        assert!(!temp.path().join("chain.sled.old").exists());
+
+        let db = open_exclusive(&db_path).unwrap();
+        let utxo = db.open_tree("utxo").unwrap();
+        assert_eq!(utxo.len(), 200);
+        for i in (0u32..20_000).step_by(100) {
+            assert_eq!(utxo.get(i.to_be_bytes()).unwrap().unwrap().as_ref(), &[7u8; 256][..]);
+        }
+        let meta = db.open_tree("meta").unwrap();
+        assert!(meta.get("schema_version").unwrap().is_some());
+
+        // A live handle holds the lock: compaction must refuse.
+        assert!(matches!(db_compact(temp.path()), Err(ReindexError::InvalidArgs(_))));
+        assert_eq!(utxo.len(), 200);
+    }
+
+    #[test]
+    fn interrupted_compaction_is_settled_before_the_db_is_opened() {
+        let temp = tempdir().unwrap();
+        let db_path = temp.path().join("chain.sled");
+        let old_path = temp.path().join("chain.sled.old");
+        {
+            let db = sled::open(&db_path).unwrap();
+            db.open_tree("heights").unwrap().insert(b"tip", vec![1u8]).unwrap();
+            db.flush().unwrap();
+        }
+
+        // Stopped after moving the original aside: the node gets it back
+        // instead of starting on a fresh empty DB.
+        fs::rename(&db_path, &old_path).unwrap();
+        let db = p2p::open_sled(temp.path()).unwrap();
+        assert!(!old_path.exists());
+        assert!(db.open_tree("heights").unwrap().get(b"tip").unwrap().is_some());
+        drop(db);
+
+        // Stopped after the copy was swapped in: the stale original goes.
+        fs::create_dir_all(&old_path).unwrap();
+        copy_dir_recursive(&db_path, &old_path).unwrap();
+        db_compact(temp.path()).unwrap();
+        assert!(!old_path.exists());
+        let db = open_exclusive(&db_path).unwrap();
+        assert!(db.open_tree("heights").unwrap().get(b"tip").unwrap().is_some());
+    }
+
+    #[test]
+    fn migrate_json_rewrites_array_hashes_as_hex() {
+        // The JSON these types produced before hashes were hex strings.
+        fn array_form<T: serde::Serialize>(value: &T) -> serde_json::Value {
+            fn convert(value: &mut serde_json::Value) {
+                match value {
+                    serde_json::Value::Object(map) => {
+                        for (key, field) in map.iter_mut() {
+                            if let (true, Some(hex)) = (super::JSON_HASH_FIELDS.contains(&key.as_str()), field.as_str()) {
+                                *field = serde_json::json!(hex::decode(hex).unwrap());
+                            } else {
+                                convert(field);
+                            }
+                        }
+                    }
+                    serde_json::Value::Array(items) => items.iter_mut().for_each(convert),
+                    _ => {}
+                }
+            }
+            let mut value = serde_json::to_value(value).unwrap();
+            convert(&mut value);
+            value
+        }
+
+        let temp = tempdir().unwrap();
+        let coinbase = Transaction {
+            version: 1,
+            vin: coinbase_input(),
+            vout: vec![TxOut {
+                value: 50,
+                script_pubkey: vec![1],
+            }],
+            lock_time: 0,
+        };
+        let spend = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: [7u8; 32],
+                    vout: 0,
+                },
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            ..coinbase.clone()
+        };
+        let block = Block::new(1, [9u8; 32], 1, 0x207fffff, 0, vec![coinbase, spend.clone()]).unwrap();
+        let hash = tenebrium_consensus::header_hash(&block.header);
+        let entry = super::UtxoEntry {
+            outpoint: OutPoint {
+                txid: [3u8; 32],
+                vout: 1,
+            },
+            txout: spend.vout[0].clone(),
+        };
+
+        let block_file = temp.path().join("blocks").join(format!("{}.json", hex::encode(hash)));
+        std::fs::create_dir_all(block_file.parent().unwrap()).unwrap();
+        std::fs::write(&block_file, serde_json::to_vec_pretty(&array_form(&block)).unwrap()).unwrap();
+        let utxo_file = temp.path().join("utxo.jsonl");
+        std::fs::write(
+            &utxo_file,
+            format!("{}\n{}\n", array_form(&entry), serde_json::to_string(&entry).unwrap()),
+        )
+        .unwrap();
+        {
+            let db = sled::open(temp.path().join("chain.sled")).unwrap();
+            let headers = db.open_tree("headers").unwrap();
+            headers.insert(hash, serde_json::to_vec(&array_form(&block.header)).unwrap()).unwrap();
+            headers.insert([1u8; 32], serde_json::to_vec(&block.header).unwrap()).unwrap();
+            let spill = db.open_tree("mempool_spill").unwrap();
+            spill.insert(spend.txid_v2().unwrap(), serde_json::to_vec(&array_form(&spend)).unwrap()).unwrap();
+            db.flush().unwrap();
+        }
+
+        let report = migrate_json(temp.path()).unwrap();
+        assert_eq!(
+            report,
+            JsonMigration {
+                files: 2,
+                values: 2,
+                already_hex: 1,
+            }
+        );
+
+        let migrated = std::fs::read_to_string(&block_file).unwrap();
+        assert!(migrated.contains(&format!("\"txid\": \"{}\"", hex::encode([7u8; 32]))));
+        assert_eq!(serde_json::from_str::<Block>(&migrated).unwrap(), block);
+        let lines: Vec<String> = std::fs::read_to_string(&utxo_file)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(lines, vec![serde_json::to_string(&entry).unwrap(); 2]);
+        {
+            let db = open_exclusive(&temp.path().join("chain.sled")).unwrap();
+            let stored = db.open_tree("headers").unwrap().get(hash).unwrap().unwrap();
+            assert_eq!(stored.as_ref(), serde_json::to_vec(&block.header).unwrap());
+            let stored = db.open_tree("mempool_spill").unwrap().iter().next().unwrap().unwrap().1;
+            assert_eq!(stored.as_ref(), serde_json::to_vec(&spend).unwrap());
+        }
+
+        // A second run finds nothing left to convert.
+        let again = migrate_json(temp.path()).unwrap();
+        assert_eq!((again.files, again.values, again.already_hex), (0, 0, 5));
+    }
+
     #[test]
     fn submit_block_rejects_merkle_mismatch() {
         let temp = tempdir().unwrap();
@@ -875,7 +2250,7 @@ mod tests {
 
         let coinbase = Transaction {
             version: 1,
-            vin: vec![],
+            vin: coinbase_input(),
             vout: vec![TxOut {
                 value: 50,
                 script_pubkey: vec![1],
@@ -891,14 +2266,14 @@ mod tests {
             vec![coinbase],
         )
         .unwrap();
-        block.header.merkle_root = [0u8; 32];
+        block.header.merkle_root = [7u8; 32];
         write_block(&block_path, &block);
 
-        let result = submit_block(block_path, utxo_path, out_path, None, true);
-        match result {
-            Err(ReindexError::Mining(msg)) => assert!(msg.contains("merkle root mismatch")),
-            _ => panic!("expected merkle root mismatch"),
-        }
+        let result = submit_block(block_path, utxo_path, out_path, None, true, at_genesis());
+        assert!(
+            matches!(result, Err(ReindexError::Consensus(ConsensusError::MerkleRootMismatch))),
+            "{result:?}"
+        );
     }
 
     #[test]
@@ -936,24 +2311,61 @@ mod tests {
         .unwrap();
         write_block(&block_path, &block);
 
-        let result = submit_block(block_path, utxo_path, out_path, None, true);
+        let result = submit_block(block_path, utxo_path, out_path, None, true, at_genesis());
         match result {
-            Err(ReindexError::Mining(msg)) => assert!(msg.contains("coinbase must have no inputs")),
+            Err(ReindexError::Consensus(ConsensusError::CoinbaseRequired)) => {}
             _ => panic!("expected coinbase input rejection"),
         }
     }
 
     #[test]
-    fn submit_block_rejects_excess_coinbase_reward() {
+    fn submit_block_rejects_input_less_non_coinbase() {
         let temp = tempdir().unwrap();
         let utxo_path = temp.path().join("utxo.jsonl");
         let block_path = temp.path().join("block.json");
         let out_path = temp.path().join("out.jsonl");
         write_empty_utxo(&utxo_path);
 
+        let output = |tag: u8| TxOut {
+            value: 50,
+            script_pubkey: vec![tag],
+        };
         let coinbase = Transaction {
             version: 1,
+            vin: coinbase_input(),
+            vout: vec![output(1)],
+            lock_time: 0,
+        };
+        let no_inputs = Transaction {
             vin: vec![],
+            vout: vec![output(2)],
+            ..coinbase.clone()
+        };
+        // Only the first transaction may go without inputs.
+        let block = Block::new(1, [0u8; 32], 0, 0x207fffff, 0, vec![coinbase, no_inputs]).unwrap();
+        write_block(&block_path, &block);
+
+        let result = submit_block(block_path, utxo_path, out_path, None, true, at_genesis());
+        assert!(
+            matches!(
+                result,
+                Err(ReindexError::Consensus(ConsensusError::Utxo(UtxoError::EmptyInputs)))
+            ),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn submit_block_rejects_excess_coinbase_reward() {
+        let temp = tempdir().unwrap();
+        let utxo_path = temp.path().join("utxo.jsonl");
+        let block_path = temp.path().join("block.json");
+        let out_path = temp.path().join("out.jsonl");
+        write_empty_utxo(&utxo_path);
+
+        let coinbase = Transaction {
+            version: 1,
+            vin: coinbase_input(),
             vout: vec![TxOut {
                 value: 100,
                 script_pubkey: vec![1],
@@ -971,26 +2383,185 @@ mod tests {
         .unwrap();
         write_block(&block_path, &block);
 
-        let result = submit_block(block_path, utxo_path, out_path, Some(50), true);
-        match result {
-            Err(ReindexError::Mining(msg)) => assert!(msg.contains("coinbase exceeds reward+fees")),
-            _ => panic!("expected coinbase reward rejection"),
+        let result = submit_block(block_path, utxo_path, out_path, Some(50), true, at_genesis());
+        assert!(
+            matches!(
+                result,
+                Err(ReindexError::Consensus(ConsensusError::ExcessCoinbase { value: 100, limit: 50 }))
+            ),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn submit_block_rejects_empty_block_typed() {
+        let temp = tempdir().unwrap();
+        let utxo_path = temp.path().join("utxo.jsonl");
+        let block_path = temp.path().join("block.json");
+        let out_path = temp.path().join("out.jsonl");
+        write_empty_utxo(&utxo_path);
+        let block = Block::new(1, [0u8; 32], 0, 0x207fffff, 0, vec![]).unwrap();
+        write_block(&block_path, &block);
+
+        let result = submit_block_in_memory(
+            block_path.clone(),
+            utxo_path.clone(),
+            out_path.clone(),
+            None,
+            true,
+            at_genesis(),
+        );
+        assert!(matches!(result, Err(ReindexError::Consensus(ConsensusError::EmptyBlock))));
+        let result = submit_block_streaming(block_path, utxo_path, out_path, None, true, at_genesis());
+        assert!(matches!(result, Err(ReindexError::Consensus(ConsensusError::EmptyBlock))));
+    }
+
+    #[test]
+    fn streaming_submit_matches_in_memory() {
+        let temp = tempdir().unwrap();
+        let utxo_path = temp.path().join("utxo.jsonl");
+        let funding = OutPoint {
+            txid: [7u8; 32],
+            vout: 0,
+        };
+        let entry = crate::utxo_db::UtxoEntry {
+            outpoint: funding.clone(),
+            txout: TxOut {
+                value: 30,
+                script_pubkey: vec![2],
+            },
+        };
+        fs::write(&utxo_path, serde_json::to_string(&entry).unwrap() + "\n").unwrap();
+
+        let coinbase = |value: u64| Transaction {
+            version: 1,
+            vin: coinbase_input(),
+            vout: vec![TxOut {
+                value,
+                script_pubkey: vec![1],
+            }],
+            lock_time: 0,
+        };
+        let spend = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: funding,
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 20,
+                script_pubkey: vec![3],
+            }],
+            lock_time: 0,
+        };
+        let block = |txs: Vec<Transaction>| Block::new(1, [0u8; 32], 0, 0x207fffff, 0, txs).unwrap();
+
+        let mut bad_merkle = block(vec![coinbase(50)]);
+        bad_merkle.header.merkle_root = [7u8; 32];
+        let mut coinbase_with_input = coinbase(50);
+        coinbase_with_input.vin = spend.vin.clone();
+        let mut extra_data_coinbase = coinbase(50);
+        extra_data_coinbase.vin[0].script_sig.extend_from_slice(b"extra");
+        let mut uncommitted_coinbase = coinbase(50);
+        uncommitted_coinbase.vin[0].script_sig = vec![1];
+        let mut time_locked = spend.clone();
+        time_locked.lock_time = 5;
+        let cases = vec![
+            ("valid", block(vec![coinbase(50), spend.clone()]), Some(60)),
+            ("bad merkle", bad_merkle, None),
+            ("coinbase input", block(vec![coinbase_with_input]), None),
+            ("coinbase extra data", block(vec![extra_data_coinbase, spend.clone()]), Some(50)),
+            ("uncommitted height", block(vec![uncommitted_coinbase]), None),
+            ("not final", block(vec![coinbase(50), time_locked]), None),
+            ("excess reward", block(vec![coinbase(100)]), Some(50)),
+            ("fees count", block(vec![coinbase(60), spend.clone()]), Some(50)),
+            ("second coinbase", block(vec![coinbase(50), coinbase(40)]), None),
+            ("double spend", block(vec![coinbase(50), spend.clone(), spend]), None),
+            ("empty", block(vec![]), None),
+        ];
+
+        for (name, block, reward) in cases {
+            let block_path = temp.path().join("block.json");
+            write_block(&block_path, &block);
+            let mem_out = temp.path().join("mem.jsonl");
+            let stream_out = temp.path().join("stream.jsonl");
+            let mem = submit_block_in_memory(
+                block_path.clone(),
+                utxo_path.clone(),
+                mem_out.clone(),
+                reward,
+                true,
+                at_genesis(),
+            );
+            let stream = submit_block_streaming(
+                block_path,
+                utxo_path.clone(),
+                stream_out.clone(),
+                reward,
+                true,
+                at_genesis(),
+            );
+            assert_eq!(format!("{mem:?}"), format!("{stream:?}"), "{name}");
+            match name {
+                "uncommitted height" => assert!(
+                    matches!(mem, Err(ReindexError::Consensus(ConsensusError::BadCoinbaseHeight(0)))),
+                    "{mem:?}"
+                ),
+                "not final" => assert!(
+                    matches!(
+                        mem,
+                        Err(ReindexError::Consensus(ConsensusError::Utxo(UtxoError::NotFinal { lock_time: 5 })))
+                    ),
+                    "{mem:?}"
+                ),
+                _ => {}
+            }
+            if mem.is_ok() {
+                let mut mem_lines: Vec<String> =
+                    fs::read_to_string(&mem_out).unwrap().lines().map(String::from).collect();
+                let mut stream_lines: Vec<String> =
+                    fs::read_to_string(&stream_out).unwrap().lines().map(String::from).collect();
+                mem_lines.sort();
+                stream_lines.sort();
+                assert_eq!(mem_lines, stream_lines, "{name}");
+            }
         }
     }
-}
 
-fn decode_hex_32(hex_str: &str) -> Result<[u8; 32], ReindexError> {
-    let bytes = hex::decode(hex_str)
-        .map_err(|e| ReindexError::InvalidArgs(format!("invalid hex: {e}")))?;
-    if bytes.len() != 32 {
-        return Err(ReindexError::InvalidArgs(format!(
-            "expected 32-byte hex, got {} bytes",
-            bytes.len()
-        )));
+    #[test]
+    fn jsonl_prepass_counts_streamed_transactions() {
+        let temp = tempdir().unwrap();
+        let db_path = temp.path().join("txs.jsonl");
+        let mut contents = String::new();
+        for i in 1..=5u8 {
+            let tx = Transaction {
+                version: 1,
+                vin: vec![],
+                vout: vec![TxOut {
+                    value: 50,
+                    script_pubkey: vec![i],
+                }],
+                lock_time: 0,
+            };
+            contents.push_str(&serde_json::to_string(&tx).unwrap());
+            contents.push_str(if i == 3 { "\n  \n\n" } else { "\n" });
+        }
+        fs::write(&db_path, contents).unwrap();
+
+        let total = jsonl_prepass_total(&db_path, DbFormat::Jsonl, false).unwrap();
+        let mut processed = 0u64;
+        stream_transactions(&db_path, DbFormat::Jsonl, |_, _| {
+            processed += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(total, Some(processed));
+        assert_eq!(processed, 5);
+
+        assert_eq!(jsonl_prepass_total(&db_path, DbFormat::Jsonl, true).unwrap(), None);
+        assert_eq!(jsonl_prepass_total(&db_path, DbFormat::JsonArray, false).unwrap(), None);
     }
-    let mut out = [0u8; 32];
-    out.copy_from_slice(&bytes);
-    Ok(out)
 }
 
 fn load_seed_file(path: &Path) -> Result<Vec<String>, ReindexError> {
@@ -1008,84 +2579,76 @@ fn load_seed_file(path: &Path) -> Result<Vec<String>, ReindexError> {
     Ok(peers)
 }
 
+/// Validate a block file against a UTXO JSONL and write the updated set.
+/// `contextual` carries the rules that need the block's place in the
+/// chain, which the node checks the same way when it connects a block.
 fn submit_block(
     block_path: PathBuf,
     utxo_path: PathBuf,
     out_path: PathBuf,
     reward: Option<u64>,
     no_pow_check: bool,
+    contextual: ContextualChecks,
 ) -> Result<(), ReindexError> {
-    let block_json = fs::read_to_string(block_path)?;
-    let block: tenebrium_consensus::Block = serde_json::from_str(&block_json)?;
-
-    if !no_pow_check {
-        let ok = check_pow(&block.header).map_err(|e| ReindexError::Mining(e.to_string()))?;
-        if !ok {
-            return Err(ReindexError::Mining("invalid PoW".to_string()));
-        }
-    }
-
-    let txids = block
-        .txs
-        .iter()
-        .map(|tx| tx.txid_v2())
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(ReindexError::Utxo)?;
-    let root = merkle_root(&txids);
-    if root != block.header.merkle_root {
-        return Err(ReindexError::Mining("merkle root mismatch".to_string()));
+    if fs::metadata(&block_path)?.len() > STREAM_BLOCK_THRESHOLD_BYTES {
+        submit_block_streaming(block_path, utxo_path, out_path, reward, no_pow_check, contextual)
+    } else {
+        submit_block_in_memory(block_path, utxo_path, out_path, reward, no_pow_check, contextual)
     }
+}
 
-    let mut utxos = InMemoryUtxoSet::new();
+/// Validate a block without holding all of its transactions in memory. The
+/// UTXO file is still loaded whole; see [`block_stream`].
+fn submit_block_streaming(
+    block_path: PathBuf,
+    utxo_path: PathBuf,
+    out_path: PathBuf,
+    reward: Option<u64>,
+    no_pow_check: bool,
+    contextual: ContextualChecks,
+) -> Result<(), ReindexError> {
+    let mut utxos = BTreeMapUtxoSet::new();
     let reader = jsonl_reader(&utxo_path);
     reader.for_each(|entry| {
         utxos.insert(entry.outpoint, entry.txout);
         Ok(())
     })?;
 
-    if block.txs.is_empty() {
-        return Err(ReindexError::Mining("empty block".to_string()));
-    }
-
-    let mut total_fees = 0u64;
-    for (i, tx) in block.txs.iter().enumerate() {
-        if i == 0 {
-            if !tx.vin.is_empty() {
-                return Err(ReindexError::Mining("coinbase must have no inputs".to_string()));
-            }
-            apply_coinbase(tx, &mut utxos)?;
-        } else {
-            let fee = Transaction::validate_value_conservation(tx, &utxos)?;
-            total_fees = total_fees.saturating_add(fee);
-            utxos.apply_tx(tx)?;
-        }
-    }
-
-    if let Some(reward) = reward {
-        let coinbase = &block.txs[0];
-        let out_sum = Transaction::sum_outputs(coinbase)?;
-        if out_sum > reward.saturating_add(total_fees) {
-            return Err(ReindexError::Mining("coinbase exceeds reward+fees".to_string()));
-        }
-    }
-
+    let file = BufReader::new(fs::File::open(block_path)?);
+    validate_block_stream(file, &mut utxos, reward, no_pow_check, contextual)?;
     write_utxo_jsonl(&utxos, out_path)?;
     Ok(())
 }
 
-fn apply_coinbase(tx: &Transaction, utxos: &mut InMemoryUtxoSet) -> Result<(), ReindexError> {
-    tx.validate()?;
-    let outpoints = Transaction::make_outpoints(tx)?;
-    for (op, txout) in outpoints.into_iter().zip(tx.vout.iter()) {
-        if utxos.get(&op).is_some() {
-            return Err(ReindexError::Mining("coinbase output already exists".to_string()));
-        }
-        utxos.insert(op, txout.clone());
-    }
+fn submit_block_in_memory(
+    block_path: PathBuf,
+    utxo_path: PathBuf,
+    out_path: PathBuf,
+    reward: Option<u64>,
+    no_pow_check: bool,
+    contextual: ContextualChecks,
+) -> Result<(), ReindexError> {
+    let block_json = fs::read_to_string(block_path)?;
+    let block: tenebrium_consensus::Block = serde_json::from_str(&block_json)?;
+
+    let mut utxos = BTreeMapUtxoSet::new();
+    let reader = jsonl_reader(&utxo_path);
+    reader.for_each(|entry| {
+        utxos.insert(entry.outpoint, entry.txout);
+        Ok(())
+    })?;
+
+    // Without --reward the coinbase amount is not limited.
+    let subsidy = reward.unwrap_or(u64::MAX);
+    block.check_contextual(contextual)?;
+    block.validate(&utxos, subsidy, no_pow_check)?;
+    utxos.apply_block(&block.txs, subsidy)?;
+
+    write_utxo_jsonl(&utxos, out_path)?;
     Ok(())
 }
 
-fn write_utxo_jsonl(utxos: &InMemoryUtxoSet, out_path: PathBuf) -> Result<(), ReindexError> {
+fn write_utxo_jsonl(utxos: &BTreeMapUtxoSet, out_path: PathBuf) -> Result<(), ReindexError> {
     let file = fs::File::create(out_path)?;
     let mut writer = BufWriter::new(file);
     for (outpoint, txout) in utxos.entries() {
@@ -1096,6 +2659,7 @@ fn write_utxo_jsonl(utxos: &InMemoryUtxoSet, out_path: PathBuf) -> Result<(), Re
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn utxo_reindex(
     db: PathBuf,
     db_format: DbFormat,
@@ -1109,26 +2673,29 @@ fn utxo_reindex(
     verify_read: bool,
     resume: bool,
     dry_run: bool,
+    quiet: bool,
 ) -> Result<(), ReindexError> {
-    println!("utxo-reindex (poc)");
-    println!("  db: {}", db.display());
-    println!("  db_format: {db_format:?}");
-    if let Some(ref utxo) = utxo {
-        println!("  utxo: {}", utxo.display());
-        println!("  utxo_format: {utxo_format:?}");
-    }
-    println!("  out: {}", out.display());
-    println!("  out_format: {out_format:?}");
-    if let Some(ref report) = report {
-        println!("  report: {}", report.display());
-    }
-    if let Some(ref checkpoint) = checkpoint {
-        println!("  checkpoint: {}", checkpoint.display());
-    }
-    println!("  verify: {verify}");
-    println!("  verify_read: {verify_read}");
-    println!("  resume: {resume}");
-    println!("  dry_run: {dry_run}");
+    if !quiet {
+        println!("utxo-reindex (poc)");
+        println!("  db: {}", db.display());
+        println!("  db_format: {db_format:?}");
+        if let Some(ref utxo) = utxo {
+            println!("  utxo: {}", utxo.display());
+            println!("  utxo_format: {utxo_format:?}");
+        }
+        println!("  out: {}", out.display());
+        println!("  out_format: {out_format:?}");
+        if let Some(ref report) = report {
+            println!("  report: {}", report.display());
+        }
+        if let Some(ref checkpoint) = checkpoint {
+            println!("  checkpoint: {}", checkpoint.display());
+        }
+        println!("  verify: {verify}");
+        println!("  verify_read: {verify_read}");
+        println!("  resume: {resume}");
+        println!("  dry_run: {dry_run}");
+    }
 
     let started_at = now_unix_seconds();
     let mut report_obj = ReindexReport::new(started_at);
@@ -1195,8 +2762,10 @@ fn utxo_reindex(
         let mut writer = BufWriter::new(file);
         let mut seen: HashSet<OutPoint> = HashSet::new();
         let mut dupe_count = 0u64;
+        let mut progress = ReindexProgress::new(jsonl_prepass_total(&db, db_format, quiet)?, quiet);
 
         stream_transactions(&db, db_format, |_, tx| {
+            progress.tick();
             if let Err(err) = tx.validate() {
                 let txid_v1 = tx.txid_v1().ok();
                 report_obj.skipped += 1;
@@ -1212,6 +2781,9 @@ fn utxo_reindex(
             report_obj.total_outputs += tx.vout.len() as u64;
 
             let pairs = map_outpoints_v1_to_v2(&tx)?;
+            if verify {
+                record_vout_coverage(&tx, &pairs, &mut report_obj);
+            }
             for (v1, v2) in pairs {
                 if verify && !seen.insert(v2.clone()) {
                     dupe_count += 1;
@@ -1232,6 +2804,7 @@ fn utxo_reindex(
         }
 
         writer.flush()?;
+        progress.finish();
     } else {
         let txs = load_transactions(&db, db_format)?;
         for (idx, tx) in txs.into_iter().enumerate() {
@@ -1253,6 +2826,9 @@ fn utxo_reindex(
         report_obj.total_outputs += tx.vout.len() as u64;
 
         let pairs = map_outpoints_v1_to_v2(&tx)?;
+        if verify {
+            record_vout_coverage(&tx, &pairs, &mut report_obj);
+        }
         for (v1, v2) in pairs {
             mappings.push(MappingEntry { v1, v2 });
         }
@@ -1282,6 +2858,94 @@ fn utxo_reindex(
 }
 
 const CHECKPOINT_INTERVAL: usize = 1000;
+/// Transactions between two progress lines
+const PROGRESS_INTERVAL: u64 = 10_000;
+/// JSONL inputs larger than this are not pre-counted; the extra full read
+/// would cost more than the ETA is worth.
+const PROGRESS_PREPASS_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Periodic `processed N[/total]` lines on stderr, with an ETA once the total
+/// is known.
+struct ReindexProgress {
+    total: Option<u64>,
+    done: u64,
+    started: std::time::Instant,
+    quiet: bool,
+}
+
+impl ReindexProgress {
+    fn new(total: Option<u64>, quiet: bool) -> Self {
+        Self {
+            total,
+            done: 0,
+            started: std::time::Instant::now(),
+            quiet,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.done += 1;
+        if self.done.is_multiple_of(PROGRESS_INTERVAL) {
+            self.print();
+        }
+    }
+
+    fn finish(&self) {
+        if !self.done.is_multiple_of(PROGRESS_INTERVAL) {
+            self.print();
+        }
+    }
+
+    fn print(&self) {
+        if self.quiet {
+            return;
+        }
+        let Some(total) = self.total.filter(|total| *total > 0) else {
+            eprintln!("processed {}", self.done);
+            return;
+        };
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let remaining = total.saturating_sub(self.done);
+        let eta = elapsed / self.done.max(1) as f64 * remaining as f64;
+        eprintln!(
+            "processed {}/{} ({:.1}%), eta {:.0}s",
+            self.done,
+            total,
+            self.done as f64 * 100.0 / total as f64,
+            eta
+        );
+    }
+}
+
+/// Number of transactions in a JSONL input, for progress reporting. `None`
+/// for JSON arrays (no cheap way to count), quiet runs, and inputs above
+/// [`PROGRESS_PREPASS_MAX_BYTES`].
+fn jsonl_prepass_total(path: &Path, format: DbFormat, quiet: bool) -> Result<Option<u64>, ReindexError> {
+    if quiet || !matches!(format, DbFormat::Jsonl) {
+        return Ok(None);
+    }
+    if fs::metadata(path)?.len() > PROGRESS_PREPASS_MAX_BYTES {
+        return Ok(None);
+    }
+    count_jsonl_lines(path).map(Some)
+}
+
+/// Count non-blank lines, matching what [`stream_transactions`] parses.
+fn count_jsonl_lines(path: &Path) -> Result<u64, ReindexError> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut line = Vec::new();
+    let mut count = 0u64;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if !line.trim_ascii().is_empty() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum DbFormat {
@@ -1316,6 +2980,17 @@ struct Checkpoint {
     report: ReindexReport,
 }
 
+/// Record an error if `pairs` don't map each of `tx`'s vout indices exactly once.
+fn record_vout_coverage(tx: &Transaction, pairs: &[(OutPoint, OutPoint)], report: &mut ReindexReport) {
+    if let Err(msg) = check_vout_coverage(tx, pairs) {
+        report.record_error(ReindexErrorEntry::new(
+            ReindexErrorKind::Other,
+            tx.txid_v1().ok(),
+            msg,
+        ));
+    }
+}
+
 fn verify_no_duplicate_v2(
     mappings: &[MappingEntry],
     report: &mut ReindexReport,
@@ -1486,6 +3161,7 @@ where
 }
 
 
+#[allow(clippy::too_many_arguments)]
 fn process_utxo_entries(
     utxo_path: &Path,
     utxo_format: UtxoFormat,
@@ -1,11 +1,64 @@
 use std::collections::{HashMap, HashSet};
-use tenebrium_utxo::{OutPoint, Transaction, UtxoError, UtxoSet};
+use std::time::{Duration, Instant};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tenebrium_consensus::params::COINBASE_MATURITY;
+use tenebrium_utxo::{fee_per_byte, InMemoryUtxoSet, OutPoint, Transaction, UtxoError, UtxoSet, DATA_CARRIER_MARKER};
+
+/// Largest script_sig relayed by default: a P2PKH spend is exactly a 64-byte
+/// signature plus a 32-byte pubkey. Blocks are still checked against the
+/// consensus `MAX_SCRIPT_SIZE`.
+pub const MAX_STANDARD_SCRIPT_SIG: usize = 96;
+/// Largest sigop count relayed for a single transaction, a fifth of the
+/// default block limit.
+pub const MAX_STANDARD_TX_SIGOPS: usize = 4_000;
+/// Default dust threshold: smaller outputs cost more to spend than they are
+/// worth and only bloat the UTXO set.
+pub const MIN_OUTPUT_VALUE: u64 = 546;
+/// Default fee-rate increase a replacement must offer over every tx it
+/// evicts, so each replacement pays for its own relay.
+pub const MIN_RBF_BUMP: f64 = 1.0;
+/// Inputs with a sequence at or below this opt their tx in to replacement.
+pub const MAX_RBF_SEQUENCE: u32 = 0xffff_fffd;
+/// Default cap on transactions waiting in the orphan pool for a parent.
+pub const MAX_ORPHANS: usize = 100;
+/// Default time an orphan waits for its parent before being dropped.
+pub const ORPHAN_TTL_SECS: u64 = 20 * 60;
+/// Default cap on a single transaction's fee, two initial block subsidies.
+/// Anything above it is far more likely a corrupt input value or a
+/// fat-fingered spend than a real payment, so it is not relayed.
+pub const MAX_TX_FEE: u64 = 100_0000_0000;
 
 #[derive(Debug, Clone)]
 pub struct MempoolConfig {
     pub max_txs: usize,
     pub max_total_bytes: usize,
     pub min_fee_rate: f64,
+    /// Keep at most this many entries in RAM; lower fee-rate overflow is
+    /// spilled to the disk store given to [`Mempool::with_spill`]. Ignored
+    /// without a spill store.
+    pub max_memory_txs: Option<usize>,
+    /// Policy limit on each input's script_sig length
+    pub max_standard_script_sig: usize,
+    /// Policy limit on [`Transaction::sigop_count`]
+    pub max_standard_tx_sigops: usize,
+    /// Outputs below this value are rejected as dust (0 disables the check)
+    pub dust_threshold: u64,
+    /// Outputs whose script starts with this byte are data carriers and
+    /// exempt from the dust check
+    pub dust_exempt_marker: Option<u8>,
+    /// Fee-rate increase a replacement needs over each conflict it evicts
+    pub min_rbf_bump: f64,
+    /// Orphan pool capacity; the oldest orphan is evicted to make room
+    pub max_orphans: usize,
+    /// Orphans older than this are dropped
+    pub orphan_ttl_secs: u64,
+    /// Transactions paying a larger fee are rejected (0 disables the check).
+    /// Policy only: blocks may still include such a transaction.
+    pub max_tx_fee: u64,
+    /// Blocks a coinbase output must be buried under before a spend of it
+    /// is accepted; should match the consensus `coinbase_maturity`
+    pub coinbase_maturity: u32,
 }
 
 impl Default for MempoolConfig {
@@ -14,6 +67,16 @@ impl Default for MempoolConfig {
             max_txs: 10_000,
             max_total_bytes: 50 * 1024 * 1024,
             min_fee_rate: 0.0,
+            max_memory_txs: None,
+            max_standard_script_sig: MAX_STANDARD_SCRIPT_SIG,
+            max_standard_tx_sigops: MAX_STANDARD_TX_SIGOPS,
+            dust_threshold: MIN_OUTPUT_VALUE,
+            dust_exempt_marker: Some(DATA_CARRIER_MARKER),
+            min_rbf_bump: MIN_RBF_BUMP,
+            max_orphans: MAX_ORPHANS,
+            orphan_ttl_secs: ORPHAN_TTL_SECS,
+            max_tx_fee: MAX_TX_FEE,
+            coinbase_maturity: COINBASE_MATURITY,
         }
     }
 }
@@ -32,6 +95,38 @@ pub enum MempoolError {
     BytesLimit,
     #[error("fee rate too low")]
     LowFee,
+    #[error("non-standard transaction: {0}")]
+    NonStandard(String),
+    #[error("spill store error: {0}")]
+    Sled(#[from] sled::Error),
+    #[error("spill store JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("replacement fee rate {offered:.2} below required {required:.2}")]
+    RbfUnderpriced { offered: f64, required: f64 },
+    #[error("fee {fee} exceeds the {max} cap")]
+    AbsurdFee { fee: u64, max: u64 },
+    #[error("spends coinbase {outpoint:?} from height {created}, immature at height {height}")]
+    ImmatureCoinbase {
+        outpoint: OutPoint,
+        created: u32,
+        height: u32,
+    },
+    #[error("spilled tx missing from store")]
+    SpillMissing,
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// How [`Mempool::add_tx_replace`] settles a double spend of existing
+/// entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    /// Opt-in replace-by-fee: every conflict must signal replaceability and
+    /// be outbid on fee rate by `min_rbf_bump`.
+    OptIn,
+    /// Highest fee wins regardless of signalling: the new tx must pay more
+    /// than the conflicts combined. Used when re-adding reorged txs.
+    HigherFee,
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +136,98 @@ pub struct MempoolEntry {
     pub txid_v2: [u8; 32],
     pub fee: u64,
     pub size_bytes: usize,
+    /// Monotonic acceptance sequence number (first-seen order)
+    pub arrival: u64,
+}
+
+/// Bookkeeping kept in RAM for an entry whose transaction lives on disk.
+#[derive(Debug, Clone)]
+struct SpilledEntry {
+    txid_v1: [u8; 32],
+    inputs: Vec<OutPoint>,
+    fee: u64,
+    size_bytes: usize,
+    arrival: u64,
+}
+
+impl SpilledEntry {
+    fn to_entry(&self, txid_v2: [u8; 32], tx: Transaction) -> MempoolEntry {
+        MempoolEntry {
+            tx,
+            txid_v1: self.txid_v1,
+            txid_v2,
+            fee: self.fee,
+            size_bytes: self.size_bytes,
+            arrival: self.arrival,
+        }
+    }
+}
+
+/// Transactions spending outputs we have not seen yet, e.g. a child relayed
+/// ahead of its parent. Indexed by the txids their inputs reference so a
+/// newly accepted parent finds its waiting children.
+#[derive(Debug, Default)]
+struct OrphanPool {
+    txs: HashMap<[u8; 32], Orphan>,
+    by_parent: HashMap<[u8; 32], HashSet<[u8; 32]>>,
+    next_seq: u64,
+}
+
+#[derive(Debug)]
+struct Orphan {
+    tx: Transaction,
+    seq: u64,
+    added: Instant,
+}
+
+impl OrphanPool {
+    fn insert(&mut self, txid: [u8; 32], tx: Transaction, added: Instant) {
+        for vin in &tx.vin {
+            self.by_parent.entry(vin.prevout.txid).or_default().insert(txid);
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.txs.insert(txid, Orphan { tx, seq, added });
+    }
+
+    fn remove(&mut self, txid: &[u8; 32]) -> Option<Transaction> {
+        let orphan = self.txs.remove(txid)?;
+        for vin in &orphan.tx.vin {
+            if let Some(children) = self.by_parent.get_mut(&vin.prevout.txid) {
+                children.remove(txid);
+                if children.is_empty() {
+                    self.by_parent.remove(&vin.prevout.txid);
+                }
+            }
+        }
+        Some(orphan.tx)
+    }
+
+    fn oldest(&self) -> Option<[u8; 32]> {
+        self.txs.iter().min_by_key(|(_, orphan)| orphan.seq).map(|(txid, _)| *txid)
+    }
+
+    fn expire(&mut self, now: Instant, ttl: Duration) {
+        let stale: Vec<[u8; 32]> = self
+            .txs
+            .iter()
+            .filter(|(_, orphan)| now.saturating_duration_since(orphan.added) >= ttl)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in stale {
+            self.remove(&txid);
+        }
+    }
+
+    /// Orphans spending an output of `parent`, oldest first.
+    fn children_of(&self, parent: &[u8; 32]) -> Vec<[u8; 32]> {
+        let Some(children) = self.by_parent.get(parent) else {
+            return Vec::new();
+        };
+        let mut out: Vec<&[u8; 32]> = children.iter().collect();
+        out.sort_by_key(|txid| self.txs.get(*txid).map(|orphan| orphan.seq));
+        out.into_iter().copied().collect()
+    }
 }
 
 #[derive(Debug, Default)]
@@ -48,8 +235,19 @@ pub struct Mempool {
     cfg: MempoolConfig,
     map_v2: HashMap<[u8; 32], MempoolEntry>,
     map_v1: HashMap<[u8; 32], [u8; 32]>,
-    spent: HashSet<OutPoint>,
+    /// Each outpoint spent by an entry, with the v2 txid spending it
+    spent_by: HashMap<OutPoint, [u8; 32]>,
     total_bytes: usize,
+    next_arrival: u64,
+    spill: Option<sled::Tree>,
+    spilled: HashMap<[u8; 32], SpilledEntry>,
+    orphans: OrphanPool,
+    /// Height and median-time-past of the block that would extend the
+    /// applied tip; transactions not final there, or spending a coinbase
+    /// that is still immature there, are refused. `None` skips the finality
+    /// check, but coinbase spends are then refused outright: maturity cannot
+    /// be judged without a height.
+    lock_point: Option<(u32, u32)>,
 }
 
 impl Mempool {
@@ -58,14 +256,36 @@ impl Mempool {
             cfg,
             map_v2: HashMap::new(),
             map_v1: HashMap::new(),
-            spent: HashSet::new(),
+            spent_by: HashMap::new(),
             total_bytes: 0,
+            next_arrival: 0,
+            spill: None,
+            spilled: HashMap::new(),
+            orphans: OrphanPool::default(),
+            lock_point: None,
         }
     }
 
-    #[allow(dead_code)]
+    /// Like [`Mempool::new`], spilling entries over `cfg.max_memory_txs` to
+    /// `tree`. The mempool is not persistent, so the tree is cleared first.
+    pub fn with_spill(cfg: MempoolConfig, tree: sled::Tree) -> Result<Self, MempoolError> {
+        tree.clear()?;
+        let mut mempool = Self::new(cfg);
+        mempool.spill = Some(tree);
+        Ok(mempool)
+    }
+
     pub fn len(&self) -> usize {
-        self.map_v2.len()
+        self.map_v2.len() + self.spilled.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of entries currently spilled to disk.
+    pub fn spilled_len(&self) -> usize {
+        self.spilled.len()
     }
 
     #[allow(dead_code)]
@@ -74,7 +294,7 @@ impl Mempool {
     }
 
     pub fn contains(&self, txid: &[u8; 32]) -> bool {
-        self.map_v2.contains_key(txid)
+        self.map_v2.contains_key(txid) || self.spilled.contains_key(txid)
     }
 
     pub fn contains_v1(&self, txid: &[u8; 32]) -> bool {
@@ -82,43 +302,170 @@ impl Mempool {
     }
 
     pub fn get_tx(&self, txid: &[u8; 32]) -> Option<Transaction> {
-        self.map_v2.get(txid).map(|entry| entry.tx.clone())
+        match self.map_v2.get(txid) {
+            Some(entry) => Some(entry.tx.clone()),
+            None if self.spilled.contains_key(txid) => self.load_spilled(txid).ok(),
+            None => None,
+        }
     }
 
     pub fn get_tx_v1(&self, txid: &[u8; 32]) -> Option<Transaction> {
         let v2 = self.map_v1.get(txid)?;
-        self.map_v2.get(v2).map(|entry| entry.tx.clone())
+        self.get_tx(v2)
     }
 
+    /// Accept `tx`, replacing conflicting entries when they opted in to
+    /// replacement and `tx` outbids them; see [`Mempool::add_tx_replace`].
     pub fn add_tx(&mut self, tx: Transaction, utxos: &dyn UtxoSet) -> Result<(), MempoolError> {
+        self.add_tx_replace(tx, utxos, Replacement::OptIn).map(|_| ())
+    }
+
+    /// Accept `tx`, settling double spends of existing entries by `policy`.
+    /// Under [`Replacement::OptIn`] every conflict must signal
+    /// replaceability (an input sequence at most [`MAX_RBF_SEQUENCE`]) and
+    /// `tx`'s fee rate must beat each evicted entry's by `min_rbf_bump`;
+    /// under [`Replacement::HigherFee`] `tx`'s fee must exceed theirs
+    /// combined. Descendants of the conflicts are evicted with them and
+    /// count towards what `tx` has to outbid. Returns the evicted txids,
+    /// parents first, so the caller can relay the replacement; if `tx` is
+    /// refused after the evictions, they are put back.
+    pub fn add_tx_replace(
+        &mut self,
+        tx: Transaction,
+        utxos: &dyn UtxoSet,
+        policy: Replacement,
+    ) -> Result<Vec<[u8; 32]>, MempoolError> {
+        let conflicts = self.conflicts(&tx);
+        if conflicts.is_empty() {
+            return self.add_tx_first_seen(tx, utxos).map(|()| Vec::new());
+        }
+        if policy == Replacement::OptIn
+            && conflicts
+                .iter()
+                .filter_map(|txid| self.entry(txid))
+                .any(|entry| !signals_rbf(&entry.tx))
+        {
+            return Err(MempoolError::DoubleSpend(self.first_spent_input(&tx)));
+        }
+        tx.validate_as(false)?;
+        let fee = self.fee_of_new(&tx, utxos)?;
+        let evicted: Vec<[u8; 32]> = conflicts
+            .iter()
+            .flat_map(|txid| self.with_descendants(txid))
+            .collect();
+        match policy {
+            Replacement::OptIn => {
+                let offered = fee_per_byte(fee, tx.vsize());
+                let required = evicted
+                    .iter()
+                    .filter_map(|txid| self.entry(txid))
+                    .map(|entry| fee_rate(&entry))
+                    .fold(0.0, f64::max)
+                    + self.cfg.min_rbf_bump;
+                if offered < required {
+                    return Err(MempoolError::RbfUnderpriced { offered, required });
+                }
+            }
+            Replacement::HigherFee => {
+                let evicted_fees = evicted
+                    .iter()
+                    .filter_map(|txid| self.fee_of(txid))
+                    .fold(0u64, |acc, fee| acc.saturating_add(fee));
+                if fee <= evicted_fees {
+                    return Err(MempoolError::DoubleSpend(self.first_spent_input(&tx)));
+                }
+            }
+        }
+        let mut replaced = Vec::new();
+        let mut outcome = Ok(());
+        for txid in &conflicts {
+            match self.remove_with_descendants(txid) {
+                Ok(entries) => replaced.extend(entries),
+                Err(err) => {
+                    outcome = Err(err);
+                    break;
+                }
+            }
+        }
+        if let Err(err) = outcome.and_then(|()| self.add_tx_first_seen(tx, utxos)) {
+            for entry in replaced {
+                let _ = self.add_tx_first_seen(entry.tx, utxos);
+            }
+            return Err(err);
+        }
+        Ok(replaced.iter().map(|entry| entry.txid_v2).collect())
+    }
+
+    /// [`Mempool::add_tx`] without replacement: any double spend of an
+    /// entry is rejected.
+    pub fn add_tx_first_seen(&mut self, tx: Transaction, utxos: &dyn UtxoSet) -> Result<(), MempoolError> {
+        tx.validate_as(false)?;
         let txid_v1 = tx.txid_v1()?;
         let txid_v2 = tx.txid_v2()?;
-        if self.map_v2.contains_key(&txid_v2) || self.map_v1.contains_key(&txid_v1) {
+        if self.contains(&txid_v2) || self.map_v1.contains_key(&txid_v1) {
             return Err(MempoolError::DuplicateTx);
         }
+        if let Some((height, mtp)) = self.lock_point {
+            tx.check_final(height, mtp)?;
+        }
+        // Same rule as block connection: the tx must be minable in the block
+        // after the tip. Height 0 makes every coinbase output immature.
+        let height = self.lock_point.map_or(0, |(height, _)| height);
+        for vin in &tx.vin {
+            let Some(meta) = utxos.meta(&vin.prevout) else { continue };
+            if !meta.is_mature(height, self.cfg.coinbase_maturity) {
+                return Err(MempoolError::ImmatureCoinbase {
+                    outpoint: vin.prevout.clone(),
+                    created: meta.height,
+                    height,
+                });
+            }
+        }
+
+        if tx.sigop_count() > self.cfg.max_standard_tx_sigops {
+            return Err(MempoolError::NonStandard(format!(
+                "{} sigops (max {})",
+                tx.sigop_count(),
+                self.cfg.max_standard_tx_sigops
+            )));
+        }
+        for vin in &tx.vin {
+            if vin.script_sig.len() > self.cfg.max_standard_script_sig {
+                return Err(MempoolError::NonStandard(format!(
+                    "script_sig {} bytes (max {})",
+                    vin.script_sig.len(),
+                    self.cfg.max_standard_script_sig
+                )));
+            }
+        }
+
+        if self.cfg.dust_threshold > 0 {
+            tx.check_dust_with_marker(self.cfg.dust_threshold, self.cfg.dust_exempt_marker)?;
+        }
 
         for vin in &tx.vin {
-            if self.spent.contains(&vin.prevout) {
+            if self.spent_by.contains_key(&vin.prevout) {
                 return Err(MempoolError::DoubleSpend(vin.prevout.clone()));
             }
         }
 
-        let fee = Transaction::validate_value_conservation(&tx, utxos)?;
-        let size_bytes = tx.canonical_bytes_v2()?.len();
-        let fee_rate = if size_bytes == 0 {
-            0.0
-        } else {
-            fee as f64 / size_bytes as f64
-        };
-        if fee_rate < self.cfg.min_fee_rate {
+        let fee = self.fee_of_new(&tx, utxos)?;
+        if self.cfg.max_tx_fee > 0 && fee > self.cfg.max_tx_fee {
+            return Err(MempoolError::AbsurdFee {
+                fee,
+                max: self.cfg.max_tx_fee,
+            });
+        }
+        let size_bytes = tx.vsize();
+        if fee_per_byte(fee, size_bytes) < self.cfg.min_fee_rate {
             return Err(MempoolError::LowFee);
         }
 
-        if self.map_v2.len() + 1 > self.cfg.max_txs
+        if self.len() + 1 > self.cfg.max_txs
             || self.total_bytes + size_bytes > self.cfg.max_total_bytes
         {
             self.evict_low_fee()?;
-            if self.map_v2.len() + 1 > self.cfg.max_txs {
+            if self.len() + 1 > self.cfg.max_txs {
                 return Err(MempoolError::Full);
             }
             if self.total_bytes + size_bytes > self.cfg.max_total_bytes {
@@ -127,9 +474,11 @@ impl Mempool {
         }
 
         for vin in &tx.vin {
-            self.spent.insert(vin.prevout.clone());
+            self.spent_by.insert(vin.prevout.clone(), txid_v2);
         }
         self.total_bytes += size_bytes;
+        let arrival = self.next_arrival;
+        self.next_arrival += 1;
         self.map_v1.insert(txid_v1, txid_v2);
         self.map_v2.insert(
             txid_v2,
@@ -139,56 +488,495 @@ impl Mempool {
                 txid_v2,
                 fee,
                 size_bytes,
+                arrival,
             },
         );
+        if let Err(err) = self.spill_overflow() {
+            // Undo the insert so an error always means the tx was not added.
+            self.remove_tx(&txid_v2)?;
+            return Err(err);
+        }
         Ok(())
     }
 
-    pub fn remove_tx(&mut self, txid: &[u8; 32]) -> Option<MempoolEntry> {
-        let entry = self.map_v2.remove(txid)?;
+    /// Hold `tx`, whose inputs are not all known yet, until a parent is
+    /// accepted; see [`Mempool::process_orphans`]. Expired orphans are
+    /// dropped first, then the oldest ones while the pool is full.
+    pub fn add_orphan(&mut self, tx: Transaction) -> Result<(), MempoolError> {
+        tx.validate_as(false)?;
+        let txid = tx.txid_v2()?;
+        if self.contains(&txid) {
+            return Err(MempoolError::DuplicateTx);
+        }
+        if self.cfg.max_orphans == 0 {
+            return Err(MempoolError::Full);
+        }
+        let now = Instant::now();
+        self.orphans.expire(now, Duration::from_secs(self.cfg.orphan_ttl_secs));
+        if self.orphans.txs.contains_key(&txid) {
+            return Ok(());
+        }
+        while self.orphans.txs.len() >= self.cfg.max_orphans {
+            let Some(oldest) = self.orphans.oldest() else { break };
+            self.orphans.remove(&oldest);
+        }
+        self.orphans.insert(txid, tx, now);
+        Ok(())
+    }
+
+    /// Retry the orphans waiting on `parent`, which was just accepted, and
+    /// in turn those waiting on each promoted orphan. Returns the promoted
+    /// txids in acceptance order. Orphans still missing an input stay; any
+    /// other rejection drops them.
+    pub fn process_orphans(&mut self, parent: &[u8; 32], utxos: &dyn UtxoSet) -> Vec<[u8; 32]> {
+        self.orphans
+            .expire(Instant::now(), Duration::from_secs(self.cfg.orphan_ttl_secs));
+        let mut promoted = Vec::new();
+        let mut queue = vec![*parent];
+        while let Some(accepted) = queue.pop() {
+            for txid in self.orphans.children_of(&accepted) {
+                let Some(tx) = self.orphans.txs.get(&txid).map(|orphan| orphan.tx.clone()) else {
+                    continue;
+                };
+                match self.add_tx(tx, utxos) {
+                    Ok(()) => {
+                        self.orphans.remove(&txid);
+                        promoted.push(txid);
+                        queue.push(txid);
+                    }
+                    Err(MempoolError::Utxo(UtxoError::MissingUtxo(_))) => {}
+                    Err(_) => {
+                        self.orphans.remove(&txid);
+                    }
+                }
+            }
+        }
+        promoted
+    }
+
+    /// Number of transactions waiting in the orphan pool.
+    #[allow(dead_code)]
+    pub fn orphan_len(&self) -> usize {
+        self.orphans.txs.len()
+    }
+
+    /// Txids of entries spending any input of `tx`, sorted.
+    pub fn conflicts(&self, tx: &Transaction) -> Vec<[u8; 32]> {
+        let mut out: Vec<[u8; 32]> = tx
+            .vin
+            .iter()
+            .filter_map(|vin| self.spender_of(&vin.prevout))
+            .collect();
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    /// v2 txid of the entry spending `outpoint`, if any.
+    pub fn spender_of(&self, outpoint: &OutPoint) -> Option<[u8; 32]> {
+        self.spent_by.get(outpoint).copied()
+    }
+
+    /// Fee of a tx not yet in the pool. Inputs may spend confirmed outputs
+    /// in `utxos` or outputs of entries (child pays for parent).
+    fn fee_of_new(&self, tx: &Transaction, utxos: &dyn UtxoSet) -> Result<u64, MempoolError> {
+        let mut view = InMemoryUtxoSet::new();
+        for vin in &tx.vin {
+            let prev = utxos.get(&vin.prevout).or_else(|| {
+                let parent = self.entry(&vin.prevout.txid)?;
+                parent.tx.vout.get(vin.prevout.vout as usize).cloned()
+            });
+            if let Some(prev) = prev {
+                view.insert(vin.prevout.clone(), prev);
+            }
+        }
+        Ok(Transaction::validate_value_conservation(tx, &view)?)
+    }
+
+    /// Entries spending an output of `txid`, sorted.
+    fn children_of(&self, txid: &[u8; 32]) -> Vec<[u8; 32]> {
+        let mut out: Vec<[u8; 32]> = self
+            .map_v2
+            .values()
+            .filter(|entry| entry.tx.vin.iter().any(|vin| &vin.prevout.txid == txid))
+            .map(|entry| entry.txid_v2)
+            .chain(
+                self.spilled
+                    .iter()
+                    .filter(|(_, spilled)| spilled.inputs.iter().any(|op| &op.txid == txid))
+                    .map(|(child, _)| *child),
+            )
+            .collect();
+        out.sort();
+        out
+    }
+
+    /// `txid` followed by every in-pool descendant, each after its parent.
+    fn with_descendants(&self, txid: &[u8; 32]) -> Vec<[u8; 32]> {
+        let mut out = vec![*txid];
+        let mut index = 0;
+        while index < out.len() {
+            for child in self.children_of(&out[index]) {
+                if !out.contains(&child) {
+                    out.push(child);
+                }
+            }
+            index += 1;
+        }
+        out
+    }
+
+    /// Remove `txid` and the entries that spend its outputs, which can no
+    /// longer be mined without it. Parents come before their children.
+    /// Children are removed first, so an error leaves no entry whose parent
+    /// is gone.
+    pub fn remove_with_descendants(&mut self, txid: &[u8; 32]) -> Result<Vec<MempoolEntry>, MempoolError> {
+        let mut removed = Vec::new();
+        for txid in self.with_descendants(txid).iter().rev() {
+            removed.extend(self.remove_tx(txid)?);
+        }
+        removed.reverse();
+        Ok(removed)
+    }
+
+    /// Entries in block order for child-pays-for-parent selection. Each
+    /// round picks the remaining entry whose package (itself plus its
+    /// remaining in-pool ancestors) has the best aggregate fee rate, ties
+    /// broken by txid, and emits that package parents first.
+    pub fn entries_in_topological_fee_order(&self) -> Vec<MempoolEntry> {
+        let entries = self.entries();
+        let index: HashMap<[u8; 32], usize> = entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (entry.txid_v2, i))
+            .collect();
+        let parents: Vec<Vec<usize>> = entries
+            .iter()
+            .map(|entry| {
+                let mut parents: Vec<usize> = entry
+                    .tx
+                    .vin
+                    .iter()
+                    .filter_map(|vin| index.get(&vin.prevout.txid).copied())
+                    .collect();
+                parents.sort_unstable();
+                parents.dedup();
+                parents
+            })
+            .collect();
+
+        let mut done = vec![false; entries.len()];
+        let mut order = Vec::with_capacity(entries.len());
+        while order.len() < entries.len() {
+            let mut best: Option<(usize, Vec<usize>, f64)> = None;
+            for i in (0..entries.len()).filter(|&i| !done[i]) {
+                let package = package_of(i, &parents, &done);
+                let fee: u64 = package.iter().map(|&j| entries[j].fee).sum();
+                let size: usize = package.iter().map(|&j| entries[j].size_bytes).sum();
+                let rate = fee_per_byte(fee, size);
+                let better = match &best {
+                    None => true,
+                    Some((b, _, best_rate)) => {
+                        rate > *best_rate || (rate == *best_rate && entries[i].txid_v2 < entries[*b].txid_v2)
+                    }
+                };
+                if better {
+                    best = Some((i, package, rate));
+                }
+            }
+            let Some((_, package, _)) = best else { break };
+            for j in package {
+                done[j] = true;
+                order.push(entries[j].clone());
+            }
+        }
+        order
+    }
+
+    /// First input of `tx` already spent by an entry, for error reporting.
+    fn first_spent_input(&self, tx: &Transaction) -> OutPoint {
+        tx.vin
+            .iter()
+            .find(|vin| self.spent_by.contains_key(&vin.prevout))
+            .map(|vin| vin.prevout.clone())
+            .unwrap_or(OutPoint::NULL)
+    }
+
+    /// The entry for `txid`, reading the tx back if it was spilled.
+    fn entry(&self, txid: &[u8; 32]) -> Option<MempoolEntry> {
+        match self.map_v2.get(txid) {
+            Some(entry) => Some(entry.clone()),
+            None => {
+                let spilled = self.spilled.get(txid)?;
+                let tx = self.load_spilled(txid).ok()?;
+                Some(spilled.to_entry(*txid, tx))
+            }
+        }
+    }
+
+    fn fee_of(&self, txid: &[u8; 32]) -> Option<u64> {
+        self.map_v2
+            .get(txid)
+            .map(|entry| entry.fee)
+            .or_else(|| self.spilled.get(txid).map(|spilled| spilled.fee))
+    }
+
+    /// Remove `txid`, returning its entry. A spilled tx is read back before
+    /// anything is dropped, so on error the entry is still in the pool.
+    pub fn remove_tx(&mut self, txid: &[u8; 32]) -> Result<Option<MempoolEntry>, MempoolError> {
+        let entry = match self.map_v2.remove(txid) {
+            Some(entry) => entry,
+            None => {
+                if !self.spilled.contains_key(txid) {
+                    return Ok(None);
+                }
+                let tx = self.load_spilled(txid)?;
+                let Some(spilled) = self.spilled.remove(txid) else {
+                    return Ok(None);
+                };
+                if let Some(tree) = &self.spill {
+                    // A leftover record is harmless: the tree is cleared on startup.
+                    let _ = tree.remove(txid);
+                }
+                spilled.to_entry(*txid, tx)
+            }
+        };
         self.map_v1.remove(&entry.txid_v1);
         for vin in &entry.tx.vin {
-            self.spent.remove(&vin.prevout);
+            self.spent_by.remove(&vin.prevout);
         }
         self.total_bytes = self.total_bytes.saturating_sub(entry.size_bytes);
-        Some(entry)
+        self.promote_spilled();
+        Ok(Some(entry))
     }
 
-    pub fn remove_tx_v1(&mut self, txid: &[u8; 32]) -> Option<MempoolEntry> {
-        let v2 = self.map_v1.get(txid).copied()?;
+    pub fn remove_tx_v1(&mut self, txid: &[u8; 32]) -> Result<Option<MempoolEntry>, MempoolError> {
+        let Some(v2) = self.map_v1.get(txid).copied() else {
+            return Ok(None);
+        };
         self.remove_tx(&v2)
     }
 
     #[allow(dead_code)]
     pub fn all_txids(&self) -> Vec<[u8; 32]> {
-        self.map_v2.keys().cloned().collect()
+        self.map_v2.keys().chain(self.spilled.keys()).cloned().collect()
     }
 
+    /// All entries, including those spilled to disk (read back on demand).
     pub fn entries(&self) -> Vec<MempoolEntry> {
-        self.map_v2.values().cloned().collect()
+        let mut entries: Vec<MempoolEntry> = self.map_v2.values().cloned().collect();
+        for (txid, spilled) in &self.spilled {
+            if let Ok(tx) = self.load_spilled(txid) {
+                entries.push(spilled.to_entry(*txid, tx));
+            }
+        }
+        entries
+    }
+
+    /// Drop entries that could not be mined in a block at `height` with
+    /// reference time `time`, e.g. after a reorg lowered the tip. Later
+    /// arrivals are held to the same lock point.
+    pub fn evict_non_final(&mut self, height: u32, time: u32) -> Result<Vec<MempoolEntry>, MempoolError> {
+        self.lock_point = Some((height, time));
+        let immature: Vec<[u8; 32]> = self
+            .entries()
+            .iter()
+            .filter(|entry| entry.tx.check_final(height, time).is_err())
+            .map(|entry| entry.txid_v2)
+            .collect();
+        let mut removed = Vec::new();
+        for txid in &immature {
+            removed.extend(self.remove_tx(txid)?);
+        }
+        Ok(removed)
+    }
+
+    /// Entries in the order they were accepted (first-seen first).
+    #[allow(dead_code)]
+    pub fn entries_by_arrival(&self) -> Vec<MempoolEntry> {
+        let mut entries = self.entries();
+        entries.sort_by_key(|entry| entry.arrival);
+        entries
+    }
+
+    /// Write every entry, one `Transaction` JSON per line, in arrival order
+    /// so a reload accepts them in the order they were first seen. The file
+    /// is written beside `path` and renamed over it.
+    pub fn save_to_path(&self, path: &Path) -> Result<(), MempoolError> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut writer = BufWriter::new(std::fs::File::create(&tmp)?);
+        for entry in self.entries_by_arrival() {
+            serde_json::to_writer(&mut writer, &entry.tx)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Load a file written by [`Mempool::save_to_path`] into a default-config
+    /// mempool. See [`Mempool::load_txs_from_path`].
+    #[allow(dead_code)]
+    pub fn load_from_path(path: &Path, utxos: &dyn UtxoSet) -> Result<Mempool, MempoolError> {
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        mempool.load_txs_from_path(path, utxos)?;
+        Ok(mempool)
+    }
+
+    /// Re-run [`Mempool::add_tx`] for each saved transaction, returning how
+    /// many were dropped because they no longer apply (inputs spent by a
+    /// block mined meanwhile, policy changes). A missing file loads nothing.
+    pub fn load_txs_from_path(&mut self, path: &Path, utxos: &dyn UtxoSet) -> Result<usize, MempoolError> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let mut skipped = 0;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let tx: Transaction = serde_json::from_str(&line)?;
+            if self.add_tx(tx, utxos).is_err() {
+                skipped += 1;
+            }
+        }
+        Ok(skipped)
     }
 
     fn evict_low_fee(&mut self) -> Result<(), MempoolError> {
-        if self.map_v2.is_empty() {
+        if self.is_empty() {
             return Ok(());
         }
-        let mut entries: Vec<MempoolEntry> = self.map_v2.values().cloned().collect();
+        let mut entries = self.entries();
         entries.sort_by(|a, b| fee_rate(a).partial_cmp(&fee_rate(b)).unwrap_or(std::cmp::Ordering::Equal));
         for entry in entries {
-            self.remove_tx(&entry.txid_v2);
-            if self.map_v2.len() < self.cfg.max_txs && self.total_bytes < self.cfg.max_total_bytes {
+            self.remove_with_descendants(&entry.txid_v2)?;
+            if self.len() < self.cfg.max_txs && self.total_bytes < self.cfg.max_total_bytes {
                 break;
             }
         }
         Ok(())
     }
+
+    fn memory_cap(&self) -> Option<usize> {
+        self.spill.as_ref().and(self.cfg.max_memory_txs)
+    }
+
+    fn load_spilled(&self, txid: &[u8; 32]) -> Result<Transaction, MempoolError> {
+        let tree = self.spill.as_ref().ok_or(MempoolError::SpillMissing)?;
+        let bytes = tree.get(txid)?.ok_or(MempoolError::SpillMissing)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Move the lowest fee-rate in-memory entries to disk until the memory
+    /// cap holds again.
+    fn spill_overflow(&mut self) -> Result<(), MempoolError> {
+        let Some(cap) = self.memory_cap() else {
+            return Ok(());
+        };
+        while self.map_v2.len() > cap {
+            let Some(txid) = self
+                .map_v2
+                .values()
+                .min_by(|a, b| {
+                    fee_rate(a)
+                        .partial_cmp(&fee_rate(b))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                        .then_with(|| b.arrival.cmp(&a.arrival))
+                })
+                .map(|entry| entry.txid_v2)
+            else {
+                break;
+            };
+            let (Some(tree), Some(entry)) = (self.spill.as_ref(), self.map_v2.get(&txid)) else {
+                break;
+            };
+            tree.insert(txid, serde_json::to_vec(&entry.tx)?)?;
+            let Some(entry) = self.map_v2.remove(&txid) else {
+                break;
+            };
+            self.spilled.insert(
+                txid,
+                SpilledEntry {
+                    txid_v1: entry.txid_v1,
+                    inputs: entry.tx.vin.iter().map(|vin| vin.prevout.clone()).collect(),
+                    fee: entry.fee,
+                    size_bytes: entry.size_bytes,
+                    arrival: entry.arrival,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Bring the best spilled entries back into memory while there is room.
+    fn promote_spilled(&mut self) {
+        let Some(cap) = self.memory_cap() else {
+            return;
+        };
+        while self.map_v2.len() < cap {
+            let Some(txid) = self
+                .spilled
+                .iter()
+                .max_by(|(_, a), (_, b)| {
+                    spilled_fee_rate(a)
+                        .partial_cmp(&spilled_fee_rate(b))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                        .then_with(|| b.arrival.cmp(&a.arrival))
+                })
+                .map(|(txid, _)| *txid)
+            else {
+                break;
+            };
+            let Ok(tx) = self.load_spilled(&txid) else {
+                break;
+            };
+            let Some(spilled) = self.spilled.remove(&txid) else {
+                break;
+            };
+            if let Some(tree) = &self.spill {
+                let _ = tree.remove(txid);
+            }
+            self.map_v2.insert(txid, spilled.to_entry(txid, tx));
+        }
+    }
 }
 
-fn fee_rate(entry: &MempoolEntry) -> f64 {
-    if entry.size_bytes == 0 {
-        return 0.0;
+fn spilled_fee_rate(entry: &SpilledEntry) -> f64 {
+    fee_per_byte(entry.fee, entry.size_bytes)
+}
+
+/// `i` and its ancestors not yet in `done`, parents before children.
+fn package_of(i: usize, parents: &[Vec<usize>], done: &[bool]) -> Vec<usize> {
+    let mut out = Vec::new();
+    let mut stack = vec![(i, false)];
+    while let Some((j, expanded)) = stack.pop() {
+        if done[j] || out.contains(&j) {
+            continue;
+        }
+        if expanded {
+            out.push(j);
+            continue;
+        }
+        stack.push((j, true));
+        for &parent in parents[j].iter().rev() {
+            stack.push((parent, false));
+        }
     }
-    entry.fee as f64 / entry.size_bytes as f64
+    out
+}
+
+fn signals_rbf(tx: &Transaction) -> bool {
+    tx.vin.iter().any(|vin| vin.sequence <= MAX_RBF_SEQUENCE)
+}
+
+fn fee_rate(entry: &MempoolEntry) -> f64 {
+    fee_per_byte(entry.fee, entry.size_bytes)
 }
 
 #[cfg(test)]
@@ -234,11 +1022,310 @@ mod tests {
         mempool.add_tx(tx.clone(), &utxos).unwrap();
         let txid = tx.txid_v2().unwrap();
         assert!(mempool.contains(&txid));
-        let removed = mempool.remove_tx(&txid).unwrap();
+        let removed = mempool.remove_tx(&txid).unwrap().unwrap();
         assert_eq!(removed.txid_v2, txid);
         assert!(!mempool.contains(&txid));
     }
 
+    #[test]
+    fn dust_outputs_rejected_unless_data_carrier() {
+        let (utxos, outpoint) = sample_utxo();
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        let dust = make_tx(outpoint.clone(), MIN_OUTPUT_VALUE - 1);
+        assert!(matches!(
+            mempool.add_tx(dust, &utxos),
+            Err(MempoolError::Utxo(UtxoError::DustOutput { index: 0, .. }))
+        ));
+
+        let mut carrier = make_tx(outpoint, 900);
+        carrier.vout.push(TxOut {
+            value: 0,
+            script_pubkey: vec![DATA_CARRIER_MARKER, 0xde, 0xad],
+        });
+        mempool.add_tx(carrier, &utxos).unwrap();
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn absurd_fee_is_rejected_by_policy() {
+        let mut utxos = InMemoryUtxoSet::new();
+        let corrupt = OutPoint {
+            txid: [8u8; 32],
+            vout: 0,
+        };
+        utxos.insert(
+            corrupt.clone(),
+            TxOut {
+                value: MAX_TX_FEE + 10_000,
+                script_pubkey: vec![1],
+            },
+        );
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        assert!(matches!(
+            mempool.add_tx(make_tx(corrupt.clone(), 1_000), &utxos),
+            Err(MempoolError::AbsurdFee { fee, max })
+                if fee == MAX_TX_FEE + 9_000 && max == MAX_TX_FEE
+        ));
+        assert!(mempool.is_empty());
+
+        // A fee right at the cap is fine, as is anything with the cap disabled.
+        mempool.add_tx(make_tx(corrupt.clone(), 10_000), &utxos).unwrap();
+        let mut uncapped = Mempool::new(MempoolConfig {
+            max_tx_fee: 0,
+            ..MempoolConfig::default()
+        });
+        uncapped.add_tx(make_tx(corrupt, 1_000), &utxos).unwrap();
+
+        let (utxos, outpoint) = sample_utxo();
+        mempool.add_tx(make_tx(outpoint, 900), &utxos).unwrap();
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn non_final_txs_are_refused_at_the_lock_point() {
+        let (utxos, outpoint) = sample_utxo();
+        let locked = |lock_time: u32| Transaction {
+            lock_time,
+            vin: vec![TxIn {
+                prevout: outpoint.clone(),
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            ..make_tx(outpoint.clone(), 900)
+        };
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        assert!(mempool.evict_non_final(100, 1_700_000_000).unwrap().is_empty());
+        assert!(matches!(
+            mempool.add_tx(locked(101), &utxos),
+            Err(MempoolError::Utxo(UtxoError::NotFinal { lock_time: 101 }))
+        ));
+        mempool.add_tx(locked(100), &utxos).unwrap();
+
+        // Lowering the lock point evicts the entry and refuses it again.
+        assert_eq!(mempool.evict_non_final(99, 1_700_000_000).unwrap().len(), 1);
+        assert!(mempool.add_tx(locked(100), &utxos).is_err());
+
+        // Without a lock point there is no check.
+        let mut unchecked = Mempool::new(MempoolConfig::default());
+        unchecked.add_tx(locked(u32::MAX), &utxos).unwrap();
+    }
+
+    #[test]
+    fn coinbase_spends_wait_for_maturity_at_the_next_block() {
+        let outpoint = OutPoint {
+            txid: [9u8; 32],
+            vout: 0,
+        };
+        let mut utxos = InMemoryUtxoSet::new();
+        let txout = TxOut {
+            value: 1_000,
+            script_pubkey: vec![1, 2, 3],
+        };
+        utxos.insert_at_height(outpoint.clone(), txout, 5, true);
+        let spend = make_tx(outpoint.clone(), 900);
+        let mut mempool = Mempool::new(MempoolConfig {
+            coinbase_maturity: 10,
+            ..MempoolConfig::default()
+        });
+
+        // No lock point yet: maturity cannot be judged, so the spend waits.
+        assert!(matches!(
+            mempool.add_tx(spend.clone(), &utxos),
+            Err(MempoolError::ImmatureCoinbase { height: 0, .. })
+        ));
+
+        // Tip at height 13: the next block is one short of maturity.
+        mempool.evict_non_final(14, 0).unwrap();
+        assert!(matches!(
+            mempool.add_tx(spend.clone(), &utxos),
+            Err(MempoolError::ImmatureCoinbase { outpoint: op, created: 5, height: 14 }) if op == outpoint
+        ));
+        assert!(mempool.is_empty());
+
+        mempool.evict_non_final(15, 0).unwrap();
+        mempool.add_tx(spend.clone(), &utxos).unwrap();
+        assert!(mempool.contains(&spend.txid_v2().unwrap()));
+    }
+
+    #[test]
+    fn replacing_add_keeps_the_higher_fee_spend() {
+        let (utxos, outpoint) = sample_utxo();
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        let first = make_tx(outpoint.clone(), 900);
+        mempool.add_tx(first.clone(), &utxos).unwrap();
+        let first_id = first.txid_v2().unwrap();
+
+        let cheaper = make_tx(outpoint.clone(), 950);
+        assert_eq!(mempool.conflicts(&cheaper), vec![first_id]);
+        assert!(matches!(
+            mempool.add_tx_replace(cheaper, &utxos, Replacement::HigherFee),
+            Err(MempoolError::DoubleSpend(op)) if op == outpoint
+        ));
+        // Equal fee does not displace the first-seen tx either.
+        let equal = Transaction {
+            lock_time: 1,
+            ..first.clone()
+        };
+        assert!(mempool.add_tx_replace(equal, &utxos, Replacement::HigherFee).is_err());
+        assert!(mempool.contains(&first_id));
+
+        let richer = make_tx(outpoint, 700);
+        let replaced = mempool
+            .add_tx_replace(richer.clone(), &utxos, Replacement::HigherFee)
+            .unwrap();
+        assert_eq!(replaced, vec![first_id]);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&richer.txid_v2().unwrap()));
+    }
+
+    #[test]
+    fn replacing_a_parent_evicts_and_outbids_its_child() {
+        let (utxos, outpoint) = sample_utxo();
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        let parent = make_tx(outpoint.clone(), 900);
+        let parent_id = parent.txid_v2().unwrap();
+        let child = make_tx(OutPoint { txid: parent_id, vout: 0 }, 800);
+        let child_id = child.txid_v2().unwrap();
+        mempool.add_tx(parent, &utxos).unwrap();
+        mempool.add_tx(child, &utxos).unwrap();
+
+        // Beats the parent's fee of 100 but not parent plus child.
+        let short = make_tx(outpoint.clone(), 850);
+        assert!(matches!(
+            mempool.add_tx_replace(short, &utxos, Replacement::HigherFee),
+            Err(MempoolError::DoubleSpend(op)) if op == outpoint
+        ));
+        assert!(mempool.contains(&parent_id) && mempool.contains(&child_id));
+
+        let richer = make_tx(outpoint, 750);
+        let replaced = mempool
+            .add_tx_replace(richer.clone(), &utxos, Replacement::HigherFee)
+            .unwrap();
+        assert_eq!(replaced, vec![parent_id, child_id]);
+        assert_eq!(mempool.all_txids(), vec![richer.txid_v2().unwrap()]);
+        assert!(mempool.spender_of(&OutPoint { txid: parent_id, vout: 0 }).is_none());
+    }
+
+    #[test]
+    fn opt_in_replacement_needs_a_fee_rate_bump() {
+        let (utxos, outpoint) = sample_utxo();
+        let signalling = |value: u64| {
+            let mut tx = make_tx(outpoint.clone(), value);
+            tx.vin[0].sequence = MAX_RBF_SEQUENCE;
+            tx
+        };
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        let original = signalling(900);
+        mempool.add_tx(original.clone(), &utxos).unwrap();
+        let original_id = original.txid_v2().unwrap();
+        let size = original.vsize() as f64;
+
+        // One sat more is a higher fee rate, but not by `min_rbf_bump`.
+        let nudge = signalling(899);
+        assert!(matches!(
+            mempool.add_tx_replace(nudge, &utxos, Replacement::OptIn),
+            Err(MempoolError::RbfUnderpriced { offered, required })
+                if offered == 101.0 / size && required == 100.0 / size + MIN_RBF_BUMP
+        ));
+        assert!(mempool.contains(&original_id));
+
+        let bumped = signalling(900 - size as u64 - 1);
+        let evicted = mempool.add_tx_replace(bumped.clone(), &utxos, Replacement::OptIn).unwrap();
+        assert_eq!(evicted, vec![original_id]);
+        assert_eq!(mempool.all_txids(), vec![bumped.txid_v2().unwrap()]);
+
+        // A conflict that never opted in keeps first-seen semantics.
+        let mut final_pool = Mempool::new(MempoolConfig::default());
+        final_pool.add_tx(make_tx(outpoint.clone(), 900), &utxos).unwrap();
+        assert!(matches!(
+            final_pool.add_tx(signalling(100), &utxos),
+            Err(MempoolError::DoubleSpend(op)) if op == outpoint
+        ));
+    }
+
+    #[test]
+    fn orphan_child_is_promoted_when_parent_arrives() {
+        let (utxos, outpoint) = sample_utxo();
+        let parent = make_tx(outpoint, 900);
+        let parent_id = parent.txid_v2().unwrap();
+        let child = make_tx(OutPoint { txid: parent_id, vout: 0 }, 800);
+        let child_id = child.txid_v2().unwrap();
+        let grandchild = make_tx(OutPoint { txid: child_id, vout: 0 }, 700);
+        let mut mempool = Mempool::new(MempoolConfig::default());
+
+        // Relayed out of order: descendants first.
+        for tx in [grandchild.clone(), child.clone()] {
+            assert!(matches!(
+                mempool.add_tx(tx.clone(), &utxos),
+                Err(MempoolError::Utxo(UtxoError::MissingUtxo(_)))
+            ));
+            mempool.add_orphan(tx).unwrap();
+        }
+        assert_eq!(mempool.orphan_len(), 2);
+        assert!(mempool.is_empty());
+
+        mempool.add_tx(parent, &utxos).unwrap();
+        let promoted = mempool.process_orphans(&parent_id, &utxos);
+        assert_eq!(promoted, vec![child_id, grandchild.txid_v2().unwrap()]);
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.orphan_len(), 0);
+    }
+
+    #[test]
+    fn orphan_pool_evicts_oldest_and_expires() {
+        let mut mempool = Mempool::new(MempoolConfig {
+            max_orphans: 2,
+            ..MempoolConfig::default()
+        });
+        let orphan = |tag: u8| make_tx(OutPoint { txid: [tag; 32], vout: 0 }, 900);
+        for tag in 1..=3u8 {
+            mempool.add_orphan(orphan(tag)).unwrap();
+        }
+        assert_eq!(mempool.orphan_len(), 2);
+        assert!(!mempool.orphans.txs.contains_key(&orphan(1).txid_v2().unwrap()));
+        assert!(!mempool.orphans.by_parent.contains_key(&[1u8; 32]));
+
+        let later = Instant::now() + Duration::from_secs(ORPHAN_TTL_SECS);
+        mempool.orphans.expire(later, Duration::from_secs(ORPHAN_TTL_SECS));
+        assert_eq!(mempool.orphan_len(), 0);
+        assert!(mempool.orphans.by_parent.is_empty());
+    }
+
+    #[test]
+    fn spender_lookup_follows_add_remove_and_eviction() {
+        let (mut utxos, outpoint) = sample_utxo();
+        let tx = make_tx(outpoint.clone(), 900);
+        let txid = tx.txid_v2().unwrap();
+        let mut mempool = Mempool::new(MempoolConfig {
+            max_txs: 1,
+            ..MempoolConfig::default()
+        });
+        assert_eq!(mempool.spender_of(&outpoint), None);
+        mempool.add_tx(tx.clone(), &utxos).unwrap();
+        assert_eq!(mempool.spender_of(&outpoint), Some(txid));
+        mempool.remove_tx(&txid).unwrap().unwrap();
+        assert_eq!(mempool.spender_of(&outpoint), None);
+
+        // A full pool evicts the cheaper entry and forgets its spend.
+        mempool.add_tx(tx, &utxos).unwrap();
+        let other = OutPoint {
+            txid: [8u8; 32],
+            vout: 0,
+        };
+        utxos.insert(
+            other.clone(),
+            TxOut {
+                value: 1_000,
+                script_pubkey: vec![1],
+            },
+        );
+        let richer = make_tx(other.clone(), 600);
+        mempool.add_tx(richer.clone(), &utxos).unwrap();
+        assert_eq!(mempool.spender_of(&outpoint), None);
+        assert_eq!(mempool.spender_of(&other), Some(richer.txid_v2().unwrap()));
+        assert_eq!(mempool.spent_by.len(), 1);
+    }
+
     #[test]
     fn double_spend_rejected() {
         let (utxos, outpoint) = sample_utxo();
@@ -249,4 +1336,181 @@ mod tests {
         let err = mempool.add_tx(tx2, &utxos).unwrap_err();
         matches!(err, MempoolError::DoubleSpend(_));
     }
+
+    #[test]
+    fn entries_by_arrival_preserves_first_seen_order() {
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        let mut expected = Vec::new();
+        for tag in [9u8, 3, 7, 1] {
+            let outpoint = OutPoint {
+                txid: [tag; 32],
+                vout: 0,
+            };
+            utxos.insert(
+                outpoint.clone(),
+                TxOut {
+                    value: 1_000,
+                    script_pubkey: vec![tag],
+                },
+            );
+            let tx = make_tx(outpoint, 900);
+            expected.push(tx.txid_v2().unwrap());
+            mempool.add_tx(tx, &utxos).unwrap();
+        }
+
+        let conflicting = make_tx(
+            OutPoint {
+                txid: [3u8; 32],
+                vout: 0,
+            },
+            800,
+        );
+        assert!(mempool.add_tx(conflicting, &utxos).is_err());
+
+        let ordered = mempool.entries_by_arrival();
+        let txids: Vec<[u8; 32]> = ordered.iter().map(|e| e.txid_v2).collect();
+        assert_eq!(txids, expected);
+        let arrivals: Vec<u64> = ordered.iter().map(|e| e.arrival).collect();
+        assert_eq!(arrivals, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn memory_cap_spills_low_fee_txs_to_disk() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = MempoolConfig {
+            max_memory_txs: Some(2),
+            ..MempoolConfig::default()
+        };
+        let mut mempool = Mempool::with_spill(cfg, db.open_tree("mempool_spill").unwrap()).unwrap();
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut txids = Vec::new();
+        // Fees 100, 300, 200: the 100-fee tx is the one that must spill.
+        for (tag, value) in [(1u8, 900), (2, 700), (3, 800)] {
+            let outpoint = OutPoint {
+                txid: [tag; 32],
+                vout: 0,
+            };
+            utxos.insert(
+                outpoint.clone(),
+                TxOut {
+                    value: 1_000,
+                    script_pubkey: vec![tag],
+                },
+            );
+            let tx = make_tx(outpoint, value);
+            txids.push(tx.txid_v2().unwrap());
+            mempool.add_tx(tx, &utxos).unwrap();
+        }
+
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.spilled_len(), 1);
+        assert!(mempool.map_v2.contains_key(&txids[1]));
+        assert!(mempool.map_v2.contains_key(&txids[2]));
+        assert!(mempool.contains(&txids[0]));
+        assert!(mempool.get_tx(&txids[0]).is_some());
+        let dup = make_tx(
+            OutPoint {
+                txid: [1u8; 32],
+                vout: 0,
+            },
+            600,
+        );
+        assert!(matches!(mempool.add_tx(dup, &utxos), Err(MempoolError::DoubleSpend(_))));
+
+        let all: Vec<[u8; 32]> = mempool.entries().iter().map(|e| e.txid_v2).collect();
+        assert_eq!(all.len(), 3);
+        assert!(all.contains(&txids[0]));
+
+        // Freeing memory promotes the spilled tx back.
+        mempool.remove_tx(&txids[1]).unwrap().unwrap();
+        assert_eq!(mempool.spilled_len(), 0);
+        assert!(mempool.map_v2.contains_key(&txids[0]));
+        assert!(mempool.spill.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unreadable_spilled_tx_is_kept_on_remove() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = MempoolConfig {
+            max_memory_txs: Some(1),
+            ..MempoolConfig::default()
+        };
+        let tree = db.open_tree("mempool_spill").unwrap();
+        let mut mempool = Mempool::with_spill(cfg, tree.clone()).unwrap();
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut txids = Vec::new();
+        for (tag, value) in [(1u8, 900), (2, 700)] {
+            let outpoint = OutPoint {
+                txid: [tag; 32],
+                vout: 0,
+            };
+            utxos.insert(
+                outpoint.clone(),
+                TxOut {
+                    value: 1_000,
+                    script_pubkey: vec![tag],
+                },
+            );
+            let tx = make_tx(outpoint, value);
+            txids.push(tx.txid_v2().unwrap());
+            mempool.add_tx(tx, &utxos).unwrap();
+        }
+        assert_eq!(mempool.spilled_len(), 1);
+        let first = OutPoint {
+            txid: [1u8; 32],
+            vout: 0,
+        };
+
+        let record = tree.insert(txids[0], b"not json".to_vec()).unwrap().unwrap();
+        assert!(matches!(mempool.remove_tx(&txids[0]), Err(MempoolError::Json(_))));
+        assert!(mempool.contains(&txids[0]));
+        assert_eq!(mempool.spender_of(&first), Some(txids[0]));
+
+        tree.insert(txids[0], record).unwrap();
+        assert_eq!(mempool.remove_tx(&txids[0]).unwrap().unwrap().txid_v2, txids[0]);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.spender_of(&first).is_none());
+    }
+
+    #[test]
+    fn saved_mempool_reloads_and_skips_spent_inputs() {
+        let (mut utxos, first) = sample_utxo();
+        let second = OutPoint {
+            txid: [8u8; 32],
+            vout: 0,
+        };
+        utxos.insert(
+            second.clone(),
+            TxOut {
+                value: 1_000,
+                script_pubkey: vec![1, 2, 3],
+            },
+        );
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        let kept = make_tx(second.clone(), 800);
+        let mined = make_tx(first.clone(), 900);
+        mempool.add_tx(kept.clone(), &utxos).unwrap();
+        mempool.add_tx(mined.clone(), &utxos).unwrap();
+
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("mempool.jsonl");
+        mempool.save_to_path(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(saved.lines().count(), 2);
+        assert!(!temp.path().join("mempool.jsonl.tmp").exists());
+
+        let reloaded = Mempool::load_from_path(&path, &utxos).unwrap();
+        let order: Vec<[u8; 32]> = reloaded.entries_by_arrival().iter().map(|e| e.txid_v2).collect();
+        assert_eq!(order, vec![kept.txid_v2().unwrap(), mined.txid_v2().unwrap()]);
+
+        // A block spent `first` while the node was down.
+        utxos.remove(&first);
+        let mut restarted = Mempool::new(MempoolConfig::default());
+        assert_eq!(restarted.load_txs_from_path(&path, &utxos).unwrap(), 1);
+        assert_eq!(restarted.all_txids(), vec![kept.txid_v2().unwrap()]);
+
+        let missing = temp.path().join("absent.jsonl");
+        assert_eq!(restarted.load_txs_from_path(&missing, &utxos).unwrap(), 0);
+    }
 }
@@ -1,22 +1,32 @@
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::OpenOptions;
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::path::PathBuf;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use hex::encode as hex_encode;
 use sled::Db;
-use tenebrium_consensus::{check_pow, header_hash, merkle_root, Block, BlockHeader, ConsensusError};
-use tenebrium_utxo::{ApplyReceipt, InMemoryUtxoSet, Transaction, TxOut, UtxoError, UtxoSet};
+use tenebrium_consensus::{
+    check_pow, difficulty, header_hash, network_hashrate, Block, BlockHeader,
+    ConsensusError, ContextualChecks, MerkleBlock, NetworkParams, COMPACT_SIGN_BIT,
+};
+use tenebrium_utxo::{
+    txid_from_display_hex, txid_to_display_hex, BlockReceipt, CoinMeta, ConcurrentUtxoSet, InMemoryUtxoSet,
+    OutPoint, Transaction, UtxoError, UtxoSet,
+};
 
-use crate::mempool::{Mempool, MempoolConfig, MempoolError};
+use crate::bloom::{BloomFilter, MAX_FILTER_ADD_BYTES};
+use crate::mempool::{Mempool, MempoolConfig, MempoolEntry, MempoolError, Replacement};
+use crate::rpc::{self, RpcError, RpcHandler};
 use crate::utxo_db::{
     decode_outpoint, decode_txout, encode_outpoint, encode_txout, jsonl_reader, UtxoDbError,
     UtxoReader,
 };
+use crate::work::Work;
+use tenebrium_consensus::params::TARGET_BLOCK_TIME_SECS;
 use crate::LogLevel;
 
 #[derive(Debug, thiserror::Error)]
@@ -39,6 +49,19 @@ pub enum P2pError {
     Sled(#[from] sled::Error),
     #[error("block invalid: {0}")]
     InvalidBlock(String),
+    #[error("bad network magic: {0:02x?}")]
+    BadMagic([u8; 4]),
+    #[error("invalid node id: {0}")]
+    InvalidNodeId(String),
+    #[error("connected to self")]
+    SelfConnection,
+    #[error("{0} received before hello")]
+    BeforeHandshake(&'static str),
+    /// Internal accounting bug, not peer misbehavior: never a ban reason.
+    #[error("UTXO value invariant violated: {0}")]
+    ValueInvariant(String),
+    #[error("block at height {0} is pruned")]
+    BlockPruned(u32),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -49,28 +72,93 @@ pub enum P2pMessage {
         network: String,
         node_id: String,
         txid_version: Option<u8>,
+        /// Peer answers `GetHeaders`; older or minimal peers leave this unset
+        /// and are synced with `GetBlocks` instead.
+        headers: Option<bool>,
     },
     Addr(Vec<String>),
     Inv { txids: Vec<[u8; 32]>, blocks: Vec<[u8; 32]> },
     GetTx(Vec<[u8; 32]>),
     GetBlock(Vec<[u8; 32]>),
     GetHeaders { locator: Vec<[u8; 32]> },
+    /// Answered with an `Inv` of best-chain block hashes after the first
+    /// known locator hash, up to and including `stop_hash` (zero for no stop).
+    GetBlocks { locator: Vec<[u8; 32]>, stop_hash: [u8; 32] },
     Headers(Vec<BlockHeader>),
     Ping,
     Pong,
     Tx(Transaction),
     Block(Block),
+    FilterLoad(BloomFilter),
+    FilterAdd(Vec<u8>),
+    FilterClear,
+    MerkleBlock(MerkleBlock),
+}
+
+impl P2pMessage {
+    /// Variant name, as used for the serde `type` tag.
+    fn kind(&self) -> &'static str {
+        match self {
+            P2pMessage::Hello { .. } => "Hello",
+            P2pMessage::Addr(_) => "Addr",
+            P2pMessage::Inv { .. } => "Inv",
+            P2pMessage::GetTx(_) => "GetTx",
+            P2pMessage::GetBlock(_) => "GetBlock",
+            P2pMessage::GetHeaders { .. } => "GetHeaders",
+            P2pMessage::GetBlocks { .. } => "GetBlocks",
+            P2pMessage::Headers(_) => "Headers",
+            P2pMessage::Ping => "Ping",
+            P2pMessage::Pong => "Pong",
+            P2pMessage::Tx(_) => "Tx",
+            P2pMessage::Block(_) => "Block",
+            P2pMessage::FilterLoad(_) => "FilterLoad",
+            P2pMessage::FilterAdd(_) => "FilterAdd",
+            P2pMessage::FilterClear => "FilterClear",
+            P2pMessage::MerkleBlock(_) => "MerkleBlock",
+        }
+    }
+}
+
+/// Handshake progress of a connection. Until the peer's `Hello` has been
+/// accepted nothing else is processed, so an unidentified peer cannot push
+/// transactions or blocks at the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeerState {
+    AwaitingHello,
+    Ready,
+}
+
+impl PeerState {
+    fn admit(self, msg: &P2pMessage) -> Result<(), P2pError> {
+        match (self, msg) {
+            (PeerState::Ready, _) | (PeerState::AwaitingHello, P2pMessage::Hello { .. }) => Ok(()),
+            (PeerState::AwaitingHello, other) => Err(P2pError::BeforeHandshake(other.kind())),
+        }
+    }
+}
+
+fn local_hello(network_id: &str, node_id: &str, txid_version: u8) -> P2pMessage {
+    P2pMessage::Hello {
+        version: PROTOCOL_VERSION,
+        network: network_id.to_string(),
+        node_id: node_id.to_string(),
+        txid_version: Some(txid_version),
+        headers: Some(true),
+    }
 }
 
 const PROTOCOL_VERSION: u32 = 1;
 const MIN_PROTOCOL_VERSION: u32 = 1;
 const MAX_PROTOCOL_VERSION: u32 = 1;
-const MAX_FUTURE_DRIFT_SECS: u32 = 2 * 60 * 60;
 const MAX_MESSAGE_BYTES: usize = 10 * 1024 * 1024;
 const MAX_ADDR: usize = 1000;
 const MAX_INV: usize = 5000;
 const MAX_GET: usize = 2000;
 const MAX_HEADERS: usize = 2000;
+/// Headers a peer address may send in full batches that cannot yet beat
+/// our tip, across reconnects, before it is banned as low-work spam.
+const MAX_LOW_WORK_HEADERS: usize = 2 * MAX_HEADERS;
+const MAX_GETBLOCKS_INV: usize = 500;
 const MAX_PEERS: usize = 64;
 const MAX_NODE_ID_LEN: usize = 64;
 const MAX_NETWORK_ID_LEN: usize = 16;
@@ -81,24 +169,140 @@ const MSG_WINDOW_SECS: u64 = 60;
 const MAX_MSGS_PER_WINDOW: u32 = 120;
 const READ_TIMEOUT_SECS: u64 = 30;
 const WRITE_TIMEOUT_SECS: u64 = 30;
+const CONNECT_TIMEOUT_SECS: u64 = 10;
 const SEED_RETRY_BASE_SECS: u64 = 2;
 const SEED_RETRY_MAX_SECS: u64 = 60;
 const SEED_RETRY_ATTEMPTS: u32 = 8;
 const SEED_DIAL_INTERVAL_SECS: u64 = 30;
-const TARGET_BLOCK_TIME_SECS: u32 = 600;
-const DIFFICULTY_WINDOW: u32 = 10;
+const REQUEST_TIMEOUT_SECS: u64 = 60;
+const MEMPOOL_PERSIST_INTERVAL_SECS: u64 = 60;
+/// Number of ancestor timestamps whose median a new header must exceed
+const MTP_WINDOW: usize = 11;
 const INITIAL_BITS: u32 = 0x207fffff;
-const INITIAL_SUBSIDY: u64 = 50_0000_0000;
-const HALVING_INTERVAL: u32 = 210_000;
-const DB_SCHEMA_VERSION: u32 = 2;
-const GENESIS_TIME: u32 = 1_769_936_400;
-const GENESIS_BITS: u32 = 0x207fffff;
-const GENESIS_NONCE: u32 = 2;
-const GENESIS_MERKLE_ROOT: [u8; 32] = [
-    169, 121, 2, 123, 39, 241, 216, 194, 36, 201, 186, 237, 157, 93, 25, 228,
-    155, 68, 174, 228, 3, 8, 168, 36, 245, 208, 58, 173, 18, 205, 179, 58,
-];
+pub(crate) const DB_SCHEMA_VERSION: u32 = 3;
+
+/// Node behaviour switches that are passed down to every connection.
+#[derive(Debug, Clone, Default)]
+pub struct P2pOptions {
+    /// Participate in block relay only: ignore loose transactions and never
+    /// request them, leaving the mempool empty.
+    pub blocks_only: bool,
+    /// Cap on in-memory mempool entries; overflow is spilled to the
+    /// `mempool_spill` tree of the data dir instead of being evicted.
+    pub mempool_memory_txs: Option<usize>,
+    /// Move unreadable or misnamed block files aside at startup and fetch
+    /// them again from peers, instead of refusing to start.
+    pub quarantine_corrupt_blocks: bool,
+    /// Memory budget in MiB, split between the in-memory block store and
+    /// unflushed writes. 0 keeps nothing cached and flushes every block.
+    pub dbcache_mb: usize,
+    /// Identity announced in `Hello`. When unset, a random id is generated
+    /// once and kept in the data dir so restarts keep the same identity.
+    pub node_id: Option<String>,
+    /// Rebuild the UTXO set from stored block bodies at startup, keeping the
+    /// header index. Requires a data dir.
+    pub reindex_chainstate: bool,
+    /// When a reorg puts disconnected transactions back into the mempool,
+    /// let one that double-spends mempool entries replace them if it pays a
+    /// higher fee, instead of keeping whichever was seen first.
+    pub prune_mempool_on_reorg: bool,
+    /// Connect and per-message read/write timeouts for peer sockets.
+    pub timeouts: PeerTimeouts,
+    /// Report the node as stale while its tip block is older than this.
+    /// It keeps syncing; only the status changes. `None` disables the check.
+    pub max_tip_age: Option<Duration>,
+    /// Serve JSON-RPC on this address alongside P2P; see [`NodeRpc`] for
+    /// the methods.
+    pub rpc_listen: Option<String>,
+}
+
+/// Socket timeouts for peer connections. Slow links (Tor, satellite) may
+/// need longer values than the defaults to avoid dropping honest peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTimeouts {
+    pub read: Duration,
+    pub write: Duration,
+    pub connect: Duration,
+}
+
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        Self {
+            read: Duration::from_secs(READ_TIMEOUT_SECS),
+            write: Duration::from_secs(WRITE_TIMEOUT_SECS),
+            connect: Duration::from_secs(CONNECT_TIMEOUT_SECS),
+        }
+    }
+}
+
+impl PeerTimeouts {
+    fn apply(&self, stream: &TcpStream) -> Result<(), P2pError> {
+        stream.set_read_timeout(Some(self.read))?;
+        stream.set_write_timeout(Some(self.write))?;
+        Ok(())
+    }
+
+    /// Dial `addr`, trying each resolved address for at most `connect`.
+    fn connect(&self, addr: &str) -> std::io::Result<TcpStream> {
+        let mut last_err = None;
+        for sock in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&sock, self.connect) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, format!("{addr} did not resolve"))
+        }))
+    }
+}
+
+const NODE_ID_FILE: &str = "node_id";
+const MEMPOOL_FILE: &str = "mempool.jsonl";
 
+fn validate_node_id(node_id: &str) -> Result<(), P2pError> {
+    if node_id.is_empty() || node_id.len() > MAX_NODE_ID_LEN {
+        return Err(P2pError::InvalidNodeId(format!(
+            "length {} (must be 1..={MAX_NODE_ID_LEN})",
+            node_id.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Use `explicit` verbatim if given; otherwise load the id stored in
+/// `data_dir`, creating a random one on first start. Without a data dir the
+/// random id only lasts for this run.
+fn resolve_node_id(explicit: Option<String>, data_dir: Option<&Path>) -> Result<String, P2pError> {
+    if let Some(node_id) = explicit {
+        validate_node_id(&node_id)?;
+        return Ok(node_id);
+    }
+    let Some(dir) = data_dir else {
+        return Ok(random_node_id());
+    };
+    let path = dir.join(NODE_ID_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(stored) => {
+            let node_id = stored.trim().to_string();
+            validate_node_id(&node_id)?;
+            Ok(node_id)
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let node_id = random_node_id();
+            std::fs::create_dir_all(dir)?;
+            std::fs::write(&path, &node_id)?;
+            Ok(node_id)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn random_node_id() -> String {
+    format!("node-{}", hex_encode(rand::random::<[u8; 8]>()))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn run_p2p(
     listen_addr: String,
     peers: Vec<String>,
@@ -110,6 +314,9 @@ pub fn run_p2p(
     log_level: LogLevel,
     log_file: Option<PathBuf>,
     txid_version: u8,
+    metrics_file: Option<PathBuf>,
+    utxo_warn_bytes: usize,
+    options: P2pOptions,
 ) -> Result<(), P2pError> {
     let listener = TcpListener::bind(&listen_addr)?;
     let logger = Arc::new(Logger::new(log_level, log_file)?);
@@ -120,27 +327,132 @@ pub fn run_p2p(
         None => None,
     };
 
-    let utxos = Arc::new(Mutex::new(load_utxos(utxo_path, data_dir.clone(), db.clone())?));
-    let mempool = Arc::new(Mutex::new(Mempool::new(MempoolConfig::default())));
+    let dbcache_bytes = options.dbcache_mb.saturating_mul(1024 * 1024);
+    let blocks = match data_dir.as_ref() {
+        Some(dir) => BlockStore::load_dir(
+            dir,
+            options.quarantine_corrupt_blocks,
+            dbcache_bytes / 2,
+            &logger,
+        )?,
+        None => BlockStore::default(),
+    };
+    let params = NetworkParams::for_network(&network_id);
+    let chain = ChainState::load_or_genesis(db.clone(), params)?;
+    let mut applied = AppliedState {
+        flush: FlushState::new(dbcache_bytes),
+        ..AppliedState::new(chain.tip_hash())
+    };
+    let utxos = match (options.reindex_chainstate, data_dir.as_ref(), db.as_ref()) {
+        (true, Some(dir), Some(db)) => {
+            logger.info("reindexing chainstate from stored blocks".to_string());
+            let utxos = reindex_chainstate(dir, db, &chain, &blocks, &mut applied)?;
+            logger.info(format!(
+                "chainstate rebuilt: {} utxos at {}",
+                utxos.len(),
+                hex_encode(applied.tip)
+            ));
+            utxos
+        }
+        _ => load_chainstate(
+            utxo_path,
+            data_dir.as_deref(),
+            db.as_ref(),
+            &chain,
+            &blocks,
+            &mut applied,
+            &logger,
+        )?,
+    };
+    // Stats and RPC lookups read through this without the `utxos` mutex.
+    let utxo_reader = utxos.reader();
+    let utxos = Arc::new(Mutex::new(utxos));
+    let mempool_cfg = MempoolConfig {
+        max_memory_txs: options.mempool_memory_txs,
+        coinbase_maturity: chain.params.coinbase_maturity,
+        ..MempoolConfig::default()
+    };
+    let mut mempool = match (options.mempool_memory_txs, db.as_ref()) {
+        (Some(_), Some(db)) => Mempool::with_spill(mempool_cfg, db.open_tree("mempool_spill")?)?,
+        _ => Mempool::new(mempool_cfg),
+    };
+    // Nothing to evict yet; this sets the lock point restored txs must meet.
+    evict_immature(&mut mempool, &chain, applied.tip)?;
+    if let Some(dir) = data_dir.as_ref() {
+        let path = dir.join(MEMPOOL_FILE);
+        let skipped = {
+            let utxos = utxos
+                .lock()
+                .map_err(|_| P2pError::InvalidBlock("utxo lock".to_string()))?;
+            mempool.load_txs_from_path(&path, &*utxos)?
+        };
+        logger.info(format!(
+            "mempool restored: {} txs ({} spilled to disk, {skipped} no longer valid)",
+            mempool.len(),
+            mempool.spilled_len()
+        ));
+    }
+    let mempool = Arc::new(Mutex::new(mempool));
+    if let Some(dir) = data_dir.as_ref() {
+        spawn_mempool_persist_thread(
+            Arc::clone(&mempool),
+            dir.join(MEMPOOL_FILE),
+            MEMPOOL_PERSIST_INTERVAL_SECS,
+            Arc::clone(&logger),
+        );
+    }
     let peers = Arc::new(Mutex::new(PeerManager::new(peers)));
-    let blocks = Arc::new(Mutex::new(BlockStore::default()));
-    let chain = Arc::new(Mutex::new(ChainState::load_or_genesis(db.clone())?));
-    let applied = Arc::new(Mutex::new(AppliedState::new(
-        chain
-            .lock()
-            .map_err(|_| P2pError::InvalidBlock("chain lock".to_string()))?
-            .tip_hash(),
-    )));
+    let blocks = Arc::new(Mutex::new(blocks));
+    let chain = Arc::new(Mutex::new(chain));
+    let applied = Arc::new(Mutex::new(applied));
+    if let Some(dir) = data_dir.clone() {
+        install_shutdown_flush(
+            dir,
+            Arc::clone(&utxos),
+            Arc::clone(&applied),
+            Arc::clone(&mempool),
+            db.clone(),
+            Arc::clone(&logger),
+        );
+    }
     let seen = Arc::new(Mutex::new(Seen::default()));
-    let node_id = format!("node-{}", std::process::id());
+    let node_id = resolve_node_id(options.node_id.clone(), data_dir.as_deref())?;
+    logger.info(format!("node id {node_id}"));
+
+    if let Some(rpc_addr) = options.rpc_listen.as_ref() {
+        let rpc_listener = TcpListener::bind(rpc_addr)?;
+        logger.info(format!("RPC listening on {rpc_addr}"));
+        let handler = Arc::new(NodeRpc {
+            mempool: Arc::clone(&mempool),
+            utxos: Arc::clone(&utxos),
+            utxo_reader: Arc::clone(&utxo_reader),
+            chain: Arc::clone(&chain),
+            peers: Arc::clone(&peers),
+            db: db.clone(),
+            magic: NetworkParams::for_network(&network_id).magic,
+            hello: local_hello(&network_id, &node_id, txid_version),
+            txid_version,
+            logger: Arc::clone(&logger),
+        });
+        let logger = Arc::clone(&logger);
+        thread::spawn(move || {
+            if let Err(err) = rpc::serve(rpc_listener, handler) {
+                logger.warn(format!("RPC server stopped: {err}"));
+            }
+        });
+    }
 
     if stats_interval_secs > 0 {
         spawn_stats_thread(
             Arc::clone(&peers),
             Arc::clone(&mempool),
-            Arc::clone(&utxos),
+            Arc::clone(&utxo_reader),
             Arc::clone(&chain),
+            Arc::clone(&seen),
             stats_interval_secs,
+            metrics_file,
+            utxo_warn_bytes,
+            options.max_tip_age,
             Arc::clone(&logger),
         );
     }
@@ -167,6 +479,7 @@ pub fn run_p2p(
             db.clone(),
             no_pow_check,
             txid_version,
+            options.clone(),
             Arc::clone(&logger),
         );
     }
@@ -185,6 +498,7 @@ pub fn run_p2p(
         db.clone(),
         no_pow_check,
         txid_version,
+        options.clone(),
         Arc::clone(&logger),
     );
 
@@ -214,6 +528,7 @@ pub fn run_p2p(
                 let node_id = node_id.clone();
                 let network_id = network_id.clone();
                 let logger = Arc::clone(&logger);
+                let options = options.clone();
                 thread::spawn(move || {
                     let peers_for_conn = Arc::clone(&peers_list);
                     let res = handle_connection(
@@ -232,14 +547,11 @@ pub fn run_p2p(
                         db,
                         no_pow_check,
                         txid_version,
+                        options,
                         Arc::clone(&logger),
                     );
                     if let Err(err) = res {
-                        if should_ban(&err) {
-                            if let Ok(mut guard) = peers_list.lock() {
-                                guard.ban(&peer);
-                            }
-                        }
+                        drop_peer(&peers_list, &peer, &err, false);
                         logger.warn(format!("[{peer}] disconnected: {err}"));
                     }
                 });
@@ -250,6 +562,22 @@ pub fn run_p2p(
     Ok(())
 }
 
+/// Drops a peer's bloom filter when its connection ends, however it ends,
+/// so a later connection from the same address starts unfiltered.
+struct FilterGuard<'a> {
+    peers: &'a Mutex<PeerManager>,
+    peer: &'a str,
+}
+
+impl Drop for FilterGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut guard) = self.peers.lock() {
+            guard.clear_filter(self.peer);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn handle_connection(
     mut stream: TcpStream,
     peer: String,
@@ -266,36 +594,48 @@ fn handle_connection(
     db: Option<Db>,
     no_pow_check: bool,
     txid_version: u8,
+    options: P2pOptions,
     logger: Arc<Logger>,
 ) -> Result<(), P2pError> {
-    stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS)))?;
-    stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)))?;
-    send_message(
-        &mut stream,
-        &P2pMessage::Hello {
-            version: PROTOCOL_VERSION,
-            network: network_id.clone(),
-            node_id: node_id.clone(),
-            txid_version: Some(txid_version),
-        },
-    )?;
-    let tip = chain
+    let _filter = FilterGuard { peers: &peers, peer: &peer };
+    let magic = NetworkParams::for_network(&network_id).magic;
+    options.timeouts.apply(&stream)?;
+    let hello = local_hello(&network_id, &node_id, txid_version);
+    send_message(&mut stream, magic, &hello)?;
+    let locator = chain
         .lock()
         .map_err(|_| P2pError::InvalidBlock("chain lock".to_string()))?
-        .tip_hash();
-    send_message(&mut stream, &P2pMessage::GetHeaders { locator: vec![tip] })?;
+        .locator();
+    send_message(&mut stream, magic, &P2pMessage::GetHeaders { locator })?;
+    let mut missing = blocks
+        .lock()
+        .map_err(|_| P2pError::InvalidBlock("block store lock".to_string()))?
+        .missing();
+    if !missing.is_empty() {
+        missing.truncate(MAX_GET);
+        send_message(&mut stream, magic, &P2pMessage::GetBlock(missing))?;
+    }
     let mut rate = RateLimiter::new();
+    let mut state = PeerState::AwaitingHello;
+    // Full low-work batches held back until the run beats our tip.
+    let mut pending_headers: Vec<BlockHeader> = Vec::new();
     loop {
-        let msg = read_message(&mut stream)?;
+        let msg = read_message(&mut stream, magic)?;
         rate.bump()?;
         validate_message(&msg)?;
+        state.admit(&msg)?;
         match msg {
             P2pMessage::Hello {
                 version,
                 network,
-                node_id,
+                node_id: peer_node_id,
                 txid_version: peer_txid_opt,
+                headers: peer_headers,
             } => {
+                if peer_node_id == node_id {
+                    close_gracefully(&mut stream);
+                    return Err(P2pError::SelfConnection);
+                }
                 if version < MIN_PROTOCOL_VERSION || version > MAX_PROTOCOL_VERSION {
                     return Err(P2pError::InvalidBlock(
                         "protocol version not supported".to_string(),
@@ -323,6 +663,7 @@ fn handle_connection(
                 if network != network_id {
                     return Err(P2pError::InvalidBlock("network mismatch".to_string()));
                 }
+                state = PeerState::Ready;
                 {
                     let mut guard = peers
                         .lock()
@@ -331,7 +672,7 @@ fn handle_connection(
                     guard.mark_seen(&peer);
                 }
                 logger.info(format!(
-                    "[{peer}] hello v{version} net={network} id={node_id} txid=v{peer_txid_version}"
+                    "[{peer}] hello v{version} net={network} id={peer_node_id} txid=v{peer_txid_version}"
                 ));
                 let list = {
                     let mut guard = peers
@@ -339,7 +680,23 @@ fn handle_connection(
                         .map_err(|_| P2pError::InvalidBlock("peers lock".to_string()))?;
                     guard.list()
                 };
-                send_message(&mut stream, &P2pMessage::Addr(list))?;
+                send_message(&mut stream, magic, &P2pMessage::Addr(list))?;
+                if peer_headers != Some(true) {
+                    // Headers-first sync is preferred, but this peer never
+                    // advertised it: ask for a block inv instead.
+                    let locator = chain
+                        .lock()
+                        .map_err(|_| P2pError::InvalidBlock("chain lock".to_string()))?
+                        .locator();
+                    send_message(
+                        &mut stream,
+                        magic,
+                        &P2pMessage::GetBlocks {
+                            locator,
+                            stop_hash: [0u8; 32],
+                        },
+                    )?;
+                }
             }
             P2pMessage::Addr(addrs) => {
                 for addr in addrs {
@@ -366,6 +723,7 @@ fn handle_connection(
                                 db.clone(),
                                 no_pow_check,
                                 txid_version,
+                                options.clone(),
                                 Arc::clone(&logger),
                             );
                         }
@@ -383,19 +741,61 @@ fn handle_connection(
                     .map_err(|_| P2pError::InvalidBlock("chain lock".to_string()))?
                     .headers_after(locator, 2000);
                 if !headers.is_empty() {
-                    send_message(&mut stream, &P2pMessage::Headers(headers))?;
+                    send_message(&mut stream, magic, &P2pMessage::Headers(headers))?;
+                }
+            }
+            P2pMessage::GetBlocks { locator, stop_hash } => {
+                let hashes = chain
+                    .lock()
+                    .map_err(|_| P2pError::InvalidBlock("chain lock".to_string()))?
+                    .block_hashes_after(&locator, stop_hash, MAX_GETBLOCKS_INV);
+                if !hashes.is_empty() {
+                    send_message(
+                        &mut stream,
+                        magic,
+                        &P2pMessage::Inv {
+                            txids: Vec::new(),
+                            blocks: hashes,
+                        },
+                    )?;
                 }
             }
             P2pMessage::Headers(headers) => {
+                let batch_len = headers.len();
+                pending_headers.extend(headers);
                 let mut chain = chain
                     .lock()
                     .map_err(|_| P2pError::InvalidBlock("chain lock".to_string()))?;
-                for header in headers {
-                    if let Err(err) = chain.add_header(&header, no_pow_check) {
-                        logger.warn(format!("[{peer}] header rejected: {err}"));
-                        break;
+                if chain.headers_beat_tip(&pending_headers) {
+                    peers
+                        .lock()
+                        .map_err(|_| P2pError::InvalidBlock("peers lock".to_string()))?
+                        .clear_low_work(&peer);
+                    for header in pending_headers.drain(..) {
+                        if let Err(err) = chain.add_header(&header, no_pow_check) {
+                            logger.warn(format!("[{peer}] header rejected: {err}"));
+                            break;
+                        }
                     }
+                    continue;
+                }
+                if batch_len < MAX_HEADERS {
+                    logger.warn(format!(
+                        "[{peer}] ignoring {} headers that cannot beat our tip",
+                        pending_headers.len()
+                    ));
+                    pending_headers.clear();
+                    continue;
                 }
+                drop(chain);
+                // A full batch may be followed by more work: ask for the rest
+                // without storing anything yet.
+                peers
+                    .lock()
+                    .map_err(|_| P2pError::InvalidBlock("peers lock".to_string()))?
+                    .note_low_work(&peer, batch_len)?;
+                let locator = pending_headers.last().map(header_hash).into_iter().collect();
+                send_message(&mut stream, magic, &P2pMessage::GetHeaders { locator })?;
             }
             P2pMessage::Inv { txids, blocks: block_hashes } => {
                 let mut want_tx = Vec::new();
@@ -406,6 +806,9 @@ fn handle_connection(
                         .lock()
                         .map_err(|_| P2pError::InvalidBlock("mempool lock".to_string()))?;
                     for txid in txids {
+                        if options.blocks_only {
+                            break;
+                        }
                         let have = if txid_version == TXID_VERSION_V1 {
                             mempool.contains_v1(&txid)
                         } else {
@@ -428,14 +831,31 @@ fn handle_connection(
                     }
                 }
 
+                // Another peer may have announced the same items already;
+                // only fetch what nobody has been asked for recently.
+                let (want_tx, want_blocks) = {
+                    let mut guard = seen
+                        .lock()
+                        .map_err(|_| P2pError::InvalidBlock("seen lock".to_string()))?;
+                    let now = Instant::now();
+                    (
+                        claim_requests(&mut guard.tx_requested, want_tx, now),
+                        claim_requests(&mut guard.block_requested, want_blocks, now),
+                    )
+                };
+
                 if !want_tx.is_empty() {
-                    send_message(&mut stream, &P2pMessage::GetTx(want_tx))?;
+                    send_message(&mut stream, magic, &P2pMessage::GetTx(want_tx))?;
                 }
                 if !want_blocks.is_empty() {
-                    send_message(&mut stream, &P2pMessage::GetBlock(want_blocks))?;
+                    send_message(&mut stream, magic, &P2pMessage::GetBlock(want_blocks))?;
                 }
             }
             P2pMessage::GetTx(txids) => {
+                let filter = peers
+                    .lock()
+                    .map_err(|_| P2pError::InvalidBlock("peers lock".to_string()))?
+                    .filter_for(&peer);
                 let mempool = mempool
                     .lock()
                     .map_err(|_| P2pError::InvalidBlock("mempool lock".to_string()))?;
@@ -446,29 +866,99 @@ fn handle_connection(
                         mempool.get_tx(&txid)
                     };
                     if let Some(tx) = tx {
-                        send_message(&mut stream, &P2pMessage::Tx(tx))?;
+                        if let Some(ref filter) = filter {
+                            if !filter.matches_tx(&tx, &txid) {
+                                continue;
+                            }
+                        }
+                        send_message(&mut stream, magic, &P2pMessage::Tx(tx))?;
                     }
                 }
             }
+            P2pMessage::FilterLoad(filter) => {
+                logger.debug(format!(
+                    "[{peer}] filterload {} bytes, {} hash funcs",
+                    filter.data.len(),
+                    filter.hash_funcs
+                ));
+                peers
+                    .lock()
+                    .map_err(|_| P2pError::InvalidBlock("peers lock".to_string()))?
+                    .set_filter(&peer, filter);
+            }
+            P2pMessage::FilterAdd(element) => {
+                let added = peers
+                    .lock()
+                    .map_err(|_| P2pError::InvalidBlock("peers lock".to_string()))?
+                    .add_to_filter(&peer, &element);
+                if !added {
+                    return Err(P2pError::InvalidBlock(
+                        "filteradd without loaded filter".to_string(),
+                    ));
+                }
+            }
+            P2pMessage::FilterClear => {
+                peers
+                    .lock()
+                    .map_err(|_| P2pError::InvalidBlock("peers lock".to_string()))?
+                    .clear_filter(&peer);
+            }
             P2pMessage::GetBlock(hashes) => {
+                let filter = peers
+                    .lock()
+                    .map_err(|_| P2pError::InvalidBlock("peers lock".to_string()))?
+                    .filter_for(&peer);
                 let blocks_store = blocks
                     .lock()
                     .map_err(|_| P2pError::InvalidBlock("block store lock".to_string()))?;
                 for hash in hashes {
-                    if let Some(block) = blocks_store.get(&hash) {
-                        send_message(&mut stream, &P2pMessage::Block(block))?;
+                    let Some(block) = blocks_store.get(&hash) else {
+                        let pruned = chain
+                            .lock()
+                            .map_err(|_| P2pError::InvalidBlock("chain lock".to_string()))?
+                            .pruned_height_of(&hash);
+                        if let Some(height) = pruned {
+                            logger.debug(format!("[{peer}] not serving {}", P2pError::BlockPruned(height)));
+                        }
+                        continue;
+                    };
+                    match filter {
+                        Some(ref filter) => {
+                            let (merkle_block, matched) = merkle_block_for_filter(&block, filter)?;
+                            send_message(&mut stream, magic, &P2pMessage::MerkleBlock(merkle_block))?;
+                            for tx in matched {
+                                send_message(&mut stream, magic, &P2pMessage::Tx(tx))?;
+                            }
+                        }
+                        None => send_message(&mut stream, magic, &P2pMessage::Block(block))?,
                     }
                 }
             }
+            P2pMessage::MerkleBlock(merkle_block) => {
+                let matched = merkle_block.verify()?;
+                logger.debug(format!(
+                    "[{peer}] merkleblock {} matched {}/{} txs",
+                    hex_encode(header_hash(&merkle_block.header)),
+                    matched.len(),
+                    merkle_block.total_txs
+                ));
+            }
             P2pMessage::Ping => {
                 logger.debug(format!("[{peer}] ping"));
-                send_message(&mut stream, &P2pMessage::Pong)?;
+                send_message(&mut stream, magic, &P2pMessage::Pong)?;
             }
             P2pMessage::Pong => {
                 logger.debug(format!("[{peer}] pong"));
             }
             P2pMessage::Tx(tx) => {
+                if options.blocks_only {
+                    logger.debug(format!("[{peer}] blocks-only: ignoring tx"));
+                    continue;
+                }
                 let txid = txid_for_version(&tx, txid_version)?;
+                if let Ok(mut guard) = seen.lock() {
+                    guard.tx_requested.remove(&txid);
+                }
                 if seen_tx(&seen, &txid)? {
                     continue;
                 }
@@ -478,10 +968,34 @@ fn handle_connection(
                 let utxos = utxos
                     .lock()
                     .map_err(|_| P2pError::InvalidBlock("utxo lock".to_string()))?;
-                match mempool.add_tx(tx, &*utxos) {
-                    Ok(()) => {
+                match mempool.add_tx_replace(tx.clone(), &*utxos, Replacement::OptIn) {
+                    Ok(replaced) => {
                         logger.info(format!("[{peer}] tx accepted {txid:?}"));
-                        broadcast_inv(&peers, vec![txid], vec![])?;
+                        for old in replaced {
+                            logger.info(format!("[{peer}] tx {} replaced by fee", hex_encode(old)));
+                        }
+                        if let Ok(mut guard) = seen.lock() {
+                            guard.txs_accepted += 1;
+                        }
+                        broadcast_tx_inv(&peers, magic, &hello, &tx, txid)?;
+                        // Children relayed ahead of this tx can go in now.
+                        for child in mempool.process_orphans(&tx.txid_v2()?, &*utxos) {
+                            let Some(child_tx) = mempool.get_tx(&child) else {
+                                continue;
+                            };
+                            logger.info(format!("[{peer}] orphan tx promoted {}", hex_encode(child)));
+                            if let Ok(mut guard) = seen.lock() {
+                                guard.txs_accepted += 1;
+                            }
+                            let child_txid = txid_for_version(&child_tx, txid_version)?;
+                            broadcast_tx_inv(&peers, magic, &hello, &child_tx, child_txid)?;
+                        }
+                    }
+                    Err(MempoolError::Utxo(UtxoError::MissingUtxo(_))) => {
+                        match mempool.add_orphan(tx) {
+                            Ok(()) => logger.debug(format!("[{peer}] tx orphaned {txid:?}")),
+                            Err(err) => logger.warn(format!("[{peer}] orphan rejected {txid:?}: {err}")),
+                        }
                     }
                     Err(err) => logger.warn(format!("[{peer}] tx rejected {txid:?}: {err}")),
                 }
@@ -489,6 +1003,9 @@ fn handle_connection(
             P2pMessage::Block(block) => {
                 logger.info(format!("[{peer}] block with {} txs", block.txs.len()));
                 let block_hash = header_hash(&block.header);
+                if let Ok(mut guard) = seen.lock() {
+                    guard.block_requested.remove(&block_hash);
+                }
                 if seen_block(&seen, &block_hash)? {
                     continue;
                 }
@@ -522,38 +1039,71 @@ fn handle_connection(
                     .map_err(|_| P2pError::InvalidBlock("chain lock".to_string()))?;
                 let mut evicted = Vec::new();
                 if let Err(err) = reorg_to_tip(
-                    &mut *applied,
-                    &*chain_ref,
-                    &*blocks_store,
-                    &mut *utxos,
+                    &mut applied,
+                    &chain_ref,
+                    &blocks_store,
+                    &mut utxos,
                     no_pow_check,
                     &mut evicted,
                 ) {
                     logger.warn(format!("[{peer}] block rejected: {err}"));
                 } else {
-                    for tx in evicted {
-                        let _ = mempool.add_tx(tx, &*utxos);
+                    if let Ok(mut guard) = seen.lock() {
+                        guard.blocks_accepted += 1;
+                    }
+                    if !options.blocks_only {
+                        readd_evicted(
+                            &mut mempool,
+                            &*utxos,
+                            evicted,
+                            options.prune_mempool_on_reorg,
+                            &peer,
+                            &logger,
+                        );
+                    }
+                    match evict_immature(&mut mempool, &chain_ref, applied.tip) {
+                        Ok(dropped) => {
+                            for entry in dropped {
+                                logger.debug(format!(
+                                    "[{peer}] evicted immature tx {}",
+                                    hex_encode(entry.txid_v2)
+                                ));
+                            }
+                        }
+                        Err(err) => logger.warn(format!("[{peer}] mempool eviction failed: {err}")),
                     }
                     for tx in &block.txs {
                         if let Ok(txid) = txid_for_version(tx, txid_version) {
-                            if txid_version == TXID_VERSION_V1 {
-                                let _ = mempool.remove_tx_v1(&txid);
+                            let removed = if txid_version == TXID_VERSION_V1 {
+                                mempool.remove_tx_v1(&txid)
                             } else {
-                                let _ = mempool.remove_tx(&txid);
+                                mempool.remove_tx(&txid)
+                            };
+                            if let Err(err) = removed {
+                                logger.warn(format!(
+                                    "[{peer}] could not drop mined tx {} from mempool: {err}",
+                                    hex_encode(txid)
+                                ));
                             }
                         }
                     }
                     if let Some(ref dir) = data_dir {
-                        if let Err(err) = persist_block(dir, &block, &block_hash, db.clone()) {
+                        let applied_tip = applied.tip;
+                        if let Err(err) = persist_accepted(
+                            dir,
+                            &block,
+                            &block_hash,
+                            &utxos,
+                            &applied_tip,
+                            db.clone(),
+                            &mut applied.flush,
+                        ) {
                             logger.warn(format!("[{peer}] persist block failed: {err}"));
                         }
-                        if let Err(err) = persist_utxos(dir, &utxos, db.clone()) {
-                            logger.warn(format!("[{peer}] persist utxos failed: {err}"));
-                        }
                     }
                     if best_tip == block_hash {
                         logger.info(format!("[{peer}] block accepted"));
-                        broadcast_inv(&peers, vec![], vec![block_hash])?;
+                        broadcast_inv(&peers, magic, &hello, vec![], vec![block_hash])?;
                     }
                 }
             }
@@ -561,15 +1111,22 @@ fn handle_connection(
     }
 }
 
-fn send_message(stream: &mut TcpStream, msg: &P2pMessage) -> Result<(), P2pError> {
+/// Frame layout: 4-byte network magic, 4-byte big-endian length, JSON body.
+fn send_message(stream: &mut TcpStream, magic: [u8; 4], msg: &P2pMessage) -> Result<(), P2pError> {
     let data = serde_json::to_vec(msg)?;
     let len = data.len() as u32;
+    stream.write_all(&magic)?;
     stream.write_all(&len.to_be_bytes())?;
     stream.write_all(&data)?;
     Ok(())
 }
 
-fn read_message(stream: &mut TcpStream) -> Result<P2pMessage, P2pError> {
+fn read_message(stream: &mut TcpStream, magic: [u8; 4]) -> Result<P2pMessage, P2pError> {
+    let mut magic_buf = [0u8; 4];
+    stream.read_exact(&mut magic_buf)?;
+    if magic_buf != magic {
+        return Err(P2pError::BadMagic(magic_buf));
+    }
     let mut len_buf = [0u8; 4];
     stream.read_exact(&mut len_buf)?;
     let len = u32::from_be_bytes(len_buf) as usize;
@@ -581,6 +1138,22 @@ fn read_message(stream: &mut TcpStream) -> Result<P2pMessage, P2pError> {
     parse_message_bytes(&data)
 }
 
+/// Send FIN and read until the peer's, so the close never turns into a
+/// reset that would discard what we already sent (on a self-connection,
+/// the Hello the other end needs to detect it too). Bounded by the read
+/// timeout and by `MAX_MESSAGE_BYTES` of leftover input.
+fn close_gracefully(stream: &mut TcpStream) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let mut buf = [0u8; 4096];
+    let mut drained = 0usize;
+    while drained < MAX_MESSAGE_BYTES {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => drained += n,
+        }
+    }
+}
+
 fn txid_for_version(tx: &Transaction, txid_version: u8) -> Result<[u8; 32], P2pError> {
     match txid_version {
         TXID_VERSION_V1 => Ok(tx.txid_v1()?),
@@ -601,6 +1174,34 @@ pub(crate) fn parse_message_bytes(data: &[u8]) -> Result<P2pMessage, P2pError> {
 struct Seen {
     tx: HashSet<[u8; 32]>,
     block: HashSet<[u8; 32]>,
+    txs_accepted: u64,
+    blocks_accepted: u64,
+    /// In-flight `GetTx`/`GetBlock` requests, shared by every connection so
+    /// overlapping `Inv`s from several peers fetch each item once.
+    tx_requested: HashMap<[u8; 32], Instant>,
+    block_requested: HashMap<[u8; 32], Instant>,
+}
+
+/// Keep the hashes not already in flight and mark them requested at `now`.
+/// A request older than `REQUEST_TIMEOUT_SECS` counts as lost, so the next
+/// peer to announce the item gets asked instead.
+fn claim_requests(
+    in_flight: &mut HashMap<[u8; 32], Instant>,
+    hashes: Vec<[u8; 32]>,
+    now: Instant,
+) -> Vec<[u8; 32]> {
+    let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    in_flight.retain(|_, requested| now.saturating_duration_since(*requested) < timeout);
+    hashes
+        .into_iter()
+        .filter(|hash| {
+            if in_flight.contains_key(hash) {
+                return false;
+            }
+            in_flight.insert(*hash, now);
+            true
+        })
+        .collect()
 }
 
 fn seen_tx(seen: &Arc<Mutex<Seen>>, txid: &[u8; 32]) -> Result<bool, P2pError> {
@@ -621,8 +1222,12 @@ fn seen_block(seen: &Arc<Mutex<Seen>>, hash: &[u8; 32]) -> Result<bool, P2pError
     Ok(false)
 }
 
+/// Announce over a short-lived connection; `hello` goes first since peers
+/// drop anything sent before a handshake.
 fn broadcast_inv(
     peers: &Arc<Mutex<PeerManager>>,
+    magic: [u8; 4],
+    hello: &P2pMessage,
     txids: Vec<[u8; 32]>,
     blocks: Vec<[u8; 32]>,
 ) -> Result<(), P2pError> {
@@ -634,8 +1239,10 @@ fn broadcast_inv(
     };
     for peer in list {
         if let Ok(mut stream) = TcpStream::connect(peer) {
+            let _ = send_message(&mut stream, magic, hello);
             let _ = send_message(
                 &mut stream,
+                magic,
                 &P2pMessage::Inv {
                     txids: txids.clone(),
                     blocks: blocks.clone(),
@@ -646,11 +1253,218 @@ fn broadcast_inv(
     Ok(())
 }
 
+/// Partial merkle tree for the transactions of `block` that match `filter`,
+/// plus the matched transactions themselves.
+fn merkle_block_for_filter(
+    block: &Block,
+    filter: &BloomFilter,
+) -> Result<(MerkleBlock, Vec<Transaction>), P2pError> {
+    let mut matches = Vec::with_capacity(block.txs.len());
+    let mut matched = Vec::new();
+    for (tx, txid) in block.txs.iter().zip(block.txids()?) {
+        let is_match = filter.matches_tx(tx, &txid);
+        if is_match {
+            matched.push(tx.clone());
+        }
+        matches.push(is_match);
+    }
+    Ok((MerkleBlock::from_block(block, &matches)?, matched))
+}
+
+/// Announce a transaction, skipping peers whose bloom filter does not match it.
+fn broadcast_tx_inv(
+    peers: &Arc<Mutex<PeerManager>>,
+    magic: [u8; 4],
+    hello: &P2pMessage,
+    tx: &Transaction,
+    txid: [u8; 32],
+) -> Result<(), P2pError> {
+    let list: Vec<String> = {
+        let mut guard = peers
+            .lock()
+            .map_err(|_| P2pError::InvalidBlock("peers lock".to_string()))?;
+        guard.tx_relay_targets(tx, &txid)
+    };
+    for peer in list {
+        if let Ok(mut stream) = TcpStream::connect(peer) {
+            let _ = send_message(&mut stream, magic, hello);
+            let _ = send_message(
+                &mut stream,
+                magic,
+                &P2pMessage::Inv {
+                    txids: vec![txid],
+                    blocks: vec![],
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+/// JSON-RPC methods over the state shared with the P2P threads. Hashes and
+/// txids are exchanged in display (byte-reversed) hex, except inside
+/// `getutxo`'s outpoint, which uses the same JSON as UTXO files.
+///
+/// - `getblockcount`: best chain height
+/// - `getbestblockhash`: best chain tip hash
+/// - `getrawmempool`: sorted txids of mempool entries
+/// - `getutxo [outpoint]`: the unspent `TxOut`, or null
+/// - `gettxout [txid, vout]`: `{bestblock, value, script_pubkey}`, or null
+///   when the output is not in the UTXO set
+/// - `getspendingtx [txid, vout]`: txid of the best-chain transaction that
+///   spent the output, or null; needs a data dir for the `spent` index
+/// - `sendrawtransaction [tx]`: accept a transaction (JSON object, or a
+///   string holding it) into the mempool, relay it and return its txid
+///
+/// `getutxo` and `gettxout` read through `utxo_reader`, so they answer while
+/// a block is being connected; only `sendrawtransaction` takes the mutex.
+struct NodeRpc {
+    mempool: Arc<Mutex<Mempool>>,
+    utxos: Arc<Mutex<InMemoryUtxoSet>>,
+    utxo_reader: Arc<ConcurrentUtxoSet>,
+    chain: Arc<Mutex<ChainState>>,
+    peers: Arc<Mutex<PeerManager>>,
+    db: Option<Db>,
+    magic: [u8; 4],
+    hello: P2pMessage,
+    txid_version: u8,
+    logger: Arc<Logger>,
+}
+
+impl RpcHandler for NodeRpc {
+    fn call(&self, method: &str, params: &serde_json::Value) -> Result<serde_json::Value, RpcError> {
+        let internal = |err: P2pError| RpcError::new(rpc::INTERNAL_ERROR, err.to_string());
+        let result = match method {
+            "getblockcount" => {
+                let chain = self.chain().map_err(internal)?;
+                serde_json::json!(chain.height_of(&chain.tip_hash()).unwrap_or(0))
+            }
+            "getbestblockhash" => {
+                serde_json::json!(txid_to_display_hex(&self.chain().map_err(internal)?.tip_hash()))
+            }
+            "getrawmempool" => {
+                let mut txids = self.mempool().map_err(internal)?.all_txids();
+                txids.sort_unstable();
+                serde_json::json!(txids.iter().map(txid_to_display_hex).collect::<Vec<_>>())
+            }
+            "getutxo" => {
+                let outpoint: OutPoint = serde_json::from_value(rpc_param(params, 0)?.clone())
+                    .map_err(|err| RpcError::invalid_params(format!("outpoint: {err}")))?;
+                serde_json::json!(self.utxo_reader.get(&outpoint))
+            }
+            "gettxout" => {
+                let txout = self.utxo_reader.get(&rpc_outpoint(params)?);
+                let bestblock = txid_to_display_hex(&self.chain().map_err(internal)?.tip_hash());
+                match txout {
+                    Some(txout) => serde_json::json!({
+                        "bestblock": bestblock,
+                        "value": txout.value,
+                        "script_pubkey": hex_encode(&txout.script_pubkey),
+                    }),
+                    None => serde_json::Value::Null,
+                }
+            }
+            "getspendingtx" => {
+                let outpoint = rpc_outpoint(params)?;
+                let db = self
+                    .db
+                    .as_ref()
+                    .ok_or_else(|| RpcError::new(rpc::INTERNAL_ERROR, "no spent index without a data dir"))?;
+                let spender = is_spent(db, &outpoint).map_err(internal)?;
+                serde_json::json!(spender.as_ref().map(txid_to_display_hex))
+            }
+            "sendrawtransaction" => {
+                let param = rpc_param(params, 0)?;
+                let tx: Transaction = match param.as_str() {
+                    Some(text) => serde_json::from_str(text),
+                    None => serde_json::from_value(param.clone()),
+                }
+                .map_err(|err| RpcError::invalid_params(format!("transaction: {err}")))?;
+                serde_json::json!(txid_to_display_hex(&self.send_transaction(tx)?))
+            }
+            _ => {
+                return Err(RpcError::new(
+                    rpc::METHOD_NOT_FOUND,
+                    format!("unknown method {method}"),
+                ))
+            }
+        };
+        Ok(result)
+    }
+}
+
+impl NodeRpc {
+    fn chain(&self) -> Result<std::sync::MutexGuard<'_, ChainState>, P2pError> {
+        self.chain
+            .lock()
+            .map_err(|_| P2pError::InvalidBlock("chain lock".to_string()))
+    }
+
+    fn mempool(&self) -> Result<std::sync::MutexGuard<'_, Mempool>, P2pError> {
+        self.mempool
+            .lock()
+            .map_err(|_| P2pError::InvalidBlock("mempool lock".to_string()))
+    }
+
+    fn utxos(&self) -> Result<std::sync::MutexGuard<'_, InMemoryUtxoSet>, P2pError> {
+        self.utxos
+            .lock()
+            .map_err(|_| P2pError::InvalidBlock("utxo lock".to_string()))
+    }
+
+    /// Add `tx` to the mempool as if a peer had relayed it (replace-by-fee
+    /// rules included, but no orphan pool), then announce it to peers.
+    fn send_transaction(&self, tx: Transaction) -> Result<[u8; 32], RpcError> {
+        let internal = |err: P2pError| RpcError::new(rpc::INTERNAL_ERROR, err.to_string());
+        let txid = tx.txid_v2().map_err(|err| RpcError::invalid_params(err.to_string()))?;
+        {
+            let mut mempool = self.mempool().map_err(internal)?;
+            let utxos = self.utxos().map_err(internal)?;
+            mempool
+                .add_tx_replace(tx.clone(), &*utxos, Replacement::OptIn)
+                .map_err(|err| RpcError::new(rpc::REJECTED, err.to_string()))?;
+        }
+        self.logger.info(format!("rpc tx accepted {}", hex_encode(txid)));
+        let relay_id = txid_for_version(&tx, self.txid_version).map_err(internal)?;
+        broadcast_tx_inv(&self.peers, self.magic, &self.hello, &tx, relay_id).map_err(internal)?;
+        Ok(txid)
+    }
+}
+
+fn rpc_param(params: &serde_json::Value, index: usize) -> Result<&serde_json::Value, RpcError> {
+    params
+        .get(index)
+        .ok_or_else(|| RpcError::invalid_params(format!("missing parameter {index}")))
+}
+
+/// The `[txid, vout]` pair taken by `gettxout` and `getspendingtx`, with the
+/// txid in display hex.
+fn rpc_outpoint(params: &serde_json::Value) -> Result<OutPoint, RpcError> {
+    let txid = rpc_param(params, 0)?
+        .as_str()
+        .ok_or_else(|| RpcError::invalid_params("txid must be a hex string"))
+        .and_then(|txid| txid_from_display_hex(txid).map_err(|err| RpcError::invalid_params(err.to_string())))?;
+    let vout = rpc_param(params, 1)?
+        .as_u64()
+        .and_then(|vout| u32::try_from(vout).ok())
+        .ok_or_else(|| RpcError::invalid_params("vout must be a u32"))?;
+    Ok(OutPoint { txid, vout })
+}
+
 #[derive(Debug, Default)]
 struct PeerManager {
     peers: HashSet<String>,
     banned: HashMap<String, Instant>,
     last_dial: HashMap<String, Instant>,
+    filters: HashMap<String, BloomFilter>,
+    /// Low-work headers sent per peer host, kept across reconnects
+    low_work: HashMap<String, usize>,
+    /// Addresses that turned out to be this node
+    self_addrs: HashSet<String>,
+    /// Local socket address of each outbound connection, with the address
+    /// it dialed. An inbound self-connection arrives from one of these, so
+    /// the accepting side can tell which dialed address is our own.
+    outbound: HashMap<String, String>,
 }
 
 impl PeerManager {
@@ -659,6 +1473,10 @@ impl PeerManager {
             peers: initial.into_iter().collect(),
             banned: HashMap::new(),
             last_dial: HashMap::new(),
+            filters: HashMap::new(),
+            low_work: HashMap::new(),
+            self_addrs: HashSet::new(),
+            outbound: HashMap::new(),
         }
     }
 
@@ -686,12 +1504,35 @@ impl PeerManager {
         if self.banned.contains_key(addr) {
             return Err(P2pError::InvalidBlock("peer banned".to_string()));
         }
+        if self.self_addrs.contains(addr) {
+            return Ok(false);
+        }
         if self.peers.len() >= MAX_PEERS {
             return Err(P2pError::InvalidBlock("peer limit reached".to_string()));
         }
         Ok(self.peers.insert(addr.to_string()))
     }
 
+    fn remove(&mut self, addr: &str) {
+        self.peers.remove(addr);
+        self.filters.remove(addr);
+    }
+
+    fn mark_self(&mut self, addr: &str) {
+        self.remove(addr);
+        self.self_addrs.insert(addr.to_string());
+    }
+
+    /// An inbound connection from `peer` turned out to be ourselves: free
+    /// its slot and, if it came from one of our own dials, remember the
+    /// dialed address as ours.
+    fn drop_inbound_self(&mut self, peer: &str) {
+        self.remove(peer);
+        if let Some(dialed) = self.outbound.get(peer).cloned() {
+            self.mark_self(&dialed);
+        }
+    }
+
     fn list(&mut self) -> Vec<String> {
         self.purge_bans();
         self.peers.iter().cloned().collect()
@@ -703,6 +1544,9 @@ impl PeerManager {
 
     fn should_dial(&mut self, addr: &str) -> bool {
         self.purge_bans();
+        if self.self_addrs.contains(addr) {
+            return false;
+        }
         let now = Instant::now();
         match self.last_dial.get(addr) {
             Some(ts) => now.duration_since(*ts).as_secs() >= SEED_DIAL_INTERVAL_SECS,
@@ -712,16 +1556,69 @@ impl PeerManager {
 
     fn ban(&mut self, addr: &str) {
         self.peers.remove(addr);
+        self.filters.remove(addr);
         self.banned
             .insert(addr.to_string(), Instant::now() + Duration::from_secs(BAN_DURATION_SECS));
     }
 
+    /// Count `count` low-work headers against the host of `addr`; past
+    /// `MAX_LOW_WORK_HEADERS` the peer is rejected, and banned by the caller.
+    fn note_low_work(&mut self, addr: &str, count: usize) -> Result<(), P2pError> {
+        let sent = self.low_work.entry(peer_host(addr).to_string()).or_default();
+        *sent += count;
+        if *sent > MAX_LOW_WORK_HEADERS {
+            self.low_work.remove(peer_host(addr));
+            return Err(P2pError::InvalidBlock("too many low-work headers".to_string()));
+        }
+        Ok(())
+    }
+
+    fn clear_low_work(&mut self, addr: &str) {
+        self.low_work.remove(peer_host(addr));
+    }
+
     fn mark_seen(&mut self, _addr: &str) {}
 
     fn count(&mut self) -> usize {
         self.purge_bans();
         self.peers.len()
     }
+
+    fn set_filter(&mut self, addr: &str, filter: BloomFilter) {
+        self.filters.insert(addr.to_string(), filter);
+    }
+
+    fn add_to_filter(&mut self, addr: &str, element: &[u8]) -> bool {
+        match self.filters.get_mut(addr) {
+            Some(filter) => {
+                filter.insert(element);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn clear_filter(&mut self, addr: &str) {
+        self.filters.remove(addr);
+    }
+
+    fn filter_for(&self, addr: &str) -> Option<BloomFilter> {
+        self.filters.get(addr).cloned()
+    }
+
+    /// Peers that should hear about `tx`: everyone without a filter, plus
+    /// filtered peers whose filter matches.
+    fn tx_relay_targets(&mut self, tx: &Transaction, txid: &[u8; 32]) -> Vec<String> {
+        self.purge_bans();
+        self.peers
+            .iter()
+            .filter(|peer| match self.filters.get(*peer) {
+                Some(filter) => filter.matches_tx(tx, txid),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
 }
 
 fn validate_message(msg: &P2pMessage) -> Result<(), P2pError> {
@@ -734,35 +1631,39 @@ fn validate_message(msg: &P2pMessage) -> Result<(), P2pError> {
                 return Err(P2pError::InvalidBlock("invalid network id length".to_string()));
             }
         }
-        P2pMessage::Addr(addrs) => {
-            if addrs.len() > MAX_ADDR {
-                return Err(P2pError::InvalidBlock("addr list too large".to_string()));
-            }
+        P2pMessage::Addr(addrs) if addrs.len() > MAX_ADDR => {
+            return Err(P2pError::InvalidBlock("addr list too large".to_string()));
         }
-        P2pMessage::Inv { txids, blocks } => {
-            if txids.len() > MAX_INV || blocks.len() > MAX_INV {
-                return Err(P2pError::InvalidBlock("inv list too large".to_string()));
-            }
+        P2pMessage::Inv { txids, blocks } if txids.len() > MAX_INV || blocks.len() > MAX_INV => {
+            return Err(P2pError::InvalidBlock("inv list too large".to_string()));
         }
-        P2pMessage::GetTx(txids) => {
-            if txids.len() > MAX_GET {
-                return Err(P2pError::InvalidBlock("gettx list too large".to_string()));
-            }
+        P2pMessage::GetTx(txids) if txids.len() > MAX_GET => {
+            return Err(P2pError::InvalidBlock("gettx list too large".to_string()));
         }
-        P2pMessage::GetBlock(hashes) => {
-            if hashes.len() > MAX_GET {
-                return Err(P2pError::InvalidBlock("getblock list too large".to_string()));
-            }
+        P2pMessage::GetBlock(hashes) if hashes.len() > MAX_GET => {
+            return Err(P2pError::InvalidBlock("getblock list too large".to_string()));
         }
-        P2pMessage::GetHeaders { locator } => {
-            if locator.len() > MAX_GET {
-                return Err(P2pError::InvalidBlock("locator list too large".to_string()));
-            }
+        P2pMessage::GetHeaders { locator } | P2pMessage::GetBlocks { locator, .. }
+            if locator.len() > MAX_GET =>
+        {
+            return Err(P2pError::InvalidBlock("locator list too large".to_string()));
         }
-        P2pMessage::Headers(headers) => {
-            if headers.len() > MAX_HEADERS {
-                return Err(P2pError::InvalidBlock("headers list too large".to_string()));
-            }
+        P2pMessage::Headers(headers) if headers.len() > MAX_HEADERS => {
+            return Err(P2pError::InvalidBlock("headers list too large".to_string()));
+        }
+        P2pMessage::FilterLoad(filter) => {
+            filter
+                .validate()
+                .map_err(|e| P2pError::InvalidBlock(e.to_string()))?;
+        }
+        P2pMessage::MerkleBlock(merkle_block)
+            if merkle_block.matched_hashes.len() > MAX_INV
+                || merkle_block.flags.len() > MAX_INV =>
+        {
+            return Err(P2pError::InvalidBlock("merkleblock too large".to_string()));
+        }
+        P2pMessage::FilterAdd(element) if element.len() > MAX_FILTER_ADD_BYTES => {
+            return Err(P2pError::InvalidBlock("filteradd element too large".to_string()));
         }
         _ => {}
     }
@@ -795,9 +1696,41 @@ impl RateLimiter {
     }
 }
 
-fn should_ban(err: &P2pError) -> bool {
-    matches!(err, P2pError::InvalidLength | P2pError::InvalidBlock(_) | P2pError::Json(_))
-}
+/// Clean up after a connection to `peer` ended with `err`. A self-connection
+/// frees the peer slot, and the dialed address is remembered as our own so
+/// it is never dialed or gossiped again. Either side can do that: the
+/// accepting side finds the dialed address among our outbound connections,
+/// so it does not matter whether the dialer ever reads the other Hello.
+fn drop_peer(peers: &Mutex<PeerManager>, peer: &str, err: &P2pError, dialed: bool) {
+    let Ok(mut guard) = peers.lock() else {
+        return;
+    };
+    match err {
+        P2pError::SelfConnection if dialed => guard.mark_self(peer),
+        P2pError::SelfConnection => guard.drop_inbound_self(peer),
+        err if should_ban(err) => guard.ban(peer),
+        _ => {}
+    }
+}
+
+/// The host part of a peer address, which stays the same when an inbound
+/// peer reconnects from a new port.
+fn peer_host(addr: &str) -> &str {
+    addr.parse::<SocketAddr>()
+        .map(|_| addr.rsplit_once(':').map_or(addr, |(host, _)| host))
+        .unwrap_or(addr)
+}
+
+fn should_ban(err: &P2pError) -> bool {
+    matches!(
+        err,
+        P2pError::InvalidLength
+            | P2pError::InvalidBlock(_)
+            | P2pError::Json(_)
+            | P2pError::BadMagic(_)
+            | P2pError::BeforeHandshake(_)
+    )
+}
 
 #[derive(Clone)]
 struct Logger {
@@ -850,37 +1783,228 @@ impl Logger {
     }
 }
 
+/// Point-in-time node metrics, logged by the stats thread and optionally
+/// exported as JSON with `--export-metrics-file`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeMetrics {
+    pub unix_time: u64,
+    pub peers: usize,
+    pub mempool_txs: usize,
+    pub mempool_bytes: usize,
+    pub utxo_count: usize,
+    pub utxo_bytes: usize,
+    pub tip: String,
+    pub height: u32,
+    /// Difficulty of the tip header (1.0 at the proof-of-work limit)
+    pub difficulty: f64,
+    /// Hash rate implied by `difficulty` at the target block time
+    pub hashrate: f64,
+    pub txs_seen: usize,
+    pub blocks_seen: usize,
+    pub txs_accepted: u64,
+    pub blocks_accepted: u64,
+    /// Block time of the tip header
+    pub tip_time: u32,
+    /// Tip older than `--max-tip-age`: still syncing, data may be stale
+    pub stale: bool,
+}
+
+/// Whether a tip timed `tip_time` is older than `max_tip_age` at `now`
+/// (local clock, unix seconds).
+fn tip_is_stale(tip_time: u32, now: u64, max_tip_age: Option<Duration>) -> bool {
+    max_tip_age.is_some_and(|max| now.saturating_sub(tip_time as u64) > max.as_secs())
+}
+
+fn collect_metrics(
+    peers: &Arc<Mutex<PeerManager>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    utxos: &ConcurrentUtxoSet,
+    chain: &Arc<Mutex<ChainState>>,
+    seen: &Arc<Mutex<Seen>>,
+    max_tip_age: Option<Duration>,
+) -> NodeMetrics {
+    let peer_count = peers.lock().map(|mut p| p.count()).unwrap_or(0);
+    let (mempool_txs, mempool_bytes) = mempool
+        .lock()
+        .map(|m| (m.len(), m.total_bytes()))
+        .unwrap_or((0, 0));
+    let (utxo_count, utxo_bytes) = (utxos.len(), utxos.approx_bytes());
+    let (tip, height, bits, tip_time, block_time) = chain
+        .lock()
+        .map(|c| {
+            let tip = c.tip_hash();
+            let height = c.heights.get(&tip).cloned().unwrap_or(0);
+            let header = c.headers.get(&tip);
+            let bits = header.map_or(0, |header| header.bits);
+            let tip_time = header.map_or(0, |header| header.time);
+            (tip, height, bits, tip_time, c.params.target_block_time_secs)
+        })
+        .unwrap_or(([0u8; 32], 0, 0, 0, TARGET_BLOCK_TIME_SECS));
+    let difficulty = difficulty(bits);
+    let (txs_seen, blocks_seen, txs_accepted, blocks_accepted) = seen
+        .lock()
+        .map(|s| (s.tx.len(), s.block.len(), s.txs_accepted, s.blocks_accepted))
+        .unwrap_or((0, 0, 0, 0));
+    let unix_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    NodeMetrics {
+        unix_time,
+        peers: peer_count,
+        mempool_txs,
+        mempool_bytes,
+        utxo_count,
+        utxo_bytes,
+        tip: hex_encode(tip),
+        height,
+        difficulty,
+        hashrate: network_hashrate(difficulty, block_time),
+        txs_seen,
+        blocks_seen,
+        txs_accepted,
+        blocks_accepted,
+        tip_time,
+        stale: tip_is_stale(tip_time, unix_time, max_tip_age),
+    }
+}
+
+/// Write the metrics snapshot atomically (tmp file + rename) so readers never
+/// see a partially written file.
+fn write_metrics_file(path: &Path, metrics: &NodeMetrics) -> Result<(), P2pError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, serde_json::to_vec_pretty(metrics)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn spawn_stats_thread(
     peers: Arc<Mutex<PeerManager>>,
     mempool: Arc<Mutex<Mempool>>,
-    utxos: Arc<Mutex<InMemoryUtxoSet>>,
+    utxos: Arc<ConcurrentUtxoSet>,
     chain: Arc<Mutex<ChainState>>,
+    seen: Arc<Mutex<Seen>>,
     interval_secs: u64,
+    metrics_file: Option<PathBuf>,
+    utxo_warn_bytes: usize,
+    max_tip_age: Option<Duration>,
     logger: Arc<Logger>,
 ) {
+    let mut over_utxo_limit = false;
+    let mut was_stale = false;
     thread::spawn(move || loop {
         thread::sleep(Duration::from_secs(interval_secs));
-        let peer_count = peers.lock().map(|mut p| p.count()).unwrap_or(0);
-        let (mempool_len, mempool_bytes) = mempool
-            .lock()
-            .map(|m| (m.len(), m.total_bytes()))
-            .unwrap_or((0, 0));
-        let utxo_count = utxos.lock().map(|u| u.entries().len()).unwrap_or(0);
-        let (tip, height) = chain
-            .lock()
-            .map(|c| {
-                let tip = c.tip_hash();
-                let height = c.heights.get(&tip).cloned().unwrap_or(0);
-                (tip, height)
-            })
-            .unwrap_or(([0u8; 32], 0));
+        let m = collect_metrics(&peers, &mempool, &utxos, &chain, &seen, max_tip_age);
         logger.info(format!(
-            "[stats] peers={peer_count} mempool={mempool_len} mempool_bytes={mempool_bytes} utxo={utxo_count} tip={} height={height}",
-            hex_encode(tip)
+            "[stats] peers={} mempool={} mempool_bytes={} utxo={} utxo_bytes={} tip={} height={} difficulty={:.4} hashrate={:.2}H/s stale={}",
+            m.peers,
+            m.mempool_txs,
+            m.mempool_bytes,
+            m.utxo_count,
+            m.utxo_bytes,
+            m.tip,
+            m.height,
+            m.difficulty,
+            m.hashrate,
+            m.stale
         ));
+        if m.stale && !was_stale {
+            logger.warn(format!(
+                "[stats] tip is {}s old; node is syncing and its chain data may be stale",
+                m.unix_time.saturating_sub(m.tip_time as u64)
+            ));
+        }
+        was_stale = m.stale;
+        let over = utxo_warn_bytes > 0 && m.utxo_bytes >= utxo_warn_bytes;
+        if over && !over_utxo_limit {
+            logger.warn(format!(
+                "[stats] in-memory UTXO set is ~{} bytes (threshold {utxo_warn_bytes}); consider a disk-backed store (--data-dir)",
+                m.utxo_bytes
+            ));
+        }
+        over_utxo_limit = over;
+        if let Some(ref path) = metrics_file {
+            if let Err(err) = write_metrics_file(path, &m) {
+                logger.warn(format!("[stats] metrics export failed: {err}"));
+            }
+        }
+    });
+}
+
+/// Snapshot the mempool to `path` every `interval_secs` so pending
+/// transactions survive a restart.
+/// On SIGINT/SIGTERM, snapshot the UTXO set at the applied tip, flush sled
+/// and save the mempool before exiting, so a clean shutdown leaves no blocks
+/// to replay at the next start.
+fn install_shutdown_flush(
+    dir: PathBuf,
+    utxos: Arc<Mutex<InMemoryUtxoSet>>,
+    applied: Arc<Mutex<AppliedState>>,
+    mempool: Arc<Mutex<Mempool>>,
+    db: Option<Db>,
+    logger: Arc<Logger>,
+) {
+    let handler_logger = Arc::clone(&logger);
+    let installed = ctrlc::set_handler(move || {
+        let logger = &handler_logger;
+        logger.info("shutting down: flushing chainstate".to_string());
+        if let Err(err) = flush_chainstate(&dir, &utxos, &applied, db.clone()) {
+            logger.warn(format!("chainstate flush failed: {err}"));
+        }
+        if let Ok(mempool) = mempool.lock() {
+            if let Err(err) = mempool.save_to_path(&dir.join(MEMPOOL_FILE)) {
+                logger.warn(format!("[mempool] persist failed: {err}"));
+            }
+        }
+        std::process::exit(0);
+    });
+    if let Err(err) = installed {
+        logger.warn(format!("no chainstate flush on shutdown: {err}"));
+    }
+}
+
+/// Snapshot `utxos` at the applied tip and flush sled.
+fn flush_chainstate(
+    dir: &Path,
+    utxos: &Mutex<InMemoryUtxoSet>,
+    applied: &Mutex<AppliedState>,
+    db: Option<Db>,
+) -> Result<(), P2pError> {
+    let utxos = utxos
+        .lock()
+        .map_err(|_| P2pError::InvalidBlock("utxo lock".to_string()))?;
+    let applied = applied
+        .lock()
+        .map_err(|_| P2pError::InvalidBlock("applied lock".to_string()))?;
+    persist_utxos(dir, &utxos, &applied.tip, db.clone())?;
+    if let Some(db) = db {
+        db.flush()?;
+    }
+    Ok(())
+}
+
+fn spawn_mempool_persist_thread(
+    mempool: Arc<Mutex<Mempool>>,
+    path: PathBuf,
+    interval_secs: u64,
+    logger: Arc<Logger>,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(interval_secs));
+        let res = match mempool.lock() {
+            Ok(guard) => guard.save_to_path(&path),
+            Err(_) => return,
+        };
+        if let Err(err) = res {
+            logger.warn(format!("[mempool] persist failed: {err}"));
+        }
     });
 }
 
+#[allow(clippy::too_many_arguments)]
 fn spawn_connect(
     peer: String,
     peers: Arc<Mutex<PeerManager>>,
@@ -896,6 +2020,7 @@ fn spawn_connect(
     db: Option<Db>,
     no_pow_check: bool,
     txid_version: u8,
+    options: P2pOptions,
     logger: Arc<Logger>,
 ) {
     thread::spawn(move || {
@@ -914,17 +2039,17 @@ fn spawn_connect(
                 return;
             }
 
-            if let Ok(mut stream) = TcpStream::connect(&peer) {
-                let node_id_clone = node_id.clone();
-                let network_id_clone = network_id.clone();
+            if let Ok(mut stream) = options.timeouts.connect(&peer) {
+                // Registered before our Hello goes out, so the accepting
+                // side of a self-dial always finds it.
+                let local = stream.local_addr().map(|addr| addr.to_string()).ok();
+                if let (Some(local), Ok(mut guard)) = (local.clone(), peers.lock()) {
+                    guard.outbound.insert(local, peer.clone());
+                }
                 let _ = send_message(
                     &mut stream,
-                    &P2pMessage::Hello {
-                        version: PROTOCOL_VERSION,
-                        network: network_id_clone,
-                        node_id: node_id_clone,
-                        txid_version: Some(txid_version),
-                    },
+                    NetworkParams::for_network(&network_id).magic,
+                    &local_hello(&network_id, &node_id, txid_version),
                 );
                 let res = handle_connection(
                     stream,
@@ -942,14 +2067,14 @@ fn spawn_connect(
                     db,
                     no_pow_check,
                     txid_version,
+                    options.clone(),
                     Arc::clone(&logger),
                 );
+                if let (Some(local), Ok(mut guard)) = (local, peers.lock()) {
+                    guard.outbound.remove(&local);
+                }
                 if let Err(err) = res {
-                    if should_ban(&err) {
-                        if let Ok(mut guard) = peers.lock() {
-                            guard.ban(&peer);
-                        }
-                    }
+                    drop_peer(&peers, &peer, &err, true);
                     logger.warn(format!("[{peer}] disconnected: {err}"));
                 }
                 return;
@@ -963,6 +2088,7 @@ fn spawn_connect(
     });
 }
 
+#[allow(clippy::too_many_arguments)]
 fn spawn_seed_dialer(
     peers: Arc<Mutex<PeerManager>>,
     mempool: Arc<Mutex<Mempool>>,
@@ -977,6 +2103,7 @@ fn spawn_seed_dialer(
     db: Option<Db>,
     no_pow_check: bool,
     txid_version: u8,
+    options: P2pOptions,
     logger: Arc<Logger>,
 ) {
     thread::spawn(move || loop {
@@ -1014,6 +2141,7 @@ fn spawn_seed_dialer(
                 db.clone(),
                 no_pow_check,
                 txid_version,
+                options.clone(),
                 Arc::clone(&logger),
             );
         }
@@ -1049,7 +2177,7 @@ fn load_utxos(
                 set.insert(outpoint, txout);
             }
             if let Some(expected) = load_utxo_count(&db)? {
-                let actual = set.entries().len() as u64;
+                let actual = set.len() as u64;
                 if expected != actual {
                     return Err(P2pError::InvalidBlock("utxo count mismatch".to_string()));
                 }
@@ -1083,6 +2211,177 @@ fn load_utxo_count(db: &Db) -> Result<Option<u64>, P2pError> {
     Ok(None)
 }
 
+fn load_utxo_tip(db: &Db) -> Result<Option<[u8; 32]>, P2pError> {
+    let meta = db.open_tree("meta")?;
+    meta.get("utxo_tip")?.map(|tip| decode_hash(&tip)).transpose()
+}
+
+/// Load the UTXO snapshot and bring it up to the best chain. Snapshots are
+/// written only every half `--dbcache` of block data (see [`FlushState`]),
+/// while block bodies are stored as they arrive, so after a crash the
+/// snapshot's `utxo_tip` can lag the chain tip: the stored blocks above it
+/// are replayed and a fresh snapshot is written. Without a recorded tip the
+/// set is taken to be at the chain tip.
+fn load_chainstate(
+    utxo_path: Option<PathBuf>,
+    data_dir: Option<&Path>,
+    db: Option<&Db>,
+    chain: &ChainState,
+    blocks: &BlockStore,
+    applied: &mut AppliedState,
+    logger: &Logger,
+) -> Result<InMemoryUtxoSet, P2pError> {
+    let snapshot_tip = match (&utxo_path, db) {
+        (None, Some(db)) => load_utxo_tip(db)?,
+        _ => None,
+    };
+    let mut utxos = load_utxos(utxo_path, data_dir.map(Path::to_path_buf), db.cloned())?;
+    let (Some(snapshot_tip), Some(dir), Some(db)) = (snapshot_tip, data_dir, db) else {
+        restore_coinbase_meta(&mut utxos, chain, blocks, applied.tip);
+        return Ok(utxos);
+    };
+    restore_coinbase_meta(&mut utxos, chain, blocks, snapshot_tip);
+    let tip = chain.tip_hash();
+    if snapshot_tip == tip {
+        return Ok(utxos);
+    }
+    // There is no undo data below the snapshot, so only blocks on top of it
+    // can be replayed.
+    let ancestor = chain
+        .height_of(&snapshot_tip)
+        .map(|_| common_ancestor(chain, snapshot_tip, tip))
+        .transpose()?;
+    if ancestor != Some(snapshot_tip) {
+        return Err(P2pError::InvalidBlock(format!(
+            "UTXO snapshot at {} is not on the best chain; restart with --reindex-chainstate",
+            hex_encode(snapshot_tip)
+        )));
+    }
+    applied.tip = snapshot_tip;
+    // Headers were checked when they were added.
+    reorg_to_tip(applied, chain, blocks, &mut utxos, true, &mut Vec::new())?;
+    persist_utxos(dir, &utxos, &applied.tip, Some(db.clone()))?;
+    logger.info(format!(
+        "replayed blocks above UTXO snapshot {} up to {}",
+        hex_encode(snapshot_tip),
+        hex_encode(applied.tip)
+    ));
+    Ok(utxos)
+}
+
+/// Rebuild the UTXO set from block bodies for `--reindex-chainstate`.
+///
+/// The header index is kept as is: every best-chain block above genesis is
+/// replayed onto an empty set, filling `applied` with undo data on the way,
+/// and the `utxo` and `spent` trees are rewritten from the result. Headers
+/// were validated when they were added, so PoW and header rules are not
+/// checked again. If the last snapshot was taken at the current tip, the
+/// rebuilt set must match its recorded `utxo_count`.
+fn reindex_chainstate(
+    dir: &Path,
+    db: &Db,
+    chain: &ChainState,
+    blocks: &BlockStore,
+    applied: &mut AppliedState,
+) -> Result<InMemoryUtxoSet, P2pError> {
+    if chain.pruned_below > 1 {
+        return Err(P2pError::BlockPruned(1));
+    }
+    let tip = chain.tip_hash();
+    let expected_count = match load_utxo_tip(db)? {
+        Some(snapshot_tip) if snapshot_tip == tip => load_utxo_count(db)?,
+        _ => None,
+    };
+
+    let mut path = Vec::new();
+    let mut hash = tip;
+    while let Some(height) = chain.height_of(&hash) {
+        if height == 0 {
+            break;
+        }
+        path.push(hash);
+        hash = chain
+            .header_of(&hash)
+            .ok_or_else(|| P2pError::InvalidBlock("missing header".to_string()))?
+            .prev_block_hash;
+    }
+    path.reverse();
+
+    // Replay into fresh state; the stored snapshot and `applied` are only
+    // replaced once the whole chain has been reconnected.
+    let mut utxos = InMemoryUtxoSet::new();
+    let mut replayed = AppliedState::new(tip);
+    for hash in &path {
+        let block = blocks
+            .get(hash)
+            .ok_or_else(|| P2pError::InvalidBlock("missing block data".to_string()))?;
+        let height = chain
+            .height_of(hash)
+            .ok_or_else(|| P2pError::InvalidBlock("missing height".to_string()))?;
+        let receipts = apply_block_with_undo(
+            &block,
+            height,
+            parent_mtp(chain, &block),
+            &mut utxos,
+            true,
+            chain.params.block_subsidy(height),
+            chain.params.max_block_sigops,
+            chain.params.max_block_outputs,
+            chain.params.coinbase_maturity,
+        )?;
+        replayed.connect(*hash, receipts, &utxos);
+    }
+
+    if let Some(expected) = expected_count {
+        let actual = utxos.len() as u64;
+        if expected != actual {
+            return Err(P2pError::InvalidBlock(format!(
+                "reindexed utxo count {actual} does not match snapshot count {expected}"
+            )));
+        }
+    }
+    persist_utxos(dir, &utxos, &tip, Some(db.clone()))?;
+    db.open_tree("spent")?.clear()?;
+    for hash in &path {
+        let block = blocks
+            .get(hash)
+            .ok_or_else(|| P2pError::InvalidBlock("missing block data".to_string()))?;
+        index_spends(db, &block)?;
+    }
+    applied.undo = replayed.undo;
+    applied.value_deltas = replayed.value_deltas;
+    applied.tip = tip;
+    Ok(utxos)
+}
+
+/// Persisted UTXOs carry no creation height, so re-tag the coinbase outputs
+/// of the last `coinbase_maturity` blocks below `tip` that are still
+/// unspent; older coinbases are mature and need no tag.
+fn restore_coinbase_meta(
+    utxos: &mut InMemoryUtxoSet,
+    chain: &ChainState,
+    blocks: &BlockStore,
+    tip: [u8; 32],
+) {
+    let mut hash = tip;
+    for _ in 0..chain.params.coinbase_maturity {
+        let (Some(height), Some(header)) = (chain.height_of(&hash), chain.header_of(&hash)) else {
+            break;
+        };
+        if let Some(coinbase) = blocks.get(&hash).and_then(|block| block.txs.first().cloned()) {
+            if let Ok(outpoints) = Transaction::make_outpoints(&coinbase) {
+                for op in outpoints {
+                    utxos.set_meta(&op, CoinMeta { height, is_coinbase: true });
+                }
+            }
+        }
+        if height == 0 {
+            break;
+        }
+        hash = header.prev_block_hash;
+    }
+}
+
 fn load_tip_meta(db: &Db) -> Result<Option<([u8; 32], u32)>, P2pError> {
     let meta = db.open_tree("meta")?;
     let hash = meta.get("tip_hash")?;
@@ -1100,6 +2399,41 @@ fn load_tip_meta(db: &Db) -> Result<Option<([u8; 32], u32)>, P2pError> {
     }
 }
 
+fn load_pruned_below(db: &Db) -> Result<u32, P2pError> {
+    match db.open_tree("meta")?.get("pruned_below_height")? {
+        Some(bytes) => Ok(u32::from_le_bytes(bytes.as_ref().try_into().map_err(|_| {
+            P2pError::InvalidBlock("invalid pruned_below_height bytes".to_string())
+        })?)),
+        None => Ok(0),
+    }
+}
+
+/// Open the node DB under `dir` and prune block bodies more than `keep`
+/// blocks below its tip. The node must not be running.
+pub fn prune_data_dir(dir: &Path, network_id: &str, keep: u32) -> Result<PruneReport, P2pError> {
+    let db = open_sled(dir)?;
+    let mut chain = ChainState::load_or_genesis(Some(db), NetworkParams::for_network(network_id))?;
+    chain.prune_blocks(dir, keep)
+}
+
+/// Open the node DB under `dir` and store `blocks`, in order, as headers and
+/// bodies the way downloaded blocks are stored. Each must extend a known
+/// header; they are validated and connected at the next start, which replays
+/// stored blocks above the UTXO snapshot. The node must not be running.
+/// Returns the height of the resulting tip.
+pub fn import_blocks(dir: &Path, network_id: &str, blocks: &[Block]) -> Result<u32, P2pError> {
+    let db = open_sled(dir)?;
+    let mut chain =
+        ChainState::load_or_genesis(Some(db.clone()), NetworkParams::for_network(network_id))?;
+    for block in blocks {
+        let hash = header_hash(&block.header);
+        chain.add_header(&block.header, false)?;
+        persist_block(dir, block, &hash, Some(db.clone()))?;
+    }
+    db.flush()?;
+    Ok(chain.height_of(&chain.tip_hash()).unwrap_or(0))
+}
+
 fn ensure_db_schema(db: &Db) -> Result<(), P2pError> {
     let meta = db.open_tree("meta")?;
     if let Some(val) = meta.get("schema_version")? {
@@ -1122,140 +2456,252 @@ fn ensure_db_schema(db: &Db) -> Result<(), P2pError> {
     Ok(())
 }
 
-fn apply_block_with_undo(
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn apply_block_with_undo(
     block: &Block,
+    height: u32,
+    mtp: u32,
     utxos: &mut InMemoryUtxoSet,
     no_pow_check: bool,
     subsidy: u64,
-) -> Result<Vec<ApplyReceipt>, P2pError> {
-    if !no_pow_check {
-        let ok = check_pow(&block.header)?;
-        if !ok {
-            return Err(P2pError::InvalidBlock("invalid PoW".to_string()));
+    max_sigops: usize,
+    max_outputs: usize,
+    coinbase_maturity: u32,
+) -> Result<BlockReceipt, P2pError> {
+    block.check_contextual(ContextualChecks::new(height, mtp, max_sigops, max_outputs))?;
+    let total_fees = block.validate(utxos, subsidy, no_pow_check)?;
+
+    let out_sum = block.coinbase_value()?;
+    let receipt = utxos.apply_block_at_height(&block.txs, subsidy, height, coinbase_maturity)?;
+    // The set grows by the subsidy minus whatever the coinbase left
+    // unclaimed, i.e. the coinbase payout minus the fees it collected.
+    if cfg!(debug_assertions) {
+        let expected = out_sum as i128 - total_fees as i128;
+        let actual = utxo_value_delta(&receipt, utxos);
+        if actual != expected {
+            // Leave the set as it was, like any other rejected block.
+            utxos.rollback_block(receipt)?;
+            return Err(P2pError::ValueInvariant(format!(
+                "block changes total value by {actual}, expected {expected}"
+            )));
         }
     }
+    Ok(receipt)
+}
 
-    if block.txs.is_empty() {
-        return Err(P2pError::InvalidBlock("empty block".to_string()));
+/// Write `block` to `blocks/<hash>.json` and the `blocks` tree, returning
+/// the encoded size. Durability is left to the caller's flush.
+fn persist_block(
+    dir: &Path,
+    block: &Block,
+    hash: &[u8; 32],
+    db: Option<Db>,
+) -> Result<usize, P2pError> {
+    let blocks_dir = dir.join("blocks");
+    std::fs::create_dir_all(&blocks_dir)?;
+    let file = block_file_path(dir, hash);
+    let json = serde_json::to_string_pretty(block)?;
+    let size = json.len();
+    replace_durably(&file, |writer| Ok(writer.write_all(json.as_bytes())?))?;
+    if let Some(db) = db {
+        let tree = db.open_tree("blocks")?;
+        let key = hash.to_vec();
+        let value = serde_json::to_vec(block)?;
+        tree.insert(key, value)?;
     }
+    Ok(size)
+}
 
-    let txids = block
-        .txs
-        .iter()
-        .map(|tx| tx.txid_v2())
-        .collect::<Result<Vec<_>, _>>()?;
-    let root = merkle_root(&txids);
-    if root != block.header.merkle_root {
-        return Err(P2pError::InvalidBlock("merkle root mismatch".to_string()));
-    }
-
-    let mut total_fees = 0u64;
-    let mut receipts = Vec::new();
-    for (i, tx) in block.txs.iter().enumerate() {
-        if i == 0 {
-            if !tx.vin.is_empty() {
-                return Err(P2pError::InvalidBlock(
-                    "coinbase must have no inputs".to_string(),
-                ));
-            }
-            receipts.push(apply_coinbase(tx, utxos)?);
-        } else {
-            let fee = Transaction::validate_value_conservation(tx, utxos)?;
-            total_fees = total_fees.saturating_add(fee);
-            let receipt = utxos.apply_tx(tx)?;
-            receipts.push(receipt);
-        }
-    }
-    let coinbase = &block.txs[0];
-    let out_sum = Transaction::sum_outputs(coinbase)?;
-    if out_sum > subsidy.saturating_add(total_fees) {
-        return Err(P2pError::InvalidBlock("coinbase exceeds subsidy+fees".to_string()));
+/// Replace `path` with what `write` produces, never leaving it missing or
+/// partly written. The content goes to `<path>.tmp` and is synced before a
+/// single rename moves it over `path`; that replaces an existing file
+/// atomically on Unix and on Windows alike, so the old file stays in place
+/// until the new one is complete. The directory is synced afterwards where
+/// the platform allows it, making the rename itself durable.
+fn replace_durably(
+    path: &Path,
+    write: impl FnOnce(&mut std::io::BufWriter<std::fs::File>) -> Result<(), P2pError>,
+) -> Result<(), P2pError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    write(&mut writer)?;
+    let file = writer.into_inner().map_err(|err| P2pError::Io(err.into_error()))?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::File::open(parent)?.sync_all()?;
     }
-    Ok(receipts)
+    Ok(())
+}
+
+/// Batches persistence of accepted blocks. Unflushed writes are held up to
+/// half the `--dbcache` budget, so a larger cache means fewer UTXO snapshots
+/// and sled flushes during sync, at the cost of replaying up to that much of
+/// stored blocks at startup after a crash (see [`load_chainstate`]).
+#[derive(Debug, Default)]
+struct FlushState {
+    threshold_bytes: usize,
+    dirty_bytes: usize,
+    flushes: u64,
 }
 
-fn apply_coinbase(tx: &Transaction, utxos: &mut InMemoryUtxoSet) -> Result<ApplyReceipt, P2pError> {
-    tx.validate()?;
-    let outpoints = Transaction::make_outpoints(tx)?;
-    let mut inserted = Vec::new();
-    for (op, txout) in outpoints.into_iter().zip(tx.vout.iter()) {
-        if utxos.get(&op).is_some() {
-            return Err(P2pError::InvalidBlock("coinbase output exists".to_string()));
+impl FlushState {
+    fn new(dbcache_bytes: usize) -> Self {
+        Self {
+            threshold_bytes: dbcache_bytes / 2,
+            ..Self::default()
         }
-        utxos.insert(op.clone(), TxOut {
-            value: txout.value,
-            script_pubkey: txout.script_pubkey.clone(),
-        });
-        inserted.push(op);
     }
-    Ok(ApplyReceipt {
-        removed: Vec::new(),
-        inserted,
-    })
 }
 
-fn persist_block(
-    dir: &PathBuf,
+/// Persist an accepted block, then snapshot the UTXO set (as of the applied
+/// `tip`) and flush sled once the unflushed bytes exceed the cache threshold.
+fn persist_accepted(
+    dir: &Path,
     block: &Block,
     hash: &[u8; 32],
+    utxos: &InMemoryUtxoSet,
+    tip: &[u8; 32],
     db: Option<Db>,
+    flush: &mut FlushState,
 ) -> Result<(), P2pError> {
-    let blocks_dir = dir.join("blocks");
-    std::fs::create_dir_all(&blocks_dir)?;
-    let file = blocks_dir.join(format!("{}.json", hex_encode(hash)));
-    let tmp = blocks_dir.join(format!("{}.json.tmp", hex_encode(hash)));
-    let json = serde_json::to_string_pretty(block)?;
-    std::fs::write(&tmp, json)?;
-    if file.exists() {
-        std::fs::remove_file(&file)?;
+    flush.dirty_bytes += persist_block(dir, block, hash, db.clone())?;
+    if flush.dirty_bytes <= flush.threshold_bytes {
+        return Ok(());
     }
-    std::fs::rename(&tmp, &file)?;
+    persist_utxos(dir, utxos, tip, db.clone())?;
     if let Some(db) = db {
-        let tree = db.open_tree("blocks")?;
-        let key = hash.to_vec();
-        let value = serde_json::to_vec(block)?;
-        tree.insert(key, value)?;
-        tree.flush()?;
+        db.flush()?;
+    }
+    flush.dirty_bytes = 0;
+    flush.flushes += 1;
+    Ok(())
+}
+
+/// Record `outpoint -> spending txid` for every input in `block` in the
+/// `spent` tree.
+fn index_spends(db: &Db, block: &Block) -> Result<(), P2pError> {
+    let tree = db.open_tree("spent")?;
+    for tx in block.txs.iter().filter(|tx| !tx.is_coinbase()) {
+        let txid = tx.txid_v2()?;
+        for vin in &tx.vin {
+            tree.insert(encode_outpoint(&vin.prevout), txid.to_vec())?;
+        }
+    }
+    Ok(())
+}
+
+/// Undo [`index_spends`] when `block` is disconnected.
+fn unindex_spends(db: &Db, block: &Block) -> Result<(), P2pError> {
+    let tree = db.open_tree("spent")?;
+    for tx in block.txs.iter().filter(|tx| !tx.is_coinbase()) {
+        for vin in &tx.vin {
+            tree.remove(encode_outpoint(&vin.prevout))?;
+        }
     }
     Ok(())
 }
 
+/// Txid of the best-chain transaction that spent `outpoint`, if any.
+pub fn is_spent(db: &Db, outpoint: &OutPoint) -> Result<Option<[u8; 32]>, P2pError> {
+    let tree = db.open_tree("spent")?;
+    tree.get(encode_outpoint(outpoint))?
+        .map(|txid| decode_hash(&txid))
+        .transpose()
+}
+
+/// Stored block on the best chain at `height`, found through the
+/// `main_index` tree. `None` if the chain is shorter or the block body has
+/// not been downloaded yet; [`P2pError::BlockPruned`] if it was pruned.
+pub fn block_at_height(db: &Db, height: u32) -> Result<Option<Block>, P2pError> {
+    if height < load_pruned_below(db)? {
+        return Err(P2pError::BlockPruned(height));
+    }
+    let Some(hash) = db.open_tree("main_index")?.get(height.to_be_bytes())? else {
+        return Ok(None);
+    };
+    let hash = decode_hash(&hash)?;
+    db.open_tree("blocks")?
+        .get(hash)?
+        .map(|bytes| serde_json::from_slice(&bytes).map_err(P2pError::from))
+        .transpose()
+}
+
+/// Snapshot `utxos` to `utxo.jsonl` and the `utxo` tree. `utxo_count` and
+/// `utxo_tip` are recorded alongside so a later rebuild can be checked
+/// against the snapshot taken at the same tip.
 fn persist_utxos(
-    dir: &PathBuf,
+    dir: &Path,
     utxos: &InMemoryUtxoSet,
+    tip: &[u8; 32],
     db: Option<Db>,
 ) -> Result<(), P2pError> {
-    let file = dir.join("utxo.jsonl");
-    let tmp = dir.join("utxo.jsonl.tmp");
-    let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
-    for (outpoint, txout) in utxos.entries() {
-        let entry = crate::utxo_db::UtxoEntry { outpoint, txout };
-        serde_json::to_writer(&mut writer, &entry)?;
-        writer.write_all(b"\n")?;
-    }
-    writer.flush()?;
-    if file.exists() {
-        std::fs::remove_file(&file)?;
-    }
-    std::fs::rename(&tmp, &file)?;
+    replace_durably(&dir.join("utxo.jsonl"), |writer| {
+        for (outpoint, txout) in utxos.entries() {
+            let entry = crate::utxo_db::UtxoEntry { outpoint, txout };
+            serde_json::to_writer(&mut *writer, &entry)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    })?;
     if let Some(db) = db {
         let tree = db.open_tree("utxo")?;
+        // One batch, so the tree holds either the old set or the new one and
+        // no coin spent since the last snapshot survives.
+        let mut batch = sled::Batch::default();
+        for key in tree.iter().keys() {
+            batch.remove(key?);
+        }
         for (outpoint, txout) in utxos.entries() {
-            let key = encode_outpoint(&outpoint);
-            let value = encode_txout(&txout);
-            tree.insert(key, value)?;
+            batch.insert(encode_outpoint(&outpoint), encode_txout(&txout));
         }
+        tree.apply_batch(batch)?;
         tree.flush()?;
 
         let meta = db.open_tree("meta")?;
-        let count = utxos.entries().len() as u64;
+        let count = utxos.len() as u64;
         meta.insert("utxo_count", count.to_le_bytes().to_vec())?;
+        meta.insert("utxo_tip", tip.to_vec())?;
         meta.flush()?;
     }
     Ok(())
 }
 
-pub(crate) fn open_sled(dir: &PathBuf) -> Result<Db, P2pError> {
+/// Fork choice: more work wins, then greater height, then the lexicographically
+/// smaller header hash. The hash tie-break is a local preference so that nodes
+/// seeing competing equal-work tips in different orders converge on the same
+/// one; it is not a consensus rule.
+pub(crate) fn prefer_tip(candidate: (Work, u32, &[u8; 32]), current: (Work, u32, &[u8; 32])) -> bool {
+    let (c_work, c_height, c_hash) = candidate;
+    let (t_work, t_height, t_hash) = current;
+    (c_work, c_height, std::cmp::Reverse(c_hash)) > (t_work, t_height, std::cmp::Reverse(t_hash))
+}
+
+/// Settle a `db-compact` swap that was interrupted. The swap renames
+/// `chain.sled` to `chain.sled.old`, moves the flushed copy into place and
+/// then deletes the old DB, so a leftover `.old` next to `chain.sled` means
+/// the copy was already in place, and one on its own means it never got
+/// there. Must run before anything opens `chain.sled`: opening would create
+/// an empty DB and make the first case indistinguishable from the second.
+pub(crate) fn recover_interrupted_compact(dir: &Path) -> std::io::Result<()> {
+    let db_path = dir.join("chain.sled");
+    let old_path = dir.join("chain.sled.old");
+    if !old_path.exists() {
+        return Ok(());
+    }
+    if db_path.exists() {
+        std::fs::remove_dir_all(&old_path)
+    } else {
+        std::fs::rename(&old_path, &db_path)
+    }
+}
+
+pub(crate) fn open_sled(dir: &Path) -> Result<Db, P2pError> {
+    recover_interrupted_compact(dir)?;
     let db_path = dir.join("chain.sled");
     let db = sled::open(db_path)?;
     ensure_db_schema(&db)?;
@@ -1266,15 +2712,32 @@ pub(crate) fn open_sled(dir: &PathBuf) -> Result<Db, P2pError> {
 struct ChainState {
     headers: HashMap<[u8; 32], BlockHeader>,
     heights: HashMap<[u8; 32], u32>,
-    work: HashMap<[u8; 32], u128>,
+    /// Best-chain hash at each height, from genesis to `tip`. Kept in sync
+    /// by `set_tip`; side branches only appear in `heights`.
+    by_height: HashMap<u32, [u8; 32]>,
+    work: HashMap<[u8; 32], Work>,
     tip: [u8; 32],
+    /// Block bodies below this height have been deleted by `prune_blocks`
+    pruned_below: u32,
     db: Option<Db>,
+    params: NetworkParams,
+}
+
+/// Outcome of [`ChainState::prune_blocks`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PruneReport {
+    pub pruned_below: u32,
+    pub removed: usize,
 }
 
 #[derive(Debug)]
 struct AppliedState {
     tip: [u8; 32],
-    undo: HashMap<[u8; 32], Vec<ApplyReceipt>>,
+    undo: HashMap<[u8; 32], BlockReceipt>,
+    /// Net change each connected block made to the UTXO set's total value,
+    /// so a disconnect can be checked against it without scanning the set.
+    value_deltas: HashMap<[u8; 32], i128>,
+    flush: FlushState,
 }
 
 impl AppliedState {
@@ -1282,43 +2745,99 @@ impl AppliedState {
         Self {
             tip,
             undo: HashMap::new(),
+            value_deltas: HashMap::new(),
+            flush: FlushState::default(),
+        }
+    }
+
+    /// Keep undo data for a block just applied to `utxos`.
+    fn connect(&mut self, hash: [u8; 32], receipt: BlockReceipt, utxos: &InMemoryUtxoSet) {
+        self.value_deltas.insert(hash, utxo_value_delta(&receipt, utxos));
+        self.undo.insert(hash, receipt);
+    }
+
+    /// Roll `hash` back out of `utxos`. In debug builds the rollback must undo
+    /// exactly the value the block added; this is checked before touching
+    /// the set, so a bad receipt is reported instead of applied.
+    fn disconnect(&mut self, hash: &[u8; 32], utxos: &mut InMemoryUtxoSet) -> Result<(), P2pError> {
+        let receipt = self
+            .undo
+            .get(hash)
+            .ok_or_else(|| P2pError::InvalidBlock("missing undo data".to_string()))?;
+        if cfg!(debug_assertions) {
+            if let Some(&connected) = self.value_deltas.get(hash) {
+                let removes = utxo_value_delta(receipt, utxos);
+                if removes != connected {
+                    return Err(P2pError::ValueInvariant(format!(
+                        "disconnecting {} would remove {removes}, connecting added {connected}",
+                        hex_encode(*hash)
+                    )));
+                }
+            }
         }
+        // Only dropped once the check passed, so a failed disconnect can be
+        // retried or investigated with the undo data intact.
+        let receipt = self
+            .undo
+            .remove(hash)
+            .ok_or_else(|| P2pError::InvalidBlock("missing undo data".to_string()))?;
+        self.value_deltas.remove(hash);
+        utxos.rollback_block(receipt)?;
+        Ok(())
     }
 }
 
+/// Total value `receipt` added to `utxos`, which must still hold the
+/// block's state: outputs created and still unspent, minus outputs spent.
+/// An output created and spent within the block cancels out. Before a
+/// rollback this is also what the rollback will remove.
+fn utxo_value_delta(receipt: &BlockReceipt, utxos: &InMemoryUtxoSet) -> i128 {
+    let created: HashSet<&OutPoint> = receipt.iter().flat_map(|r| &r.inserted).collect();
+    let added: i128 = created
+        .iter()
+        .filter_map(|op| utxos.get(op))
+        .map(|txout| txout.value as i128)
+        .sum();
+    let spent: i128 = receipt
+        .iter()
+        .flat_map(|r| &r.removed)
+        .filter(|(op, _)| !created.contains(op))
+        .map(|(_, txout)| txout.value as i128)
+        .sum();
+    added - spent
+}
+
 impl ChainState {
-    fn with_genesis(db: Option<Db>) -> Self {
-        let genesis = BlockHeader {
-            version: 1,
-            prev_block_hash: [0u8; 32],
-            merkle_root: GENESIS_MERKLE_ROOT,
-            time: GENESIS_TIME,
-            bits: GENESIS_BITS,
-            nonce: GENESIS_NONCE,
-        };
+    fn with_genesis(db: Option<Db>, params: NetworkParams) -> Self {
+        let genesis = Block::genesis(&params, params.genesis_coinbase())
+            .expect("genesis coinbase is well-formed")
+            .header;
         let hash = header_hash(&genesis);
         let mut headers = HashMap::new();
         let mut heights = HashMap::new();
         let mut work = HashMap::new();
         headers.insert(hash, genesis);
         heights.insert(hash, 0);
-        work.insert(hash, 0);
+        work.insert(hash, Work::ZERO);
         Self {
             headers,
             heights,
+            by_height: HashMap::from([(0, hash)]),
             work,
             tip: hash,
+            pruned_below: 0,
             db,
+            params,
         }
     }
 
-    fn load_or_genesis(db: Option<Db>) -> Result<Self, P2pError> {
+    fn load_or_genesis(db: Option<Db>, params: NetworkParams) -> Result<Self, P2pError> {
         let Some(db) = db.clone() else {
-            return Ok(Self::with_genesis(db));
+            return Ok(Self::with_genesis(db, params));
         };
         let headers_tree = db.open_tree("headers")?;
         if headers_tree.is_empty() {
-            return Ok(Self::with_genesis(Some(db)));
+            return Ok(Self::with_genesis(Some(db), params));
         }
         let heights_tree = db.open_tree("heights")?;
         let work_tree = db.open_tree("work")?;
@@ -1345,46 +2864,63 @@ impl ChainState {
         for item in work_tree.iter() {
             let (k, v) = item?;
             let hash = decode_hash(&k)?;
-            if v.len() == 16 {
-                let w = u128::from_le_bytes(v.as_ref().try_into().map_err(|_| {
+            if v.len() == 32 {
+                let w = Work::from_le_bytes(v.as_ref().try_into().map_err(|_| {
                     P2pError::InvalidBlock("invalid work bytes".to_string())
                 })?);
                 work.insert(hash, w);
             }
         }
 
-        let mut tip = [0u8; 32];
-        let mut tip_work = 0u128;
-        let mut tip_height = 0u32;
+        // Recompute the tip with the same ordering as add_header; the stored
+        // tip meta is only a fallback when no work entries are present.
+        let mut best: Option<([u8; 32], Work, u32)> = None;
         for (hash, w) in work.iter() {
             let h = *heights.get(hash).unwrap_or(&0);
-            if *w > tip_work || (*w == tip_work && h > tip_height) {
-                tip_work = *w;
-                tip_height = h;
-                tip = *hash;
+            let better = match best {
+                Some((b_hash, b_work, b_height)) => {
+                    prefer_tip((*w, h, hash), (b_work, b_height, &b_hash))
+                }
+                None => true,
+            };
+            if better {
+                best = Some((*hash, *w, h));
+            }
+        }
+        let (mut tip, mut tip_height) = match best {
+            Some((hash, _, height)) => (hash, height),
+            None => ([0u8; 32], 0),
+        };
+        if best.is_none() {
+            if let Some((meta_tip, meta_height)) = load_tip_meta(&db)? {
+                if heights.get(&meta_tip) == Some(&meta_height) {
+                    tip = meta_tip;
+                    tip_height = meta_height;
+                }
             }
         }
         if tip == [0u8; 32] && !headers.is_empty() {
             tip = *headers.keys().next().unwrap();
         }
-        if let Some((meta_tip, meta_height)) = load_tip_meta(&db)? {
-            if heights.get(&meta_tip) == Some(&meta_height) {
-                tip = meta_tip;
-                tip_height = meta_height;
-            }
-        }
         let meta = db.open_tree("meta")?;
         meta.insert("tip_hash", tip.to_vec())?;
         meta.insert("tip_height", tip_height.to_le_bytes().to_vec())?;
         meta.flush()?;
 
-        Ok(Self {
+        let pruned_below = load_pruned_below(&db)?;
+        let mut chain = Self {
             headers,
             heights,
+            by_height: HashMap::new(),
             work,
             tip,
+            pruned_below,
             db: Some(db),
-        })
+            params,
+        };
+        chain.set_tip(tip);
+        chain.persist_main_index()?;
+        Ok(chain)
     }
 
     fn tip_hash(&self) -> [u8; 32] {
@@ -1398,34 +2934,75 @@ impl ChainState {
         }
         let prev = header.prev_block_hash;
         let (height, prev_work, prev_header) = if let Some(prev_h) = self.heights.get(&prev) {
-            let prev_work = *self.work.get(&prev).unwrap_or(&0);
+            let prev_work = self.work.get(&prev).copied().unwrap_or_default();
             let prev_header = self
                 .headers
                 .get(&prev)
                 .ok_or_else(|| P2pError::InvalidBlock("missing prev header".to_string()))?;
             (prev_h + 1, prev_work, Some(prev_header))
         } else if prev == [0u8; 32] {
-            (0, 0, None)
+            (0, Work::ZERO, None)
         } else {
             return Err(P2pError::InvalidBlock("unknown prev header".to_string()));
         };
 
         let expected_bits = self.expected_bits(prev_header, height)?;
-        validate_header_rules(header, prev_header, no_pow_check, expected_bits)?;
+        validate_header_rules(
+            header,
+            prev_header,
+            no_pow_check,
+            expected_bits,
+            self.params.max_future_drift_secs,
+        )?;
+        if prev_header.is_some() {
+            let mtp = self
+                .median_time_past(prev)
+                .ok_or_else(|| P2pError::InvalidBlock("missing prev header".to_string()))?;
+            if header.time <= mtp {
+                return Err(P2pError::InvalidBlock(format!(
+                    "time {} not after median-time-past {mtp}",
+                    header.time
+                )));
+            }
+        }
         let work = prev_work.saturating_add(work_from_bits(header.bits)?);
         self.headers.insert(hash, header.clone());
         self.heights.insert(hash, height);
         self.work.insert(hash, work);
         let tip_height = *self.heights.get(&self.tip).unwrap_or(&0);
-        let tip_work = *self.work.get(&self.tip).unwrap_or(&0);
-        if work > tip_work || (work == tip_work && height > tip_height) {
-            self.tip = hash;
+        let tip_work = self.work.get(&self.tip).copied().unwrap_or_default();
+        if prefer_tip((work, height, &hash), (tip_work, tip_height, &self.tip)) {
+            self.set_tip(hash);
         }
         self.persist_header(hash, header, height, work)?;
         self.persist_tip()?;
         Ok(())
     }
 
+    /// Move the tip to `tip` and update `by_height` to its chain. Only the
+    /// heights that differ from the old best chain are rewritten, so
+    /// extending the tip is O(1) and a reorg costs its depth.
+    fn set_tip(&mut self, tip: [u8; 32]) {
+        self.tip = tip;
+        let Some(tip_height) = self.heights.get(&tip).copied() else {
+            return;
+        };
+        self.by_height.retain(|height, _| *height <= tip_height);
+        let mut hash = tip;
+        let mut height = tip_height;
+        while self.by_height.get(&height) != Some(&hash) {
+            self.by_height.insert(height, hash);
+            let Some(header) = self.headers.get(&hash) else {
+                break;
+            };
+            if height == 0 {
+                break;
+            }
+            hash = header.prev_block_hash;
+            height -= 1;
+        }
+    }
+
     #[allow(dead_code)]
     fn next_height(&self, prev_hash: &[u8; 32]) -> Result<u32, P2pError> {
         if *prev_hash == [0u8; 32] {
@@ -1437,6 +3014,22 @@ impl ChainState {
             .ok_or_else(|| P2pError::InvalidBlock("unknown prev header".to_string()))
     }
 
+    /// Whether a run of headers is worth storing: its total work, added to
+    /// its fork point's, must beat our tip. Runs with an unknown parent pass,
+    /// as `add_header` rejects them itself.
+    fn headers_beat_tip(&self, headers: &[BlockHeader]) -> bool {
+        let Some(first) = headers.first() else {
+            return true;
+        };
+        let Some(fork_work) = self.work.get(&first.prev_block_hash).copied() else {
+            return true;
+        };
+        let reach = headers.iter().fold(fork_work, |acc, header| {
+            acc.saturating_add(Work::from_bits(header.bits).unwrap_or(Work::ZERO))
+        });
+        reach > self.work.get(&self.tip).copied().unwrap_or_default()
+    }
+
     fn headers_after(&self, locator: Vec<[u8; 32]>, limit: usize) -> Vec<BlockHeader> {
         let mut start = None;
         for hash in locator {
@@ -1455,12 +3048,7 @@ impl ChainState {
         let mut out = Vec::new();
         let mut current_height = start_height + 1;
         while out.len() < limit {
-            let next = self
-                .heights
-                .iter()
-                .find(|(_, h)| **h == current_height)
-                .map(|(hash, _)| *hash);
-            let Some(next_hash) = next else {
+            let Some(next_hash) = self.by_height.get(&current_height).copied() else {
                 break;
             };
             if let Some(header) = self.headers.get(&next_hash) {
@@ -1479,16 +3067,19 @@ impl ChainState {
         let Some(prev) = prev else {
             return Ok(INITIAL_BITS);
         };
-        if height == 0 || height % DIFFICULTY_WINDOW != 0 {
+        // No retarget before the first full window: heights 1..window-1 keep
+        // the genesis bits.
+        let window = self.params.difficulty_window.max(1);
+        if height < window || !height.is_multiple_of(window) {
             return Ok(prev.bits);
         }
 
-        let window_start_height = height.saturating_sub(DIFFICULTY_WINDOW);
+        // height >= window here, so the first retarget starts at
+        // genesis. The start must be an ancestor of `prev`: another branch
+        // can have a header at the same height.
+        let window_start_height = height - window;
         let start_hash = self
-            .heights
-            .iter()
-            .find(|(_, h)| **h == window_start_height)
-            .map(|(hash, _)| *hash)
+            .ancestor_at(header_hash(prev), window_start_height)
             .ok_or_else(|| P2pError::InvalidBlock("missing window start".to_string()))?;
         let start_header = self
             .headers
@@ -1496,7 +3087,7 @@ impl ChainState {
             .ok_or_else(|| P2pError::InvalidBlock("missing window header".to_string()))?;
 
         let actual_time = prev.time.saturating_sub(start_header.time);
-        let expected_time = TARGET_BLOCK_TIME_SECS.saturating_mul(DIFFICULTY_WINDOW);
+        let expected_time = self.params.target_block_time_secs.saturating_mul(window);
 
         let prev_target = bits_to_target_u128(prev.bits)?;
         let mut new_target = prev_target
@@ -1511,12 +3102,98 @@ impl ChainState {
         Ok(target_to_bits(new_target))
     }
 
+    /// Block locator for the current tip: the last ten hashes, then
+    /// exponentially sparser ancestors, always ending at genesis.
+    fn locator(&self) -> Vec<[u8; 32]> {
+        let mut out = Vec::new();
+        let mut hash = self.tip;
+        let mut height = self.heights.get(&hash).copied().unwrap_or(0);
+        let mut step = 1u32;
+        loop {
+            out.push(hash);
+            if height == 0 {
+                break;
+            }
+            if out.len() >= 10 {
+                step = step.saturating_mul(2);
+            }
+            height = height.saturating_sub(step);
+            match self.ancestor_at(hash, height) {
+                Some(ancestor) => hash = ancestor,
+                None => break,
+            }
+        }
+        out
+    }
+
+    /// Best-chain block hashes after the first locator hash on the best chain
+    /// (genesis if none is), in height order, stopping after `stop_hash`.
+    fn block_hashes_after(
+        &self,
+        locator: &[[u8; 32]],
+        stop_hash: [u8; 32],
+        limit: usize,
+    ) -> Vec<[u8; 32]> {
+        let start_height = locator
+            .iter()
+            .filter_map(|hash| {
+                let height = self.height_of(hash)?;
+                (self.hash_at_height(height) == Some(*hash)).then_some(height)
+            })
+            .next()
+            .unwrap_or(0);
+        let tip_height = self.heights.get(&self.tip).copied().unwrap_or(0);
+        let limit = u32::try_from(limit).unwrap_or(u32::MAX);
+        let end_height = tip_height.min(start_height.saturating_add(limit));
+        let mut out = Vec::new();
+        for height in start_height + 1..=end_height {
+            let Some(hash) = self.hash_at_height(height) else { break };
+            out.push(hash);
+            if hash == stop_hash {
+                break;
+            }
+        }
+        out
+    }
+
+    /// Median timestamp of `hash` and up to `MTP_WINDOW - 1` of its
+    /// ancestors; shorter chains use whatever is available. A child header
+    /// must be strictly later, so time cannot stall at one value.
+    fn median_time_past(&self, mut hash: [u8; 32]) -> Option<u32> {
+        let mut times = Vec::with_capacity(MTP_WINDOW);
+        while times.len() < MTP_WINDOW {
+            let Some(header) = self.headers.get(&hash) else {
+                break;
+            };
+            times.push(header.time);
+            hash = header.prev_block_hash;
+        }
+        times.sort_unstable();
+        times.get(times.len() / 2).copied()
+    }
+
+    /// Walk back from `hash` along `prev_block_hash` to its ancestor at `height`.
+    fn ancestor_at(&self, mut hash: [u8; 32], height: u32) -> Option<[u8; 32]> {
+        let mut current = *self.heights.get(&hash)?;
+        if current < height {
+            return None;
+        }
+        if self.by_height.get(&current) == Some(&hash) {
+            return self.hash_at_height(height);
+        }
+        while current > height {
+            hash = self.headers.get(&hash)?.prev_block_hash;
+            current -= 1;
+        }
+        Some(hash)
+    }
+
     fn persist_header(
         &self,
         hash: [u8; 32],
         header: &BlockHeader,
         height: u32,
-        work: u128,
+        work: Work,
     ) -> Result<(), P2pError> {
         let Some(db) = self.db.as_ref() else {
             return Ok(());
@@ -1524,9 +3201,9 @@ impl ChainState {
         let headers_tree = db.open_tree("headers")?;
         let heights_tree = db.open_tree("heights")?;
         let work_tree = db.open_tree("work")?;
-        headers_tree.insert(hash.to_vec(), serde_json::to_vec(header)?)?;
-        heights_tree.insert(hash.to_vec(), height.to_le_bytes().to_vec())?;
-        work_tree.insert(hash.to_vec(), work.to_le_bytes().to_vec())?;
+        headers_tree.insert(hash, serde_json::to_vec(header)?)?;
+        heights_tree.insert(hash, height.to_le_bytes().to_vec())?;
+        work_tree.insert(hash, work.to_le_bytes().to_vec())?;
         Ok(())
     }
 
@@ -1539,9 +3216,87 @@ impl ChainState {
         meta.insert("tip_hash", self.tip.to_vec())?;
         meta.insert("tip_height", tip_height.to_le_bytes().to_vec())?;
         meta.flush()?;
+        self.persist_main_index()
+    }
+
+    /// Bring the `main_index` tree (big-endian height -> hash, so keys sort
+    /// by height) in line with `by_height`: heights above the tip are
+    /// dropped, then entries are rewritten from the tip down until one
+    /// already matches, as `set_tip` does in memory.
+    fn persist_main_index(&self) -> Result<(), P2pError> {
+        let Some(db) = self.db.as_ref() else {
+            return Ok(());
+        };
+        let tree = db.open_tree("main_index")?;
+        let tip_height = *self.heights.get(&self.tip).unwrap_or(&0);
+        for item in tree.range(tip_height.saturating_add(1).to_be_bytes()..) {
+            let (key, _) = item?;
+            tree.remove(key)?;
+        }
+        for height in (0..=tip_height).rev() {
+            let Some(hash) = self.hash_at_height(height) else {
+                break;
+            };
+            if tree.get(height.to_be_bytes())?.as_deref() == Some(&hash[..]) {
+                break;
+            }
+            tree.insert(height.to_be_bytes(), hash.to_vec())?;
+        }
         Ok(())
     }
 
+    /// Delete the bodies of blocks more than `keep` below the tip, on any
+    /// branch, from `<dir>/blocks/` and the `blocks` tree. Headers and the
+    /// UTXO set are untouched, so new blocks still validate; the new
+    /// `pruned_below_height` marker is flushed before anything is deleted.
+    fn prune_blocks(&mut self, dir: &Path, keep: u32) -> Result<PruneReport, P2pError> {
+        let db = self
+            .db
+            .clone()
+            .ok_or_else(|| P2pError::InvalidBlock("pruning needs a data directory".to_string()))?;
+        let tip_height = *self.heights.get(&self.tip).unwrap_or(&0);
+        let below = tip_height.saturating_sub(keep);
+        if below <= self.pruned_below {
+            return Ok(PruneReport {
+                pruned_below: self.pruned_below,
+                removed: 0,
+            });
+        }
+        let meta = db.open_tree("meta")?;
+        meta.insert("pruned_below_height", below.to_le_bytes().to_vec())?;
+        meta.flush()?;
+        self.pruned_below = below;
+
+        let tree = db.open_tree("blocks")?;
+        let mut removed = 0;
+        for (hash, _) in self.heights.iter().filter(|(_, height)| **height < below) {
+            let file = block_file_path(dir, hash);
+            let had_file = match std::fs::remove_file(&file) {
+                Ok(()) => true,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+                Err(err) => return Err(err.into()),
+            };
+            if tree.remove(hash)?.is_some() || had_file {
+                removed += 1;
+            }
+        }
+        db.flush()?;
+        Ok(PruneReport {
+            pruned_below: below,
+            removed,
+        })
+    }
+
+    /// Height of `hash` if its body has been pruned.
+    fn pruned_height_of(&self, hash: &[u8; 32]) -> Option<u32> {
+        self.height_of(hash).filter(|height| *height < self.pruned_below)
+    }
+
+    /// Best-chain hash at `height`, if the chain is that long.
+    fn hash_at_height(&self, height: u32) -> Option<[u8; 32]> {
+        self.by_height.get(&height).copied()
+    }
+
     fn height_of(&self, hash: &[u8; 32]) -> Option<u32> {
         self.heights.get(hash).copied()
     }
@@ -1582,12 +3337,9 @@ fn reorg_to_tip(
                 evicted.push(tx.clone());
             }
         }
-        let receipts = applied
-            .undo
-            .remove(hash)
-            .ok_or_else(|| P2pError::InvalidBlock("missing undo data".to_string()))?;
-        for receipt in receipts.into_iter().rev() {
-            utxos.rollback(receipt)?;
+        applied.disconnect(hash, utxos)?;
+        if let (Some(db), Some(block)) = (chain.db.as_ref(), blocks.get(hash)) {
+            unindex_spends(db, &block)?;
         }
     }
 
@@ -1598,15 +3350,89 @@ fn reorg_to_tip(
         let height = chain
             .height_of(hash)
             .ok_or_else(|| P2pError::InvalidBlock("missing height".to_string()))?;
-        let subsidy = block_subsidy(height);
-        let receipts = apply_block_with_undo(&block, utxos, no_pow_check, subsidy)?;
-        applied.undo.insert(*hash, receipts);
+        let subsidy = chain.params.block_subsidy(height);
+        let receipts = apply_block_with_undo(
+            &block,
+            height,
+            parent_mtp(chain, &block),
+            utxos,
+            no_pow_check,
+            subsidy,
+            chain.params.max_block_sigops,
+            chain.params.max_block_outputs,
+            chain.params.coinbase_maturity,
+        )?;
+        applied.connect(*hash, receipts, utxos);
+        if let Some(db) = chain.db.as_ref() {
+            index_spends(db, &block)?;
+        }
     }
 
     applied.tip = new_tip;
     Ok(())
 }
 
+/// Put transactions from disconnected blocks back into the mempool, logging
+/// what happened to each. With `resolve_conflicts`, double spends against
+/// existing entries are settled by [`Replacement::HigherFee`] (higher fee
+/// wins, taking the loser's descendants with it); otherwise the entry already in the mempool is kept.
+fn readd_evicted(
+    mempool: &mut Mempool,
+    utxos: &dyn UtxoSet,
+    evicted: Vec<Transaction>,
+    resolve_conflicts: bool,
+    peer: &str,
+    logger: &Logger,
+) {
+    for tx in evicted {
+        let txid = tx.txid_v2().map(hex_encode).unwrap_or_default();
+        let result = if resolve_conflicts {
+            mempool.add_tx_replace(tx, utxos, Replacement::HigherFee)
+        } else {
+            mempool.add_tx_first_seen(tx, utxos).map(|()| Vec::new())
+        };
+        match result {
+            Ok(replaced) if replaced.is_empty() => {
+                logger.debug(format!("[{peer}] re-added reorged tx {txid}"));
+            }
+            Ok(replaced) => {
+                for old in replaced {
+                    logger.info(format!(
+                        "[{peer}] reorged tx {txid} replaced lower-fee {}",
+                        hex_encode(old)
+                    ));
+                }
+            }
+            Err(err) => logger.debug(format!("[{peer}] dropped reorged tx {txid}: {err}")),
+        }
+    }
+}
+
+/// Median-time-past of `block`'s parent, the reference time for the block's
+/// time locks.
+fn parent_mtp(chain: &ChainState, block: &Block) -> u32 {
+    chain
+        .median_time_past(block.header.prev_block_hash)
+        .unwrap_or(0)
+}
+
+/// Re-check mempool timelocks against the block that would extend `tip`,
+/// the applied tip (the header tip may be ahead of the blocks connected so
+/// far), and make that the mempool's lock point for new arrivals. Needed
+/// after a reorg to a lower tip, where transactions that were final at the
+/// old height can become premature again.
+fn evict_immature(
+    mempool: &mut Mempool,
+    chain: &ChainState,
+    tip: [u8; 32],
+) -> Result<Vec<MempoolEntry>, MempoolError> {
+    let Some(height) = chain.height_of(&tip) else {
+        return Ok(Vec::new());
+    };
+    let mtp = chain.median_time_past(tip).unwrap_or(0);
+    mempool.evict_non_final(height + 1, mtp)
+}
+
 fn common_ancestor(
     chain: &ChainState,
     mut a: [u8; 32],
@@ -1674,40 +3500,8 @@ fn path_from_ancestor(
     Ok(rev)
 }
 
-fn block_subsidy(height: u32) -> u64 {
-    let halvings = height / HALVING_INTERVAL;
-    if halvings >= 64 {
-        return 0;
-    }
-    INITIAL_SUBSIDY >> halvings
-}
-
-fn work_from_bits(bits: u32) -> Result<u128, P2pError> {
-    if bits == 0 {
-        return Err(P2pError::InvalidBlock("invalid bits".to_string()));
-    }
-    let exponent = (bits >> 24) as u32;
-    let mantissa = bits & 0x007f_ffff;
-    if mantissa == 0 {
-        return Err(P2pError::InvalidBlock("invalid bits".to_string()));
-    }
-
-    let target_u128 = if exponent <= 3 {
-        let shift = 8 * (3 - exponent);
-        (mantissa as u128) >> shift
-    } else {
-        let shift = 8 * (exponent - 3);
-        if shift >= 128 {
-            u128::MAX
-        } else {
-            (mantissa as u128) << shift
-        }
-    };
-
-    if target_u128 == 0 {
-        return Ok(1u128 << 120);
-    }
-    Ok((1u128 << 120) / (target_u128.saturating_add(1)))
+fn work_from_bits(bits: u32) -> Result<Work, P2pError> {
+    Work::from_bits(bits).ok_or_else(|| P2pError::InvalidBlock("invalid bits".to_string()))
 }
 
 fn validate_header_rules(
@@ -1715,6 +3509,7 @@ fn validate_header_rules(
     prev: Option<&BlockHeader>,
     no_pow_check: bool,
     expected_bits: u32,
+    max_future_drift: u32,
 ) -> Result<(), P2pError> {
     if !no_pow_check {
         let ok = check_pow(header)?;
@@ -1740,17 +3535,20 @@ fn validate_header_rules(
         .duration_since(UNIX_EPOCH)
         .map_err(|_| P2pError::InvalidBlock("invalid system time".to_string()))?
         .as_secs() as u32;
-    if header.time > now.saturating_add(MAX_FUTURE_DRIFT_SECS) {
+    if header.time > now.saturating_add(max_future_drift) {
         return Err(P2pError::InvalidBlock("time too far in future".to_string()));
     }
     Ok(())
 }
 
 fn bits_to_target_u128(bits: u32) -> Result<u128, P2pError> {
+    if bits & COMPACT_SIGN_BIT != 0 {
+        return Err(ConsensusError::InvalidBits.into());
+    }
     if bits == 0 {
         return Err(P2pError::InvalidBlock("invalid bits".to_string()));
     }
-    let exponent = (bits >> 24) as u32;
+    let exponent = bits >> 24;
     let mantissa = bits & 0x007f_ffff;
     if mantissa == 0 {
         return Err(P2pError::InvalidBlock("invalid bits".to_string()));
@@ -1768,35 +3566,43 @@ fn bits_to_target_u128(bits: u32) -> Result<u128, P2pError> {
     }
 }
 
+/// Compact encoding as in Bitcoin's `GetCompact`: when the top mantissa
+/// byte would have the sign bit set, the mantissa is shifted down a byte and
+/// the exponent bumped so the target stays positive.
 fn target_to_bits(target: u128) -> u32 {
     if target == 0 {
         return 0;
     }
-    let mut tmp = target;
-    let mut exponent: u32 = 0;
-    while tmp > 0 {
-        exponent += 1;
-        tmp >>= 8;
-    }
-    let exponent = exponent.max(1);
-    let shift = 8 * (exponent.saturating_sub(3));
-    let mantissa = if shift >= 128 {
-        0x007f_ffff
+    let mut exponent = (128 - target.leading_zeros()).div_ceil(8);
+    let mut mantissa = if exponent <= 3 {
+        (target << (8 * (3 - exponent))) as u32
     } else {
-        ((target >> shift) as u32) & 0x007f_ffff
+        (target >> (8 * (exponent - 3))) as u32
     };
+    if mantissa & COMPACT_SIGN_BIT != 0 {
+        mantissa >>= 8;
+        exponent += 1;
+    }
     (exponent << 24) | mantissa
 }
 
 #[cfg(test)]
 mod reorg_tests {
     use super::*;
-    use tenebrium_utxo::{OutPoint, Transaction, TxOut, UtxoSet};
+    use tenebrium_consensus::encode_coinbase_height;
+    use tenebrium_consensus::params::{
+        COINBASE_MATURITY, DIFFICULTY_WINDOW, GENESIS_TIME, MAX_BLOCK_OUTPUTS, MAX_BLOCK_SIGOPS, MAX_FUTURE_DRIFT_SECS,
+    };
+    use tenebrium_utxo::{OutPoint, Transaction, TxIn, TxOut, UtxoSet};
 
-    fn make_coinbase(value: u64, tag: u8) -> Transaction {
+    fn make_coinbase(value: u64, tag: u8, height: u32) -> Transaction {
         Transaction {
             version: 1,
-            vin: vec![],
+            vin: vec![TxIn {
+                prevout: OutPoint::NULL,
+                script_sig: encode_coinbase_height(height),
+                sequence: u32::MAX,
+            }],
             vout: vec![TxOut {
                 value,
                 script_pubkey: vec![tag],
@@ -1816,17 +3622,257 @@ mod reorg_tests {
         }
     }
 
+    #[test]
+    fn header_must_be_later_than_median_time_past() {
+        let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain.tip_hash();
+        let stalled = make_header(genesis, GENESIS_TIME);
+        // The plain prev-time rule allows an equal timestamp...
+        validate_header_rules(
+            &stalled,
+            chain.header_of(&genesis),
+            true,
+            INITIAL_BITS,
+            MAX_FUTURE_DRIFT_SECS,
+        )
+        .unwrap();
+        // ...median-time-past does not.
+        let err = chain.add_header(&stalled, true).unwrap_err();
+        assert!(matches!(err, P2pError::InvalidBlock(msg) if msg.contains("median-time-past")));
+
+        // With a full window the median, not the previous time, is the bar.
+        let mut prev = genesis;
+        for i in 1..=12u32 {
+            let header = BlockHeader {
+                bits: chain.expected_bits(chain.header_of(&prev), i).unwrap(),
+                ..make_header(prev, GENESIS_TIME + i * TARGET_BLOCK_TIME_SECS)
+            };
+            chain.add_header(&header, true).unwrap();
+            prev = header_hash(&header);
+        }
+        // The last 11 headers are heights 2..=12, so the median is height 7.
+        assert_eq!(
+            chain.median_time_past(prev),
+            Some(GENESIS_TIME + 7 * TARGET_BLOCK_TIME_SECS)
+        );
+        assert_eq!(chain.median_time_past(genesis), Some(GENESIS_TIME));
+    }
+
+    fn extend_chain(chain: &mut ChainState, mut prev: [u8; 32], count: u32, skew: u32) -> [u8; 32] {
+        for _ in 0..count {
+            let height = chain.height_of(&prev).unwrap() + 1;
+            let header = BlockHeader {
+                bits: chain.expected_bits(chain.header_of(&prev), height).unwrap(),
+                ..make_header(prev, GENESIS_TIME + height * TARGET_BLOCK_TIME_SECS + skew)
+            };
+            chain.add_header(&header, true).unwrap();
+            prev = header_hash(&header);
+        }
+        prev
+    }
+
+    #[test]
+    fn low_work_header_spam_is_bounded() {
+        let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain.tip_hash();
+        let tip = extend_chain(&mut chain, genesis, 1, 0);
+        // Our tip stands for more work than any run of headers can add.
+        chain.work.insert(tip, Work::MAX);
+
+        // The spammer's chain, built on a scratch copy; blocks slower than
+        // target keep its difficulty from rising.
+        let mut scratch = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let mut prev = genesis;
+        let spam: Vec<BlockHeader> = (1..=(3 * MAX_HEADERS) as u32)
+            .map(|height| {
+                let header = BlockHeader {
+                    bits: scratch.expected_bits(scratch.header_of(&prev), height).unwrap(),
+                    ..make_header(prev, GENESIS_TIME + height * (TARGET_BLOCK_TIME_SECS + 100))
+                };
+                scratch.add_header(&header, true).unwrap();
+                prev = header_hash(&header);
+                header
+            })
+            .collect();
+
+        // No run of the spammer's headers is worth storing.
+        assert!(!chain.headers_beat_tip(&spam[..10]));
+        assert!(!chain.headers_beat_tip(&spam));
+
+        // The count follows the host, so reconnecting from a new port does
+        // not reset it.
+        let mut peers = PeerManager::new(Vec::new());
+        let mut outcome = Ok(());
+        let mut sent = 0;
+        for (port, batch) in spam.chunks(MAX_HEADERS).enumerate() {
+            outcome = peers.note_low_work(&format!("10.0.0.7:{}", 4000 + port), batch.len());
+            if outcome.is_err() {
+                break;
+            }
+            sent += batch.len();
+        }
+        assert!(matches!(outcome, Err(P2pError::InvalidBlock(msg)) if msg == "too many low-work headers"));
+        assert_eq!(sent, MAX_LOW_WORK_HEADERS);
+        assert!(peers.note_low_work("10.0.0.8:4000", MAX_HEADERS).is_ok());
+
+        // Work that can overtake the tip is taken, and clears the count.
+        chain.work.insert(tip, Work::from(1));
+        assert!(chain.headers_beat_tip(&spam[MAX_LOW_WORK_HEADERS..]));
+        peers.clear_low_work("10.0.0.8:4001");
+        assert!(peers.note_low_work("10.0.0.8:4002", MAX_LOW_WORK_HEADERS).is_ok());
+        assert_eq!(chain.tip_hash(), tip);
+    }
+
+    #[test]
+    fn height_index_serves_headers_and_follows_reorgs() {
+        fn serve_time(chain: &ChainState, locator: [u8; 32]) -> std::time::Duration {
+            (0..5)
+                .map(|_| {
+                    let started = std::time::Instant::now();
+                    assert_eq!(chain.headers_after(vec![locator], 100).len(), 100);
+                    started.elapsed()
+                })
+                .min()
+                .unwrap()
+        }
+
+        let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain.tip_hash();
+        let tip = extend_chain(&mut chain, genesis, 100, 0);
+        let short = serve_time(&chain, genesis);
+        let tip = extend_chain(&mut chain, tip, 1900, 0);
+        assert_eq!(chain.height_of(&tip), Some(2000));
+        // Per-header cost must not grow with the chain (a scan would be ~20x).
+        let long = serve_time(&chain, genesis);
+        assert!(long < short * 5, "100 headers took {long:?} at 2000 vs {short:?} at 100");
+
+        let served = chain.headers_after(vec![genesis], 2000);
+        assert_eq!(served.len(), 2000);
+        assert_eq!(header_hash(served.last().unwrap()), tip);
+
+        // A longer fork from height 1995 takes over the top of the index.
+        let fork_point = chain.ancestor_at(tip, 1995).unwrap();
+        let fork_tip = extend_chain(&mut chain, fork_point, 10, 1);
+        assert_eq!(chain.tip_hash(), fork_tip);
+        assert_eq!(chain.by_height.len(), 2006);
+        let mut hash = fork_tip;
+        for height in (0..=2005).rev() {
+            assert_eq!(chain.by_height.get(&height), Some(&hash));
+            hash = chain.header_of(&hash).unwrap().prev_block_hash;
+        }
+        let served = chain.headers_after(vec![fork_point], 100);
+        assert_eq!(served.len(), 10);
+        assert_eq!(header_hash(served.last().unwrap()), fork_tip);
+
+        // Switching back truncates the index to the shorter chain.
+        chain.set_tip(tip);
+        assert_eq!(chain.by_height.len(), 2001);
+        assert_eq!(chain.by_height.get(&2000), Some(&tip));
+    }
+
+    #[test]
+    fn main_index_finds_stored_blocks_by_height() {
+        let temp = tempfile::tempdir().unwrap();
+        let db = open_sled(temp.path()).unwrap();
+        let mut chain = ChainState::with_genesis(Some(db.clone()), NetworkParams::mainnet());
+        let genesis = chain.tip_hash();
+        let store = |chain: &mut ChainState, prev: [u8; 32], tag: u8, height: u32| {
+            let time = GENESIS_TIME + u32::from(tag);
+            let block = Block::new(1, prev, time, INITIAL_BITS, 0, vec![make_coinbase(50, tag, height)])
+                .unwrap();
+            let hash = header_hash(&block.header);
+            chain.add_header(&block.header, true).unwrap();
+            persist_block(temp.path(), &block, &hash, Some(db.clone())).unwrap();
+            (hash, block)
+        };
+        let (a1, _) = store(&mut chain, genesis, 1, 1);
+        let (a2, block_a2) = store(&mut chain, a1, 2, 2);
+        assert_eq!(chain.hash_at_height(2), Some(a2));
+        assert_eq!(block_at_height(&db, 2).unwrap(), Some(block_a2));
+        assert_eq!(block_at_height(&db, 3).unwrap(), None);
+
+        // A longer fork from a1 rewrites the index above the fork point.
+        let (b2, block_b2) = store(&mut chain, a1, 3, 2);
+        let (b3, block_b3) = store(&mut chain, b2, 4, 3);
+        assert_eq!(chain.tip_hash(), b3);
+        assert_eq!(chain.hash_at_height(2), Some(b2));
+        assert_eq!(block_at_height(&db, 2).unwrap(), Some(block_b2));
+        assert_eq!(block_at_height(&db, 3).unwrap(), Some(block_b3));
+
+        // Reloading keeps the index, and a shorter tip truncates it.
+        drop(chain);
+        let mut chain = ChainState::load_or_genesis(Some(db.clone()), NetworkParams::mainnet()).unwrap();
+        assert_eq!(chain.hash_at_height(3), Some(b3));
+        chain.set_tip(a2);
+        chain.persist_tip().unwrap();
+        assert_eq!(block_at_height(&db, 2).unwrap().map(|b| header_hash(&b.header)), Some(a2));
+        assert_eq!(block_at_height(&db, 3).unwrap(), None);
+    }
+
+    #[test]
+    fn pruning_drops_old_bodies_and_reports_pruned_heights() {
+        let temp = tempfile::tempdir().unwrap();
+        let db = open_sled(temp.path()).unwrap();
+        let mut chain = ChainState::with_genesis(Some(db.clone()), NetworkParams::mainnet());
+        let mut hashes = vec![chain.tip_hash()];
+        for height in 1..=5u32 {
+            let block = Block::new(
+                1,
+                hashes[height as usize - 1],
+                GENESIS_TIME + height,
+                INITIAL_BITS,
+                0,
+                vec![make_coinbase(50, height as u8, height)],
+            )
+            .unwrap();
+            let hash = header_hash(&block.header);
+            chain.add_header(&block.header, true).unwrap();
+            persist_block(temp.path(), &block, &hash, Some(db.clone())).unwrap();
+            hashes.push(hash);
+        }
+
+        let report = chain.prune_blocks(temp.path(), 2).unwrap();
+        assert_eq!(report, PruneReport { pruned_below: 3, removed: 2 });
+        for (height, hash) in hashes.iter().enumerate().skip(1) {
+            let kept = height >= 3;
+            assert_eq!(block_file_path(temp.path(), hash).exists(), kept, "height {height}");
+            assert_eq!(db.open_tree("blocks").unwrap().contains_key(hash).unwrap(), kept);
+            assert!(chain.header_of(hash).is_some());
+        }
+        assert!(matches!(block_at_height(&db, 1), Err(P2pError::BlockPruned(1))));
+        assert_eq!(
+            block_at_height(&db, 3).unwrap().map(|block| header_hash(&block.header)),
+            Some(hashes[3])
+        );
+        // Nothing more to prune until the tip moves.
+        assert_eq!(chain.prune_blocks(temp.path(), 2).unwrap().removed, 0);
+
+        drop(chain);
+        let chain = ChainState::load_or_genesis(Some(db.clone()), NetworkParams::mainnet()).unwrap();
+        assert_eq!(chain.pruned_height_of(&hashes[2]), Some(2));
+        assert_eq!(chain.pruned_height_of(&hashes[3]), None);
+        let logger = Logger::new(LogLevel::Error, None).unwrap();
+        let blocks = BlockStore::load_dir(temp.path(), false, usize::MAX, &logger).unwrap();
+        assert!(blocks.get(&hashes[2]).is_none());
+        let mut applied = AppliedState::new(chain.tip_hash());
+        assert!(matches!(
+            reindex_chainstate(temp.path(), &db, &chain, &blocks, &mut applied),
+            Err(P2pError::BlockPruned(1))
+        ));
+    }
+
     #[test]
     fn expected_bits_window_boundary_keeps_bits_when_on_target() {
-        let mut chain = ChainState::with_genesis(None);
+        let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
         let mut prev_hash = chain.tip_hash();
         let expected_time = TARGET_BLOCK_TIME_SECS * DIFFICULTY_WINDOW;
         let base_time = GENESIS_TIME;
         for i in 0..9u32 {
+            // Intermediate times only need to advance past the median.
             let time = if i == 8 {
                 base_time + expected_time
             } else {
-                base_time
+                base_time + i + 1
             };
             let header = make_header(prev_hash, time);
             chain.add_header(&header, true).unwrap();
@@ -1843,12 +3889,193 @@ mod reorg_tests {
             bits: expected,
             nonce: 0,
         };
-        validate_header_rules(&header, Some(prev_header), true, expected).unwrap();
+        validate_header_rules(&header, Some(prev_header), true, expected, MAX_FUTURE_DRIFT_SECS)
+            .unwrap();
+    }
+
+    #[test]
+    fn difficulty_windows_at_chain_start() {
+        let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain.tip_hash();
+        let retarget = |prev: &BlockHeader, start_time: u32| {
+            let span = prev.time.saturating_sub(start_time) as u128;
+            let prev_target = bits_to_target_u128(prev.bits).unwrap();
+            let expected = (TARGET_BLOCK_TIME_SECS * DIFFICULTY_WINDOW) as u128;
+            let target = prev_target.saturating_mul(span) / expected;
+            target_to_bits(target.min(prev_target))
+        };
+
+        // First window: fast blocks, but no retarget before height DIFFICULTY_WINDOW.
+        let mut prev = genesis;
+        for height in 1..DIFFICULTY_WINDOW {
+            let header = make_header(prev, GENESIS_TIME + 60 * height);
+            let prev_header = chain.header_of(&prev).unwrap().clone();
+            assert_eq!(chain.expected_bits(Some(&prev_header), height).unwrap(), INITIAL_BITS);
+            chain.add_header(&header, true).unwrap();
+            prev = header_hash(&header);
+        }
+
+        // First retarget measures from genesis.
+        let last = chain.header_of(&prev).unwrap().clone();
+        let bits_w1 = chain.expected_bits(Some(&last), DIFFICULTY_WINDOW).unwrap();
+        assert_eq!(bits_w1, retarget(&last, GENESIS_TIME));
+        assert_ne!(bits_w1, INITIAL_BITS);
+
+        // Two headers at the window-start height of the second window.
+        let start = BlockHeader {
+            bits: bits_w1,
+            ..make_header(prev, GENESIS_TIME + 600)
+        };
+        let side = BlockHeader {
+            bits: bits_w1,
+            ..make_header(prev, GENESIS_TIME + 3_000)
+        };
+        chain.add_header(&side, true).unwrap();
+        chain.add_header(&start, true).unwrap();
+
+        let mut prev = header_hash(&start);
+        for offset in 1..DIFFICULTY_WINDOW {
+            let prev_header = chain.header_of(&prev).unwrap().clone();
+            assert_eq!(
+                chain.expected_bits(Some(&prev_header), DIFFICULTY_WINDOW + offset).unwrap(),
+                bits_w1
+            );
+            let header = BlockHeader {
+                bits: bits_w1,
+                ..make_header(prev, start.time + 60 * offset)
+            };
+            chain.add_header(&header, true).unwrap();
+            prev = header_hash(&header);
+        }
+
+        // Second retarget measures from this branch's window start, not the side header.
+        let last = chain.header_of(&prev).unwrap().clone();
+        let bits_w2 = chain.expected_bits(Some(&last), 2 * DIFFICULTY_WINDOW).unwrap();
+        assert_eq!(bits_w2, retarget(&last, start.time));
+        assert_ne!(bits_w2, retarget(&last, side.time));
+    }
+
+    #[test]
+    fn target_block_time_drives_retarget() {
+        let fast_devnet = NetworkParams {
+            target_block_time_secs: 1,
+            ..NetworkParams::devnet()
+        };
+        let mut mainnet = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let mut devnet = ChainState::with_genesis(None, fast_devnet);
+        assert_eq!(mainnet.tip_hash(), devnet.tip_hash());
+
+        // One-second blocks: far too fast for mainnet, about on target for the devnet.
+        let mut prev = mainnet.tip_hash();
+        for height in 1..DIFFICULTY_WINDOW {
+            let header = make_header(prev, GENESIS_TIME + height);
+            mainnet.add_header(&header, true).unwrap();
+            devnet.add_header(&header, true).unwrap();
+            prev = header_hash(&header);
+        }
+        let last = mainnet.header_of(&prev).unwrap().clone();
+        let mainnet_bits = mainnet.expected_bits(Some(&last), DIFFICULTY_WINDOW).unwrap();
+        let devnet_bits = devnet.expected_bits(Some(&last), DIFFICULTY_WINDOW).unwrap();
+        assert_ne!(mainnet_bits, devnet_bits);
+        let mainnet_target = bits_to_target_u128(mainnet_bits).unwrap();
+        let devnet_target = bits_to_target_u128(devnet_bits).unwrap();
+        assert!(mainnet_target < devnet_target / 100);
+    }
+
+    #[test]
+    fn block_with_non_final_tx_is_rejected() {
+        let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain.tip_hash();
+        let locked_coinbase = |lock_time: u32| {
+            let mut coinbase = make_coinbase(50, 1, 1);
+            coinbase.vin[0].sequence = 0;
+            coinbase.lock_time = lock_time;
+            coinbase
+        };
+        let mut blocks = BlockStore::default();
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut applied = AppliedState::new(genesis);
+        let mut evicted = Vec::new();
+
+        // Locked until height 2, so not minable at height 1.
+        let early = Block::new(1, genesis, GENESIS_TIME + 1, INITIAL_BITS, 0, vec![locked_coinbase(2)]).unwrap();
+        chain.add_header(&early.header, true).unwrap();
+        blocks.insert(header_hash(&early.header), early);
+        let err = reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted)
+            .unwrap_err();
+        assert!(
+            matches!(err, P2pError::Consensus(ConsensusError::Utxo(UtxoError::NotFinal { lock_time: 2 }))),
+            "{err}"
+        );
+
+        let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let ready = Block::new(1, genesis, GENESIS_TIME + 1, INITIAL_BITS, 0, vec![locked_coinbase(1)]).unwrap();
+        chain.add_header(&ready.header, true).unwrap();
+        blocks.insert(header_hash(&ready.header), ready.clone());
+        reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted).unwrap();
+        assert_eq!(applied.tip, header_hash(&ready.header));
+    }
+
+    #[test]
+    fn devnet_subsidy_halves_at_boundary() {
+        let params = NetworkParams {
+            halving_interval: 2,
+            ..NetworkParams::devnet()
+        };
+        let full = params.initial_subsidy;
+        assert_eq!(params.block_subsidy(1), full);
+        assert_eq!(params.block_subsidy(2), full / 2);
+        assert_eq!(params.block_subsidy(4), full / 4);
+
+        let mut chain = ChainState::with_genesis(None, params);
+        let genesis = chain.tip_hash();
+        let mut blocks = BlockStore::default();
+        let mut prev = genesis;
+        for (height, value) in [(1u32, full), (2, full / 2)] {
+            let block = Block::new(
+                1,
+                prev,
+                GENESIS_TIME + height,
+                INITIAL_BITS,
+                0,
+                vec![make_coinbase(value, height as u8, height)],
+            )
+            .unwrap();
+            chain.add_header(&block.header, true).unwrap();
+            prev = header_hash(&block.header);
+            blocks.insert(prev, block);
+        }
+
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut applied = AppliedState::new(genesis);
+        let mut evicted = Vec::new();
+        reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted).unwrap();
+        assert_eq!(applied.tip, prev);
+
+        // Height 3 is past the boundary: claiming the pre-halving amount fails.
+        let greedy = Block::new(
+            1,
+            prev,
+            GENESIS_TIME + 3,
+            INITIAL_BITS,
+            0,
+            vec![make_coinbase(full, 3, 3)],
+        )
+        .unwrap();
+        chain.add_header(&greedy.header, true).unwrap();
+        blocks.insert(header_hash(&greedy.header), greedy);
+        let err = reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted)
+            .unwrap_err();
+        assert!(
+            matches!(err, P2pError::Consensus(ConsensusError::ExcessCoinbase { .. })),
+            "expected subsidy rejection, got {err}"
+        );
+        assert_eq!(applied.tip, prev);
     }
 
     #[test]
     fn reorg_switches_tip_and_utxo_state() {
-        let mut chain = ChainState::with_genesis(None);
+        let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
         let genesis = chain.tip_hash();
         let base_time = GENESIS_TIME;
 
@@ -1864,16 +4091,16 @@ mod reorg_tests {
         chain.add_header(&header_b1, true).unwrap();
         let hash_b1 = header_hash(&header_b1);
 
-        chain.work.insert(hash_a1, 1);
-        chain.work.insert(hash_a2, 2);
-        chain.work.insert(hash_b1, 3);
-        chain.tip = hash_b1;
+        chain.work.insert(hash_a1, Work::from(1));
+        chain.work.insert(hash_a2, Work::from(2));
+        chain.work.insert(hash_b1, Work::from(3));
+        chain.set_tip(hash_b1);
 
-        let block_a1 = Block::new(1, genesis, 1, INITIAL_BITS, 0, vec![make_coinbase(50, 1)])
+        let block_a1 = Block::new(1, genesis, 1, INITIAL_BITS, 0, vec![make_coinbase(50, 1, 1)])
             .unwrap();
-        let block_a2 = Block::new(1, hash_a1, 2, INITIAL_BITS, 0, vec![make_coinbase(50, 2)])
+        let block_a2 = Block::new(1, hash_a1, 2, INITIAL_BITS, 0, vec![make_coinbase(50, 2, 2)])
             .unwrap();
-        let block_b1 = Block::new(1, genesis, 1, INITIAL_BITS, 0, vec![make_coinbase(50, 3)])
+        let block_b1 = Block::new(1, genesis, 1, INITIAL_BITS, 0, vec![make_coinbase(50, 3, 1)])
             .unwrap();
 
         let mut blocks = BlockStore::default();
@@ -1883,8 +4110,8 @@ mod reorg_tests {
 
         let mut utxos = InMemoryUtxoSet::new();
         let mut applied = AppliedState::new(hash_a2);
-        let receipts_a1 = apply_block_with_undo(&block_a1, &mut utxos, true, 50).unwrap();
-        let receipts_a2 = apply_block_with_undo(&block_a2, &mut utxos, true, 50).unwrap();
+        let receipts_a1 = apply_block_with_undo(&block_a1, 1, 0, &mut utxos, true, 50, MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS, COINBASE_MATURITY).unwrap();
+        let receipts_a2 = apply_block_with_undo(&block_a2, 2, 0, &mut utxos, true, 50, MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS, COINBASE_MATURITY).unwrap();
         applied.undo.insert(hash_a1, receipts_a1);
         applied.undo.insert(hash_a2, receipts_a2);
 
@@ -1904,75 +4131,2193 @@ mod reorg_tests {
         assert!(utxos.get(&out_a2).is_none());
         assert!(utxos.get(&out_b1).is_some());
     }
-}
 
-#[derive(Debug, Default)]
-struct BlockStore {
-    map: HashMap<[u8; 32], Block>,
-}
+    /// Mainnet rules, except the next block may already spend a coinbase.
+    fn spend_coinbase_params() -> NetworkParams {
+        NetworkParams {
+            coinbase_maturity: 0,
+            ..NetworkParams::mainnet()
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn corrupted_undo_receipt_trips_value_invariant() {
+        use tenebrium_utxo::TxIn;
 
-    fn header_with_time(time: u32, bits: u32) -> BlockHeader {
-        BlockHeader {
+        let mut chain = ChainState::with_genesis(None, spend_coinbase_params());
+        let genesis = chain.tip_hash();
+        let block_a1 = Block::new(1, genesis, GENESIS_TIME + 1, INITIAL_BITS, 0, vec![make_coinbase(50, 1, 1)])
+            .unwrap();
+        let hash_a1 = header_hash(&block_a1.header);
+        let spend = Transaction {
             version: 1,
-            prev_block_hash: [0u8; 32],
-            merkle_root: [0u8; 32],
-            time,
-            bits,
-            nonce: 0,
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: block_a1.txs[0].txid_v2().unwrap(),
+                    vout: 0,
+                },
+                script_sig: vec![],
+                sequence: u32::MAX,
+            }],
+            vout: vec![TxOut {
+                value: 40,
+                script_pubkey: vec![7],
+            }],
+            lock_time: 0,
+        };
+        // Claims 55 of the 60 available; the other 5 are burned.
+        let block_a2 = Block::new(
+            1,
+            hash_a1,
+            GENESIS_TIME + 2,
+            INITIAL_BITS,
+            0,
+            vec![make_coinbase(55, 2, 2), spend],
+        )
+        .unwrap();
+        let hash_a2 = header_hash(&block_a2.header);
+        let mut blocks = BlockStore::default();
+        for block in [&block_a1, &block_a2] {
+            chain.add_header(&block.header, true).unwrap();
+            blocks.insert(header_hash(&block.header), block.clone());
         }
-    }
 
-    #[test]
-    fn header_time_too_old_rejected() {
-        let prev = header_with_time(100, INITIAL_BITS);
-        let header = header_with_time(99, INITIAL_BITS);
-        let err = validate_header_rules(&header, Some(&prev), true, INITIAL_BITS).unwrap_err();
-        match err {
-            P2pError::InvalidBlock(msg) => assert!(msg.contains("time too old")),
-            _ => panic!("expected time too old"),
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut applied = AppliedState::new(genesis);
+        let mut evicted = Vec::new();
+        reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted).unwrap();
+        // 50 from a1, then 55 claimed minus 10 paid in fees by the spend.
+        assert_eq!(applied.value_deltas.get(&hash_a1), Some(&50));
+        assert_eq!(applied.value_deltas.get(&hash_a2), Some(&45));
+
+        // A receipt that would restore the spent output with the wrong value.
+        applied.undo.get_mut(&hash_a2).unwrap().txs[0].removed[0].1.value = 49;
+        let before = utxos.entries();
+
+        let mut prev = genesis;
+        for tag in 3..6u32 {
+            let block = Block::new(1, prev, GENESIS_TIME + tag, INITIAL_BITS, 0, vec![make_coinbase(50, tag as u8, tag - 2)])
+                .unwrap();
+            prev = header_hash(&block.header);
+            chain.add_header(&block.header, true).unwrap();
+            blocks.insert(prev, block);
         }
+        assert_eq!(chain.tip_hash(), prev);
+
+        let err = reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted)
+            .unwrap_err();
+        assert!(matches!(&err, P2pError::ValueInvariant(msg) if msg.contains("would remove 46")));
+        assert!(!should_ban(&err));
+        assert_eq!(applied.tip, hash_a2);
+        assert_eq!(utxos.entries(), before);
+        // The failed disconnect kept what a retry needs.
+        assert!(applied.undo.contains_key(&hash_a2));
+        assert_eq!(applied.value_deltas.get(&hash_a2), Some(&45));
     }
 
     #[test]
-    fn header_time_too_far_future_rejected() {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs() as u32;
-        let header = header_with_time(now.saturating_add(MAX_FUTURE_DRIFT_SECS + 1), INITIAL_BITS);
-        let err = validate_header_rules(&header, None, true, INITIAL_BITS).unwrap_err();
-        match err {
-            P2pError::InvalidBlock(msg) => assert!(msg.contains("time too far in future")),
-            _ => panic!("expected future time rejection"),
-        }
+    fn reorg_to_lower_tip_evicts_immature_mempool_tx() {
+        use tenebrium_utxo::TxIn;
+
+        let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain.tip_hash();
+        let block_a1 = Block::new(1, genesis, GENESIS_TIME + 1, INITIAL_BITS, 0, vec![make_coinbase(50, 1, 1)])
+            .unwrap();
+        let hash_a1 = header_hash(&block_a1.header);
+        let block_a2 = Block::new(1, hash_a1, GENESIS_TIME + 2, INITIAL_BITS, 0, vec![make_coinbase(50, 2, 2)])
+            .unwrap();
+        let hash_a2 = header_hash(&block_a2.header);
+        let block_b1 = Block::new(1, genesis, GENESIS_TIME + 3, INITIAL_BITS, 0, vec![make_coinbase(50, 3, 1)])
+            .unwrap();
+        let hash_b1 = header_hash(&block_b1.header);
+        let mut blocks = BlockStore::default();
+        for block in [&block_a1, &block_a2, &block_b1] {
+            chain.add_header(&block.header, true).unwrap();
+            blocks.insert(header_hash(&block.header), block.clone());
+        }
+        assert_eq!(chain.tip_hash(), hash_a2);
+
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut applied = AppliedState::new(genesis);
+        let mut evicted = Vec::new();
+        reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted).unwrap();
+
+        let spend = |tag: u8, lock_time: u32| Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: [tag; 32],
+                    vout: 0,
+                },
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 90,
+                script_pubkey: vec![tag],
+            }],
+            lock_time,
+        };
+        let mut mempool = Mempool::new(MempoolConfig {
+            dust_threshold: 0,
+            ..MempoolConfig::default()
+        });
+        for (tag, lock_time) in [(20u8, 3u32), (21, 0)] {
+            utxos.insert(
+                OutPoint {
+                    txid: [tag; 32],
+                    vout: 0,
+                },
+                TxOut {
+                    value: 100,
+                    script_pubkey: vec![1],
+                },
+            );
+            mempool.add_tx(spend(tag, lock_time), &utxos).unwrap();
+        }
+        // Height-locked to 3: minable on top of a2.
+        assert!(evict_immature(&mut mempool, &chain, applied.tip).unwrap().is_empty());
+
+        // The shorter branch wins (more work): next block height drops to 2.
+        // Until b1 is connected the applied tip, not the header tip, counts.
+        chain.work.insert(hash_b1, Work::MAX);
+        chain.set_tip(hash_b1);
+        assert_eq!(applied.tip, hash_a2);
+        assert!(evict_immature(&mut mempool, &chain, applied.tip).unwrap().is_empty());
+        reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted).unwrap();
+        assert_eq!(applied.tip, hash_b1);
+
+        let dropped = evict_immature(&mut mempool, &chain, applied.tip).unwrap();
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].tx, spend(20, 3));
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&spend(21, 0).txid_v2().unwrap()));
+    }
+
+    #[test]
+    fn reorged_tx_replaces_lower_fee_mempool_conflict() {
+        let funding = OutPoint {
+            txid: [30u8; 32],
+            vout: 0,
+        };
+        let spend = |value: u64| Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: funding.clone(),
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value,
+                script_pubkey: vec![value as u8],
+            }],
+            lock_time: 0,
+        };
+        // Fee 1000 in a1; the mempool holds a fee-100 double spend.
+        let confirmed = spend(9_000);
+        let conflicting = spend(9_900);
+
+        let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain.tip_hash();
+        let block_a1 = Block::new(
+            1,
+            genesis,
+            GENESIS_TIME + 1,
+            INITIAL_BITS,
+            0,
+            vec![make_coinbase(50, 1, 1), confirmed.clone()],
+        )
+        .unwrap();
+        let block_b1 = Block::new(1, genesis, GENESIS_TIME + 2, INITIAL_BITS, 0, vec![make_coinbase(50, 2, 1)])
+            .unwrap();
+        let hash_b1 = header_hash(&block_b1.header);
+        let mut blocks = BlockStore::default();
+        for block in [&block_a1, &block_b1] {
+            chain.add_header(&block.header, true).unwrap();
+            blocks.insert(header_hash(&block.header), block.clone());
+        }
+        chain.work.insert(hash_b1, Work::from(0));
+        chain.set_tip(header_hash(&block_a1.header));
+
+        let mut utxos = InMemoryUtxoSet::new();
+        let coin = TxOut {
+            value: 10_000,
+            script_pubkey: vec![1],
+        };
+        utxos.insert(funding.clone(), coin.clone());
+        let mut pre_a1 = InMemoryUtxoSet::new();
+        pre_a1.insert(funding.clone(), coin);
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        mempool.add_tx(conflicting.clone(), &pre_a1).unwrap();
+
+        let mut applied = AppliedState::new(genesis);
+        let mut evicted = Vec::new();
+        reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted).unwrap();
+        assert!(evicted.is_empty());
+        chain.work.insert(hash_b1, Work::MAX);
+        chain.set_tip(hash_b1);
+        reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted).unwrap();
+        assert_eq!(evicted, vec![confirmed.clone()]);
+
+        let logger = Logger::new(LogLevel::Error, None).unwrap();
+        // Without the pass the first-seen entry stays.
+        readd_evicted(&mut mempool, &utxos, evicted.clone(), false, "test", &logger);
+        assert!(mempool.contains(&conflicting.txid_v2().unwrap()));
+        assert!(!mempool.contains(&confirmed.txid_v2().unwrap()));
+
+        readd_evicted(&mut mempool, &utxos, evicted, true, "test", &logger);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&confirmed.txid_v2().unwrap()));
+    }
+
+    #[test]
+    fn spent_index_follows_apply_and_rollback() {
+        use tenebrium_utxo::TxIn;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut chain = ChainState::with_genesis(Some(db.clone()), spend_coinbase_params());
+        let genesis = chain.tip_hash();
+        let coinbase_a1 = make_coinbase(50, 1, 1);
+        let funding = OutPoint {
+            txid: coinbase_a1.txid_v2().unwrap(),
+            vout: 0,
+        };
+        let spend = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: funding.clone(),
+                script_sig: vec![],
+                sequence: u32::MAX,
+            }],
+            vout: vec![TxOut {
+                value: 40,
+                script_pubkey: vec![9],
+            }],
+            lock_time: 0,
+        };
+        let spend_txid = spend.txid_v2().unwrap();
+        let block_a1 = Block::new(1, genesis, GENESIS_TIME + 1, INITIAL_BITS, 0, vec![coinbase_a1])
+            .unwrap();
+        let hash_a1 = header_hash(&block_a1.header);
+        let block_a2 = Block::new(
+            1,
+            hash_a1,
+            GENESIS_TIME + 2,
+            INITIAL_BITS,
+            0,
+            vec![make_coinbase(50, 2, 2), spend],
+        )
+        .unwrap();
+        let hash_a2 = header_hash(&block_a2.header);
+        let block_b2 = Block::new(1, hash_a1, GENESIS_TIME + 3, INITIAL_BITS, 0, vec![make_coinbase(50, 3, 2)])
+            .unwrap();
+        let hash_b2 = header_hash(&block_b2.header);
+        let mut blocks = BlockStore::default();
+        for block in [&block_a1, &block_a2] {
+            chain.add_header(&block.header, true).unwrap();
+            blocks.insert(header_hash(&block.header), block.clone());
+        }
+        assert_eq!(chain.tip_hash(), hash_a2);
+
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut applied = AppliedState::new(genesis);
+        let mut evicted = Vec::new();
+        reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted).unwrap();
+        assert_eq!(is_spent(&db, &funding).unwrap(), Some(spend_txid));
+
+        // Reorg a2 out for b2: the output is unspent again.
+        chain.add_header(&block_b2.header, true).unwrap();
+        blocks.insert(hash_b2, block_b2);
+        chain.work.insert(hash_b2, Work::MAX);
+        chain.set_tip(hash_b2);
+        reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut evicted).unwrap();
+        assert_eq!(applied.tip, hash_b2);
+        assert_eq!(is_spent(&db, &funding).unwrap(), None);
+        assert!(utxos.get(&funding).is_some());
+    }
+
+    #[test]
+    fn fewer_harder_headers_outweigh_many_easy_ones() {
+        let easy = work_from_bits(INITIAL_BITS).unwrap();
+        let hard = work_from_bits(0x1f00_ffff).unwrap();
+        // Both targets exceed u128, so the old 2^120-scaled work was zero for
+        // each and the taller, easier chain won on height alone.
+        let tall = easy.saturating_add(easy).saturating_add(easy);
+        assert!(prefer_tip((hard, 1, &[1u8; 32]), (tall, 3, &[2u8; 32])));
+        assert!(!prefer_tip((tall, 3, &[2u8; 32]), (hard, 1, &[1u8; 32])));
+
+        // Cumulative work survives a reload as 32-byte entries.
+        let temp = tempfile::tempdir().unwrap();
+        let db = open_sled(temp.path()).unwrap();
+        let tip = {
+            let mut chain =
+                ChainState::load_or_genesis(Some(db.clone()), NetworkParams::mainnet()).unwrap();
+            let genesis = chain.tip_hash();
+            extend_chain(&mut chain, genesis, 2, 0)
+        };
+        let chain = ChainState::load_or_genesis(Some(db.clone()), NetworkParams::mainnet()).unwrap();
+        assert_eq!(chain.tip_hash(), tip);
+        assert_eq!(chain.work.get(&tip), Some(&easy.saturating_add(easy)));
+        assert_eq!(db.open_tree("work").unwrap().get(tip).unwrap().unwrap().len(), 32);
+    }
+
+    #[test]
+    fn equal_work_tips_break_ties_by_hash() {
+        let genesis = ChainState::with_genesis(None, NetworkParams::mainnet()).tip_hash();
+        let header_a = make_header(genesis, GENESIS_TIME + 10);
+        let header_b = make_header(genesis, GENESIS_TIME + 20);
+        let expected = std::cmp::min(header_hash(&header_a), header_hash(&header_b));
+
+        for order in [[&header_a, &header_b], [&header_b, &header_a]] {
+            let mut chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+            for header in order {
+                chain.add_header(header, true).unwrap();
+            }
+            assert_eq!(chain.tip_hash(), expected);
+        }
+
+        for order in [[&header_a, &header_b], [&header_b, &header_a]] {
+            let temp = tempfile::tempdir().unwrap();
+            // Reuse the handle: sled's flusher can still hold the file lock
+            // briefly after the first handle is dropped.
+            let db = open_sled(temp.path()).unwrap();
+            {
+                let mut chain =
+                    ChainState::load_or_genesis(Some(db.clone()), NetworkParams::mainnet()).unwrap();
+                for header in order {
+                    chain.add_header(header, true).unwrap();
+                }
+            }
+            let chain = ChainState::load_or_genesis(Some(db), NetworkParams::mainnet()).unwrap();
+            assert_eq!(chain.tip_hash(), expected);
+        }
+    }
+
+    #[test]
+    fn long_script_sig_is_nonstandard_but_valid_in_block() {
+        use tenebrium_utxo::TxIn;
+
+        let funding = OutPoint {
+            txid: [4u8; 32],
+            vout: 0,
+        };
+        let mut utxos = InMemoryUtxoSet::new();
+        utxos.insert(
+            funding.clone(),
+            TxOut {
+                value: 100,
+                script_pubkey: vec![1],
+            },
+        );
+        let tx = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: funding,
+                script_sig: vec![0xab; 200],
+                sequence: u32::MAX,
+            }],
+            vout: vec![TxOut {
+                value: 90,
+                script_pubkey: vec![2],
+            }],
+            lock_time: 0,
+        };
+
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        let err = mempool.add_tx(tx.clone(), &utxos).unwrap_err();
+        assert!(matches!(err, MempoolError::NonStandard(_)));
+        assert!(mempool.is_empty());
+
+        let block = Block::new(
+            1,
+            [0u8; 32],
+            GENESIS_TIME,
+            INITIAL_BITS,
+            0,
+            vec![make_coinbase(50, 9, 1), tx],
+        )
+        .unwrap();
+        apply_block_with_undo(&block, 1, 0, &mut utxos, true, 50, MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS, COINBASE_MATURITY).unwrap();
+    }
+
+    #[test]
+    fn block_sigop_limit_counts_inputs_not_bytes() {
+        use tenebrium_utxo::TxIn;
+
+        let input = |tag: u8, script_len: usize| TxIn {
+            prevout: OutPoint {
+                txid: [tag; 32],
+                vout: 0,
+            },
+            script_sig: vec![0; script_len],
+            sequence: u32::MAX,
+        };
+        let spend = |vin: Vec<TxIn>| Transaction {
+            version: 1,
+            vin,
+            vout: vec![TxOut {
+                value: 10,
+                script_pubkey: vec![2],
+            }],
+            lock_time: 0,
+        };
+        // Three 48-byte inputs vs one input padded by the two missing ones.
+        let many = spend(vec![input(1, 0), input(2, 0), input(3, 0)]);
+        let few = spend(vec![input(1, 2 * 48)]);
+        assert_eq!(
+            many.canonical_bytes_v2().unwrap().len(),
+            few.canonical_bytes_v2().unwrap().len()
+        );
+        assert_eq!(many.sigop_count(), 3);
+        assert_eq!(few.sigop_count(), 1);
+        assert_eq!(make_coinbase(50, 9, 1).sigop_count(), 0);
+
+        let run = |tx: Transaction| {
+            let mut utxos = InMemoryUtxoSet::new();
+            for tag in 1..=3u8 {
+                utxos.insert(
+                    OutPoint {
+                        txid: [tag; 32],
+                        vout: 0,
+                    },
+                    TxOut {
+                        value: 10,
+                        script_pubkey: vec![1],
+                    },
+                );
+            }
+            let block = Block::new(1, [0u8; 32], GENESIS_TIME, INITIAL_BITS, 0, vec![make_coinbase(50, 9, 1), tx])
+                .unwrap();
+            apply_block_with_undo(&block, 1, 0, &mut utxos, true, 50, 2, MAX_BLOCK_OUTPUTS, COINBASE_MATURITY)
+        };
+        let err = run(many).unwrap_err();
+        assert!(matches!(err, P2pError::Consensus(ConsensusError::TooManySigops { limit: 2, .. })));
+        run(few).unwrap();
+    }
+
+    #[test]
+    fn block_output_limit_sums_across_transactions() {
+        let fund = |tag: u8| OutPoint {
+            txid: [tag; 32],
+            vout: 0,
+        };
+        let fan_out = |tag: u8, outputs: u8| Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: fund(tag),
+                script_sig: vec![],
+                sequence: u32::MAX,
+            }],
+            vout: (0..outputs)
+                .map(|i| TxOut {
+                    value: 1,
+                    script_pubkey: vec![i],
+                })
+                .collect(),
+            lock_time: 0,
+        };
+        let run = |txs: Vec<Transaction>| {
+            let mut utxos = InMemoryUtxoSet::new();
+            for tag in 1..=2u8 {
+                utxos.insert(
+                    fund(tag),
+                    TxOut {
+                        value: 10,
+                        script_pubkey: vec![1],
+                    },
+                );
+            }
+            let mut all = vec![make_coinbase(50, 9, 1)];
+            all.extend(txs);
+            let block = Block::new(1, [0u8; 32], GENESIS_TIME, INITIAL_BITS, 0, all).unwrap();
+            // Coinbase plus two 4-output spends is 9 outputs.
+            apply_block_with_undo(&block, 1, 0, &mut utxos, true, 50, MAX_BLOCK_SIGOPS, 8, COINBASE_MATURITY)
+                .map(|receipt| receipt.iter().count())
+        };
+        let err = run(vec![fan_out(1, 4), fan_out(2, 4)]).unwrap_err();
+        assert!(matches!(
+            err,
+            P2pError::Consensus(ConsensusError::TooManyOutputs { count: 9, limit: 8 })
+        ));
+        assert_eq!(run(vec![fan_out(1, 4), fan_out(2, 3)]).unwrap(), 3);
+    }
+
+    #[test]
+    fn apply_block_rejects_duplicated_tail() {
+        let spend = |tag: u8| Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: [tag; 32],
+                    vout: 0,
+                },
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 10,
+                script_pubkey: vec![tag],
+            }],
+            lock_time: 0,
+        };
+        let mut block = Block::new(
+            1,
+            [0u8; 32],
+            1,
+            INITIAL_BITS,
+            0,
+            vec![make_coinbase(50, 1, 1), spend(2), spend(3)],
+        )
+        .unwrap();
+        // Same merkle root as the original block.
+        block.txs.push(spend(3));
+        let mut utxos = InMemoryUtxoSet::new();
+        let err = apply_block_with_undo(&block, 1, 0, &mut utxos, true, 50, MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS, COINBASE_MATURITY).unwrap_err();
+        assert!(matches!(err, P2pError::Consensus(ConsensusError::MutatedMerkleTree)));
+        assert!(utxos.entries().is_empty());
+    }
+
+    #[test]
+    fn apply_block_rejects_empty_block_typed() {
+        let block = Block::new(1, [0u8; 32], 1, INITIAL_BITS, 0, vec![]).unwrap();
+        let mut utxos = InMemoryUtxoSet::new();
+        let err = apply_block_with_undo(&block, 1, 0, &mut utxos, true, 50, MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS, COINBASE_MATURITY).unwrap_err();
+        assert!(matches!(err, P2pError::Consensus(ConsensusError::EmptyBlock)));
+    }
+
+    #[test]
+    fn coinbase_must_commit_to_block_height() {
+        let block_with = |coinbase: Transaction| {
+            Block::new(1, [0u8; 32], GENESIS_TIME, INITIAL_BITS, 0, vec![coinbase]).unwrap()
+        };
+        let mut utxos = InMemoryUtxoSet::new();
+        for (height, committed) in [(5, 4), (5, 6), (256, 0)] {
+            let block = block_with(make_coinbase(50, 1, committed));
+            let err = apply_block_with_undo(&block, height, 0, &mut utxos, true, 50, MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS, COINBASE_MATURITY)
+                .unwrap_err();
+            assert!(matches!(err, P2pError::Consensus(ConsensusError::BadCoinbaseHeight(h)) if h == height));
+        }
+        let no_input = Transaction {
+            vin: vec![],
+            ..make_coinbase(50, 1, 5)
+        };
+        let err = apply_block_with_undo(&block_with(no_input), 5, 0, &mut utxos, true, 50, MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS, COINBASE_MATURITY)
+            .unwrap_err();
+        assert!(matches!(err, P2pError::Consensus(ConsensusError::CoinbaseRequired)));
+        assert!(utxos.entries().is_empty());
+
+        // Same payout at two heights: only the committed height tells them apart.
+        let at_5 = block_with(make_coinbase(50, 1, 5));
+        let at_6 = block_with(make_coinbase(50, 1, 6));
+        assert_ne!(at_5.txs[0].txid_v2().unwrap(), at_6.txs[0].txid_v2().unwrap());
+        apply_block_with_undo(&at_5, 5, 0, &mut utxos, true, 50, MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS, COINBASE_MATURITY).unwrap();
+        apply_block_with_undo(&at_6, 6, 0, &mut utxos, true, 50, MAX_BLOCK_SIGOPS, MAX_BLOCK_OUTPUTS, COINBASE_MATURITY).unwrap();
+        assert_eq!(utxos.entries().len(), 2);
+    }
+
+    #[test]
+    fn target_to_bits_round_trips_and_avoids_sign_bit() {
+        // 0x80 in the top mantissa byte carries into the exponent.
+        assert_eq!(target_to_bits(0x80), 0x0200_8000);
+        assert_eq!(target_to_bits(0x0080_0000), 0x0400_8000);
+        assert_eq!(target_to_bits(0x12_3456), 0x0312_3456);
+        assert_eq!(target_to_bits(0x12), 0x0112_0000);
+        assert!(matches!(
+            bits_to_target_u128(0x0492_3456),
+            Err(P2pError::Consensus(ConsensusError::InvalidBits))
+        ));
+
+        let mut targets = vec![1u128, 0x7f, 0x80, 0xff, 0x7fff, 0x8000, 0x00ff_ffff, u128::MAX];
+        for shift in (0..120).step_by(7) {
+            targets.push(0x80u128 << shift);
+            targets.push(0xffu128 << shift);
+            targets.push(0x7f_ffffu128 << shift);
+            targets.push(0x12_3456u128 << shift);
+        }
+        for target in targets {
+            let bits = target_to_bits(target);
+            assert_eq!(bits & COMPACT_SIGN_BIT, 0, "{target:#x} -> {bits:#010x}");
+            let decoded = bits_to_target_u128(bits).unwrap();
+            // Compact keeps the top 23 bits: decoding is exact for targets
+            // that fit in the mantissa and never rounds up.
+            assert!(decoded <= target, "{target:#x} -> {bits:#010x}");
+            assert_eq!(target_to_bits(decoded), bits);
+            if target < 0x80_0000 {
+                assert_eq!(decoded, target);
+            }
+        }
+    }
+
+    #[test]
+    fn restart_between_flushes_replays_blocks_above_the_snapshot() {
+        let temp = tempfile::tempdir().unwrap();
+        let db = open_sled(temp.path()).unwrap();
+        let mut chain = ChainState::with_genesis(Some(db.clone()), NetworkParams::mainnet());
+        let mut blocks = BlockStore::default();
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut applied = AppliedState::new(chain.tip_hash());
+        // Flush after the first block only.
+        let mut flush = FlushState::new(0);
+        let mut tip = chain.tip_hash();
+        let mut snapshot_tip = tip;
+        for height in 1..=3u32 {
+            let block = Block::new(
+                1,
+                tip,
+                GENESIS_TIME + height,
+                INITIAL_BITS,
+                0,
+                vec![make_coinbase(50, height as u8, height)],
+            )
+            .unwrap();
+            tip = header_hash(&block.header);
+            chain.add_header(&block.header, true).unwrap();
+            blocks.insert(tip, block.clone());
+            reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut Vec::new()).unwrap();
+            persist_accepted(temp.path(), &block, &tip, &utxos, &applied.tip, Some(db.clone()), &mut flush)
+                .unwrap();
+            if height == 1 {
+                snapshot_tip = tip;
+                flush.threshold_bytes = usize::MAX;
+            }
+        }
+        assert_eq!(load_utxo_tip(&db).unwrap(), Some(snapshot_tip));
+        let expected: std::collections::BTreeMap<_, _> = utxos.entries().into_iter().collect();
+
+        // Killed here: only the in-memory state is lost.
+        drop((chain, blocks, utxos, applied));
+        let chain = ChainState::load_or_genesis(Some(db.clone()), NetworkParams::mainnet()).unwrap();
+        assert_eq!(chain.tip_hash(), tip);
+        let logger = Logger::new(LogLevel::Error, None).unwrap();
+        let blocks = BlockStore::load_dir(temp.path(), false, usize::MAX, &logger).unwrap();
+        let mut applied = AppliedState::new(chain.tip_hash());
+        let utxos =
+            load_chainstate(None, Some(temp.path()), Some(&db), &chain, &blocks, &mut applied, &logger)
+                .unwrap();
+        assert_eq!(utxos.entries().into_iter().collect::<std::collections::BTreeMap<_, _>>(), expected);
+        assert_eq!(applied.tip, tip);
+        assert_eq!(applied.undo.len(), 2);
+        assert_eq!(load_utxo_tip(&db).unwrap(), Some(tip));
+    }
+
+    #[test]
+    fn reindex_chainstate_rebuilds_corrupted_utxo_tree() {
+        use tenebrium_utxo::TxIn;
+
+        let temp = tempfile::tempdir().unwrap();
+        let db = open_sled(temp.path()).unwrap();
+        let mut chain = ChainState::with_genesis(Some(db.clone()), spend_coinbase_params());
+        let genesis = chain.tip_hash();
+        let mut blocks = BlockStore::default();
+        let mut tip = genesis;
+        // Block 2 spends block 1's coinbase.
+        let first_coinbase = OutPoint {
+            txid: make_coinbase(50, 1, 1).txid_v2().unwrap(),
+            vout: 0,
+        };
+        let spend = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: first_coinbase.clone(),
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 40,
+                script_pubkey: vec![2],
+            }],
+            lock_time: 0,
+        };
+        for height in 1..=3u32 {
+            let mut txs = vec![make_coinbase(50, height as u8, height)];
+            if height == 2 {
+                txs.push(spend.clone());
+            }
+            let block = Block::new(1, tip, GENESIS_TIME + height, INITIAL_BITS, 0, txs).unwrap();
+            chain.add_header(&block.header, true).unwrap();
+            tip = header_hash(&block.header);
+            persist_block(temp.path(), &block, &tip, Some(db.clone())).unwrap();
+            blocks.insert(tip, block);
+        }
+        let mut utxos = InMemoryUtxoSet::new();
+        let mut applied = AppliedState::new(genesis);
+        reorg_to_tip(&mut applied, &chain, &blocks, &mut utxos, true, &mut Vec::new()).unwrap();
+        persist_utxos(temp.path(), &utxos, &tip, Some(db.clone())).unwrap();
+        let sorted = |set: &InMemoryUtxoSet| {
+            set.entries()
+                .into_iter()
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+        let expected = sorted(&utxos);
+        let headers_before = db.open_tree("headers").unwrap().len();
+
+        // Swap one coin for a bogus one; the count still matches, so a
+        // plain load happily returns the wrong set.
+        let tree = db.open_tree("utxo").unwrap();
+        let (key, _) = tree.iter().next().unwrap().unwrap();
+        tree.remove(key).unwrap();
+        let bogus = OutPoint {
+            txid: [0xee; 32],
+            vout: 0,
+        };
+        let coin = TxOut {
+            value: 1_000_000,
+            script_pubkey: vec![0xee],
+        };
+        tree.insert(encode_outpoint(&bogus), encode_txout(&coin)).unwrap();
+        let corrupted = load_utxos(None, None, Some(db.clone())).unwrap();
+        assert_ne!(sorted(&corrupted), expected);
+        // The spent index lost the real spend and gained a bogus one.
+        let spent = db.open_tree("spent").unwrap();
+        spent.clear().unwrap();
+        spent.insert(encode_outpoint(&bogus), vec![0xee; 32]).unwrap();
+
+        // Restart with --reindex-chainstate: headers come from the db as is.
+        let chain = ChainState::load_or_genesis(Some(db.clone()), spend_coinbase_params()).unwrap();
+        assert_eq!(chain.tip_hash(), tip);
+        let logger = Logger::new(LogLevel::Error, None).unwrap();
+        let blocks = BlockStore::load_dir(temp.path(), false, usize::MAX, &logger).unwrap();
+        let mut applied = AppliedState::new(chain.tip_hash());
+        let rebuilt = reindex_chainstate(temp.path(), &db, &chain, &blocks, &mut applied).unwrap();
+        assert_eq!(sorted(&rebuilt), expected);
+        assert_eq!(applied.tip, tip);
+        assert_eq!(applied.undo.len(), 3);
+        assert_eq!(db.open_tree("headers").unwrap().len(), headers_before);
+        assert_eq!(sorted(&load_utxos(None, None, Some(db.clone())).unwrap()), expected);
+        assert_eq!(is_spent(&db, &first_coinbase).unwrap(), Some(spend.txid_v2().unwrap()));
+        assert_eq!(is_spent(&db, &bogus).unwrap(), None);
+
+        // A snapshot count taken at this tip that disagrees is reported.
+        let meta = db.open_tree("meta").unwrap();
+        meta.insert("utxo_count", 99u64.to_le_bytes().to_vec()).unwrap();
+        let err = reindex_chainstate(temp.path(), &db, &chain, &blocks, &mut applied).unwrap_err();
+        assert!(matches!(err, P2pError::InvalidBlock(msg) if msg.contains("snapshot count 99")));
+        meta.insert("utxo_count", (expected.len() as u64).to_le_bytes().to_vec()).unwrap();
+
+        // A failed replay leaves the stored snapshot and undo data alone.
+        let err = reindex_chainstate(temp.path(), &db, &chain, &BlockStore::default(), &mut applied).unwrap_err();
+        assert!(matches!(err, P2pError::InvalidBlock(msg) if msg == "missing block data"));
+        assert_eq!(sorted(&load_utxos(None, None, Some(db.clone())).unwrap()), expected);
+        assert_eq!(applied.undo.len(), 3);
+    }
+}
+
+#[derive(Debug, Default)]
+struct BlockStore {
+    map: HashMap<[u8; 32], Block>,
+    /// Blocks whose files were quarantined at startup, to be re-downloaded.
+    missing: HashSet<[u8; 32]>,
+    /// Data dir whose `blocks/` backs blocks evicted from memory
+    dir: Option<PathBuf>,
+    /// In-memory blocks, oldest first, and their approximate size
+    order: VecDeque<[u8; 32]>,
+    bytes: usize,
+    cap_bytes: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tenebrium_consensus::encode_coinbase_height;
+    use tenebrium_consensus::params::{
+        DEVNET_MAGIC, GENESIS_TIME, MAINNET_MAGIC, MAX_FUTURE_DRIFT_SECS,
+    };
+    use tenebrium_utxo::{TxIn, TxOut};
+
+    fn coinbase_input() -> Vec<TxIn> {
+        vec![TxIn {
+            prevout: OutPoint::NULL,
+            script_sig: encode_coinbase_height(0),
+            sequence: u32::MAX,
+        }]
+    }
+
+    #[test]
+    fn configured_timeouts_bound_peer_reads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let writer = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            std::thread::sleep(Duration::from_millis(300));
+            let _ = stream.write_all(b"x");
+        });
+
+        let timeouts = PeerTimeouts {
+            read: Duration::from_millis(50),
+            write: Duration::from_secs(1),
+            connect: Duration::from_secs(2),
+        };
+        let mut stream = timeouts.connect(&addr).unwrap();
+        timeouts.apply(&stream).unwrap();
+        let mut buf = [0u8; 1];
+        let err = stream.read_exact(&mut buf).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        ));
+
+        let patient = PeerTimeouts {
+            read: Duration::from_secs(2),
+            ..timeouts
+        };
+        patient.apply(&stream).unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"x");
+        writer.join().unwrap();
+    }
+
+    fn header_with_time(time: u32, bits: u32) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block_hash: [0u8; 32],
+            merkle_root: [0u8; 32],
+            time,
+            bits,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn header_time_too_old_rejected() {
+        let prev = header_with_time(100, INITIAL_BITS);
+        let header = header_with_time(99, INITIAL_BITS);
+        let err = validate_header_rules(&header, Some(&prev), true, INITIAL_BITS, MAX_FUTURE_DRIFT_SECS)
+            .unwrap_err();
+        match err {
+            P2pError::InvalidBlock(msg) => assert!(msg.contains("time too old")),
+            _ => panic!("expected time too old"),
+        }
+    }
+
+    #[test]
+    fn header_time_too_far_future_rejected() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        let header = header_with_time(now.saturating_add(MAX_FUTURE_DRIFT_SECS + 1), INITIAL_BITS);
+        let err = validate_header_rules(&header, None, true, INITIAL_BITS, MAX_FUTURE_DRIFT_SECS)
+            .unwrap_err();
+        match err {
+            P2pError::InvalidBlock(msg) => assert!(msg.contains("time too far in future")),
+            _ => panic!("expected future time rejection"),
+        }
+    }
+
+    #[test]
+    fn tighter_network_drift_rejects_header_within_default() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        let tight = NetworkParams {
+            max_future_drift_secs: 10 * 60,
+            ..NetworkParams::devnet()
+        };
+        let genesis = ChainState::with_genesis(None, tight.clone()).tip_hash();
+        let header = BlockHeader {
+            prev_block_hash: genesis,
+            ..header_with_time(now + 20 * 60, INITIAL_BITS)
+        };
+
+        let mut chain = ChainState::with_genesis(None, tight);
+        match chain.add_header(&header, true).unwrap_err() {
+            P2pError::InvalidBlock(msg) => assert!(msg.contains("time too far in future")),
+            other => panic!("expected future time rejection, got {other}"),
+        }
+
+        let mut chain = ChainState::with_genesis(None, NetworkParams::devnet());
+        chain.add_header(&header, true).unwrap();
+        assert_eq!(chain.tip_hash(), header_hash(&header));
+    }
+
+    #[test]
+    fn filtered_peer_only_receives_matching_tx() {
+        use tenebrium_utxo::{OutPoint, TxIn};
+
+        let make_tx = |tag: u8| Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint {
+                    txid: [tag; 32],
+                    vout: 0,
+                },
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 10,
+                script_pubkey: vec![tag; 32],
+            }],
+            lock_time: 0,
+        };
+        let mut peers = PeerManager::new(vec!["spv:1".to_string(), "full:1".to_string()]);
+        let mut filter = BloomFilter::new(1, 0.0001, 42);
+        filter.insert(&[2u8; 32]);
+        peers.set_filter("spv:1", filter);
+
+        let mut spv_received = Vec::new();
+        let mut full_received = 0;
+        for tag in 1..=4u8 {
+            let tx = make_tx(tag);
+            let txid = tx.txid_v2().unwrap();
+            let targets = peers.tx_relay_targets(&tx, &txid);
+            if targets.iter().any(|p| p == "spv:1") {
+                spv_received.push(tag);
+            }
+            if targets.iter().any(|p| p == "full:1") {
+                full_received += 1;
+            }
+        }
+        assert_eq!(spv_received, vec![2]);
+        assert_eq!(full_received, 4);
+
+        peers.clear_filter("spv:1");
+        let tx = make_tx(3);
+        let txid = tx.txid_v2().unwrap();
+        assert_eq!(peers.tx_relay_targets(&tx, &txid).len(), 2);
+    }
+
+    #[test]
+    fn merkle_block_for_filter_proves_single_match() {
+        let txs: Vec<Transaction> = (1..=5u8)
+            .map(|tag| Transaction {
+                version: 1,
+                vin: vec![],
+                vout: vec![TxOut {
+                    value: 10,
+                    script_pubkey: vec![tag; 32],
+                }],
+                lock_time: 0,
+            })
+            .collect();
+        let block = Block::new(1, [0u8; 32], 0, INITIAL_BITS, 0, txs.clone()).unwrap();
+        let mut filter = BloomFilter::new(1, 0.0001, 9);
+        filter.insert(&[4u8; 32]);
+
+        let (merkle_block, matched) = merkle_block_for_filter(&block, &filter).unwrap();
+        assert_eq!(matched, vec![txs[3].clone()]);
+        assert!(merkle_block.matched_hashes.len() < txs.len());
+
+        // The client only holds the merkleblock and the matched tx.
+        let msg = serde_json::to_vec(&P2pMessage::MerkleBlock(merkle_block)).unwrap();
+        let P2pMessage::MerkleBlock(received) = parse_message_bytes(&msg).unwrap() else {
+            panic!("expected merkleblock");
+        };
+        let proven = received.verify().unwrap();
+        assert_eq!(proven, vec![matched[0].txid_v2().unwrap()]);
+        assert_eq!(received.header.merkle_root, block.header.merkle_root);
+    }
+
+    #[test]
+    fn oversized_filter_messages_rejected() {
+        let load = P2pMessage::FilterLoad(BloomFilter {
+            data: vec![0u8; crate::bloom::MAX_BLOOM_FILTER_BYTES + 1],
+            hash_funcs: 1,
+            tweak: 0,
+        });
+        assert!(validate_message(&load).is_err());
+        let add = P2pMessage::FilterAdd(vec![0u8; MAX_FILTER_ADD_BYTES + 1]);
+        assert!(validate_message(&add).is_err());
+    }
+
+    #[test]
+    fn filter_is_dropped_when_the_connection_ends() {
+        let chain_state = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain_state.tip_hash();
+        let peers = Arc::new(Mutex::new(PeerManager::new(Vec::new())));
+        let node = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_addr = node.local_addr().unwrap();
+        let server = {
+            let peers = Arc::clone(&peers);
+            thread::spawn(move || {
+                let (stream, addr) = node.accept().unwrap();
+                let _ = handle_connection(
+                    stream,
+                    addr.to_string(),
+                    peers,
+                    Arc::new(Mutex::new(Mempool::new(MempoolConfig::default()))),
+                    Arc::new(Mutex::new(InMemoryUtxoSet::new())),
+                    Arc::new(Mutex::new(BlockStore::default())),
+                    Arc::new(Mutex::new(chain_state)),
+                    Arc::new(Mutex::new(AppliedState::new(genesis))),
+                    Arc::new(Mutex::new(Seen::default())),
+                    "node-test".to_string(),
+                    "mainnet".to_string(),
+                    None,
+                    None,
+                    true,
+                    TXID_VERSION_V2,
+                    P2pOptions::default(),
+                    Arc::new(Logger::new(LogLevel::Error, None).unwrap()),
+                );
+            })
+        };
+
+        let mut client = TcpStream::connect(node_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Hello { .. }));
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::GetHeaders { .. }));
+        send_message(
+            &mut client,
+            MAINNET_MAGIC,
+            &local_hello("mainnet", "client-test", TXID_VERSION_V2),
+        )
+        .unwrap();
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Addr(_)));
+        send_message(
+            &mut client,
+            MAINNET_MAGIC,
+            &P2pMessage::FilterLoad(BloomFilter::new(1, 0.0001, 9)),
+        )
+        .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while peers.lock().unwrap().filters.is_empty() {
+            assert!(Instant::now() < deadline, "filter never loaded");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // The peer hangs up: the node's side sees EOF and returns.
+        drop(client);
+        server.join().unwrap();
+        assert!(peers.lock().unwrap().filters.is_empty());
     }
 
     #[test]
     fn header_unexpected_bits_rejected() {
         let prev = header_with_time(100, INITIAL_BITS);
         let header = header_with_time(100, INITIAL_BITS + 1);
-        let err = validate_header_rules(&header, Some(&prev), true, INITIAL_BITS).unwrap_err();
+        let err = validate_header_rules(&header, Some(&prev), true, INITIAL_BITS, MAX_FUTURE_DRIFT_SECS)
+            .unwrap_err();
         match err {
             P2pError::InvalidBlock(msg) => assert!(msg.contains("unexpected difficulty bits")),
             _ => panic!("expected unexpected difficulty bits"),
         }
     }
+
+    #[test]
+    fn blocks_only_node_drops_tx_but_relays_block() {
+        use std::sync::mpsc;
+        use tenebrium_utxo::{OutPoint, TxIn, UtxoSet};
+
+        let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay_addr = relay.local_addr().unwrap().to_string();
+        let (relay_tx, relay_rx) = mpsc::channel();
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = relay.accept() {
+                // The relay connection opens with the node's own Hello.
+                let _ = read_message(&mut stream, MAINNET_MAGIC);
+                let _ = relay_tx.send(read_message(&mut stream, MAINNET_MAGIC));
+            }
+        });
+
+        let funding = OutPoint {
+            txid: [8u8; 32],
+            vout: 0,
+        };
+        let mut utxo_set = InMemoryUtxoSet::new();
+        utxo_set.insert(
+            funding.clone(),
+            TxOut {
+                value: 100,
+                script_pubkey: vec![1],
+            },
+        );
+        let mempool = Arc::new(Mutex::new(Mempool::new(MempoolConfig::default())));
+        let chain_state = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain_state.tip_hash();
+        let peers = Arc::new(Mutex::new(PeerManager::new(vec![relay_addr])));
+
+        let node = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_addr = node.local_addr().unwrap();
+        {
+            let peers = Arc::clone(&peers);
+            let mempool = Arc::clone(&mempool);
+            let applied = Arc::new(Mutex::new(AppliedState::new(genesis)));
+            thread::spawn(move || {
+                let (stream, addr) = node.accept().unwrap();
+                let _ = handle_connection(
+                    stream,
+                    addr.to_string(),
+                    peers,
+                    mempool,
+                    Arc::new(Mutex::new(utxo_set)),
+                    Arc::new(Mutex::new(BlockStore::default())),
+                    Arc::new(Mutex::new(chain_state)),
+                    applied,
+                    Arc::new(Mutex::new(Seen::default())),
+                    "node-test".to_string(),
+                    "mainnet".to_string(),
+                    None,
+                    None,
+                    true,
+                    TXID_VERSION_V2,
+                    P2pOptions {
+                        blocks_only: true,
+                        ..P2pOptions::default()
+                    },
+                    Arc::new(Logger::new(LogLevel::Error, None).unwrap()),
+                );
+            });
+        }
+
+        let mut client = TcpStream::connect(node_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Hello { .. }));
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::GetHeaders { .. }));
+        send_message(
+            &mut client,
+            MAINNET_MAGIC,
+            &local_hello("mainnet", "client-test", TXID_VERSION_V2),
+        )
+        .unwrap();
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Addr(_)));
+
+        let tx = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: funding,
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 90,
+                script_pubkey: vec![2],
+            }],
+            lock_time: 0,
+        };
+        let coinbase = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: OutPoint::NULL,
+                script_sig: encode_coinbase_height(1),
+                sequence: u32::MAX,
+            }],
+            vout: vec![TxOut {
+                value: 50,
+                script_pubkey: vec![3],
+            }],
+            lock_time: 0,
+        };
+        let block = Block::new(1, genesis, GENESIS_TIME + 600, INITIAL_BITS, 0, vec![coinbase]).unwrap();
+        let block_hash = header_hash(&block.header);
+
+        // An announced txid must not be requested: the Ping reply comes first.
+        send_message(
+            &mut client,
+            MAINNET_MAGIC,
+            &P2pMessage::Inv {
+                txids: vec![tx.txid_v2().unwrap()],
+                blocks: vec![],
+            },
+        )
+        .unwrap();
+        send_message(&mut client, MAINNET_MAGIC, &P2pMessage::Ping).unwrap();
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Pong));
+
+        send_message(&mut client, MAINNET_MAGIC, &P2pMessage::Tx(tx)).unwrap();
+        send_message(&mut client, MAINNET_MAGIC, &P2pMessage::Block(block)).unwrap();
+
+        match relay_rx.recv_timeout(Duration::from_secs(10)).unwrap().unwrap() {
+            P2pMessage::Inv { txids, blocks } => {
+                assert!(txids.is_empty());
+                assert_eq!(blocks, vec![block_hash]);
+            }
+            other => panic!("expected block inv, got {other:?}"),
+        }
+        assert!(mempool.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn overlapping_block_inv_is_fetched_once_until_timeout() {
+        let chain_state = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain_state.tip_hash();
+        let peers = Arc::new(Mutex::new(PeerManager::new(vec![])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(MempoolConfig::default())));
+        let utxos = Arc::new(Mutex::new(InMemoryUtxoSet::new()));
+        let blocks = Arc::new(Mutex::new(BlockStore::default()));
+        let chain = Arc::new(Mutex::new(chain_state));
+        let applied = Arc::new(Mutex::new(AppliedState::new(genesis)));
+        let seen = Arc::new(Mutex::new(Seen::default()));
+
+        let node = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_addr = node.local_addr().unwrap();
+        {
+            let seen = Arc::clone(&seen);
+            thread::spawn(move || {
+                for _ in 0..2 {
+                    let (stream, addr) = node.accept().unwrap();
+                    let peers = Arc::clone(&peers);
+                    let mempool = Arc::clone(&mempool);
+                    let utxos = Arc::clone(&utxos);
+                    let blocks = Arc::clone(&blocks);
+                    let chain = Arc::clone(&chain);
+                    let applied = Arc::clone(&applied);
+                    let seen = Arc::clone(&seen);
+                    thread::spawn(move || {
+                        let _ = handle_connection(
+                            stream,
+                            addr.to_string(),
+                            peers,
+                            mempool,
+                            utxos,
+                            blocks,
+                            chain,
+                            applied,
+                            seen,
+                            "node-test".to_string(),
+                            "mainnet".to_string(),
+                            None,
+                            None,
+                            true,
+                            TXID_VERSION_V2,
+                            P2pOptions::default(),
+                            Arc::new(Logger::new(LogLevel::Error, None).unwrap()),
+                        );
+                    });
+                }
+            });
+        }
+
+        let announced = [0x42u8; 32];
+        let mut replies = Vec::new();
+        for client_id in ["client-a", "client-b"] {
+            let mut client = TcpStream::connect(node_addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Hello { .. }));
+            assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::GetHeaders { .. }));
+            send_message(&mut client, MAINNET_MAGIC, &local_hello("mainnet", client_id, TXID_VERSION_V2))
+                .unwrap();
+            assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Addr(_)));
+
+            send_message(
+                &mut client,
+                MAINNET_MAGIC,
+                &P2pMessage::Inv {
+                    txids: vec![],
+                    blocks: vec![announced],
+                },
+            )
+            .unwrap();
+            send_message(&mut client, MAINNET_MAGIC, &P2pMessage::Ping).unwrap();
+            let mut got = Vec::new();
+            loop {
+                match read_message(&mut client, MAINNET_MAGIC).unwrap() {
+                    P2pMessage::Pong => break,
+                    other => got.push(other),
+                }
+            }
+            replies.push(got);
+        }
+
+        // Only the first announcer is asked; the second waits on that request.
+        assert!(matches!(&replies[0][..], [P2pMessage::GetBlock(hashes)] if hashes == &vec![announced]));
+        assert!(replies[1].is_empty(), "duplicate fetch: {:?}", replies[1]);
+
+        let mut guard = seen.lock().unwrap();
+        let requested_at = guard.block_requested[&announced];
+        let within = requested_at + Duration::from_secs(REQUEST_TIMEOUT_SECS - 1);
+        assert!(claim_requests(&mut guard.block_requested, vec![announced], within).is_empty());
+        let expired = requested_at + Duration::from_secs(REQUEST_TIMEOUT_SECS);
+        assert_eq!(
+            claim_requests(&mut guard.block_requested, vec![announced], expired),
+            vec![announced]
+        );
+    }
+
+    #[test]
+    fn dialing_own_listen_address_is_dropped_without_using_a_slot() {
+        use std::sync::mpsc;
+
+        let chain_state = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain_state.tip_hash();
+        let node = TcpListener::bind("127.0.0.1:0").unwrap();
+        let own_addr = node.local_addr().unwrap().to_string();
+        let peers = Arc::new(Mutex::new(PeerManager::new(vec![own_addr.clone()])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(MempoolConfig::default())));
+        let utxos = Arc::new(Mutex::new(InMemoryUtxoSet::new()));
+        let blocks = Arc::new(Mutex::new(BlockStore::default()));
+        let chain = Arc::new(Mutex::new(chain_state));
+        let applied = Arc::new(Mutex::new(AppliedState::new(genesis)));
+        let seen = Arc::new(Mutex::new(Seen::default()));
+        let logger = Arc::new(Logger::new(LogLevel::Error, None).unwrap());
+
+        // Accept side, as in the run_p2p listener loop.
+        let (result_tx, result_rx) = mpsc::channel();
+        {
+            let (peers, mempool, utxos, blocks, chain, applied, seen, logger) = (
+                Arc::clone(&peers),
+                Arc::clone(&mempool),
+                Arc::clone(&utxos),
+                Arc::clone(&blocks),
+                Arc::clone(&chain),
+                Arc::clone(&applied),
+                Arc::clone(&seen),
+                Arc::clone(&logger),
+            );
+            thread::spawn(move || {
+                let (stream, addr) = node.accept().unwrap();
+                let addr = addr.to_string();
+                assert!(peers.lock().unwrap().allow_incoming(&addr));
+                let result = handle_connection(
+                    stream,
+                    addr.clone(),
+                    Arc::clone(&peers),
+                    mempool,
+                    utxos,
+                    blocks,
+                    chain,
+                    applied,
+                    seen,
+                    "node-self".to_string(),
+                    "mainnet".to_string(),
+                    None,
+                    None,
+                    true,
+                    TXID_VERSION_V2,
+                    P2pOptions::default(),
+                    logger,
+                );
+                if let Err(err) = &result {
+                    drop_peer(&peers, &addr, err, false);
+                }
+                let _ = result_tx.send(result);
+            });
+        }
+
+        spawn_connect(
+            own_addr.clone(),
+            Arc::clone(&peers),
+            mempool,
+            utxos,
+            blocks,
+            chain,
+            applied,
+            seen,
+            "node-self".to_string(),
+            "mainnet".to_string(),
+            None,
+            None,
+            true,
+            TXID_VERSION_V2,
+            P2pOptions::default(),
+            logger,
+        );
+
+        // The accepting side alone records the self-dial, so nothing here
+        // depends on whether the dialer got to read its Hello.
+        let inbound = result_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(matches!(inbound, Err(P2pError::SelfConnection)));
+
+        let mut guard = peers.lock().unwrap();
+        assert!(guard.self_addrs.contains(&own_addr));
+        assert_eq!(guard.count(), 0);
+        assert!(guard.banned.is_empty());
+        assert!(!guard.should_dial(&own_addr));
+        assert!(!guard.add_peer(&own_addr).unwrap());
+        assert!(guard.list().is_empty());
+    }
+
+    #[test]
+    fn tx_before_hello_drops_peer_but_is_accepted_after() {
+        use std::sync::mpsc;
+        use tenebrium_utxo::{OutPoint, TxIn};
+
+        let funding = OutPoint {
+            txid: [9u8; 32],
+            vout: 0,
+        };
+        let mut utxo_set = InMemoryUtxoSet::new();
+        utxo_set.insert(
+            funding.clone(),
+            TxOut {
+                value: 10_000,
+                script_pubkey: vec![1],
+            },
+        );
+        let tx = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: funding,
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 9_000,
+                script_pubkey: vec![2],
+            }],
+            lock_time: 0,
+        };
+        let chain_state = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain_state.tip_hash();
+        let peers = Arc::new(Mutex::new(PeerManager::new(vec![])));
+        let mempool = Arc::new(Mutex::new(Mempool::new(MempoolConfig::default())));
+        let utxos = Arc::new(Mutex::new(utxo_set));
+        let blocks = Arc::new(Mutex::new(BlockStore::default()));
+        let chain = Arc::new(Mutex::new(chain_state));
+        let applied = Arc::new(Mutex::new(AppliedState::new(genesis)));
+
+        let node = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_addr = node.local_addr().unwrap();
+        let (result_tx, result_rx) = mpsc::channel();
+        {
+            let (peers, mempool) = (Arc::clone(&peers), Arc::clone(&mempool));
+            thread::spawn(move || {
+                for _ in 0..2 {
+                    let (stream, addr) = node.accept().unwrap();
+                    let result = handle_connection(
+                        stream,
+                        addr.to_string(),
+                        Arc::clone(&peers),
+                        Arc::clone(&mempool),
+                        Arc::clone(&utxos),
+                        Arc::clone(&blocks),
+                        Arc::clone(&chain),
+                        Arc::clone(&applied),
+                        Arc::new(Mutex::new(Seen::default())),
+                        "node-test".to_string(),
+                        "mainnet".to_string(),
+                        None,
+                        None,
+                        true,
+                        TXID_VERSION_V2,
+                        P2pOptions::default(),
+                        Arc::new(Logger::new(LogLevel::Error, None).unwrap()),
+                    );
+                    let _ = result_tx.send(result);
+                }
+            });
+        }
+        let connect = || {
+            let mut client = TcpStream::connect(node_addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Hello { .. }));
+            assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::GetHeaders { .. }));
+            client
+        };
+
+        let mut client = connect();
+        send_message(&mut client, MAINNET_MAGIC, &P2pMessage::Tx(tx.clone())).unwrap();
+        let err = result_rx.recv_timeout(Duration::from_secs(10)).unwrap().unwrap_err();
+        assert!(matches!(err, P2pError::BeforeHandshake("Tx")));
+        assert!(should_ban(&err));
+        let mut buf = [0u8; 1];
+        assert!(!matches!(client.read(&mut buf), Ok(n) if n > 0));
+        assert!(mempool.lock().unwrap().is_empty());
+
+        let mut client = connect();
+        send_message(
+            &mut client,
+            MAINNET_MAGIC,
+            &local_hello("mainnet", "client-test", TXID_VERSION_V2),
+        )
+        .unwrap();
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Addr(_)));
+        send_message(&mut client, MAINNET_MAGIC, &P2pMessage::Tx(tx.clone())).unwrap();
+        send_message(&mut client, MAINNET_MAGIC, &P2pMessage::Ping).unwrap();
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Pong));
+        assert!(mempool.lock().unwrap().contains(&tx.txid_v2().unwrap()));
+    }
+
+    #[test]
+    fn wrong_magic_drops_peer_before_json() {
+        use std::sync::mpsc;
+
+        let chain_state = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain_state.tip_hash();
+        let node = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_addr = node.local_addr().unwrap();
+        let (result_tx, result_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, addr) = node.accept().unwrap();
+            let result = handle_connection(
+                stream,
+                addr.to_string(),
+                Arc::new(Mutex::new(PeerManager::new(vec![]))),
+                Arc::new(Mutex::new(Mempool::new(MempoolConfig::default()))),
+                Arc::new(Mutex::new(InMemoryUtxoSet::new())),
+                Arc::new(Mutex::new(BlockStore::default())),
+                Arc::new(Mutex::new(chain_state)),
+                Arc::new(Mutex::new(AppliedState::new(genesis))),
+                Arc::new(Mutex::new(Seen::default())),
+                "node-test".to_string(),
+                "mainnet".to_string(),
+                None,
+                None,
+                true,
+                TXID_VERSION_V2,
+                P2pOptions::default(),
+                Arc::new(Logger::new(LogLevel::Error, None).unwrap()),
+            );
+            let _ = result_tx.send(result);
+        });
+
+        let mut client = TcpStream::connect(node_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Hello { .. }));
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::GetHeaders { .. }));
+
+        // A devnet frame whose body is not JSON: the magic check must fire first.
+        let body = b"not json";
+        let mut frame = DEVNET_MAGIC.to_vec();
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(body);
+        let _ = client.write_all(&frame);
+
+        let err = result_rx.recv_timeout(Duration::from_secs(10)).unwrap().unwrap_err();
+        assert!(matches!(err, P2pError::BadMagic(magic) if magic == DEVNET_MAGIC));
+        assert!(should_ban(&err));
+        // Closed by the node: EOF, or a reset if the unread body was discarded.
+        let mut buf = [0u8; 1];
+        assert!(!matches!(client.read(&mut buf), Ok(n) if n > 0));
+    }
+
+    #[test]
+    fn getblocks_from_genesis_locator_lists_following_blocks() {
+        let mut chain_state = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain_state.tip_hash();
+        let mut hashes = Vec::new();
+        let mut prev = genesis;
+        for i in 1..=3u32 {
+            let header = BlockHeader {
+                prev_block_hash: prev,
+                ..header_with_time(GENESIS_TIME + i * 600, INITIAL_BITS)
+            };
+            chain_state.add_header(&header, true).unwrap();
+            prev = header_hash(&header);
+            hashes.push(prev);
+        }
+        assert_eq!(chain_state.locator().last(), Some(&genesis));
+
+        let node = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_addr = node.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, addr) = node.accept().unwrap();
+            let _ = handle_connection(
+                stream,
+                addr.to_string(),
+                Arc::new(Mutex::new(PeerManager::new(vec![]))),
+                Arc::new(Mutex::new(Mempool::new(MempoolConfig::default()))),
+                Arc::new(Mutex::new(InMemoryUtxoSet::new())),
+                Arc::new(Mutex::new(BlockStore::default())),
+                Arc::new(Mutex::new(chain_state)),
+                Arc::new(Mutex::new(AppliedState::new(genesis))),
+                Arc::new(Mutex::new(Seen::default())),
+                "node-test".to_string(),
+                "mainnet".to_string(),
+                None,
+                None,
+                true,
+                TXID_VERSION_V2,
+                P2pOptions::default(),
+                Arc::new(Logger::new(LogLevel::Error, None).unwrap()),
+            );
+        });
+
+        let mut client = TcpStream::connect(node_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        assert!(matches!(
+            read_message(&mut client, MAINNET_MAGIC).unwrap(),
+            P2pMessage::Hello { headers: Some(true), .. }
+        ));
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::GetHeaders { .. }));
+
+        // A peer that does not advertise headers support is asked for blocks.
+        send_message(
+            &mut client,
+            MAINNET_MAGIC,
+            &P2pMessage::Hello {
+                version: PROTOCOL_VERSION,
+                network: "mainnet".to_string(),
+                node_id: "client-test".to_string(),
+                txid_version: Some(TXID_VERSION_V2),
+                headers: None,
+            },
+        )
+        .unwrap();
+        assert!(matches!(read_message(&mut client, MAINNET_MAGIC).unwrap(), P2pMessage::Addr(_)));
+        match read_message(&mut client, MAINNET_MAGIC).unwrap() {
+            P2pMessage::GetBlocks { locator, stop_hash } => {
+                assert_eq!(locator.first(), hashes.last());
+                assert_eq!(locator.last(), Some(&genesis));
+                assert_eq!(stop_hash, [0u8; 32]);
+            }
+            other => panic!("expected getblocks, got {other:?}"),
+        }
+
+        send_message(
+            &mut client,
+            MAINNET_MAGIC,
+            &P2pMessage::GetBlocks {
+                locator: vec![genesis],
+                stop_hash: [0u8; 32],
+            },
+        )
+        .unwrap();
+        match read_message(&mut client, MAINNET_MAGIC).unwrap() {
+            P2pMessage::Inv { txids, blocks } => {
+                assert!(txids.is_empty());
+                assert_eq!(blocks, hashes);
+            }
+            other => panic!("expected block inv, got {other:?}"),
+        }
+
+        send_message(
+            &mut client,
+            MAINNET_MAGIC,
+            &P2pMessage::GetBlocks {
+                locator: vec![[9u8; 32], genesis],
+                stop_hash: hashes[1],
+            },
+        )
+        .unwrap();
+        match read_message(&mut client, MAINNET_MAGIC).unwrap() {
+            P2pMessage::Inv { blocks, .. } => assert_eq!(blocks, hashes[..2].to_vec()),
+            other => panic!("expected block inv, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn corrupt_block_file_is_quarantined_at_startup() {
+        let temp = tempfile::tempdir().unwrap();
+        let blocks_dir = temp.path().join("blocks");
+        std::fs::create_dir_all(&blocks_dir).unwrap();
+        let block_at = |time: u32| {
+            let coinbase = Transaction {
+                version: 1,
+                vin: coinbase_input(),
+                vout: vec![TxOut {
+                    value: 50,
+                    script_pubkey: vec![time as u8],
+                }],
+                lock_time: 0,
+            };
+            Block::new(1, [0u8; 32], time, INITIAL_BITS, 0, vec![coinbase]).unwrap()
+        };
+        let good = block_at(GENESIS_TIME + 600);
+        let good_hash = header_hash(&good.header);
+        persist_block(temp.path(), &good, &good_hash, None).unwrap();
+        let bad_hash = header_hash(&block_at(GENESIS_TIME + 1200).header);
+        let bad_name = format!("{}.json", hex_encode(bad_hash));
+        std::fs::write(blocks_dir.join(&bad_name), b"{\"header\": trunc").unwrap();
+
+        let logger = Logger::new(LogLevel::Error, None).unwrap();
+        let err = BlockStore::load_dir(temp.path(), false, usize::MAX, &logger).unwrap_err();
+        assert!(matches!(err, P2pError::InvalidBlock(msg) if msg.contains("corrupt block file")));
+        assert!(blocks_dir.join(&bad_name).exists());
+
+        let store = BlockStore::load_dir(temp.path(), true, usize::MAX, &logger).unwrap();
+        assert!(store.contains(&good_hash));
+        assert!(!store.contains(&bad_hash));
+        assert_eq!(store.missing(), vec![bad_hash]);
+        assert!(!blocks_dir.join(&bad_name).exists());
+        assert!(blocks_dir.join("quarantine").join(&bad_name).exists());
+    }
+
+    #[test]
+    fn node_id_is_explicit_or_persisted() {
+        let temp = tempfile::tempdir().unwrap();
+        let explicit = resolve_node_id(Some("seed-a".to_string()), Some(temp.path())).unwrap();
+        assert_eq!(explicit, "seed-a");
+        assert!(!temp.path().join(NODE_ID_FILE).exists());
+        assert!(resolve_node_id(Some(String::new()), None).is_err());
+        assert!(resolve_node_id(Some("x".repeat(MAX_NODE_ID_LEN + 1)), None).is_err());
+
+        let first = resolve_node_id(None, Some(temp.path())).unwrap();
+        assert!(first.starts_with("node-") && first.len() == "node-".len() + 16);
+        // Simulated restart: same data dir, no flag.
+        let second = resolve_node_id(None, Some(temp.path())).unwrap();
+        assert_eq!(first, second);
+        assert_ne!(first, resolve_node_id(None, None).unwrap());
+    }
+
+    #[test]
+    fn larger_dbcache_flushes_less() {
+        let blocks: Vec<Block> = (1..=10u32)
+            .map(|i| {
+                let coinbase = Transaction {
+                    version: 1,
+                    vin: coinbase_input(),
+                    vout: vec![TxOut {
+                        value: 50,
+                        script_pubkey: vec![i as u8],
+                    }],
+                    lock_time: 0,
+                };
+                Block::new(1, [0u8; 32], GENESIS_TIME + i, INITIAL_BITS, 0, vec![coinbase]).unwrap()
+            })
+            .collect();
+        let flushes_with = |dbcache_bytes: usize| {
+            let temp = tempfile::tempdir().unwrap();
+            let db = sled::Config::new().temporary(true).open().unwrap();
+            let utxos = InMemoryUtxoSet::new();
+            let mut flush = FlushState::new(dbcache_bytes);
+            for block in &blocks {
+                let hash = header_hash(&block.header);
+                persist_accepted(
+                    temp.path(),
+                    block,
+                    &hash,
+                    &utxos,
+                    &hash,
+                    Some(db.clone()),
+                    &mut flush,
+                )
+                .unwrap();
+            }
+            flush.flushes
+        };
+        assert_eq!(flushes_with(0), 10);
+        assert!(flushes_with(16 * 1024) < 10);
+        assert_eq!(flushes_with(1024 * 1024), 0);
+    }
+
+    #[test]
+    fn capped_block_store_reads_evicted_blocks_from_disk() {
+        let temp = tempfile::tempdir().unwrap();
+        let logger = Logger::new(LogLevel::Error, None).unwrap();
+        let mut store = BlockStore::load_dir(temp.path(), false, 0, &logger).unwrap();
+        let coinbase = Transaction {
+            version: 1,
+            vin: coinbase_input(),
+            vout: vec![TxOut {
+                value: 50,
+                script_pubkey: vec![1],
+            }],
+            lock_time: 0,
+        };
+        let block = Block::new(1, [0u8; 32], GENESIS_TIME, INITIAL_BITS, 0, vec![coinbase]).unwrap();
+        let hash = header_hash(&block.header);
+
+        // Not on disk yet: must stay in memory despite the zero cap.
+        store.insert(hash, block.clone());
+        assert!(store.map.contains_key(&hash));
+
+        persist_block(temp.path(), &block, &hash, None).unwrap();
+        store.evict_persisted();
+        assert!(store.map.is_empty());
+        assert!(store.contains(&hash));
+        assert_eq!(store.get(&hash), Some(block));
+    }
+
+    #[test]
+    fn persist_replaces_existing_files_in_place() {
+        use tenebrium_utxo::OutPoint;
+
+        let temp = tempfile::tempdir().unwrap();
+        let coinbase = |tag: u8| Transaction {
+            version: 1,
+            vin: coinbase_input(),
+            vout: vec![TxOut {
+                value: 50,
+                script_pubkey: vec![tag],
+            }],
+            lock_time: 0,
+        };
+        let block = Block::new(1, [0u8; 32], GENESIS_TIME, INITIAL_BITS, 0, vec![coinbase(1)]).unwrap();
+        let hash = header_hash(&block.header);
+        let path = block_file_path(temp.path(), &hash);
+
+        // A damaged copy and a leftover temp file from an interrupted write.
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"{\"truncated").unwrap();
+        std::fs::write(temp.path().join("blocks").join(format!("{}.json.tmp", hex_encode(hash))), b"junk")
+            .unwrap();
+        for _ in 0..2 {
+            persist_block(temp.path(), &block, &hash, None).unwrap();
+            let stored: Block = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+            assert_eq!(stored, block);
+        }
+        let leftovers: Vec<_> = std::fs::read_dir(temp.path().join("blocks"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(leftovers.len(), 1);
+
+        let utxo_path = temp.path().join("utxo.jsonl");
+        let mut utxos = InMemoryUtxoSet::new();
+        for tag in [1u8, 2] {
+            utxos.insert(
+                OutPoint {
+                    txid: [tag; 32],
+                    vout: 0,
+                },
+                TxOut {
+                    value: tag as u64,
+                    script_pubkey: vec![tag],
+                },
+            );
+            persist_utxos(temp.path(), &utxos, &hash, None).unwrap();
+            let lines = std::fs::read_to_string(&utxo_path).unwrap();
+            assert_eq!(lines.lines().count(), tag as usize);
+        }
+        assert!(!temp.path().join("utxo.jsonl.tmp").exists());
+    }
+
+    #[test]
+    fn stats_thread_exports_metrics_file() {
+        use tenebrium_utxo::{OutPoint, TxIn, UtxoSet};
+
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("metrics.json");
+        let funding = OutPoint {
+            txid: [5u8; 32],
+            vout: 0,
+        };
+        let mut utxo_set = InMemoryUtxoSet::new();
+        utxo_set.insert(
+            funding.clone(),
+            TxOut {
+                value: 100,
+                script_pubkey: vec![1],
+            },
+        );
+        let tx = Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: funding,
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value: 90,
+                script_pubkey: vec![2],
+            }],
+            lock_time: 0,
+        };
+        let mut pool = Mempool::new(MempoolConfig {
+            dust_threshold: 0,
+            ..MempoolConfig::default()
+        });
+        pool.add_tx(tx.clone(), &utxo_set).unwrap();
+        let mut seen_state = Seen::default();
+        seen_state.tx.insert(tx.txid_v2().unwrap());
+        seen_state.txs_accepted = 1;
+
+        let chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = hex_encode(chain.tip_hash());
+        spawn_stats_thread(
+            Arc::new(Mutex::new(PeerManager::new(vec!["127.0.0.1:1".to_string()]))),
+            Arc::new(Mutex::new(pool)),
+            utxo_set.reader(),
+            Arc::new(Mutex::new(chain)),
+            Arc::new(Mutex::new(seen_state)),
+            1,
+            Some(path.clone()),
+            0,
+            None,
+            Arc::new(Logger::new(LogLevel::Error, None).unwrap()),
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !path.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        let metrics: NodeMetrics =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(metrics.mempool_txs, 1);
+        assert!(metrics.mempool_bytes > 0);
+        assert_eq!(metrics.utxo_count, 1);
+        assert!(metrics.utxo_bytes > 0);
+        assert_eq!(metrics.tip, genesis);
+        assert_eq!(metrics.height, 0);
+        assert_eq!(metrics.difficulty, 1.0);
+        assert!(metrics.hashrate > 0.0);
+        assert_eq!(metrics.txs_seen, 1);
+        assert_eq!(metrics.txs_accepted, 1);
+        assert_eq!(metrics.blocks_accepted, 0);
+        assert!(!metrics.stale);
+        assert!(!temp.path().join("metrics.json.tmp").exists());
+    }
+
+    #[test]
+    fn old_tip_is_reported_stale() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        assert!(tip_is_stale(1_000, 1_000 + 86_401, Some(day)));
+        assert!(!tip_is_stale(1_000, 1_000 + 86_400, Some(day)));
+        assert!(!tip_is_stale(1_000, 10_000_000, None));
+        // A tip from the future (clock skew) is not stale.
+        assert!(!tip_is_stale(2_000, 1_000, Some(day)));
+
+        let metrics_for = |chain: ChainState| {
+            collect_metrics(
+                &Arc::new(Mutex::new(PeerManager::new(vec![]))),
+                &Arc::new(Mutex::new(Mempool::new(MempoolConfig::default()))),
+                &ConcurrentUtxoSet::new(),
+                &Arc::new(Mutex::new(chain)),
+                &Arc::new(Mutex::new(Seen::default())),
+                Some(day),
+            )
+        };
+        let mut old = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = old.tip_hash();
+        let stale_header = BlockHeader {
+            version: 1,
+            prev_block_hash: genesis,
+            merkle_root: [0u8; 32],
+            time: now - 2 * 24 * 60 * 60,
+            bits: INITIAL_BITS,
+            nonce: 0,
+        };
+        old.add_header(&stale_header, true).unwrap();
+        let metrics = metrics_for(old);
+        assert_eq!(metrics.tip_time, stale_header.time);
+        assert!(metrics.stale);
+
+        let mut fresh = ChainState::with_genesis(None, NetworkParams::mainnet());
+        fresh
+            .add_header(&BlockHeader { time: now, ..stale_header }, true)
+            .unwrap();
+        assert!(!metrics_for(fresh).stale);
+    }
+
+    #[test]
+    fn rpc_methods_answer_over_loopback() {
+        let funding = OutPoint {
+            txid: [40u8; 32],
+            vout: 1,
+        };
+        let coin = TxOut {
+            value: 10_000,
+            script_pubkey: vec![0xab, 0xcd],
+        };
+        let spend = |value: u64| Transaction {
+            version: 1,
+            vin: vec![TxIn {
+                prevout: funding.clone(),
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            vout: vec![TxOut {
+                value,
+                script_pubkey: vec![value as u8],
+            }],
+            lock_time: 0,
+        };
+        let chain = ChainState::with_genesis(None, NetworkParams::mainnet());
+        let genesis = chain.tip_hash();
+        let mut utxos = InMemoryUtxoSet::new();
+        utxos.insert(funding.clone(), coin.clone());
+        let utxo_reader = utxos.reader();
+        let utxos = Arc::new(Mutex::new(utxos));
+        let temp = tempfile::tempdir().unwrap();
+        let db = open_sled(temp.path()).unwrap();
+        let node = NodeRpc {
+            mempool: Arc::new(Mutex::new(Mempool::new(MempoolConfig::default()))),
+            utxos: Arc::clone(&utxos),
+            utxo_reader,
+            chain: Arc::new(Mutex::new(chain)),
+            peers: Arc::new(Mutex::new(PeerManager::new(vec![]))),
+            db: Some(db.clone()),
+            magic: MAINNET_MAGIC,
+            hello: local_hello("mainnet", "test", 2),
+            txid_version: 2,
+            logger: Arc::new(Logger::new(LogLevel::Error, None).unwrap()),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || rpc::serve(listener, Arc::new(node)));
+        let call = |method: &str, params: serde_json::Value| rpc::post(&addr, method, params);
+
+        assert_eq!(call("getblockcount", json!([]))["result"], json!(0));
+        assert_eq!(
+            call("getbestblockhash", json!([]))["result"],
+            json!(txid_to_display_hex(&genesis))
+        );
+        assert_eq!(call("getrawmempool", json!([]))["result"], json!([]));
+        assert_eq!(
+            call("getutxo", json!([funding]))["result"],
+            serde_json::to_value(&coin).unwrap()
+        );
+        let unknown = OutPoint { txid: [41u8; 32], vout: 0 };
+        assert_eq!(call("getutxo", json!([unknown]))["result"], serde_json::Value::Null);
+
+        let display = txid_to_display_hex(&funding.txid);
+        let txout = call("gettxout", json!([display, 1]))["result"].clone();
+        assert_eq!(txout["value"], json!(10_000));
+        assert_eq!(txout["script_pubkey"], json!("abcd"));
+        assert_eq!(txout["bestblock"], json!(txid_to_display_hex(&genesis)));
+        assert_eq!(call("gettxout", json!([display, 0]))["result"], serde_json::Value::Null);
+        {
+            // Lookups do not wait for a block being connected under the mutex.
+            let _connecting = utxos.lock().unwrap();
+            assert_eq!(
+                call("getutxo", json!([funding]))["result"],
+                serde_json::to_value(&coin).unwrap()
+            );
+            assert_eq!(call("gettxout", json!([display, 1]))["result"]["value"], json!(10_000));
+        }
+        assert_eq!(call("gettxout", json!(["zz", 1]))["error"]["code"], json!(rpc::INVALID_PARAMS));
+
+        let tx = spend(9_000);
+        let txid = txid_to_display_hex(&tx.txid_v2().unwrap());
+        assert_eq!(call("sendrawtransaction", json!([tx]))["result"], json!(txid));
+        assert_eq!(call("getrawmempool", json!([]))["result"], json!([txid]));
+        // A double spend paying no more fee is refused by the mempool.
+        let conflict = serde_json::to_string(&spend(9_500)).unwrap();
+        assert_eq!(
+            call("sendrawtransaction", json!([conflict]))["error"]["code"],
+            json!(rpc::REJECTED)
+        );
+        assert_eq!(
+            call("sendrawtransaction", json!(["garbage"]))["error"]["code"],
+            json!(rpc::INVALID_PARAMS)
+        );
+        assert_eq!(call("getblock", json!([]))["error"]["code"], json!(rpc::METHOD_NOT_FOUND));
+
+        assert_eq!(call("getspendingtx", json!([display, 1]))["result"], serde_json::Value::Null);
+        let mined = spend(8_000);
+        // Only the spend matters to the index.
+        let block = Block::new(1, genesis, GENESIS_TIME + 1, INITIAL_BITS, 0, vec![mined.clone()]).unwrap();
+        index_spends(&db, &block).unwrap();
+        assert_eq!(
+            call("getspendingtx", json!([display, 1]))["result"],
+            json!(txid_to_display_hex(&mined.txid_v2().unwrap()))
+        );
+        assert_eq!(call("getspendingtx", json!([display, 0]))["result"], serde_json::Value::Null);
+        assert_eq!(call("getspendingtx", json!([display]))["error"]["code"], json!(rpc::INVALID_PARAMS));
+    }
+}
+
+fn block_file_path(dir: &Path, hash: &[u8; 32]) -> PathBuf {
+    dir.join("blocks").join(format!("{}.json", hex_encode(hash)))
+}
+
+/// Rough in-memory footprint of a block, for the block store cap.
+fn block_size_estimate(block: &Block) -> usize {
+    80 + block.txs.iter().map(Transaction::size).sum::<usize>()
+}
+
+/// Parse a stored block file and check it is named after its header hash.
+fn read_block_file(path: &Path, name_hash: Option<[u8; 32]>) -> Result<([u8; 32], Block), String> {
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    let block: Block = serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+    let hash = name_hash.ok_or_else(|| "file name is not a block hash".to_string())?;
+    if header_hash(&block.header) != hash {
+        return Err("header hash does not match file name".to_string());
+    }
+    Ok((hash, block))
 }
 
 impl BlockStore {
+    /// Load `<dir>/blocks/*.json`. A file that does not parse, or whose
+    /// header does not hash to its file name, fails the load unless
+    /// `quarantine` is set, in which case it is moved to
+    /// `blocks/quarantine/` and its hash is queued for re-download.
+    ///
+    /// Blocks beyond `cap_bytes` are only kept on disk and read back on
+    /// demand.
+    fn load_dir(
+        dir: &Path,
+        quarantine: bool,
+        cap_bytes: usize,
+        logger: &Logger,
+    ) -> Result<Self, P2pError> {
+        let mut store = Self {
+            dir: Some(dir.to_path_buf()),
+            cap_bytes: Some(cap_bytes),
+            ..Self::default()
+        };
+        let blocks_dir = dir.join("blocks");
+        if !blocks_dir.is_dir() {
+            return Ok(store);
+        }
+        for entry in std::fs::read_dir(&blocks_dir)? {
+            let path = entry?.path();
+            if !path.is_file() || path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let name_hash = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| hex::decode(stem).ok())
+                .and_then(|bytes| decode_hash(&bytes).ok());
+            let reason = match read_block_file(&path, name_hash) {
+                Ok((hash, block)) => {
+                    store.insert(hash, block);
+                    continue;
+                }
+                Err(reason) => reason,
+            };
+            if !quarantine {
+                return Err(P2pError::InvalidBlock(format!(
+                    "corrupt block file {}: {reason}",
+                    path.display()
+                )));
+            }
+            let quarantine_dir = blocks_dir.join("quarantine");
+            std::fs::create_dir_all(&quarantine_dir)?;
+            if let Some(name) = path.file_name() {
+                std::fs::rename(&path, quarantine_dir.join(name))?;
+            }
+            logger.warn(format!(
+                "quarantined corrupt block file {}: {reason}",
+                path.display()
+            ));
+            if let Some(hash) = name_hash {
+                store.missing.insert(hash);
+            }
+        }
+        Ok(store)
+    }
+
     fn insert(&mut self, hash: [u8; 32], block: Block) {
-        self.map.insert(hash, block);
+        self.missing.remove(&hash);
+        let size = block_size_estimate(&block);
+        if let Some(old) = self.map.insert(hash, block) {
+            self.bytes = self.bytes.saturating_sub(block_size_estimate(&old));
+        } else {
+            self.order.push_back(hash);
+        }
+        self.bytes += size;
+        self.evict_persisted();
+    }
+
+    /// Drop the oldest blocks that already have a file on disk until the
+    /// store fits its cap. Unpersisted blocks stay in memory.
+    fn evict_persisted(&mut self) {
+        let (Some(cap), Some(dir)) = (self.cap_bytes, self.dir.as_ref()) else {
+            return;
+        };
+        let mut kept = Vec::new();
+        while self.bytes > cap {
+            let Some(hash) = self.order.pop_front() else {
+                break;
+            };
+            if !block_file_path(dir, &hash).exists() {
+                kept.push(hash);
+                continue;
+            }
+            if let Some(block) = self.map.remove(&hash) {
+                self.bytes = self.bytes.saturating_sub(block_size_estimate(&block));
+            }
+        }
+        for hash in kept.into_iter().rev() {
+            self.order.push_front(hash);
+        }
+    }
+
+    fn missing(&self) -> Vec<[u8; 32]> {
+        self.missing.iter().copied().collect()
     }
 
     fn get(&self, hash: &[u8; 32]) -> Option<Block> {
-        self.map.get(hash).cloned()
+        if let Some(block) = self.map.get(hash) {
+            return Some(block.clone());
+        }
+        let dir = self.dir.as_ref()?;
+        read_block_file(&block_file_path(dir, hash), Some(*hash))
+            .ok()
+            .map(|(_, block)| block)
     }
 
     fn contains(&self, hash: &[u8; 32]) -> bool {
         self.map.contains_key(hash)
+            || self
+                .dir
+                .as_ref()
+                .is_some_and(|dir| block_file_path(dir, hash).exists())
     }
 }
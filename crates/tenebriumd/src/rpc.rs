@@ -0,0 +1,217 @@
+//! Minimal HTTP JSON-RPC 2.0 transport behind `--rpc-listen`.
+//!
+//! Each connection carries one POST whose body is a single JSON-RPC request
+//! object; batches are not supported. The reply is always an HTTP 200 with a
+//! `{jsonrpc, result | error, id}` envelope, except for requests that are not
+//! readable HTTP at all. Method dispatch is left to an [`RpcHandler`], so
+//! this module knows nothing about node state.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// Server-defined code for a well-formed call the node refused, e.g. a
+/// transaction the mempool rejected
+pub const REJECTED: i64 = -32000;
+
+/// Largest request body accepted
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+/// Largest request line or header line accepted
+const MAX_LINE_BYTES: usize = 8 * 1024;
+const READ_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+/// Answers JSON-RPC calls. `params` is the request's `params` member, or an
+/// empty array when it was omitted.
+pub trait RpcHandler: Send + Sync {
+    fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError>;
+}
+
+/// Serve `listener` until accepting fails, one thread per connection.
+pub fn serve(listener: TcpListener, handler: Arc<dyn RpcHandler>) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let handler = Arc::clone(&handler);
+        thread::spawn(move || {
+            let _ = handle_connection(stream, &*handler);
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(mut stream: TcpStream, handler: &dyn RpcHandler) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    match read_http_body(&mut reader) {
+        Ok(body) => {
+            let response = serde_json::to_vec(&dispatch(&body, handler))?;
+            write_http(&mut stream, "200 OK", &response)
+        }
+        Err(err) => write_http(&mut stream, "400 Bad Request", err.to_string().as_bytes()),
+    }
+}
+
+/// Skip the request line and headers, then read `Content-Length` bytes.
+fn read_http_body(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut content_length = None;
+    let mut first = true;
+    loop {
+        let mut line = String::new();
+        let read = reader.by_ref().take(MAX_LINE_BYTES as u64).read_line(&mut line)?;
+        if read == 0 || !line.ends_with('\n') {
+            return Err(bad("truncated request head"));
+        }
+        let line = line.trim_end();
+        if first {
+            if !line.starts_with("POST ") {
+                return Err(bad("expected a POST request"));
+            }
+            first = false;
+            continue;
+        }
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                let len: usize = value.trim().parse().map_err(|_| bad("bad Content-Length"))?;
+                content_length = Some(len);
+            }
+        }
+    }
+    let len = content_length.ok_or_else(|| bad("missing Content-Length"))?;
+    if len > MAX_BODY_BYTES {
+        return Err(bad("request body too large"));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn write_http(stream: &mut TcpStream, status: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/// Decode one JSON-RPC request and wrap the handler's answer in a response
+/// envelope carrying the request's `id`.
+pub fn dispatch(body: &[u8], handler: &dyn RpcHandler) -> Value {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(err) => return envelope(Value::Null, Err(RpcError::new(PARSE_ERROR, err.to_string()))),
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let version = request.get("jsonrpc").and_then(Value::as_str);
+    let Some(method) = request.get("method").and_then(Value::as_str).filter(|_| version == Some("2.0"))
+    else {
+        return envelope(
+            id,
+            Err(RpcError::new(INVALID_REQUEST, "expected a JSON-RPC 2.0 request object")),
+        );
+    };
+    let params = request.get("params").cloned().unwrap_or_else(|| json!([]));
+    envelope(id, handler.call(method, &params))
+}
+
+fn envelope(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
+
+/// POST one call to a server at `addr` and return the decoded envelope.
+#[cfg(test)]
+pub(crate) fn post(addr: &str, method: &str, params: Value) -> Value {
+    let body = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }).to_string();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    serde_json::from_str(body).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl RpcHandler for Echo {
+        fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+            match method {
+                "echo" => Ok(params.clone()),
+                _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {method}"))),
+            }
+        }
+    }
+
+    #[test]
+    fn envelopes_results_and_errors() {
+        let ok = dispatch(br#"{"jsonrpc":"2.0","method":"echo","params":[1],"id":"a"}"#, &Echo);
+        assert_eq!(ok, json!({ "jsonrpc": "2.0", "result": [1], "id": "a" }));
+        let no_params = dispatch(br#"{"jsonrpc":"2.0","method":"echo","id":2}"#, &Echo);
+        assert_eq!(no_params["result"], json!([]));
+
+        let code = |body: &[u8]| dispatch(body, &Echo)["error"]["code"].as_i64();
+        assert_eq!(code(br#"{"jsonrpc":"2.0","method":"nope","id":3}"#), Some(METHOD_NOT_FOUND));
+        assert_eq!(code(br#"{"method":"echo","id":4}"#), Some(INVALID_REQUEST));
+        assert_eq!(code(br#"[{"jsonrpc":"2.0","method":"echo","id":5}]"#), Some(INVALID_REQUEST));
+        assert_eq!(code(b"{not json"), Some(PARSE_ERROR));
+        assert_eq!(dispatch(b"{not json", &Echo)["id"], Value::Null);
+    }
+
+    #[test]
+    fn serves_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(listener, Arc::new(Echo)));
+        assert_eq!(post(&addr, "echo", json!({ "k": "v" }))["result"], json!({ "k": "v" }));
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+    }
+}
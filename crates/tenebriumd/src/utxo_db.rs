@@ -27,6 +27,22 @@ pub trait UtxoStore {
     fn get(&self, outpoint: &OutPoint) -> Result<Option<TxOut>, UtxoDbError>;
     fn put(&mut self, outpoint: &OutPoint, txout: &TxOut) -> Result<(), UtxoDbError>;
     fn remove(&mut self, outpoint: &OutPoint) -> Result<(), UtxoDbError>;
+
+    /// Store several entries. The default calls `put` per entry.
+    fn put_batch(&mut self, entries: &[(OutPoint, TxOut)]) -> Result<(), UtxoDbError> {
+        for (outpoint, txout) in entries {
+            self.put(outpoint, txout)?;
+        }
+        Ok(())
+    }
+
+    /// Delete several outpoints. The default calls `remove` per outpoint.
+    fn remove_batch(&mut self, outpoints: &[OutPoint]) -> Result<(), UtxoDbError> {
+        for outpoint in outpoints {
+            self.remove(outpoint)?;
+        }
+        Ok(())
+    }
 }
 
 /// KV-store adapter skeleton (backend wiring to be implemented).
@@ -64,6 +80,25 @@ impl UtxoStore for KvUtxoStore {
         self.db.remove(key)?;
         Ok(())
     }
+
+    /// One sled batch, so the entries land together or not at all.
+    fn put_batch(&mut self, entries: &[(OutPoint, TxOut)]) -> Result<(), UtxoDbError> {
+        let mut batch = sled::Batch::default();
+        for (outpoint, txout) in entries {
+            batch.insert(encode_outpoint(outpoint), encode_txout(txout));
+        }
+        self.db.apply_batch(batch)?;
+        Ok(())
+    }
+
+    fn remove_batch(&mut self, outpoints: &[OutPoint]) -> Result<(), UtxoDbError> {
+        let mut batch = sled::Batch::default();
+        for outpoint in outpoints {
+            batch.remove(encode_outpoint(outpoint));
+        }
+        self.db.apply_batch(batch)?;
+        Ok(())
+    }
 }
 
 pub trait UtxoReader {
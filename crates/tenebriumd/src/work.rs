@@ -0,0 +1,226 @@
+//! 256-bit cumulative chain work.
+//!
+//! A header's work is the expected number of hashes needed to meet its target,
+//! `2^256 / (target + 1)`. Targets span the full 256-bit range, so neither
+//! per-header nor cumulative work fits a `u128` without losing precision.
+
+use serde::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Unsigned 256-bit integer as little-endian 64-bit limbs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+struct U256([u64; 4]);
+
+impl U256 {
+    const ZERO: U256 = U256([0; 4]);
+    const ONE: U256 = U256([1, 0, 0, 0]);
+    const MAX: U256 = U256([u64::MAX; 4]);
+
+    /// Expand compact `bits` into a target. `None` for negative, zero-mantissa
+    /// or overflowing encodings.
+    fn from_compact(bits: u32) -> Option<U256> {
+        let exponent = bits >> 24;
+        let mantissa = bits & 0x007f_ffff;
+        if bits & 0x0080_0000 != 0 || mantissa == 0 {
+            return None;
+        }
+        if exponent <= 3 {
+            return Some(U256::from_u128((mantissa >> (8 * (3 - exponent))) as u128));
+        }
+        let shift = 8 * (exponent - 3);
+        let value = U256::from_u128(mantissa as u128);
+        // The mantissa's top set bit must stay below bit 256.
+        if shift + (32 - mantissa.leading_zeros()) > 256 {
+            return None;
+        }
+        Some(value.shl(shift))
+    }
+
+    fn from_u128(value: u128) -> U256 {
+        U256([value as u64, (value >> 64) as u64, 0, 0])
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    fn not(self) -> U256 {
+        U256(self.0.map(|limb| !limb))
+    }
+
+    fn overflowing_add(self, other: U256) -> (U256, bool) {
+        let mut out = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in out.iter_mut().enumerate() {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = c1 || c2;
+        }
+        (U256(out), carry)
+    }
+
+    /// `self - other`; callers guarantee `self >= other`.
+    fn sub(self, other: U256) -> U256 {
+        let mut out = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in out.iter_mut().enumerate() {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            *limb = diff;
+            borrow = b1 || b2;
+        }
+        U256(out)
+    }
+
+    fn shl(self, shift: u32) -> U256 {
+        if shift >= 256 {
+            return U256::ZERO;
+        }
+        let limbs = (shift / 64) as usize;
+        let bits = shift % 64;
+        let mut out = [0u64; 4];
+        for (i, limb) in out.iter_mut().enumerate().skip(limbs) {
+            *limb = self.0[i - limbs] << bits;
+            if bits > 0 && i > limbs {
+                *limb |= self.0[i - limbs - 1] >> (64 - bits);
+            }
+        }
+        U256(out)
+    }
+
+    fn bit(&self, index: u32) -> bool {
+        self.0[(index / 64) as usize] >> (index % 64) & 1 == 1
+    }
+
+    /// Binary long division; `divisor` must be non-zero.
+    fn div(self, divisor: U256) -> U256 {
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for index in (0..256).rev() {
+            remainder = remainder.shl(1);
+            remainder.0[0] |= self.bit(index) as u64;
+            if remainder >= divisor {
+                remainder = remainder.sub(divisor);
+                quotient.0[(index / 64) as usize] |= 1 << (index % 64);
+            }
+        }
+        quotient
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Cumulative proof-of-work of a chain, compared to pick the best tip.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Work(U256);
+
+impl Work {
+    pub const ZERO: Work = Work(U256::ZERO);
+    pub const MAX: Work = Work(U256::MAX);
+
+    /// Work of one header with compact `bits`, computed as
+    /// `~target / (target + 1) + 1` so `2^256` never has to be represented.
+    /// A zero target saturates to `Work::MAX`.
+    pub fn from_bits(bits: u32) -> Option<Work> {
+        let target = U256::from_compact(bits)?;
+        if target.is_zero() {
+            return Some(Work::MAX);
+        }
+        if target == U256::MAX {
+            return Some(Work(U256::ONE));
+        }
+        let (divisor, _) = target.overflowing_add(U256::ONE);
+        let (work, _) = target.not().div(divisor).overflowing_add(U256::ONE);
+        Some(Work(work))
+    }
+
+    pub fn saturating_add(self, other: Work) -> Work {
+        match self.0.overflowing_add(other.0) {
+            (_, true) => Work::MAX,
+            (sum, false) => Work(sum),
+        }
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        for (chunk, limb) in out.chunks_exact_mut(8).zip(self.0 .0) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn from_le_bytes(bytes: [u8; 32]) -> Work {
+        let mut limbs = [0u64; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_le_bytes(chunk.try_into().expect("8-byte chunk"));
+        }
+        Work(U256(limbs))
+    }
+}
+
+impl From<u128> for Work {
+    fn from(value: u128) -> Self {
+        Work(U256::from_u128(value))
+    }
+}
+
+/// Big-endian hex, zero-padded to 64 digits (the usual `chainwork` form).
+impl fmt::Display for Work {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for limb in self.0 .0.iter().rev() {
+            write!(f, "{limb:016x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Work {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn work_matches_two_pow_256_over_target() {
+        // target = 2^255 - 2^232 + ... : about two hashes per block.
+        assert_eq!(Work::from_bits(0x207f_ffff), Some(Work::from(2)));
+        // target = 0xffff * 2^208, Bitcoin's difficulty-1 work.
+        assert_eq!(Work::from_bits(0x1d00_ffff), Some(Work::from(0x1_0001_0001)));
+        // target = 1: half of the 256-bit space.
+        assert_eq!(
+            Work::from_bits(0x0300_0001).map(Work::to_le_bytes).map(|b| b[31]),
+            Some(0x80)
+        );
+        assert_eq!(Work::from_bits(0x0100_3456), Some(Work::MAX));
+        assert_eq!(Work::from_bits(0x2200_ffff), None);
+        assert_eq!(Work::from_bits(0x0480_0000), None);
+    }
+
+    #[test]
+    fn bytes_and_display_round_trip() {
+        let work = Work::from(u128::MAX).saturating_add(Work::from(u128::MAX));
+        assert_eq!(Work::from_le_bytes(work.to_le_bytes()), work);
+        assert_eq!(
+            work.to_string(),
+            "00000000000000000000000000000001fffffffffffffffffffffffffffffffe"
+        );
+        assert!(work > Work::from(u128::MAX));
+        assert_eq!(Work::MAX.saturating_add(Work::from(1)), Work::MAX);
+    }
+}